@@ -0,0 +1,133 @@
+use reqwest::header::{HeaderMap, LOCATION};
+use reqwest::redirect::{Attempt, Policy};
+use reqwest::{StatusCode, Url};
+
+const ACCESS_LOGIN_DOMAIN: &str = "cloudflareaccess.com";
+const ACCESS_LOGIN_PATH_PREFIX: &str = "/cdn-cgi/access/login";
+const ACCESS_HEADER_PREFIX: &str = "cf-access-";
+
+const MAX_REDIRECTS: usize = 10;
+
+/// Check if the url points to the Cloudflare Access login page.
+pub fn is_access_login_url(url: &Url) -> bool {
+    let is_access_host = url.host_str().is_some_and(|host| {
+        host == ACCESS_LOGIN_DOMAIN
+            || host
+                .strip_suffix(ACCESS_LOGIN_DOMAIN)
+                .is_some_and(|subdomain| subdomain.ends_with('.'))
+    });
+
+    is_access_host || url.path().starts_with(ACCESS_LOGIN_PATH_PREFIX)
+}
+
+/// Check if the upstream response is Cloudflare Access rejecting our service token.
+pub fn is_access_rejection(status: StatusCode, headers: &HeaderMap) -> bool {
+    if status.is_redirection() {
+        return headers
+            .get(LOCATION)
+            .and_then(|location| location.to_str().ok())
+            .is_some_and(is_access_login_location);
+    }
+
+    if status == StatusCode::FORBIDDEN || status == StatusCode::UNAUTHORIZED {
+        return headers
+            .keys()
+            .any(|name| name.as_str().starts_with(ACCESS_HEADER_PREFIX));
+    }
+
+    false
+}
+
+/// Location headers can be relative, in which case only the path is checked.
+fn is_access_login_location(location: &str) -> bool {
+    match Url::parse(location) {
+        Ok(url) => is_access_login_url(&url),
+        Err(_) => location.starts_with(ACCESS_LOGIN_PATH_PREFIX),
+    }
+}
+
+/// Redirect policy that follows redirects, except towards the Cloudflare Access login page.
+pub fn redirect_policy() -> Policy {
+    Policy::custom(follow_redirect)
+}
+
+fn follow_redirect(attempt: Attempt) -> reqwest::redirect::Action {
+    if is_access_login_url(attempt.url()) {
+        debug!("Not following redirect to Cloudflare Access login page");
+        attempt.stop()
+    } else if attempt.previous().len() >= MAX_REDIRECTS {
+        attempt.error("too many redirects")
+    } else {
+        attempt.follow()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use reqwest::header::HeaderValue;
+
+    fn headers(values: Vec<(&'static str, &'static str)>) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        for (key, value) in values {
+            headers.insert(key, HeaderValue::from_static(value));
+        }
+
+        headers
+    }
+
+    #[test]
+    fn test_is_access_login_url() {
+        assert!(is_access_login_url(
+            &Url::parse("https://team.cloudflareaccess.com/cdn-cgi/access/login/example.com")
+                .unwrap()
+        ));
+        assert!(is_access_login_url(
+            &Url::parse("https://example.com/cdn-cgi/access/login/example.com").unwrap()
+        ));
+        assert!(!is_access_login_url(
+            &Url::parse("https://example.com/login").unwrap()
+        ));
+        assert!(!is_access_login_url(
+            &Url::parse("https://evilcloudflareaccess.com/login").unwrap()
+        ));
+    }
+
+    #[test]
+    fn test_is_access_rejection_redirect() {
+        let status = StatusCode::FOUND;
+
+        assert!(is_access_rejection(
+            status,
+            &headers(vec![(
+                "location",
+                "https://team.cloudflareaccess.com/cdn-cgi/access/login/example.com"
+            )])
+        ));
+        assert!(is_access_rejection(
+            status,
+            &headers(vec![("location", "/cdn-cgi/access/login/example.com")])
+        ));
+        assert!(!is_access_rejection(
+            status,
+            &headers(vec![("location", "https://example.com/other")])
+        ));
+        assert!(!is_access_rejection(status, &headers(vec![])));
+    }
+
+    #[test]
+    fn test_is_access_rejection_forbidden() {
+        assert!(is_access_rejection(
+            StatusCode::FORBIDDEN,
+            &headers(vec![("cf-access-domain", "example.com")])
+        ));
+        assert!(!is_access_rejection(
+            StatusCode::FORBIDDEN,
+            &headers(vec![("content-type", "text/html")])
+        ));
+        assert!(!is_access_rejection(
+            StatusCode::OK,
+            &headers(vec![("cf-access-domain", "example.com")])
+        ));
+    }
+}
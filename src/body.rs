@@ -0,0 +1,397 @@
+use actix_web::http::header::{CONTENT_ENCODING, CONTENT_TYPE, HeaderMap};
+use actix_web::web::Bytes;
+use serde_json::{Map, Value};
+use std::io::Read;
+use thiserror::Error;
+
+/// Content coding of a request body.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub enum ContentEncoding {
+    Identity,
+    Gzip,
+    Deflate,
+    Brotli,
+    Zstd,
+    Unsupported(String),
+}
+
+impl ContentEncoding {
+    pub fn from_headers(headers: &HeaderMap) -> Self {
+        let Some(value) = headers.get(CONTENT_ENCODING) else {
+            return ContentEncoding::Identity;
+        };
+
+        let value = String::from_utf8_lossy(value.as_bytes())
+            .trim()
+            .to_ascii_lowercase();
+        match value.as_str() {
+            "" | "identity" => ContentEncoding::Identity,
+            "gzip" | "x-gzip" => ContentEncoding::Gzip,
+            "deflate" => ContentEncoding::Deflate,
+            "br" => ContentEncoding::Brotli,
+            "zstd" => ContentEncoding::Zstd,
+            _ => ContentEncoding::Unsupported(value),
+        }
+    }
+}
+
+/// Media type of a request body, deciding how it is parsed.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub enum BodyFormat {
+    Form,
+    Multipart(String),
+    // JSON or unknown, parsed as JSON if possible
+    Other,
+}
+
+impl BodyFormat {
+    pub fn from_headers(headers: &HeaderMap) -> Self {
+        let Some(content_type) = headers
+            .get(CONTENT_TYPE)
+            .and_then(|value| value.to_str().ok())
+        else {
+            return BodyFormat::Other;
+        };
+
+        let essence = content_type
+            .split(';')
+            .next()
+            .unwrap_or_default()
+            .trim()
+            .to_ascii_lowercase();
+        match essence.as_str() {
+            "application/x-www-form-urlencoded" => BodyFormat::Form,
+            "multipart/form-data" => multer::parse_boundary(content_type)
+                .map(BodyFormat::Multipart)
+                .unwrap_or(BodyFormat::Other),
+            _ => BodyFormat::Other,
+        }
+    }
+}
+
+/// Request body as received from the caller, decoded on demand.
+#[derive(Getters, Debug)]
+#[getset(get = "pub")]
+pub struct RequestBody {
+    // Bytes as sent by the caller, signatures are computed over these
+    raw: Bytes,
+    encoding: ContentEncoding,
+    format: BodyFormat,
+    #[getset(skip)]
+    max_size: Option<usize>,
+}
+
+impl RequestBody {
+    pub fn new(raw: Bytes, headers: &HeaderMap, max_size: Option<usize>) -> Self {
+        Self {
+            raw,
+            encoding: ContentEncoding::from_headers(headers),
+            format: BodyFormat::from_headers(headers),
+            max_size,
+        }
+    }
+
+    pub fn is_encoded(&self) -> bool {
+        self.encoding != ContentEncoding::Identity
+    }
+
+    /// Decode the body, limited to the same max size as the raw body.
+    pub fn decoded(&self) -> Result<Bytes, BodyError> {
+        let reader: Box<dyn Read + '_> = match &self.encoding {
+            ContentEncoding::Identity => return Ok(self.raw.clone()),
+            ContentEncoding::Gzip => Box::new(flate2::read::MultiGzDecoder::new(&self.raw[..])),
+            ContentEncoding::Deflate => Box::new(flate2::read::ZlibDecoder::new(&self.raw[..])),
+            ContentEncoding::Brotli => Box::new(brotli::Decompressor::new(&self.raw[..], 4096)),
+            ContentEncoding::Zstd => Box::new(
+                zstd::stream::read::Decoder::new(&self.raw[..]).map_err(BodyError::Decode)?,
+            ),
+            ContentEncoding::Unsupported(encoding) => {
+                return Err(BodyError::UnsupportedEncoding(encoding.clone()));
+            }
+        };
+
+        // Read one byte more than allowed to notice decompression bombs
+        let limit = self
+            .max_size
+            .map_or(u64::MAX, |max_size| max_size as u64 + 1);
+        let mut decoded = Vec::with_capacity(self.raw.len());
+        reader
+            .take(limit)
+            .read_to_end(&mut decoded)
+            .map_err(BodyError::Decode)?;
+
+        if let Some(max_size) = self.max_size
+            && decoded.len() > max_size
+        {
+            return Err(BodyError::TooLarge(max_size));
+        }
+
+        Ok(Bytes::from(decoded))
+    }
+
+    /// The decoded body as JSON, or `None` if it is not JSON, form or multipart data.
+    ///
+    /// Form and multipart fields become an object of strings, repeated fields an array of them.
+    /// Uploaded files are described by their `filename`, `content_type` and `size`.
+    pub async fn structured(&self) -> Result<Option<Value>, BodyError> {
+        let decoded = self.decoded()?;
+        match &self.format {
+            BodyFormat::Form => {
+                let mut fields = Map::new();
+                for (name, value) in form_urlencoded::parse(&decoded) {
+                    insert_field(
+                        &mut fields,
+                        name.into_owned(),
+                        Value::String(value.into_owned()),
+                    );
+                }
+
+                Ok(Some(Value::Object(fields)))
+            }
+            BodyFormat::Multipart(boundary) => {
+                let stream = tokio_stream::once(Ok::<_, std::io::Error>(decoded));
+                let mut multipart = multer::Multipart::new(stream, boundary.as_str());
+                let mut fields = Map::new();
+                while let Some(field) = multipart.next_field().await? {
+                    let Some(name) = field.name().map(str::to_string) else {
+                        continue;
+                    };
+
+                    let value = match field.file_name().map(str::to_string) {
+                        Some(filename) => {
+                            let content_type = field.content_type().map(ToString::to_string);
+                            let size = field.bytes().await?.len();
+                            serde_json::json!({
+                                "filename": filename,
+                                "content_type": content_type,
+                                "size": size,
+                            })
+                        }
+                        None => Value::String(field.text().await?),
+                    };
+                    insert_field(&mut fields, name, value);
+                }
+
+                Ok(Some(Value::Object(fields)))
+            }
+            BodyFormat::Other => Ok(serde_json::from_slice(&decoded).ok()),
+        }
+    }
+}
+
+fn insert_field(fields: &mut Map<String, Value>, name: String, value: Value) {
+    match fields.get_mut(&name) {
+        Some(Value::Array(values)) => values.push(value),
+        Some(existing) => *existing = Value::Array(vec![existing.take(), value]),
+        None => {
+            fields.insert(name, value);
+        }
+    }
+}
+
+#[derive(Error, Debug)]
+pub enum BodyError {
+    #[error("Unsupported content encoding: {0}")]
+    UnsupportedEncoding(String),
+    #[error("Failed to decode body")]
+    Decode(#[source] std::io::Error),
+    #[error("Decoded body larger than {0} bytes")]
+    TooLarge(usize),
+    #[error("Invalid multipart body")]
+    Multipart(#[from] multer::Error),
+}
+
+impl From<BodyError> for actix_web::Error {
+    fn from(e: BodyError) -> Self {
+        match e {
+            BodyError::UnsupportedEncoding(_) => actix_web::error::ErrorUnsupportedMediaType(e),
+            BodyError::Decode(_) => actix_web::error::ErrorBadRequest(e),
+            BodyError::TooLarge(_) => actix_web::error::ErrorPayloadTooLarge(e),
+            BodyError::Multipart(_) => actix_web::error::ErrorBadRequest(e),
+        }
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod test_utils {
+    use std::io::Write;
+
+    pub fn gzip(data: &[u8]) -> Vec<u8> {
+        let mut encoder = flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::default());
+        encoder.write_all(data).unwrap();
+        encoder.finish().unwrap()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::body::test_utils::gzip;
+    use crate::body::{BodyError, BodyFormat, ContentEncoding, RequestBody};
+    use actix_web::http::header::{CONTENT_ENCODING, CONTENT_TYPE, HeaderMap, HeaderValue};
+    use actix_web::web::Bytes;
+    use std::io::Write;
+
+    const DATA: &[u8] = br#"{"action":"opened"}"#;
+
+    fn headers(encoding: &'static str) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        headers.insert(CONTENT_ENCODING, HeaderValue::from_static(encoding));
+        headers
+    }
+
+    fn request_body(raw: Vec<u8>, encoding: &'static str, max_size: Option<usize>) -> RequestBody {
+        RequestBody::new(Bytes::from(raw), &headers(encoding), max_size)
+    }
+
+    #[test]
+    fn test_from_headers() {
+        assert_eq!(
+            ContentEncoding::from_headers(&HeaderMap::new()),
+            ContentEncoding::Identity
+        );
+        assert_eq!(
+            ContentEncoding::from_headers(&headers("GZIP")),
+            ContentEncoding::Gzip
+        );
+        assert_eq!(
+            ContentEncoding::from_headers(&headers("br")),
+            ContentEncoding::Brotli
+        );
+        assert_eq!(
+            ContentEncoding::from_headers(&headers("compress")),
+            ContentEncoding::Unsupported("compress".to_string())
+        );
+    }
+
+    #[test]
+    fn test_decoded_gzip() {
+        let raw = gzip(DATA);
+        let body = request_body(raw.clone(), "gzip", None);
+
+        assert!(body.is_encoded());
+        assert_eq!(body.decoded().unwrap(), DATA);
+        assert_eq!(body.raw(), &raw);
+    }
+
+    #[test]
+    fn test_decoded_brotli() {
+        let mut raw = Vec::new();
+        {
+            let mut encoder = brotli::CompressorWriter::new(&mut raw, 4096, 5, 22);
+            encoder.write_all(DATA).unwrap();
+        }
+
+        assert_eq!(request_body(raw, "br", None).decoded().unwrap(), DATA);
+    }
+
+    #[test]
+    fn test_decoded_zstd() {
+        let raw = zstd::encode_all(DATA, 3).unwrap();
+        assert_eq!(request_body(raw, "zstd", None).decoded().unwrap(), DATA);
+    }
+
+    #[test]
+    fn test_decoded_too_large() {
+        let raw = gzip(&[0; 4096]);
+        let body = request_body(raw, "gzip", Some(1024));
+
+        assert!(matches!(body.decoded(), Err(BodyError::TooLarge(1024))));
+    }
+
+    #[test]
+    fn test_decoded_unsupported() {
+        let body = request_body(DATA.to_vec(), "compress", None);
+        assert!(matches!(
+            body.decoded(),
+            Err(BodyError::UnsupportedEncoding(_))
+        ));
+    }
+
+    #[test]
+    fn test_decoded_invalid() {
+        let body = request_body(DATA.to_vec(), "gzip", None);
+        assert!(matches!(body.decoded(), Err(BodyError::Decode(_))));
+    }
+
+    fn typed_body(raw: &[u8], content_type: &'static str) -> RequestBody {
+        let mut headers = HeaderMap::new();
+        headers.insert(CONTENT_TYPE, HeaderValue::from_static(content_type));
+        RequestBody::new(Bytes::copy_from_slice(raw), &headers, None)
+    }
+
+    #[test]
+    fn test_format_from_headers() {
+        assert_eq!(
+            typed_body(b"", "application/json").format(),
+            &BodyFormat::Other
+        );
+        assert_eq!(
+            typed_body(b"", "Application/X-WWW-Form-Urlencoded; charset=utf-8").format(),
+            &BodyFormat::Form
+        );
+        assert_eq!(
+            typed_body(b"", "multipart/form-data; boundary=abc").format(),
+            &BodyFormat::Multipart("abc".to_string())
+        );
+        assert_eq!(
+            typed_body(b"", "multipart/form-data").format(),
+            &BodyFormat::Other
+        );
+    }
+
+    #[tokio::test]
+    async fn test_structured_json() {
+        let body = typed_body(DATA, "application/json");
+        assert_eq!(
+            body.structured().await.unwrap(),
+            Some(serde_json::json!({ "action": "opened" }))
+        );
+
+        let body = typed_body(b"opened", "text/plain");
+        assert_eq!(body.structured().await.unwrap(), None);
+    }
+
+    #[tokio::test]
+    async fn test_structured_form() {
+        let body = typed_body(
+            b"command=%2Fdeploy&text=api+prod&channel=ops&channel=ci",
+            "application/x-www-form-urlencoded",
+        );
+        assert_eq!(
+            body.structured().await.unwrap(),
+            Some(serde_json::json!({
+                "command": "/deploy",
+                "text": "api prod",
+                "channel": ["ops", "ci"],
+            }))
+        );
+    }
+
+    #[tokio::test]
+    async fn test_structured_multipart() {
+        let raw = concat!(
+            "--abc\r\n",
+            "Content-Disposition: form-data; name=\"From\"\r\n\r\n",
+            "+15550100\r\n",
+            "--abc\r\n",
+            "Content-Disposition: form-data; name=\"media\"; filename=\"a.txt\"\r\n",
+            "Content-Type: text/plain\r\n\r\n",
+            "hello\r\n",
+            "--abc--\r\n",
+        );
+        let body = typed_body(raw.as_bytes(), "multipart/form-data; boundary=abc");
+        assert_eq!(
+            body.structured().await.unwrap(),
+            Some(serde_json::json!({
+                "From": "+15550100",
+                "media": { "filename": "a.txt", "content_type": "text/plain", "size": 5 },
+            }))
+        );
+
+        let body = typed_body(b"--abc\r\nbroken", "multipart/form-data; boundary=abc");
+        assert!(matches!(
+            body.structured().await,
+            Err(BodyError::Multipart(_))
+        ));
+    }
+}
@@ -0,0 +1,594 @@
+use crate::Result;
+use crate::access;
+use crate::config::{ProxyConfig, TlsVersion, UpstreamTlsConfig};
+use crate::error::Error;
+use crate::tls;
+use base64::Engine;
+use reqwest_middleware::{ClientBuilder, ClientWithMiddleware};
+use reqwest_tracing::{SpanBackendWithUrl, TracingMiddleware};
+use rustls::client::WebPkiServerVerifier;
+use rustls::client::danger::{HandshakeSignatureValid, ServerCertVerified, ServerCertVerifier};
+use rustls::crypto::WebPkiSupportedAlgorithms;
+use rustls::pki_types::{CertificateDer, ServerName, UnixTime};
+use rustls::{DigitallySignedStruct, RootCertStore, SignatureScheme};
+use secrecy::ExposeSecret;
+use std::sync::Arc;
+use std::time::Duration;
+
+// Clients for other endpoints than targets limit their requests themselves
+const DEFAULT_CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Create a client without an overall request timeout.
+/// Proxy environment variables are only used without a configured proxy and when enabled.
+pub fn build_client(
+    tls: Option<&UpstreamTlsConfig>,
+    proxy: Option<&ProxyConfig>,
+    proxy_from_env: bool,
+) -> Result<ClientWithMiddleware> {
+    build_client_from(
+        reqwest::Client::builder().connect_timeout(DEFAULT_CONNECT_TIMEOUT),
+        tls,
+        proxy,
+        proxy_from_env,
+    )
+}
+
+/// Create the client used to forward requests to a target, so a hung target doesn't hold its
+/// concurrency slot and circuit breaker probe forever.
+pub fn build_client_with_timeout(
+    tls: Option<&UpstreamTlsConfig>,
+    proxy: Option<&ProxyConfig>,
+    proxy_from_env: bool,
+    connect_timeout: Duration,
+    timeout: Duration,
+) -> Result<ClientWithMiddleware> {
+    build_client_from(
+        reqwest::Client::builder()
+            .connect_timeout(connect_timeout)
+            .timeout(timeout),
+        tls,
+        proxy,
+        proxy_from_env,
+    )
+}
+
+fn build_client_from(
+    builder: reqwest::ClientBuilder,
+    tls: Option<&UpstreamTlsConfig>,
+    proxy: Option<&ProxyConfig>,
+    proxy_from_env: bool,
+) -> Result<ClientWithMiddleware> {
+    let mut builder = builder.redirect(access::redirect_policy());
+    if let Some(tls) = tls {
+        builder = builder.use_preconfigured_tls(tls_config(tls)?);
+    }
+
+    builder = match proxy {
+        Some(proxy) => builder.proxy(build_proxy(proxy)?),
+        None if proxy_from_env => builder,
+        None => builder.no_proxy(),
+    };
+
+    Ok(ClientBuilder::new(builder.build()?)
+        .with(TracingMiddleware::<SpanBackendWithUrl>::new())
+        .build())
+}
+
+pub fn build_proxy(config: &ProxyConfig) -> Result<reqwest::Proxy> {
+    let mut proxy = reqwest::Proxy::all(config.url())?;
+    if let Some(username) = config.username() {
+        let password = config
+            .password()
+            .as_ref()
+            .map(|password| password.expose_secret())
+            .unwrap_or_default();
+        proxy = proxy.basic_auth(username, password);
+    }
+
+    if let Some(no_proxy) = config.no_proxy() {
+        proxy = proxy.no_proxy(reqwest::NoProxy::from_string(no_proxy));
+    }
+
+    Ok(proxy)
+}
+
+pub fn tls_config(config: &UpstreamTlsConfig) -> Result<rustls::ClientConfig> {
+    let provider = tls::crypto_provider();
+
+    let mut roots = RootCertStore {
+        roots: webpki_roots::TLS_SERVER_ROOTS.to_vec(),
+    };
+    for ca_path in config.ca_paths() {
+        for certificate in tls::load_certificates(ca_path)? {
+            roots.add(certificate)?;
+        }
+    }
+
+    let versions: &[&rustls::SupportedProtocolVersion] = match config.min_version() {
+        TlsVersion::Tls12 => &[&rustls::version::TLS13, &rustls::version::TLS12],
+        TlsVersion::Tls13 => &[&rustls::version::TLS13],
+    };
+
+    let roots = Arc::new(roots);
+    let verifier = WebPkiServerVerifier::builder_with_provider(roots.clone(), provider.clone())
+        .build()
+        .map_err(|e| Error::custom(format!("Failed to build server verifier: {e}")))?;
+
+    let algorithms = provider.signature_verification_algorithms;
+    let builder =
+        rustls::ClientConfig::builder_with_provider(provider).with_protocol_versions(versions)?;
+    let builder = if config.spki_pins().is_empty() {
+        builder.with_webpki_verifier(verifier)
+    } else {
+        builder
+            .dangerous()
+            .with_custom_certificate_verifier(Arc::new(PinnedServerVerifier::new(
+                verifier,
+                roots,
+                algorithms,
+                config.spki_pins(),
+            )?))
+    };
+
+    match (config.client_cert_path(), config.client_key_path()) {
+        (Some(cert_path), Some(key_path)) => Ok(builder.with_client_auth_cert(
+            tls::load_certificates(cert_path)?,
+            tls::load_private_key(key_path)?,
+        )?),
+        (None, None) => Ok(builder.with_no_client_auth()),
+        _ => Err(Error::custom(
+            "Client certificate and key must be configured together",
+        )),
+    }
+}
+
+/// Verifies the certificate chain like the default verifier and additionally
+/// requires one certificate of the verified path to use a pinned public key.
+#[derive(Debug)]
+struct PinnedServerVerifier {
+    inner: Arc<WebPkiServerVerifier>,
+    roots: Arc<RootCertStore>,
+    algorithms: WebPkiSupportedAlgorithms,
+    pins: Vec<Vec<u8>>,
+}
+
+impl PinnedServerVerifier {
+    fn new(
+        inner: Arc<WebPkiServerVerifier>,
+        roots: Arc<RootCertStore>,
+        algorithms: WebPkiSupportedAlgorithms,
+        pins: &[String],
+    ) -> Result<Self> {
+        let pins = pins
+            .iter()
+            .map(|pin| {
+                let pin = pin.strip_prefix("sha256/").unwrap_or(pin);
+                base64::engine::general_purpose::STANDARD
+                    .decode(pin)
+                    .map_err(|e| Error::custom(format!("Invalid SPKI pin {pin}: {e}")))
+            })
+            .collect::<Result<Vec<_>>>()?;
+
+        Ok(Self {
+            inner,
+            roots,
+            algorithms,
+            pins,
+        })
+    }
+
+    fn is_pinned(&self, spki: &[u8]) -> bool {
+        let hash = ring::digest::digest(&ring::digest::SHA256, spki);
+        self.pins.iter().any(|pin| pin.as_slice() == hash.as_ref())
+    }
+
+    /// Certificates the server sends but that are not part of the path to a root are ignored,
+    /// anyone could append the public certificate of a pinned CA to their chain.
+    fn verify_pinned_path(
+        &self,
+        end_entity: &CertificateDer<'_>,
+        intermediates: &[CertificateDer<'_>],
+        now: UnixTime,
+    ) -> std::result::Result<(), webpki::Error> {
+        let end_entity = webpki::EndEntityCert::try_from(end_entity)?;
+        let is_pinned_path = |path: &webpki::VerifiedPath<'_>| {
+            let anchor_spki = der_sequence(path.anchor().subject_public_key_info.as_ref());
+            let pinned = self.is_pinned(&anchor_spki)
+                || std::iter::once(&**path.end_entity())
+                    .chain(path.intermediate_certificates())
+                    .any(|certificate| self.is_pinned(&certificate.subject_public_key_info()));
+            match pinned {
+                true => Ok(()),
+                false => Err(webpki::Error::UnknownIssuer),
+            }
+        };
+
+        end_entity
+            .verify_for_usage(
+                self.algorithms.all,
+                &self.roots.roots,
+                intermediates,
+                now,
+                webpki::KeyUsage::server_auth(),
+                None,
+                Some(&is_pinned_path),
+            )
+            .map(|_| ())
+    }
+}
+
+/// Wrap the content of a SEQUENCE, trust anchors only keep the content of their SubjectPublicKeyInfo.
+fn der_sequence(content: &[u8]) -> Vec<u8> {
+    let length = content.len().to_be_bytes();
+    let length = match content.len() {
+        0..0x80 => vec![length[length.len() - 1]],
+        _ => {
+            let bytes = length.iter().skip_while(|byte| **byte == 0);
+            let mut encoded = vec![0x80 | bytes.clone().count() as u8];
+            encoded.extend(bytes);
+            encoded
+        }
+    };
+
+    let mut sequence = Vec::with_capacity(1 + length.len() + content.len());
+    sequence.push(0x30);
+    sequence.extend(length);
+    sequence.extend_from_slice(content);
+    sequence
+}
+
+/// SHA-256 hash of the SubjectPublicKeyInfo of the certificate.
+pub fn spki_hash(certificate: &CertificateDer<'_>) -> Option<Vec<u8>> {
+    let (_, certificate) = x509_parser::parse_x509_certificate(certificate.as_ref()).ok()?;
+    let hash = ring::digest::digest(&ring::digest::SHA256, certificate.public_key().raw);
+    Some(hash.as_ref().to_vec())
+}
+
+impl ServerCertVerifier for PinnedServerVerifier {
+    fn verify_server_cert(
+        &self,
+        end_entity: &CertificateDer<'_>,
+        intermediates: &[CertificateDer<'_>],
+        server_name: &ServerName<'_>,
+        ocsp_response: &[u8],
+        now: UnixTime,
+    ) -> std::result::Result<ServerCertVerified, rustls::Error> {
+        let verified = self.inner.verify_server_cert(
+            end_entity,
+            intermediates,
+            server_name,
+            ocsp_response,
+            now,
+        )?;
+
+        match self.verify_pinned_path(end_entity, intermediates, now) {
+            Ok(()) => Ok(verified),
+            Err(e) => Err(rustls::Error::General(format!(
+                "Certificate chain does not match any SPKI pin: {e}"
+            ))),
+        }
+    }
+
+    fn verify_tls12_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> std::result::Result<HandshakeSignatureValid, rustls::Error> {
+        self.inner.verify_tls12_signature(message, cert, dss)
+    }
+
+    fn verify_tls13_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> std::result::Result<HandshakeSignatureValid, rustls::Error> {
+        self.inner.verify_tls13_signature(message, cert, dss)
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+        self.inner.supported_verify_schemes()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tls::test_utils::TestTlsServer;
+    use std::path::PathBuf;
+
+    fn config(values: serde_json::Value) -> UpstreamTlsConfig {
+        serde_json::from_value(values).unwrap()
+    }
+
+    async fn get(client: &ClientWithMiddleware, server: &TestTlsServer) -> Option<String> {
+        match client.get(server.url()).send().await {
+            Ok(response) => Some(response.text().await.unwrap()),
+            Err(_) => None,
+        }
+    }
+
+    fn pin(server: &TestTlsServer) -> String {
+        let hash = spki_hash(server.server_key.cert.der()).unwrap();
+        base64::engine::general_purpose::STANDARD.encode(hash)
+    }
+
+    #[actix_web::test]
+    async fn test_build_client_unknown_ca() {
+        let server = TestTlsServer::start(false);
+
+        let client = build_client(Some(&UpstreamTlsConfig::default()), None, false).unwrap();
+        assert_eq!(get(&client, &server).await, None);
+
+        server.stop().await;
+    }
+
+    #[actix_web::test]
+    async fn test_build_client_custom_ca() {
+        let server = TestTlsServer::start(false);
+
+        let client = build_client(
+            Some(&config(serde_json::json!({
+                "ca_paths": [server.ca_path],
+                "min_version": "TLSv1.3",
+            }))),
+            None,
+            false,
+        )
+        .unwrap();
+        assert_eq!(get(&client, &server).await.as_deref(), Some("anonymous"));
+
+        server.stop().await;
+    }
+
+    #[actix_web::test]
+    async fn test_build_client_certificate() {
+        let server = TestTlsServer::start(true);
+        let (cert_path, key_path) = server.write_client_certificate("proxy");
+
+        // Server requires a client certificate
+        let client = build_client(
+            Some(&config(serde_json::json!({
+                "ca_paths": [server.ca_path],
+            }))),
+            None,
+            false,
+        )
+        .unwrap();
+        assert_eq!(get(&client, &server).await, None);
+
+        let client = build_client(
+            Some(&config(serde_json::json!({
+                "ca_paths": [server.ca_path],
+                "client_cert_path": cert_path,
+                "client_key_path": key_path,
+            }))),
+            None,
+            false,
+        )
+        .unwrap();
+        assert_eq!(get(&client, &server).await.as_deref(), Some("CN=proxy"));
+
+        server.stop().await;
+    }
+
+    #[actix_web::test]
+    async fn test_build_client_spki_pins() {
+        let server = TestTlsServer::start(false);
+
+        let client = build_client(
+            Some(&config(serde_json::json!({
+                "ca_paths": [server.ca_path],
+                "spki_pins": format!("sha256/{}", pin(&server)),
+            }))),
+            None,
+            false,
+        )
+        .unwrap();
+        assert_eq!(get(&client, &server).await.as_deref(), Some("anonymous"));
+
+        let client = build_client(
+            Some(&config(serde_json::json!({
+                "ca_paths": [server.ca_path],
+                "spki_pins": ["47DEQpj8HBSa+/TImW+5JCeuQeRkm5NMpJWZG3hSuFU="],
+            }))),
+            None,
+            false,
+        )
+        .unwrap();
+        assert_eq!(get(&client, &server).await, None);
+
+        server.stop().await;
+    }
+
+    fn pin_of(certificate: &CertificateDer<'_>) -> String {
+        base64::engine::general_purpose::STANDARD.encode(spki_hash(certificate).unwrap())
+    }
+
+    fn verify_pinned(
+        root: &CertificateDer<'static>,
+        pinned: &CertificateDer<'_>,
+        chain: &[CertificateDer<'static>],
+    ) -> bool {
+        let provider = tls::crypto_provider();
+        let mut roots = RootCertStore::empty();
+        roots.add(root.clone()).unwrap();
+        let roots = Arc::new(roots);
+        let inner = WebPkiServerVerifier::builder_with_provider(roots.clone(), provider.clone())
+            .build()
+            .unwrap();
+        let verifier = PinnedServerVerifier::new(
+            inner,
+            roots,
+            provider.signature_verification_algorithms,
+            &[pin_of(pinned)],
+        )
+        .unwrap();
+
+        verifier
+            .verify_server_cert(
+                &chain[0],
+                &chain[1..],
+                &ServerName::try_from("localhost").unwrap(),
+                &[],
+                UnixTime::now(),
+            )
+            .is_ok()
+    }
+
+    #[test]
+    fn test_pinned_server_verifier_path() {
+        use crate::tls::test_utils::{certificate_authority, issue};
+        use rcgen::{BasicConstraints, CertificateParams, CertifiedIssuer, IsCa, KeyPair};
+
+        let root = certificate_authority();
+        let mut params = CertificateParams::new(vec![]).unwrap();
+        params.is_ca = IsCa::Ca(BasicConstraints::Unconstrained);
+        let intermediate =
+            CertifiedIssuer::signed_by(params, KeyPair::generate().unwrap(), &*root).unwrap();
+        let leaf = issue(&intermediate, "localhost", vec!["localhost".to_string()]);
+        let chain = [leaf.cert.der().clone(), intermediate.der().clone()];
+
+        assert!(verify_pinned(root.der(), leaf.cert.der(), &chain));
+        assert!(verify_pinned(root.der(), intermediate.der(), &chain));
+        assert!(verify_pinned(root.der(), root.der(), &chain));
+
+        // The pinned CA is public, appending it to a chain it did not sign does not pass the pin
+        let pinned_ca = certificate_authority();
+        let mut chain = chain.to_vec();
+        chain.push(pinned_ca.der().clone());
+        assert!(!verify_pinned(root.der(), pinned_ca.der(), &chain));
+        assert!(verify_pinned(root.der(), root.der(), &chain));
+    }
+
+    #[test]
+    fn test_tls_config_incomplete_client_certificate() {
+        let result = tls_config(&config(serde_json::json!({
+            "client_cert_path": PathBuf::from("client.crt"),
+        })));
+        assert!(result.is_err());
+    }
+
+    fn proxy(values: serde_json::Value) -> ProxyConfig {
+        serde_json::from_value(values).unwrap()
+    }
+
+    async fn mock_server(expected: u64) -> wiremock::MockServer {
+        let server = wiremock::MockServer::start().await;
+        wiremock::Mock::given(wiremock::matchers::path("/test"))
+            .respond_with(wiremock::ResponseTemplate::new(200))
+            .expect(expected)
+            .mount(&server)
+            .await;
+
+        server
+    }
+
+    #[tokio::test]
+    async fn test_build_client_proxy() {
+        let proxy_server = mock_server(1).await;
+
+        let client = build_client(
+            None,
+            Some(&proxy(serde_json::json!({
+                "url": proxy_server.uri(),
+                "username": "user",
+                "password": "password",
+            }))),
+            false,
+        )
+        .unwrap();
+        let response = client
+            .get("http://upstream.invalid/test")
+            .send()
+            .await
+            .unwrap();
+        assert!(response.status().is_success());
+
+        let requests = proxy_server.received_requests().await.unwrap();
+        assert_eq!(
+            requests[0].headers.get("proxy-authorization").unwrap(),
+            "Basic dXNlcjpwYXNzd29yZA=="
+        );
+        assert_eq!(requests[0].headers.get("host").unwrap(), "upstream.invalid");
+    }
+
+    #[tokio::test]
+    async fn test_build_client_no_proxy() {
+        let proxy_server = mock_server(0).await;
+        let upstream_server = mock_server(1).await;
+
+        let client = build_client(
+            None,
+            Some(&proxy(serde_json::json!({
+                "url": proxy_server.uri(),
+                "no_proxy": "example.com, 127.0.0.1",
+            }))),
+            false,
+        )
+        .unwrap();
+        let response = client
+            .get(format!("{}/test", upstream_server.uri()))
+            .send()
+            .await
+            .unwrap();
+        assert!(response.status().is_success());
+    }
+
+    #[tokio::test]
+    async fn test_build_client_proxy_from_env() {
+        let proxy_server = mock_server(1).await;
+
+        let (client_from_env, client_without_env) =
+            temp_env::with_var("HTTP_PROXY", Some(proxy_server.uri()), || {
+                (
+                    build_client(None, None, true).unwrap(),
+                    build_client(None, None, false).unwrap(),
+                )
+            });
+
+        let response = client_from_env
+            .get("http://upstream.invalid/test")
+            .send()
+            .await
+            .unwrap();
+        assert!(response.status().is_success());
+
+        // Ignores the environment and connects to the target directly
+        let target_server = mock_server(1).await;
+        let response = client_without_env
+            .get(format!("{}/test", target_server.uri()))
+            .send()
+            .await
+            .unwrap();
+        assert!(response.status().is_success());
+    }
+
+    #[tokio::test]
+    async fn test_build_client_with_timeout() {
+        let server = wiremock::MockServer::start().await;
+        wiremock::Mock::given(wiremock::matchers::path("/test"))
+            .respond_with(
+                wiremock::ResponseTemplate::new(200).set_delay(std::time::Duration::from_secs(5)),
+            )
+            .mount(&server)
+            .await;
+
+        let client = build_client_with_timeout(
+            None,
+            None,
+            false,
+            Duration::from_secs(1),
+            Duration::from_millis(100),
+        )
+        .unwrap();
+        let result = client.get(format!("{}/test", server.uri())).send().await;
+        assert!(matches!(result, Err(reqwest_middleware::Error::Reqwest(e)) if e.is_timeout()));
+    }
+
+    #[test]
+    fn test_build_proxy_invalid_url() {
+        assert!(build_proxy(&proxy(serde_json::json!({ "url": "not a url" }))).is_err());
+    }
+}
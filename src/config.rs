@@ -1,57 +1,1156 @@
-use crate::data::{AllowedPath, AllowedPaths};
+use crate::client;
+use crate::data::{
+    AccessJwtValidator, AccessKeysSource, AccessTokenCache, AllowedPath, AllowedPaths, ApiKeyAuth,
+    ApiKeySource, CircuitBreaker, ClientIdentityRequirement, ConcurrencyLimiter,
+    DEFAULT_TARGET_NAME, EventFilter, FanOut, FanOutPolicy, FilterOperator, FilterSource, Mirror,
+    NonceStore, OAuth2ClientCredentials, PayloadTransform, RateLimitKey, RateLimiter, ReplayGuard,
+    ReplayTimestamp, RouteOptions, ServiceToken, Target, UpstreamAuth, WebHookData, json_pointer,
+};
+use actix_web::http::StatusCode;
+use regex::Regex;
 use reqwest::Url;
-use secrecy::SecretString;
-use serde::{Deserialize, Deserializer};
+use reqwest_middleware::ClientWithMiddleware;
+use secrecy::{ExposeSecret, SecretString};
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
 use std::collections::{HashMap, HashSet};
+use std::path::PathBuf;
 use std::str::FromStr;
+use std::time::Duration;
 
 use crate::error::Error;
 
+pub const ENV_CONFIG_FILE: &str = "CONFIG_FILE";
+
+// Shown instead of secrets when the configuration is serialized
+const REDACTED: &str = "[REDACTED]";
+// Shown instead of passwords in URLs, which would be percent-encoded otherwise
+const REDACTED_PASSWORD: &str = "REDACTED";
+
 const DEFAULT_SERVER_HOST: &str = "127.0.0.1";
 const DEFAULT_SERVER_PORT: u16 = 8080;
-
-#[derive(Debug, serde::Deserialize, Getters)]
+const DEFAULT_TLS_PORT: u16 = 8443;
+const DEFAULT_ADMIN_PORT: u16 = 8081;
+const DEFAULT_TLS_RELOAD_INTERVAL: u64 = 60;
+const DEFAULT_CONFIG_RELOAD_INTERVAL: u64 = 10;
+const DEFAULT_SHUTDOWN_DELAY: u64 = 5;
+const DEFAULT_SHUTDOWN_TIMEOUT: u64 = 30;
+const DEFAULT_TOKEN_REFRESH_MARGIN: u64 = 60;
+const DEFAULT_ACCESS_KEYS_CACHE_DURATION: u64 = 3600;
+const ACCESS_CERTS_PATH: &str = "/cdn-cgi/access/certs";
+const DEFAULT_RATE_LIMIT_PERIOD: u64 = 1;
+const DEFAULT_REPLAY_WINDOW: u64 = 300;
+const DEFAULT_REPLAY_MAX_ENTRIES: usize = 100_000;
+const DEFAULT_QUEUE_TIMEOUT: u64 = 5;
+const DEFAULT_MAX_BODY_SIZE: usize = 10 * 1024 * 1024;
+const DEFAULT_CONNECT_TIMEOUT: u64 = 10;
+const DEFAULT_REQUEST_TIMEOUT: u64 = 60;
+const DEFAULT_CIRCUIT_FAILURE_THRESHOLD: u32 = 5;
+const DEFAULT_CIRCUIT_WINDOW: usize = 20;
+const DEFAULT_CIRCUIT_OPEN_DURATION: u64 = 30;
+const DEFAULT_CIRCUIT_HALF_OPEN_PROBES: u32 = 1;
+const DEFAULT_FAN_OUT_RETRIES: u32 = 3;
+const DEFAULT_FAN_OUT_RETRY_DELAY: u64 = 1;
+const DEFAULT_MIRROR_PERCENT: f64 = 100.0;
+const DEFAULT_TRANSFORM_CONTENT_TYPE: &str = "application/json";
+// Reserved for the copies of targets used by routes with their own proxy
+const ROUTE_TARGET_PREFIX: &str = "route.";
+
+#[derive(Debug, serde::Deserialize, serde::Serialize, Getters)]
 #[getset(get = "pub")]
 pub struct Config {
     server: ServerConfig,
+    // Only required while the target base or a target authenticates with the Cloudflare service token
+    #[serde(default)]
     cloudflare: CloudFlareConfig,
     webhook: WebhookConfig,
 }
 
-#[derive(Debug, serde::Deserialize, Getters)]
+#[derive(Debug, Clone, serde::Deserialize, serde::Serialize, Getters)]
 #[getset(get = "pub")]
 pub struct CloudFlareConfig {
-    client_id: SecretString,
-    client_secret: SecretString,
+    #[serde(flatten)]
+    credentials: ServiceTokenConfig,
+    // Tried when Access rejects the credentials above, e.g. while rotating the service token
+    secondary: Option<ServiceTokenConfig>,
+    // Send the CF_Authorization token issued by Access instead of the credentials
+    #[serde(default)]
+    token_cache: bool,
+    // Seconds before the expiry of the token it is refreshed
+    #[serde(default = "default_token_refresh_margin")]
+    token_refresh_margin: u64,
+}
+
+impl Default for CloudFlareConfig {
+    fn default() -> Self {
+        Self {
+            credentials: ServiceTokenConfig::default(),
+            secondary: None,
+            token_cache: false,
+            token_refresh_margin: DEFAULT_TOKEN_REFRESH_MARGIN,
+        }
+    }
+}
+
+impl CloudFlareConfig {
+    pub fn build_service_token(&self) -> crate::Result<ServiceToken> {
+        let token_cache = self
+            .token_cache
+            .then(|| AccessTokenCache::new(Duration::from_secs(self.token_refresh_margin)));
+        let secondary = self
+            .secondary
+            .as_ref()
+            .map(ServiceTokenConfig::build)
+            .transpose()?;
+
+        Ok(self
+            .credentials
+            .build()?
+            .with_secondary(secondary)
+            .with_token_cache(token_cache))
+    }
+
+    /// Files the credentials are read from.
+    pub fn secret_files(&self) -> impl Iterator<Item = &PathBuf> {
+        self.credentials.secret_files().chain(
+            self.secondary
+                .iter()
+                .flat_map(ServiceTokenConfig::secret_files),
+        )
+    }
+}
+
+/// Service token credentials, set directly or read from files such as Kubernetes secret mounts.
+#[derive(Debug, Clone, Default, serde::Deserialize, serde::Serialize)]
+pub struct ServiceTokenConfig {
+    #[serde(
+        default,
+        deserialize_with = "deserialize_secret",
+        serialize_with = "serialize_secret"
+    )]
+    client_id: Option<SecretString>,
+    client_id_file: Option<PathBuf>,
+    #[serde(
+        default,
+        deserialize_with = "deserialize_secret",
+        serialize_with = "serialize_secret"
+    )]
+    client_secret: Option<SecretString>,
+    client_secret_file: Option<PathBuf>,
+}
+
+impl ServiceTokenConfig {
+    pub fn client_id(&self) -> crate::Result<SecretString> {
+        read_secret("client_id", &self.client_id, &self.client_id_file)
+    }
+
+    pub fn client_secret(&self) -> crate::Result<SecretString> {
+        read_secret(
+            "client_secret",
+            &self.client_secret,
+            &self.client_secret_file,
+        )
+    }
+
+    fn build(&self) -> crate::Result<ServiceToken> {
+        ServiceToken::new(&self.client_id()?, &self.client_secret()?)
+    }
+
+    fn secret_files(&self) -> impl Iterator<Item = &PathBuf> {
+        self.client_id_file
+            .iter()
+            .chain(self.client_secret_file.iter())
+    }
+}
+
+/// Authentication towards a target, instead of the Cloudflare service token.
+#[derive(Debug, Clone, serde::Deserialize, serde::Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum UpstreamAuthConfig {
+    CloudflareAccess(CloudFlareConfig),
+    Headers {
+        // Header name: Value sent with every request
+        #[serde(
+            deserialize_with = "deserialize_secret_map",
+            serialize_with = "serialize_secret_map"
+        )]
+        headers: HashMap<String, SecretString>,
+    },
+    Basic {
+        username: String,
+        #[serde(
+            default,
+            deserialize_with = "deserialize_secret",
+            serialize_with = "serialize_secret"
+        )]
+        password: Option<SecretString>,
+        password_file: Option<PathBuf>,
+    },
+    #[serde(rename = "oauth2")]
+    OAuth2 {
+        #[serde(
+            deserialize_with = "deserialize_url_from_string",
+            serialize_with = "serialize_url"
+        )]
+        token_url: Url,
+        client_id: String,
+        #[serde(
+            default,
+            deserialize_with = "deserialize_secret",
+            serialize_with = "serialize_secret"
+        )]
+        client_secret: Option<SecretString>,
+        client_secret_file: Option<PathBuf>,
+        #[serde(default, deserialize_with = "deserialize_list")]
+        scopes: Vec<String>,
+        audience: Option<String>,
+        // Seconds before the expiry of the token it is refreshed
+        #[serde(default = "default_token_refresh_margin")]
+        refresh_margin: u64,
+    },
+}
+
+impl UpstreamAuthConfig {
+    /// Create the authentication, requesting OAuth2 tokens through the given proxy. The TLS
+    /// settings of the target are not applied, as its pins and client certificate belong to the
+    /// target instead of the token endpoint.
+    pub fn build(
+        &self,
+        proxy: Option<&ProxyConfig>,
+        proxy_from_env: bool,
+    ) -> crate::Result<UpstreamAuth> {
+        match self {
+            UpstreamAuthConfig::CloudflareAccess(cloudflare) => Ok(UpstreamAuth::CloudflareAccess(
+                cloudflare.build_service_token()?,
+            )),
+            UpstreamAuthConfig::Headers { headers } => {
+                let mut header_map = reqwest::header::HeaderMap::with_capacity(headers.len());
+                for (name, value) in headers {
+                    let name = reqwest::header::HeaderName::from_str(name)
+                        .map_err(|e| Error::custom(format!("Invalid auth header {name}: {e}")))?;
+                    let mut value = reqwest::header::HeaderValue::from_str(value.expose_secret())
+                        .map_err(|_| {
+                        Error::custom(format!("Failed to map auth header {name} to header value"))
+                    })?;
+                    value.set_sensitive(true);
+                    header_map.insert(name, value);
+                }
+
+                Ok(UpstreamAuth::Headers(header_map))
+            }
+            UpstreamAuthConfig::Basic {
+                username,
+                password,
+                password_file,
+            } => UpstreamAuth::basic(
+                username,
+                read_secret("password", password, password_file)?.expose_secret(),
+            ),
+            UpstreamAuthConfig::OAuth2 {
+                token_url,
+                client_id,
+                client_secret,
+                client_secret_file,
+                scopes,
+                audience,
+                refresh_margin,
+            } => Ok(UpstreamAuth::OAuth2(OAuth2ClientCredentials::new(
+                client::build_client(None, proxy, proxy_from_env)?,
+                token_url.clone(),
+                client_id.clone(),
+                read_secret("client_secret", client_secret, client_secret_file)?,
+                scopes.clone(),
+                audience.clone(),
+                Duration::from_secs(*refresh_margin),
+            ))),
+        }
+    }
+
+    /// Files the credentials are read from.
+    pub fn secret_files(&self) -> Box<dyn Iterator<Item = &PathBuf> + '_> {
+        match self {
+            UpstreamAuthConfig::CloudflareAccess(cloudflare) => Box::new(cloudflare.secret_files()),
+            UpstreamAuthConfig::Headers { .. } => Box::new(std::iter::empty()),
+            UpstreamAuthConfig::Basic { password_file, .. } => Box::new(password_file.iter()),
+            UpstreamAuthConfig::OAuth2 {
+                client_secret_file, ..
+            } => Box::new(client_secret_file.iter()),
+        }
+    }
+}
+
+/// The secret set directly or the trimmed content of the file.
+fn read_secret(
+    name: &str,
+    value: &Option<SecretString>,
+    file: &Option<PathBuf>,
+) -> crate::Result<SecretString> {
+    match (value, file) {
+        (Some(value), None) => Ok(value.clone()),
+        (None, Some(file)) => std::fs::read_to_string(file)
+            .map(|content| SecretString::from(content.trim()))
+            .map_err(|e| {
+                Error::custom(format!(
+                    "Failed to read {name} from {}: {e}",
+                    file.display()
+                ))
+            }),
+        (Some(_), Some(_)) => Err(Error::custom(format!(
+            "Only one of {name} and {name}_file can be set"
+        ))),
+        (None, None) => Err(Error::custom(format!("Missing {name} or {name}_file"))),
+    }
 }
 
-#[derive(Debug, serde::Deserialize, Getters)]
+#[derive(Debug, Clone, serde::Deserialize, serde::Serialize, Getters)]
 #[getset(get = "pub")]
 pub struct ServerConfig {
     host: String,
     port: u16,
+    tls: Option<TlsConfig>,
+    // Compress responses based on the Accept-Encoding of the caller
+    #[serde(default)]
+    compress_responses: bool,
+    // Serve /metrics on the webhook listener, exposing route patterns and target names to callers
+    #[serde(default)]
+    metrics_enabled: bool,
+    // Serve /ready on the webhook listener, failing once the shutdown started
+    #[serde(default)]
+    ready_enabled: bool,
+    // Seconds between checks of CONFIG_FILE for changes, 0 to only reload on SIGHUP
+    #[serde(default = "default_config_reload_interval")]
+    config_reload_interval: u64,
+    // Seconds between failing readiness and no longer accepting connections on shutdown
+    #[serde(default = "default_shutdown_delay")]
+    shutdown_delay: u64,
+    // Seconds requests in flight and background deliveries get to finish on shutdown
+    #[serde(default = "default_shutdown_timeout")]
+    shutdown_timeout: u64,
+    // Separate listener exposing the effective configuration and route table
+    admin: Option<AdminConfig>,
+}
+
+impl ServerConfig {
+    /// Whether the other server is configured the same way, admin keys included.
+    pub fn same_settings(&self, other: &ServerConfig) -> bool {
+        let admin_keys = |server: &ServerConfig| {
+            server.admin.as_ref().map(|admin| {
+                admin
+                    .api_key
+                    .keys
+                    .iter()
+                    .map(|key| key.expose_secret().to_string())
+                    .collect::<Vec<_>>()
+            })
+        };
+
+        // Secrets are redacted when serialized
+        serde_json::to_value(self).ok() == serde_json::to_value(other).ok()
+            && admin_keys(self) == admin_keys(other)
+    }
+}
+
+#[derive(Debug, Clone, serde::Deserialize, serde::Serialize, Getters)]
+#[getset(get = "pub")]
+pub struct AdminConfig {
+    // Keep the admin listener off the public interface
+    #[serde(default = "default_admin_host")]
+    host: String,
+    #[serde(default = "default_admin_port")]
+    port: u16,
+    // Keys callers of the admin API have to present, read at startup
+    api_key: ApiKeyConfig,
+}
+
+#[derive(Debug, Clone, serde::Deserialize, serde::Serialize, Getters)]
+#[getset(get = "pub")]
+pub struct TlsConfig {
+    cert_path: PathBuf,
+    key_path: PathBuf,
+    #[serde(default = "default_tls_port")]
+    port: u16,
+    // Keep serving plain HTTP on the server port next to HTTPS
+    #[serde(default)]
+    http_enabled: bool,
+    // Seconds between checks for renewed certificate files
+    #[serde(default = "default_tls_reload_interval")]
+    reload_interval: u64,
+    // CA bundle used to verify client certificates, enables mutual TLS
+    client_ca_path: Option<PathBuf>,
+    // Reject callers without a client certificate during the handshake
+    #[serde(default = "default_true")]
+    client_auth_required: bool,
+}
+
+fn default_true() -> bool {
+    true
+}
+
+fn default_admin_host() -> String {
+    DEFAULT_SERVER_HOST.to_string()
+}
+
+fn default_admin_port() -> u16 {
+    DEFAULT_ADMIN_PORT
+}
+
+fn default_tls_port() -> u16 {
+    DEFAULT_TLS_PORT
+}
+
+fn default_tls_reload_interval() -> u64 {
+    DEFAULT_TLS_RELOAD_INTERVAL
+}
+
+fn default_config_reload_interval() -> u64 {
+    DEFAULT_CONFIG_RELOAD_INTERVAL
+}
+
+fn default_access_keys_cache_duration() -> u64 {
+    DEFAULT_ACCESS_KEYS_CACHE_DURATION
 }
 
-#[derive(Debug, serde::Deserialize, Getters)]
+fn default_token_refresh_margin() -> u64 {
+    DEFAULT_TOKEN_REFRESH_MARGIN
+}
+
+fn default_shutdown_delay() -> u64 {
+    DEFAULT_SHUTDOWN_DELAY
+}
+
+fn default_shutdown_timeout() -> u64 {
+    DEFAULT_SHUTDOWN_TIMEOUT
+}
+
+fn default_rate_limit_period() -> u64 {
+    DEFAULT_RATE_LIMIT_PERIOD
+}
+
+fn default_max_body_size() -> usize {
+    DEFAULT_MAX_BODY_SIZE
+}
+
+fn default_queue_timeout() -> u64 {
+    DEFAULT_QUEUE_TIMEOUT
+}
+
+fn default_connect_timeout() -> u64 {
+    DEFAULT_CONNECT_TIMEOUT
+}
+
+fn default_request_timeout() -> u64 {
+    DEFAULT_REQUEST_TIMEOUT
+}
+
+fn default_circuit_failure_threshold() -> u32 {
+    DEFAULT_CIRCUIT_FAILURE_THRESHOLD
+}
+
+fn default_circuit_window() -> usize {
+    DEFAULT_CIRCUIT_WINDOW
+}
+
+fn default_circuit_open_duration() -> u64 {
+    DEFAULT_CIRCUIT_OPEN_DURATION
+}
+
+fn default_circuit_half_open_probes() -> u32 {
+    DEFAULT_CIRCUIT_HALF_OPEN_PROBES
+}
+
+fn default_fan_out_retries() -> u32 {
+    DEFAULT_FAN_OUT_RETRIES
+}
+
+fn default_fan_out_retry_delay() -> u64 {
+    DEFAULT_FAN_OUT_RETRY_DELAY
+}
+
+fn default_replay_window() -> u64 {
+    DEFAULT_REPLAY_WINDOW
+}
+
+fn default_replay_max_entries() -> usize {
+    DEFAULT_REPLAY_MAX_ENTRIES
+}
+
+fn default_mirror_percent() -> f64 {
+    DEFAULT_MIRROR_PERCENT
+}
+
+fn default_transform_content_type() -> String {
+    DEFAULT_TRANSFORM_CONTENT_TYPE.to_string()
+}
+
+#[derive(Debug, serde::Deserialize, serde::Serialize, Getters)]
 #[getset(get = "pub")]
 pub struct WebhookConfig {
-    #[serde(deserialize_with = "deserialize_url_from_string")]
+    #[serde(
+        deserialize_with = "deserialize_url_from_string",
+        serialize_with = "serialize_url"
+    )]
     target_base: Url,
     // Regex path: Allowed methods
-    #[serde(deserialize_with = "deserialize_paths_from_string")]
+    #[serde(default, deserialize_with = "deserialize_paths_from_string")]
     paths: HashMap<String, HashSet<AllowedMethod>>,
+    // Route name: Route with additional options
+    #[serde(default)]
+    routes: HashMap<String, RouteConfig>,
+    // Forward the verified client certificate subject with this header
+    client_identity_header: Option<String>,
+    // Authentication towards the target base and targets without their own, defaults to the CLOUDFLARE service token
+    auth: Option<UpstreamAuthConfig>,
+    // TLS options towards the target base
+    tls: Option<UpstreamTlsConfig>,
+    // Target name: Additional target routes can forward to
+    #[serde(default)]
+    targets: HashMap<String, TargetConfig>,
+    // Egress proxy for all targets without their own proxy
+    proxy: Option<ProxyConfig>,
+    // Use HTTP_PROXY, HTTPS_PROXY and NO_PROXY for targets without a configured proxy
+    #[serde(default)]
+    proxy_from_env: bool,
+    // Rate limit shared by all routes
+    rate_limit: Option<RateLimitConfig>,
+    // In flight limit for all targets without their own
+    concurrency: Option<ConcurrencyConfig>,
+    // Circuit breaker for all targets without their own
+    circuit_breaker: Option<CircuitBreakerConfig>,
+    // Timeouts for all targets without their own
+    #[serde(default)]
+    timeout: TimeoutConfig,
+    // Request body limit in bytes for routes without their own
+    #[serde(default = "default_max_body_size")]
+    max_body_size: usize,
+}
+
+#[derive(Debug, Clone, serde::Deserialize, serde::Serialize, Getters)]
+#[getset(get = "pub")]
+pub struct TargetConfig {
+    #[serde(
+        deserialize_with = "deserialize_url_from_string",
+        serialize_with = "serialize_url"
+    )]
+    base: Url,
+    tls: Option<UpstreamTlsConfig>,
+    proxy: Option<ProxyConfig>,
+    concurrency: Option<ConcurrencyConfig>,
+    circuit_breaker: Option<CircuitBreakerConfig>,
+    timeout: Option<TimeoutConfig>,
+    // Service token used instead of CLOUDFLARE.CLIENT_ID and CLOUDFLARE.CLIENT_SECRET
+    cloudflare: Option<CloudFlareConfig>,
+    // Authentication used instead of WEBHOOK.AUTH, can not be combined with cloudflare
+    auth: Option<UpstreamAuthConfig>,
+}
+
+#[derive(Debug, Clone, serde::Deserialize, serde::Serialize, Getters)]
+#[getset(get = "pub")]
+pub struct CircuitBreakerConfig {
+    // Consecutive failures that open the circuit
+    #[serde(default = "default_circuit_failure_threshold")]
+    failure_threshold: u32,
+    // Failure rate between 0 and 1 within the window that opens the circuit
+    error_rate: Option<f64>,
+    // Latest requests the error rate is calculated from
+    #[serde(default = "default_circuit_window")]
+    window: usize,
+    // Seconds the circuit stays open before probing the target
+    #[serde(default = "default_circuit_open_duration")]
+    open_duration: u64,
+    // Successful probes required to close the circuit
+    #[serde(default = "default_circuit_half_open_probes")]
+    half_open_probes: u32,
+}
+
+#[derive(Debug, Clone, serde::Deserialize, serde::Serialize, Getters)]
+#[getset(get = "pub")]
+pub struct TimeoutConfig {
+    // Seconds to connect to the target
+    #[serde(default = "default_connect_timeout")]
+    connect: u64,
+    // Seconds until the response of the target was read, including the connection
+    #[serde(default = "default_request_timeout")]
+    request: u64,
+}
+
+impl Default for TimeoutConfig {
+    fn default() -> Self {
+        Self {
+            connect: DEFAULT_CONNECT_TIMEOUT,
+            request: DEFAULT_REQUEST_TIMEOUT,
+        }
+    }
+}
+
+#[derive(Debug, Clone, serde::Deserialize, serde::Serialize, Getters)]
+#[getset(get = "pub")]
+pub struct ConcurrencyConfig {
+    // Requests forwarded to the target at the same time
+    max_in_flight: usize,
+    // Requests waiting for a free slot, further requests are rejected right away
+    #[serde(default)]
+    queue_size: usize,
+    // Seconds a request waits in the queue
+    #[serde(default = "default_queue_timeout")]
+    queue_timeout: u64,
+}
+
+#[derive(Debug, Clone, serde::Deserialize, serde::Serialize, Getters)]
+#[getset(get = "pub")]
+pub struct ProxyConfig {
+    // http://, https://, socks5:// or socks5h:// proxy url
+    #[serde(serialize_with = "serialize_url")]
+    url: String,
+    username: Option<String>,
+    #[serde(
+        default,
+        deserialize_with = "deserialize_secret",
+        serialize_with = "serialize_secret"
+    )]
+    password: Option<SecretString>,
+    // Comma separated hosts, domains and IP ranges reached without the proxy
+    no_proxy: Option<String>,
+}
+
+#[derive(Debug, Clone, Default, serde::Deserialize, serde::Serialize, Getters)]
+#[getset(get = "pub")]
+pub struct UpstreamTlsConfig {
+    // PEM CA bundles trusted in addition to the default roots
+    #[serde(default, deserialize_with = "deserialize_list")]
+    ca_paths: Vec<PathBuf>,
+    // PEM client certificate and key presented to the target
+    client_cert_path: Option<PathBuf>,
+    client_key_path: Option<PathBuf>,
+    #[serde(default)]
+    min_version: TlsVersion,
+    // Base64 encoded SHA-256 hashes of the accepted SubjectPublicKeyInfo
+    #[serde(default, deserialize_with = "deserialize_list")]
+    spki_pins: Vec<String>,
+}
+
+#[derive(Debug, Clone, Copy, Default, Eq, PartialEq, serde::Deserialize, serde::Serialize)]
+pub enum TlsVersion {
+    #[default]
+    #[serde(rename = "TLSv1.2")]
+    Tls12,
+    #[serde(rename = "TLSv1.3")]
+    Tls13,
+}
+
+#[derive(Debug, Clone, serde::Deserialize, serde::Serialize, Getters)]
+#[getset(get = "pub")]
+pub struct RouteConfig {
+    path: String,
+    #[serde(deserialize_with = "deserialize_methods")]
+    methods: HashSet<AllowedMethod>,
+    // Routes with a higher priority are matched first when several paths match a request
+    #[serde(default)]
+    priority: i32,
+    client_identity: Option<ClientIdentityConfig>,
+    // Validate the Cloudflare Access token of callers that are behind Access themselves
+    access_jwt: Option<AccessJwtConfig>,
+    // Static keys callers have to present
+    api_key: Option<ApiKeyConfig>,
+    // Name of the target in WEBHOOK.TARGETS, defaults to the target base
+    target: Option<String>,
+    // Targets the route is delivered to instead of target, default being the target base
+    #[serde(default, deserialize_with = "deserialize_list")]
+    targets: Vec<String>,
+    // How the response of multiple targets is chosen
+    fan_out: Option<FanOutConfig>,
+    // Shadow target receiving a copy of the requests
+    mirror: Option<MirrorConfig>,
+    // Forwarded path, e.g. hooks/github?repo=${repo} for the path gh/(?P<repo>[^/]+)
+    rewrite: Option<String>,
+    // Egress proxy used for this route instead of the one of its target
+    proxy: Option<ProxyConfig>,
+    // Rate limit applied in addition to the global one
+    rate_limit: Option<RateLimitConfig>,
+    // Rejects requests outside the time window and nonces already seen
+    replay: Option<ReplayConfig>,
+    // Request body limit in bytes
+    max_body_size: Option<usize>,
+    // Decode gzip, deflate, br and zstd request bodies before forwarding them
+    #[serde(default)]
+    decompress: bool,
+    // Events are only forwarded when they match all filters
+    #[serde(default)]
+    filters: Vec<FilterConfig>,
+    // 2xx status acknowledging filtered out events
+    filtered_status: Option<u16>,
+    // Renders the forwarded body from the parsed body
+    transform: Option<TransformConfig>,
+}
+
+#[derive(Debug, Clone, serde::Deserialize, serde::Serialize, Getters)]
+#[getset(get = "pub")]
+pub struct ApiKeyConfig {
+    #[serde(default)]
+    source: ApiKeySourceKind,
+    // Header or query parameter carrying the key with the header and query sources
+    name: Option<String>,
+    // Accepted keys, user:password with the basic source
+    #[serde(
+        default,
+        deserialize_with = "deserialize_list",
+        serialize_with = "serialize_secrets"
+    )]
+    keys: Vec<SecretString>,
+    // File with one accepted key per line
+    keys_file: Option<PathBuf>,
+}
+
+#[derive(Debug, Clone, Copy, Default, Eq, PartialEq, serde::Deserialize, serde::Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ApiKeySourceKind {
+    #[default]
+    Bearer,
+    Header,
+    Query,
+    Basic,
+}
+
+#[derive(Debug, Clone, serde::Deserialize, serde::Serialize, Getters)]
+#[getset(get = "pub")]
+pub struct AccessJwtConfig {
+    // Team domain like team.cloudflareaccess.com, the issuer of the tokens and the source of the keys
+    team_domain: Option<String>,
+    // JWKS URL used instead of the one of the team domain
+    certs_url: Option<Url>,
+    // Local JWKS file used instead of the one of the team domain
+    certs_path: Option<PathBuf>,
+    // Application audience tags accepted in the aud claim
+    #[serde(deserialize_with = "deserialize_list")]
+    audiences: Vec<String>,
+    // Allowed email claims, any email when empty
+    #[serde(default, deserialize_with = "deserialize_list")]
+    emails: Vec<String>,
+    // Allowed group claims, any group when empty
+    #[serde(default, deserialize_with = "deserialize_list")]
+    groups: Vec<String>,
+    // Seconds the keys are cached
+    #[serde(default = "default_access_keys_cache_duration")]
+    cache_duration: u64,
+}
+
+#[derive(Debug, Clone, serde::Deserialize, serde::Serialize, Getters)]
+#[getset(get = "pub")]
+pub struct FanOutConfig {
+    #[serde(default)]
+    policy: FanOutPolicy,
+    // Retries of background deliveries with the first policy
+    #[serde(default = "default_fan_out_retries")]
+    retries: u32,
+    // Seconds before the first retry, doubled for each further one
+    #[serde(default = "default_fan_out_retry_delay")]
+    retry_delay: u64,
+}
+
+#[derive(Debug, Clone, serde::Deserialize, serde::Serialize, Getters)]
+#[getset(get = "pub")]
+pub struct MirrorConfig {
+    // Name of the target in WEBHOOK.TARGETS or default
+    target: String,
+    // Share of the requests mirrored
+    #[serde(default = "default_mirror_percent")]
+    percent: f64,
+}
+
+#[derive(Debug, Clone, serde::Deserialize, serde::Serialize, Getters)]
+#[getset(get = "pub")]
+pub struct TransformConfig {
+    // Minijinja template of the forwarded body
+    template: Option<String>,
+    // File containing the template, instead of template
+    template_path: Option<PathBuf>,
+    // Content type of the rendered body, JSON output is escaped automatically
+    #[serde(default = "default_transform_content_type")]
+    content_type: String,
+}
+
+#[derive(Debug, Clone, serde::Deserialize, serde::Serialize, Getters)]
+#[getset(get = "pub")]
+pub struct FilterConfig {
+    // Header the value is read from
+    header: Option<String>,
+    // JSON pointer or simple JSONPath into the body, e.g. $.repository.name
+    json: Option<String>,
+    equals: Option<String>,
+    regex: Option<String>,
+    #[serde(rename = "in", default, deserialize_with = "deserialize_list")]
+    one_of: Vec<String>,
+}
+
+#[derive(Debug, Clone, serde::Deserialize, serde::Serialize, Getters)]
+#[getset(get = "pub")]
+pub struct ReplayConfig {
+    // Header or JSON path with the unix timestamp in seconds or milliseconds
+    timestamp_header: Option<String>,
+    timestamp_json: Option<String>,
+    // Regex with the timestamp as first capture group, e.g. t=(\d+) for Stripe-Signature
+    timestamp_pattern: Option<String>,
+    // Header or JSON path with the unique ID of the delivery
+    nonce_header: Option<String>,
+    nonce_json: Option<String>,
+    // Seconds requests are accepted around their timestamp and nonces are remembered
+    #[serde(default = "default_replay_window")]
+    window: u64,
+    // Directory the nonces are stored in, shared by instances mounting it, instead of the memory
+    store_path: Option<PathBuf>,
+    // Nonces kept in memory
+    #[serde(default = "default_replay_max_entries")]
+    max_entries: usize,
+}
+
+#[derive(Debug, Clone, serde::Deserialize, serde::Serialize, Getters)]
+#[getset(get = "pub")]
+pub struct RateLimitConfig {
+    // Requests allowed per period
+    requests: u32,
+    // Period in seconds
+    #[serde(default = "default_rate_limit_period")]
+    period: u64,
+    // Requests allowed at once, defaults to the requests per period
+    burst: Option<u32>,
+    #[serde(default)]
+    key: RateLimitKeyKind,
+    // Header used as key with the header key kind
+    header: Option<String>,
+}
+
+#[derive(Debug, Clone, Copy, Default, Eq, PartialEq, serde::Deserialize, serde::Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum RateLimitKeyKind {
+    #[default]
+    ClientIp,
+    Route,
+    Header,
+}
+
+#[derive(Debug, Clone, serde::Deserialize, serde::Serialize, Getters)]
+#[getset(get = "pub")]
+pub struct ClientIdentityConfig {
+    // Regex matched against the subject, e.g. "CN=ci, O=Example"
+    subject: Option<String>,
+    // Regex matched against each DNS, email, URI and IP subject alternative name
+    san: Option<String>,
 }
 
 impl Config {
+    /// Keep the server settings of the running instance, they are only applied on startup.
+    pub fn with_server_of(mut self, running: &Config) -> Self {
+        self.server = running.server.clone();
+        self
+    }
+
     pub fn get_configuration() -> crate::Result<Self> {
-        config::Config::builder()
+        let mut builder = config::Config::builder();
+        // Environment variables take precedence over the config file
+        if let Ok(path) = std::env::var(ENV_CONFIG_FILE) {
+            builder = builder.add_source(config::File::with_name(&path));
+        }
+
+        let config = builder
             .add_source(config::Environment::default().try_parsing(true))
             .set_default("server.host", DEFAULT_SERVER_HOST)?
             .set_default("server.port", DEFAULT_SERVER_PORT)?
             .build()
             .map_err(|e| Error::custom(format!("Can't parse config: {e}")))?
             .try_deserialize::<Config>()
-            .map_err(|e| Error::custom(format!("Failed to deserialize configuration: {e}")))
+            .map_err(|e| Error::custom(format!("Failed to deserialize configuration: {e}")))?;
+
+        if config.webhook.paths.is_empty() && config.webhook.routes.is_empty() {
+            return Err(Error::custom(
+                "Either WEBHOOK.PATHS or WEBHOOK.ROUTES must be configured",
+            ));
+        }
+
+        Ok(config)
+    }
+
+    /// Files credentials and API keys are read from, to reload them when they change.
+    pub fn secret_files(&self) -> Vec<PathBuf> {
+        self.cloudflare
+            .secret_files()
+            .chain(
+                self.webhook
+                    .targets
+                    .values()
+                    .filter_map(|target| target.cloudflare.as_ref())
+                    .flat_map(CloudFlareConfig::secret_files),
+            )
+            .chain(
+                self.webhook
+                    .auth
+                    .iter()
+                    .chain(
+                        self.webhook
+                            .targets
+                            .values()
+                            .filter_map(|target| target.auth.as_ref()),
+                    )
+                    .flat_map(UpstreamAuthConfig::secret_files),
+            )
+            .chain(
+                self.webhook
+                    .routes
+                    .values()
+                    .filter_map(|route| route.api_key.as_ref()?.keys_file.as_ref()),
+            )
+            .cloned()
+            .collect()
+    }
+
+    /// Authentication towards the target base and targets without their own.
+    pub fn build_auth(&self) -> crate::Result<UpstreamAuth> {
+        match &self.webhook.auth {
+            Some(auth) => auth.build(self.webhook.proxy.as_ref(), self.webhook.proxy_from_env),
+            None => Ok(UpstreamAuth::CloudflareAccess(
+                self.cloudflare.build_service_token()?,
+            )),
+        }
+    }
+
+    /// Create the web hook data, validating the whole webhook configuration.
+    pub fn build_web_hook_data(&self) -> crate::Result<WebHookData> {
+        let client_identity_header = self
+            .webhook
+            .client_identity_header
+            .as_deref()
+            .map(reqwest::header::HeaderName::from_str)
+            .transpose()
+            .map_err(|e| Error::custom(format!("Invalid client identity header: {e}")))?;
+
+        let default_target = self.webhook.build_default_target()?;
+        let targets = self.webhook.build_targets(&default_target)?;
+
+        Ok(WebHookData::new(
+            default_target,
+            self.webhook.allowed_paths()?,
+            self.build_auth()?,
+        )
+        .with_client_identity_header(client_identity_header)
+        .with_targets(targets)
+        .with_rate_limiter(self.webhook.rate_limiter()?)
+        .with_max_body_size(Some(self.webhook.max_body_size)))
+    }
+}
+
+impl WebhookConfig {
+    /// Combine the simple paths and the routes into the allowed paths.
+    pub fn allowed_paths(&self) -> crate::Result<AllowedPaths> {
+        // Cloudflare Access keys are fetched through the egress proxy of the targets
+        let keys_client = client::build_client(None, self.proxy.as_ref(), self.proxy_from_env)?;
+        // Keyed by the escaped pattern, test and ^test$ being the same path
+        let mut allowed_paths = HashMap::with_capacity(self.paths.len() + self.routes.len());
+        for (path, methods) in &self.paths {
+            let pattern = AllowedPaths::escape_regex(path);
+            if allowed_paths.contains_key(&pattern) {
+                return Err(Error::custom(format!(
+                    "Path {path} is configured more than once"
+                )));
+            }
+
+            allowed_paths.insert(pattern, methods.clone().try_into()?);
+        }
+
+        for (name, route) in &self.routes {
+            if let Some(target) = route.target()
+                && target != DEFAULT_TARGET_NAME
+                && !self.targets.contains_key(target)
+            {
+                return Err(Error::custom(format!(
+                    "Route {name} uses the unknown target {target}"
+                )));
+            }
+
+            // A single target is delivered to like any other route
+            if route.fan_out.is_some() && route.targets.len() < 2 {
+                return Err(Error::custom(format!(
+                    "Route {name} fan_out requires at least two targets"
+                )));
+            }
+
+            if !route.targets.is_empty() {
+                if route.target.is_some() || route.proxy.is_some() {
+                    return Err(Error::custom(format!(
+                        "Route {name} can not combine targets with target or proxy"
+                    )));
+                }
+
+                if let Some(target) = route.targets.iter().find(|target| {
+                    target.as_str() != DEFAULT_TARGET_NAME && !self.targets.contains_key(*target)
+                }) {
+                    return Err(Error::custom(format!(
+                        "Route {name} uses the unknown target {target}"
+                    )));
+                }
+            }
+
+            if let Some(mirror) = route.mirror()
+                && mirror.target() != DEFAULT_TARGET_NAME
+                && !self.targets.contains_key(mirror.target())
+            {
+                return Err(Error::custom(format!(
+                    "Route {name} mirrors to the unknown target {}",
+                    mirror.target()
+                )));
+            }
+
+            let pattern = AllowedPaths::escape_regex(route.path());
+            if allowed_paths.contains_key(&pattern) {
+                return Err(Error::custom(format!(
+                    "Route {name} uses the already configured path {}",
+                    route.path()
+                )));
+            }
+
+            let mut route = route.clone();
+            if route.proxy.is_some() {
+                route.target = Some(WebhookConfig::route_target_name(name));
+            }
+
+            allowed_paths.insert(pattern, route.build_allowed_path(&keys_client)?);
+        }
+
+        AllowedPaths::new(allowed_paths)
+    }
+
+    /// Create the rate limiter shared by all routes.
+    pub fn rate_limiter(&self) -> crate::Result<Option<RateLimiter>> {
+        self.rate_limit
+            .clone()
+            .map(RateLimiter::try_from)
+            .transpose()
+    }
+
+    /// Routes with their own proxy forward to a copy of their target with this name.
+    fn route_target_name(route_name: &str) -> String {
+        format!("{ROUTE_TARGET_PREFIX}{route_name}")
+    }
+
+    fn default_target(&self) -> TargetConfig {
+        TargetConfig {
+            base: self.target_base.clone(),
+            tls: self.tls.clone(),
+            proxy: None,
+            concurrency: None,
+            circuit_breaker: None,
+            timeout: None,
+            cloudflare: None,
+            auth: None,
+        }
+    }
+
+    /// Client forwarding requests to the target.
+    fn build_target_client(
+        &self,
+        target: &TargetConfig,
+        proxy: Option<&ProxyConfig>,
+    ) -> crate::Result<ClientWithMiddleware> {
+        let timeout = target.timeout.as_ref().unwrap_or(&self.timeout);
+        client::build_client_with_timeout(
+            target.tls.as_ref(),
+            proxy,
+            self.proxy_from_env,
+            Duration::from_secs(timeout.connect),
+            Duration::from_secs(timeout.request),
+        )
+    }
+
+    fn build_target(&self, name: &str, target: &TargetConfig) -> crate::Result<Target> {
+        let proxy = target.proxy.as_ref().or(self.proxy.as_ref());
+        let client = self.build_target_client(target, proxy)?;
+        let concurrency_limiter = target
+            .concurrency
+            .as_ref()
+            .or(self.concurrency.as_ref())
+            .cloned()
+            .map(ConcurrencyLimiter::try_from)
+            .transpose()?;
+
+        let circuit_breaker = target
+            .circuit_breaker
+            .as_ref()
+            .or(self.circuit_breaker.as_ref())
+            .map(|circuit_breaker| circuit_breaker.build(name))
+            .transpose()?;
+
+        let auth = match (&target.cloudflare, &target.auth) {
+            (Some(_), Some(_)) => {
+                return Err(Error::custom(format!(
+                    "Target {name} can not combine cloudflare with auth"
+                )));
+            }
+            (Some(cloudflare), None) => Some(UpstreamAuth::CloudflareAccess(
+                cloudflare.build_service_token()?,
+            )),
+            (None, Some(auth)) => Some(auth.build(proxy, self.proxy_from_env)?),
+            (None, None) => None,
+        };
+
+        Ok(Target::new(name.to_string(), target.base.clone(), client)
+            .with_concurrency_limiter(concurrency_limiter)
+            .with_circuit_breaker(circuit_breaker)
+            .with_auth(auth))
+    }
+
+    /// Create the target base.
+    pub fn build_default_target(&self) -> crate::Result<Target> {
+        self.build_target(DEFAULT_TARGET_NAME, &self.default_target())
+    }
+
+    /// Create the clients for all additional targets.
+    pub fn build_targets(&self, default_target: &Target) -> crate::Result<HashMap<String, Target>> {
+        let mut targets = HashMap::with_capacity(self.targets.len());
+        for (name, target) in &self.targets {
+            if name.starts_with(ROUTE_TARGET_PREFIX) {
+                return Err(Error::custom(format!(
+                    "Target name {name} is reserved for routes with their own proxy"
+                )));
+            }
+
+            if name == DEFAULT_TARGET_NAME {
+                return Err(Error::custom(format!(
+                    "Target name {name} is reserved for the target base"
+                )));
+            }
+
+            targets.insert(name.clone(), self.build_target(name, target)?);
+        }
+
+        // Routes with their own proxy share the limits and credentials of their target
+        let mut route_targets = HashMap::new();
+        for (name, route) in &self.routes {
+            let Some(proxy) = &route.proxy else {
+                continue;
+            };
+
+            let (config, target) = match route
+                .target
+                .as_ref()
+                .filter(|target_name| target_name.as_str() != DEFAULT_TARGET_NAME)
+            {
+                Some(target_name) => self
+                    .targets
+                    .get(target_name)
+                    .cloned()
+                    .zip(targets.get(target_name))
+                    .ok_or_else(|| {
+                        Error::custom(format!(
+                            "Route {name} uses the unknown target {target_name}"
+                        ))
+                    })?,
+                None => (self.default_target(), default_target),
+            };
+            let client = self.build_target_client(&config, Some(proxy))?;
+            route_targets.insert(
+                WebhookConfig::route_target_name(name),
+                target.with_client(client),
+            );
+        }
+        targets.extend(route_targets);
+
+        Ok(targets)
     }
 }
 
@@ -99,35 +1198,154 @@ where
     values
 }
 
-#[derive(Debug, serde::Deserialize, Eq, PartialEq, Hash, Clone)]
-pub enum AllowedMethod {
-    ALL,
-    GET,
-    POST,
-    PUT,
-    PATCH,
-    DELETE,
+/// Values as comma separated string from the environment or as list from the config file.
+pub fn deserialize_list<'de, D, T>(deserializer: D) -> Result<Vec<T>, D::Error>
+where
+    D: Deserializer<'de>,
+    T: From<String>,
+{
+    #[derive(serde::Deserialize)]
+    #[serde(untagged)]
+    enum List {
+        String(String),
+        List(Vec<String>),
+    }
+
+    let values = match List::deserialize(deserializer)? {
+        List::String(string) => string
+            .split(',')
+            .map(|s| s.trim().to_string())
+            .filter(|s| !s.is_empty())
+            .collect(),
+        List::List(list) => list,
+    };
+
+    Ok(values.into_iter().map(T::from).collect())
 }
 
-impl AllowedMethod {
-    pub fn name(&self) -> &str {
-        match self {
-            AllowedMethod::ALL => "ALL",
-            AllowedMethod::GET => "GET",
-            AllowedMethod::POST => "POST",
-            AllowedMethod::PUT => "PUT",
-            AllowedMethod::PATCH => "PATCH",
-            AllowedMethod::DELETE => "DELETE",
-        }
+/// Secrets the environment parsed as number or boolean are turned back into strings.
+#[derive(serde::Deserialize)]
+#[serde(untagged)]
+enum Secret {
+    String(String),
+    Unsigned(u64),
+    Integer(i64),
+    Float(f64),
+    Bool(bool),
+}
+
+impl From<Secret> for SecretString {
+    fn from(secret: Secret) -> Self {
+        SecretString::from(match secret {
+            Secret::String(string) => string,
+            Secret::Unsigned(number) => number.to_string(),
+            Secret::Integer(number) => number.to_string(),
+            Secret::Float(number) => number.to_string(),
+            Secret::Bool(bool) => bool.to_string(),
+        })
     }
 }
 
-impl TryFrom<&String> for AllowedMethod {
-    type Error = crate::Error;
+pub fn deserialize_secret<'de, D>(deserializer: D) -> Result<Option<SecretString>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    Ok(Option::<Secret>::deserialize(deserializer)?.map(SecretString::from))
+}
 
-    fn try_from(value: &String) -> Result<Self, Self::Error> {
-        match value.to_uppercase().as_str() {
-            "ALL" => Ok(AllowedMethod::ALL),
+pub fn deserialize_secret_map<'de, D>(
+    deserializer: D,
+) -> Result<HashMap<String, SecretString>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    Ok(HashMap::<String, Secret>::deserialize(deserializer)?
+        .into_iter()
+        .map(|(name, secret)| (name, SecretString::from(secret)))
+        .collect())
+}
+
+/// Secrets are shown as set, never with their value.
+fn serialize_secret<S>(secret: &Option<SecretString>, serializer: S) -> Result<S::Ok, S::Error>
+where
+    S: Serializer,
+{
+    secret.as_ref().map(|_| REDACTED).serialize(serializer)
+}
+
+fn serialize_secrets<S>(secrets: &[SecretString], serializer: S) -> Result<S::Ok, S::Error>
+where
+    S: Serializer,
+{
+    serializer.collect_seq(secrets.iter().map(|_| REDACTED))
+}
+
+fn serialize_secret_map<S>(
+    secrets: &HashMap<String, SecretString>,
+    serializer: S,
+) -> Result<S::Ok, S::Error>
+where
+    S: Serializer,
+{
+    serializer.collect_map(secrets.keys().map(|name| (name, REDACTED)))
+}
+
+/// URLs with the password of their user info redacted.
+fn serialize_url<S, T>(url: &T, serializer: S) -> Result<S::Ok, S::Error>
+where
+    S: Serializer,
+    T: AsRef<str>,
+{
+    match Url::parse(url.as_ref()) {
+        Ok(mut url) if url.password().is_some() => {
+            let _ = url.set_password(Some(REDACTED_PASSWORD));
+            serializer.serialize_str(url.as_str())
+        }
+        _ => serializer.serialize_str(url.as_ref()),
+    }
+}
+
+pub fn deserialize_methods<'de, D>(deserializer: D) -> Result<HashSet<AllowedMethod>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    let methods: Vec<String> = deserialize_list(deserializer)?;
+    methods
+        .iter()
+        .map(AllowedMethod::try_from)
+        .collect::<Result<HashSet<AllowedMethod>, _>>()
+        .map_err(serde::de::Error::custom)
+}
+
+#[derive(Debug, serde::Deserialize, serde::Serialize, Eq, PartialEq, Hash, Clone)]
+pub enum AllowedMethod {
+    ALL,
+    GET,
+    POST,
+    PUT,
+    PATCH,
+    DELETE,
+}
+
+impl AllowedMethod {
+    pub fn name(&self) -> &str {
+        match self {
+            AllowedMethod::ALL => "ALL",
+            AllowedMethod::GET => "GET",
+            AllowedMethod::POST => "POST",
+            AllowedMethod::PUT => "PUT",
+            AllowedMethod::PATCH => "PATCH",
+            AllowedMethod::DELETE => "DELETE",
+        }
+    }
+}
+
+impl TryFrom<&String> for AllowedMethod {
+    type Error = crate::Error;
+
+    fn try_from(value: &String) -> Result<Self, Self::Error> {
+        match value.to_uppercase().as_str() {
+            "ALL" => Ok(AllowedMethod::ALL),
             "GET" => Ok(AllowedMethod::GET),
             "POST" => Ok(AllowedMethod::POST),
             "PUT" => Ok(AllowedMethod::PUT),
@@ -170,6 +1388,384 @@ impl TryFrom<HashSet<AllowedMethod>> for AllowedPath {
     }
 }
 
+impl RouteConfig {
+    /// Create the route, fetching Cloudflare Access keys with the client.
+    pub fn build_allowed_path(
+        self,
+        keys_client: &ClientWithMiddleware,
+    ) -> crate::Result<AllowedPath> {
+        let mut options = RouteOptions::default();
+        if let Some(client_identity) = self.client_identity {
+            options = options.with_client_identity(client_identity.try_into()?);
+        }
+
+        if let Some(access_jwt) = self.access_jwt {
+            options = options.with_access_jwt(access_jwt.build_validator(keys_client.clone())?);
+        }
+
+        if let Some(api_key) = self.api_key {
+            options = options.with_api_key(api_key.try_into()?);
+        }
+
+        if let Some(target) = self.target {
+            options = options.with_target(target);
+        }
+
+        let mut targets = self.targets.into_iter();
+        if let Some(target) = targets.next() {
+            options = options.with_target(target);
+        }
+
+        let targets: Vec<_> = targets.collect();
+        if !targets.is_empty() {
+            let fan_out = self.fan_out.unwrap_or(FanOutConfig {
+                policy: FanOutPolicy::default(),
+                retries: DEFAULT_FAN_OUT_RETRIES,
+                retry_delay: DEFAULT_FAN_OUT_RETRY_DELAY,
+            });
+            options = options.with_fan_out(FanOut::new(
+                targets,
+                fan_out.policy,
+                fan_out.retries,
+                Duration::from_secs(fan_out.retry_delay),
+            ));
+        }
+
+        if let Some(mirror) = self.mirror {
+            if !(0.0..=100.0).contains(&mirror.percent) {
+                return Err(Error::custom(format!(
+                    "Mirror percent {} is not between 0 and 100",
+                    mirror.percent
+                )));
+            }
+
+            options = options.with_mirror(Mirror::new(mirror.target, mirror.percent));
+        }
+
+        if let Some(rewrite) = self.rewrite {
+            options = options.with_rewrite(rewrite);
+        }
+
+        if let Some(rate_limit) = self.rate_limit {
+            options = options.with_rate_limiter(rate_limit.try_into()?);
+        }
+
+        if let Some(replay) = self.replay {
+            options = options.with_replay_guard(replay.try_into()?);
+        }
+
+        if let Some(max_body_size) = self.max_body_size {
+            options = options.with_max_body_size(max_body_size);
+        }
+
+        options = options.with_decompress(self.decompress);
+
+        if !self.filters.is_empty() || self.filtered_status.is_some() {
+            let filtered_status = match self.filtered_status {
+                Some(status) => StatusCode::from_u16(status)
+                    .ok()
+                    .filter(StatusCode::is_success)
+                    .ok_or_else(|| {
+                        Error::custom(format!("Filtered status {status} is not a 2xx status"))
+                    })?,
+                None => StatusCode::ACCEPTED,
+            };
+            let filters = self
+                .filters
+                .into_iter()
+                .map(EventFilter::try_from)
+                .collect::<crate::Result<_>>()?;
+
+            options = options.with_filters(filters, filtered_status);
+        }
+
+        if let Some(transform) = self.transform {
+            options = options.with_transform(transform.try_into()?);
+        }
+
+        let allowed_path: AllowedPath = self.methods.try_into()?;
+        Ok(allowed_path
+            .with_options(options)
+            .with_priority(self.priority))
+    }
+}
+
+impl TryFrom<ClientIdentityConfig> for ClientIdentityRequirement {
+    type Error = Error;
+
+    fn try_from(value: ClientIdentityConfig) -> Result<Self, Self::Error> {
+        let subject = value.subject.as_deref().map(Regex::new).transpose()?;
+        let san = value.san.as_deref().map(Regex::new).transpose()?;
+
+        Ok(ClientIdentityRequirement::new(subject, san))
+    }
+}
+
+impl TryFrom<FilterConfig> for EventFilter {
+    type Error = Error;
+
+    fn try_from(value: FilterConfig) -> Result<Self, Self::Error> {
+        let source = filter_source("filter", value.header.as_ref(), value.json.as_ref())?
+            .ok_or_else(|| Error::custom("Filters require either a header or a json path"))?;
+
+        let operator = match (value.equals, value.regex, value.one_of.is_empty()) {
+            (Some(equals), None, true) => FilterOperator::Equals(equals),
+            (None, Some(regex), true) => FilterOperator::Regex(Regex::new(&regex)?),
+            (None, None, false) => FilterOperator::In(value.one_of),
+            _ => {
+                return Err(Error::custom(
+                    "Filters require exactly one of equals, regex or in",
+                ));
+            }
+        };
+
+        Ok(EventFilter::new(source, operator))
+    }
+}
+
+impl TryFrom<ApiKeyConfig> for ApiKeyAuth {
+    type Error = Error;
+
+    fn try_from(value: ApiKeyConfig) -> Result<Self, Self::Error> {
+        let name = || {
+            value.name.clone().ok_or_else(|| {
+                Error::custom("API keys with the header or query source require a name")
+            })
+        };
+        let source = match value.source {
+            ApiKeySourceKind::Bearer => ApiKeySource::Bearer,
+            ApiKeySourceKind::Header => ApiKeySource::Header(
+                actix_web::http::header::HeaderName::from_str(&name()?)
+                    .map_err(|e| Error::custom(format!("Invalid API key header: {e}")))?,
+            ),
+            ApiKeySourceKind::Query => ApiKeySource::Query(name()?),
+            ApiKeySourceKind::Basic => ApiKeySource::Basic,
+        };
+
+        let mut keys = value.keys;
+        if let Some(file) = &value.keys_file {
+            let content = std::fs::read_to_string(file).map_err(|e| {
+                Error::custom(format!(
+                    "Failed to read API keys from {}: {e}",
+                    file.display()
+                ))
+            })?;
+            keys.extend(
+                content
+                    .lines()
+                    .map(str::trim)
+                    .filter(|key| !key.is_empty())
+                    .map(SecretString::from),
+            );
+        }
+
+        if keys.is_empty() {
+            return Err(Error::custom("API key authentication requires keys"));
+        }
+
+        Ok(ApiKeyAuth::new(source, &keys))
+    }
+}
+
+impl AccessJwtConfig {
+    /// Create the validator, fetching the keys with the client.
+    pub fn build_validator(
+        self,
+        keys_client: ClientWithMiddleware,
+    ) -> crate::Result<AccessJwtValidator> {
+        if self.audiences.is_empty() {
+            return Err(Error::custom("Access JWT validation requires audiences"));
+        }
+
+        let issuer = self
+            .team_domain
+            .as_ref()
+            .map(|team_domain| format!("https://{}", team_domain.trim_end_matches('/')));
+        let source = match (self.certs_path, self.certs_url, &issuer) {
+            (Some(path), None, _) => AccessKeysSource::File(path),
+            (None, Some(url), _) => AccessKeysSource::Url(url),
+            (None, None, Some(issuer)) => AccessKeysSource::Url(
+                Url::parse(&format!("{issuer}{ACCESS_CERTS_PATH}"))
+                    .map_err(|e| Error::custom(format!("Invalid team domain: {e}")))?,
+            ),
+            _ => {
+                return Err(Error::custom(
+                    "Access JWT validation requires a team domain, certs url or certs path",
+                ));
+            }
+        };
+
+        Ok(AccessJwtValidator::new(
+            source,
+            keys_client,
+            issuer,
+            self.audiences,
+            self.emails,
+            self.groups,
+            Duration::from_secs(self.cache_duration),
+        ))
+    }
+}
+
+impl TryFrom<TransformConfig> for PayloadTransform {
+    type Error = Error;
+
+    fn try_from(value: TransformConfig) -> Result<Self, Self::Error> {
+        let template = match (value.template, value.template_path) {
+            (Some(template), None) => template,
+            (None, Some(path)) => std::fs::read_to_string(path)?,
+            _ => {
+                return Err(Error::custom(
+                    "Transforms require either a template or a template path",
+                ));
+            }
+        };
+
+        PayloadTransform::new(template, &value.content_type)
+    }
+}
+
+/// Header or JSON path of a value the request is checked on.
+fn filter_source(
+    name: &str,
+    header: Option<&String>,
+    json: Option<&String>,
+) -> crate::Result<Option<FilterSource>> {
+    match (header, json) {
+        (Some(header), None) => Ok(Some(FilterSource::Header(
+            actix_web::http::header::HeaderName::from_str(header)
+                .map_err(|e| Error::custom(format!("Invalid {name} header: {e}")))?,
+        ))),
+        (None, Some(path)) => Ok(Some(FilterSource::Json(json_pointer(path)?))),
+        (None, None) => Ok(None),
+        (Some(_), Some(_)) => Err(Error::custom(format!(
+            "The {name} is read from either a header or a json path"
+        ))),
+    }
+}
+
+impl TryFrom<ReplayConfig> for ReplayGuard {
+    type Error = Error;
+
+    fn try_from(value: ReplayConfig) -> Result<Self, Self::Error> {
+        let timestamp = filter_source(
+            "replay timestamp",
+            value.timestamp_header.as_ref(),
+            value.timestamp_json.as_ref(),
+        )?;
+        let nonce = filter_source(
+            "replay nonce",
+            value.nonce_header.as_ref(),
+            value.nonce_json.as_ref(),
+        )?;
+        if timestamp.is_none() && nonce.is_none() {
+            return Err(Error::custom(
+                "Replay protection requires a timestamp or a nonce",
+            ));
+        }
+        if value.window == 0 {
+            return Err(Error::custom("Replay window must be greater than 0"));
+        }
+
+        let pattern = value
+            .timestamp_pattern
+            .as_deref()
+            .map(Regex::new)
+            .transpose()?;
+        let timestamp = timestamp.map(|source| ReplayTimestamp::new(source, pattern));
+        let store = match value.store_path {
+            Some(path) => NonceStore::directory(path)?,
+            None => NonceStore::memory(value.max_entries.max(1)),
+        };
+
+        Ok(ReplayGuard::new(
+            timestamp,
+            nonce,
+            Duration::from_secs(value.window),
+            store,
+        ))
+    }
+}
+
+impl TryFrom<RateLimitConfig> for RateLimiter {
+    type Error = Error;
+
+    fn try_from(value: RateLimitConfig) -> Result<Self, Self::Error> {
+        if value.requests == 0 || value.period == 0 {
+            return Err(Error::custom(
+                "Rate limit requests and period must be greater than 0",
+            ));
+        }
+
+        let key = match value.key {
+            RateLimitKeyKind::ClientIp => RateLimitKey::ClientIp,
+            RateLimitKeyKind::Route => RateLimitKey::Route,
+            RateLimitKeyKind::Header => {
+                let header = value.header.as_deref().ok_or_else(|| {
+                    Error::custom("Rate limit with the header key requires a header")
+                })?;
+                RateLimitKey::Header(
+                    actix_web::http::header::HeaderName::from_str(header)
+                        .map_err(|e| Error::custom(format!("Invalid rate limit header: {e}")))?,
+                )
+            }
+        };
+
+        Ok(RateLimiter::new(
+            key,
+            value.requests,
+            Duration::from_secs(value.period),
+            value.burst.unwrap_or(value.requests).max(1),
+        ))
+    }
+}
+
+impl CircuitBreakerConfig {
+    /// The circuit breaker of the target with this name.
+    fn build(&self, target: &str) -> crate::Result<CircuitBreaker> {
+        if self.failure_threshold == 0 || self.window == 0 || self.half_open_probes == 0 {
+            return Err(Error::custom(
+                "Circuit breaker failure_threshold, window and half_open_probes must be greater than 0",
+            ));
+        }
+
+        if let Some(error_rate) = self.error_rate
+            && !(error_rate > 0.0 && error_rate <= 1.0)
+        {
+            return Err(Error::custom(
+                "Circuit breaker error_rate must be between 0 and 1",
+            ));
+        }
+
+        Ok(CircuitBreaker::new(
+            target.to_string(),
+            self.failure_threshold,
+            self.error_rate,
+            self.window,
+            Duration::from_secs(self.open_duration),
+            self.half_open_probes,
+        ))
+    }
+}
+
+impl TryFrom<ConcurrencyConfig> for ConcurrencyLimiter {
+    type Error = Error;
+
+    fn try_from(value: ConcurrencyConfig) -> Result<Self, Self::Error> {
+        if value.max_in_flight == 0 {
+            return Err(Error::custom(
+                "Concurrency max_in_flight must be greater than 0",
+            ));
+        }
+
+        Ok(ConcurrencyLimiter::new(
+            value.max_in_flight,
+            value.queue_size,
+            Duration::from_secs(value.queue_timeout),
+        ))
+    }
+}
+
 impl TryFrom<AllowedMethod> for actix_web::http::Method {
     type Error = Error;
 
@@ -338,18 +1934,80 @@ mod tests_try_from {
 
 #[cfg(test)]
 mod tests {
-    use crate::config::{AllowedMethod, Config};
+    use crate::config::{AllowedMethod, Config, RateLimitKeyKind, TlsVersion};
+    use crate::data::{
+        ApiKeySource, DEFAULT_TARGET_NAME, FanOutPolicy, FilterSource, RateLimitKey, UpstreamAuth,
+    };
     use secrecy::ExposeSecret;
     use std::collections::{HashMap, HashSet};
+    use std::time::Duration;
 
     const ENV_SERVER_HOST: &str = "SERVER.HOST";
     const ENV_SERVER_PORT: &str = "SERVER.PORT";
+    const ENV_SERVER_TLS_CERT_PATH: &str = "SERVER.TLS.CERT_PATH";
+    const ENV_SERVER_TLS_KEY_PATH: &str = "SERVER.TLS.KEY_PATH";
+    const ENV_SERVER_TLS_HTTP_ENABLED: &str = "SERVER.TLS.HTTP_ENABLED";
+    const ENV_SERVER_ADMIN_PORT: &str = "SERVER.ADMIN.PORT";
+    const ENV_SERVER_ADMIN_API_KEY_KEYS: &str = "SERVER.ADMIN.API_KEY.KEYS";
 
     const ENV_CLOUDFLARE_CLIENT_ID: &str = "CLOUDFLARE.CLIENT_ID";
     const ENV_CLOUDFLARE_CLIENT_SECRET: &str = "CLOUDFLARE.CLIENT_SECRET";
 
     const ENV_WEBHOOK_TARGET_BASE: &str = "WEBHOOK.TARGET_BASE";
     const ENV_WEBHOOK_PATHS: &str = "WEBHOOK.PATHS";
+    const ENV_WEBHOOK_ROUTE_PATH: &str = "WEBHOOK.ROUTES.GITHUB.PATH";
+    const ENV_WEBHOOK_ROUTE_METHODS: &str = "WEBHOOK.ROUTES.GITHUB.METHODS";
+    const ENV_WEBHOOK_ROUTE_SUBJECT: &str = "WEBHOOK.ROUTES.GITHUB.CLIENT_IDENTITY.SUBJECT";
+    const ENV_WEBHOOK_ROUTE_API_KEY_SOURCE: &str = "WEBHOOK.ROUTES.GITHUB.API_KEY.SOURCE";
+    const ENV_WEBHOOK_ROUTE_API_KEY_NAME: &str = "WEBHOOK.ROUTES.GITHUB.API_KEY.NAME";
+    const ENV_WEBHOOK_ROUTE_API_KEY_KEYS: &str = "WEBHOOK.ROUTES.GITHUB.API_KEY.KEYS";
+    const ENV_WEBHOOK_ROUTE_API_KEY_KEYS_FILE: &str = "WEBHOOK.ROUTES.GITHUB.API_KEY.KEYS_FILE";
+    const ENV_WEBHOOK_ROUTE_REPLAY_TIMESTAMP_HEADER: &str =
+        "WEBHOOK.ROUTES.GITHUB.REPLAY.TIMESTAMP_HEADER";
+    const ENV_WEBHOOK_ROUTE_REPLAY_TIMESTAMP_PATTERN: &str =
+        "WEBHOOK.ROUTES.GITHUB.REPLAY.TIMESTAMP_PATTERN";
+    const ENV_WEBHOOK_ROUTE_REPLAY_NONCE_JSON: &str = "WEBHOOK.ROUTES.GITHUB.REPLAY.NONCE_JSON";
+    const ENV_WEBHOOK_ROUTE_REPLAY_WINDOW: &str = "WEBHOOK.ROUTES.GITHUB.REPLAY.WINDOW";
+    const ENV_WEBHOOK_ROUTE_REPLAY_STORE_PATH: &str = "WEBHOOK.ROUTES.GITHUB.REPLAY.STORE_PATH";
+    const ENV_WEBHOOK_ROUTE_ACCESS_JWT_TEAM_DOMAIN: &str =
+        "WEBHOOK.ROUTES.GITHUB.ACCESS_JWT.TEAM_DOMAIN";
+    const ENV_WEBHOOK_ROUTE_ACCESS_JWT_AUDIENCES: &str =
+        "WEBHOOK.ROUTES.GITHUB.ACCESS_JWT.AUDIENCES";
+    const ENV_WEBHOOK_ROUTE_ACCESS_JWT_EMAILS: &str = "WEBHOOK.ROUTES.GITHUB.ACCESS_JWT.EMAILS";
+    const ENV_WEBHOOK_ROUTE_TARGET: &str = "WEBHOOK.ROUTES.GITHUB.TARGET";
+    const ENV_WEBHOOK_ROUTE_REWRITE: &str = "WEBHOOK.ROUTES.GITHUB.REWRITE";
+    const ENV_WEBHOOK_ROUTE_TRANSFORM_TEMPLATE_PATH: &str =
+        "WEBHOOK.ROUTES.GITHUB.TRANSFORM.TEMPLATE_PATH";
+    const ENV_WEBHOOK_ROUTE_TRANSFORM_CONTENT_TYPE: &str =
+        "WEBHOOK.ROUTES.GITHUB.TRANSFORM.CONTENT_TYPE";
+    const ENV_WEBHOOK_TARGET_AUDIT_BASE: &str = "WEBHOOK.TARGETS.AUDIT.BASE";
+    const ENV_WEBHOOK_TARGET_AUDIT_MIN_VERSION: &str = "WEBHOOK.TARGETS.AUDIT.TLS.MIN_VERSION";
+    const ENV_WEBHOOK_TLS_CA_PATHS: &str = "WEBHOOK.TLS.CA_PATHS";
+    const ENV_WEBHOOK_ROUTE_PROXY_URL: &str = "WEBHOOK.ROUTES.GITHUB.PROXY.URL";
+    const ENV_WEBHOOK_PROXY_URL: &str = "WEBHOOK.PROXY.URL";
+    const ENV_WEBHOOK_PROXY_NO_PROXY: &str = "WEBHOOK.PROXY.NO_PROXY";
+    const ENV_WEBHOOK_PROXY_USERNAME: &str = "WEBHOOK.PROXY.USERNAME";
+    const ENV_WEBHOOK_PROXY_PASSWORD: &str = "WEBHOOK.PROXY.PASSWORD";
+    const ENV_WEBHOOK_RATE_LIMIT_REQUESTS: &str = "WEBHOOK.RATE_LIMIT.REQUESTS";
+    const ENV_WEBHOOK_RATE_LIMIT_PERIOD: &str = "WEBHOOK.RATE_LIMIT.PERIOD";
+    const ENV_WEBHOOK_ROUTE_RATE_LIMIT_REQUESTS: &str = "WEBHOOK.ROUTES.GITHUB.RATE_LIMIT.REQUESTS";
+    const ENV_WEBHOOK_ROUTE_RATE_LIMIT_KEY: &str = "WEBHOOK.ROUTES.GITHUB.RATE_LIMIT.KEY";
+    const ENV_WEBHOOK_ROUTE_RATE_LIMIT_HEADER: &str = "WEBHOOK.ROUTES.GITHUB.RATE_LIMIT.HEADER";
+    const ENV_WEBHOOK_CONCURRENCY_MAX_IN_FLIGHT: &str = "WEBHOOK.CONCURRENCY.MAX_IN_FLIGHT";
+    const ENV_WEBHOOK_TARGET_AUDIT_MAX_IN_FLIGHT: &str =
+        "WEBHOOK.TARGETS.AUDIT.CONCURRENCY.MAX_IN_FLIGHT";
+    const ENV_WEBHOOK_TARGET_AUDIT_QUEUE_SIZE: &str =
+        "WEBHOOK.TARGETS.AUDIT.CONCURRENCY.QUEUE_SIZE";
+    const ENV_WEBHOOK_CIRCUIT_BREAKER_ERROR_RATE: &str = "WEBHOOK.CIRCUIT_BREAKER.ERROR_RATE";
+    const ENV_WEBHOOK_TARGET_AUDIT_OPEN_DURATION: &str =
+        "WEBHOOK.TARGETS.AUDIT.CIRCUIT_BREAKER.OPEN_DURATION";
+    const ENV_CLOUDFLARE_CLIENT_ID_FILE: &str = "CLOUDFLARE.CLIENT_ID_FILE";
+    const ENV_CLOUDFLARE_CLIENT_SECRET_FILE: &str = "CLOUDFLARE.CLIENT_SECRET_FILE";
+    const ENV_CLOUDFLARE_SECONDARY_CLIENT_ID: &str = "CLOUDFLARE.SECONDARY.CLIENT_ID";
+    const ENV_CLOUDFLARE_SECONDARY_CLIENT_SECRET: &str = "CLOUDFLARE.SECONDARY.CLIENT_SECRET";
+    const ENV_CLOUDFLARE_TOKEN_CACHE: &str = "CLOUDFLARE.TOKEN_CACHE";
+    const ENV_CLOUDFLARE_TOKEN_REFRESH_MARGIN: &str = "CLOUDFLARE.TOKEN_REFRESH_MARGIN";
+    const ENV_CONFIG_FILE: &str = "CONFIG_FILE";
 
     const CORRECT_SERVER_HOST: &str = "0.0.0.0";
     const CORRECT_SERVER_PORT: &str = "8080";
@@ -376,11 +2034,19 @@ mod tests {
         )?;
 
         assert_eq!(
-            config.cloudflare().client_id().expose_secret(),
+            config
+                .cloudflare()
+                .credentials()
+                .client_id()?
+                .expose_secret(),
             CORRECT_CLOUDFLARE_CLIENT_ID
         );
         assert_eq!(
-            config.cloudflare().client_secret().expose_secret(),
+            config
+                .cloudflare()
+                .credentials()
+                .client_secret()?
+                .expose_secret(),
             CORRECT_CLOUDFLARE_CLIENT_SECRET
         );
 
@@ -396,6 +2062,103 @@ mod tests {
         paths.insert("/test".to_string(), methods);
 
         assert_eq!(config.webhook().paths(), &paths);
+        assert!(!config.cloudflare().token_cache());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_get_configurations_token_cache() -> Result<(), Box<dyn std::error::Error>> {
+        let config = temp_env::with_vars(
+            vec![
+                (ENV_CLOUDFLARE_CLIENT_ID, Some(CORRECT_CLOUDFLARE_CLIENT_ID)),
+                (
+                    ENV_CLOUDFLARE_CLIENT_SECRET,
+                    Some(CORRECT_CLOUDFLARE_CLIENT_SECRET),
+                ),
+                (ENV_CLOUDFLARE_TOKEN_CACHE, Some("true")),
+                (ENV_CLOUDFLARE_TOKEN_REFRESH_MARGIN, Some("120")),
+                (ENV_WEBHOOK_TARGET_BASE, Some(CORRECT_WEBHOOK_TARGET_BASE)),
+                (ENV_WEBHOOK_PATHS, Some(CORRECT_WEBHOOK_PATHS)),
+            ],
+            Config::get_configuration,
+        )?;
+
+        assert!(config.cloudflare().token_cache());
+        assert_eq!(config.cloudflare().token_refresh_margin(), &120);
+
+        let web_hook_data = config.build_web_hook_data()?;
+        let service_token = web_hook_data.auth().service_token().unwrap();
+        assert!(service_token.token_cache().is_some());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_get_configurations_secret_files() -> Result<(), Box<dyn std::error::Error>> {
+        let dir = tempfile::tempdir()?;
+        let client_id_file = dir.path().join("client_id");
+        let client_secret_file = dir.path().join("client_secret");
+        std::fs::write(&client_id_file, "file_id\n")?;
+        std::fs::write(&client_secret_file, "file_secret\n")?;
+
+        let config = temp_env::with_vars(
+            vec![
+                (
+                    ENV_CLOUDFLARE_CLIENT_ID_FILE,
+                    Some(client_id_file.to_str().unwrap()),
+                ),
+                (
+                    ENV_CLOUDFLARE_CLIENT_SECRET_FILE,
+                    Some(client_secret_file.to_str().unwrap()),
+                ),
+                (ENV_CLOUDFLARE_SECONDARY_CLIENT_ID, Some("12345")),
+                (ENV_CLOUDFLARE_SECONDARY_CLIENT_SECRET, Some("true")),
+                (ENV_WEBHOOK_TARGET_BASE, Some(CORRECT_WEBHOOK_TARGET_BASE)),
+                (ENV_WEBHOOK_PATHS, Some(CORRECT_WEBHOOK_PATHS)),
+            ],
+            Config::get_configuration,
+        )?;
+
+        assert_eq!(
+            config
+                .cloudflare()
+                .credentials()
+                .client_id()?
+                .expose_secret(),
+            "file_id"
+        );
+        assert_eq!(
+            config.secret_files(),
+            vec![client_id_file, client_secret_file]
+        );
+
+        let service_token = config.cloudflare().build_service_token()?;
+        assert_eq!(service_token.client_secret(), "file_secret");
+        let secondary = service_token.secondary().as_ref().unwrap();
+        assert_eq!(secondary.client_id(), "12345");
+        assert_eq!(secondary.client_secret(), "true");
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_get_configurations_secret_files_invalid() -> Result<(), Box<dyn std::error::Error>> {
+        let config = temp_env::with_vars(
+            vec![
+                (ENV_CLOUDFLARE_CLIENT_ID, Some(CORRECT_CLOUDFLARE_CLIENT_ID)),
+                (ENV_CLOUDFLARE_CLIENT_ID_FILE, Some("/missing/client_id")),
+                (ENV_WEBHOOK_TARGET_BASE, Some(CORRECT_WEBHOOK_TARGET_BASE)),
+                (ENV_WEBHOOK_PATHS, Some(CORRECT_WEBHOOK_PATHS)),
+            ],
+            Config::get_configuration,
+        )?;
+
+        // Both set
+        assert!(config.cloudflare().credentials().client_id().is_err());
+        // Neither set
+        assert!(config.cloudflare().credentials().client_secret().is_err());
+        assert!(config.build_web_hook_data().is_err());
 
         Ok(())
     }
@@ -422,13 +2185,26 @@ mod tests {
 
         assert_eq!(config.server().host(), CORRECT_SERVER_HOST);
         assert_eq!(config.server().port(), &8080u16);
+        assert!(config.server().tls().is_none());
+        assert!(!config.server().metrics_enabled());
+        assert!(!config.server().ready_enabled());
+        assert_eq!(config.server().shutdown_delay(), &5);
+        assert_eq!(config.server().shutdown_timeout(), &30);
 
         assert_eq!(
-            config.cloudflare().client_id().expose_secret(),
+            config
+                .cloudflare()
+                .credentials()
+                .client_id()?
+                .expose_secret(),
             CORRECT_CLOUDFLARE_CLIENT_ID
         );
         assert_eq!(
-            config.cloudflare().client_secret().expose_secret(),
+            config
+                .cloudflare()
+                .credentials()
+                .client_secret()?
+                .expose_secret(),
             CORRECT_CLOUDFLARE_CLIENT_SECRET
         );
 
@@ -456,4 +2232,1244 @@ mod tests {
 
         Ok(())
     }
+
+    #[test]
+    fn test_get_configurations_tls() -> Result<(), Box<dyn std::error::Error>> {
+        let config = temp_env::with_vars(
+            vec![
+                (ENV_SERVER_TLS_CERT_PATH, Some("/certs/tls.crt")),
+                (ENV_SERVER_TLS_KEY_PATH, Some("/certs/tls.key")),
+                (ENV_SERVER_TLS_HTTP_ENABLED, Some("true")),
+                (ENV_CLOUDFLARE_CLIENT_ID, Some(CORRECT_CLOUDFLARE_CLIENT_ID)),
+                (
+                    ENV_CLOUDFLARE_CLIENT_SECRET,
+                    Some(CORRECT_CLOUDFLARE_CLIENT_SECRET),
+                ),
+                (ENV_WEBHOOK_TARGET_BASE, Some(CORRECT_WEBHOOK_TARGET_BASE)),
+                (ENV_WEBHOOK_PATHS, Some(CORRECT_WEBHOOK_PATHS)),
+            ],
+            Config::get_configuration,
+        )?;
+
+        let tls = config.server().tls().as_ref().unwrap();
+        assert_eq!(tls.cert_path().to_str(), Some("/certs/tls.crt"));
+        assert_eq!(tls.key_path().to_str(), Some("/certs/tls.key"));
+        assert_eq!(tls.port(), &8443u16);
+        assert!(tls.http_enabled());
+        assert_eq!(tls.reload_interval(), &60u64);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_get_configurations_admin() -> Result<(), Box<dyn std::error::Error>> {
+        let config = temp_env::with_vars(
+            vec![
+                (ENV_SERVER_ADMIN_PORT, Some("9001")),
+                (ENV_SERVER_ADMIN_API_KEY_KEYS, Some("admin-key")),
+                (ENV_CLOUDFLARE_CLIENT_ID, Some(CORRECT_CLOUDFLARE_CLIENT_ID)),
+                (
+                    ENV_CLOUDFLARE_CLIENT_SECRET,
+                    Some(CORRECT_CLOUDFLARE_CLIENT_SECRET),
+                ),
+                (ENV_WEBHOOK_TARGET_BASE, Some(CORRECT_WEBHOOK_TARGET_BASE)),
+                (ENV_WEBHOOK_PATHS, Some(CORRECT_WEBHOOK_PATHS)),
+            ],
+            Config::get_configuration,
+        )?;
+
+        let admin = config.server().admin().as_ref().unwrap();
+        assert_eq!(admin.host(), "127.0.0.1");
+        assert_eq!(admin.port(), &9001u16);
+        assert_eq!(
+            admin.api_key().source(),
+            &crate::config::ApiKeySourceKind::Bearer
+        );
+
+        // Secrets are redacted when the configuration is shown
+        let serialized = serde_json::to_value(&config)?;
+        assert_eq!(
+            serialized["server"]["admin"]["api_key"]["keys"],
+            serde_json::json!(["[REDACTED]"])
+        );
+        assert_eq!(serialized["cloudflare"]["client_id"], "[REDACTED]");
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_get_configurations_routes() -> Result<(), Box<dyn std::error::Error>> {
+        let config = temp_env::with_vars(
+            vec![
+                (ENV_CLOUDFLARE_CLIENT_ID, Some(CORRECT_CLOUDFLARE_CLIENT_ID)),
+                (
+                    ENV_CLOUDFLARE_CLIENT_SECRET,
+                    Some(CORRECT_CLOUDFLARE_CLIENT_SECRET),
+                ),
+                (ENV_WEBHOOK_TARGET_BASE, Some(CORRECT_WEBHOOK_TARGET_BASE)),
+                (ENV_WEBHOOK_ROUTE_PATH, Some("gh/.*")),
+                (ENV_WEBHOOK_ROUTE_METHODS, Some("POST,put")),
+                (ENV_WEBHOOK_ROUTE_SUBJECT, Some("CN=ci")),
+            ],
+            Config::get_configuration,
+        )?;
+
+        assert!(config.webhook().paths().is_empty());
+
+        let route = config.webhook().routes().get("github").unwrap();
+        assert_eq!(route.path(), "gh/.*");
+        assert_eq!(
+            route.methods(),
+            &vec![AllowedMethod::POST, AllowedMethod::PUT]
+                .into_iter()
+                .collect()
+        );
+
+        let allowed_paths = config.webhook().allowed_paths()?;
+        let allowed_path = allowed_paths
+            .find("gh/repo", &actix_web::http::Method::POST)
+            .unwrap();
+        assert!(allowed_path.options().client_identity().is_some());
+        assert!(
+            allowed_paths
+                .find("gh/repo", &actix_web::http::Method::GET)
+                .is_none()
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_get_configurations_file() -> Result<(), Box<dyn std::error::Error>> {
+        let dir = tempfile::tempdir()?;
+        let path = dir.path().join("config.yaml");
+        std::fs::write(
+            &path,
+            r#"
+cloudflare:
+  client_id: client_id
+  client_secret: file_secret
+webhook:
+  target_base: https://example.com/
+  routes:
+    github:
+      path: gh/.*
+      methods: [POST, PUT]
+      max_body_size: 65536
+      client_identity:
+        san: ^ci\.example\.com$
+"#,
+        )?;
+
+        let config = temp_env::with_vars(
+            vec![
+                (ENV_CONFIG_FILE, Some(path.to_str().unwrap())),
+                // Environment variables take precedence
+                (
+                    ENV_CLOUDFLARE_CLIENT_SECRET,
+                    Some(CORRECT_CLOUDFLARE_CLIENT_SECRET),
+                ),
+            ],
+            Config::get_configuration,
+        )?;
+
+        assert_eq!(
+            config
+                .cloudflare()
+                .credentials()
+                .client_secret()?
+                .expose_secret(),
+            CORRECT_CLOUDFLARE_CLIENT_SECRET
+        );
+
+        let route = config.webhook().routes().get("github").unwrap();
+        assert_eq!(
+            route.client_identity().as_ref().unwrap().san().as_deref(),
+            Some(r"^ci\.example\.com$")
+        );
+        assert_eq!(*route.max_body_size(), Some(65536));
+        assert_eq!(*config.webhook().max_body_size(), 10 * 1024 * 1024);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_get_configurations_missing_paths() {
+        let config = temp_env::with_vars(
+            vec![
+                (ENV_CLOUDFLARE_CLIENT_ID, Some(CORRECT_CLOUDFLARE_CLIENT_ID)),
+                (
+                    ENV_CLOUDFLARE_CLIENT_SECRET,
+                    Some(CORRECT_CLOUDFLARE_CLIENT_SECRET),
+                ),
+                (ENV_WEBHOOK_TARGET_BASE, Some(CORRECT_WEBHOOK_TARGET_BASE)),
+            ],
+            Config::get_configuration,
+        );
+
+        assert!(config.is_err());
+    }
+
+    #[test]
+    fn test_get_configurations_duplicate_route_path() -> Result<(), Box<dyn std::error::Error>> {
+        let config = temp_env::with_vars(
+            vec![
+                (ENV_CLOUDFLARE_CLIENT_ID, Some(CORRECT_CLOUDFLARE_CLIENT_ID)),
+                (
+                    ENV_CLOUDFLARE_CLIENT_SECRET,
+                    Some(CORRECT_CLOUDFLARE_CLIENT_SECRET),
+                ),
+                (ENV_WEBHOOK_TARGET_BASE, Some(CORRECT_WEBHOOK_TARGET_BASE)),
+                (ENV_WEBHOOK_PATHS, Some("gh/.*:POST")),
+                (ENV_WEBHOOK_ROUTE_PATH, Some("gh/.*")),
+                (ENV_WEBHOOK_ROUTE_METHODS, Some("POST")),
+            ],
+            Config::get_configuration,
+        )?;
+
+        assert!(config.webhook().allowed_paths().is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_get_configurations_duplicate_escaped_route_path()
+    -> Result<(), Box<dyn std::error::Error>> {
+        let config = temp_env::with_vars(
+            vec![
+                (ENV_CLOUDFLARE_CLIENT_ID, Some(CORRECT_CLOUDFLARE_CLIENT_ID)),
+                (
+                    ENV_CLOUDFLARE_CLIENT_SECRET,
+                    Some(CORRECT_CLOUDFLARE_CLIENT_SECRET),
+                ),
+                (ENV_WEBHOOK_TARGET_BASE, Some(CORRECT_WEBHOOK_TARGET_BASE)),
+                (ENV_WEBHOOK_PATHS, Some("gh/.*:POST")),
+                (ENV_WEBHOOK_ROUTE_PATH, Some("^gh/.*$")),
+                (ENV_WEBHOOK_ROUTE_METHODS, Some("POST")),
+            ],
+            Config::get_configuration,
+        )?;
+
+        assert!(config.webhook().allowed_paths().is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_get_configurations_route_priority() -> Result<(), Box<dyn std::error::Error>> {
+        let config = temp_env::with_vars(
+            vec![
+                (ENV_CLOUDFLARE_CLIENT_ID, Some(CORRECT_CLOUDFLARE_CLIENT_ID)),
+                (
+                    ENV_CLOUDFLARE_CLIENT_SECRET,
+                    Some(CORRECT_CLOUDFLARE_CLIENT_SECRET),
+                ),
+                (ENV_WEBHOOK_TARGET_BASE, Some(CORRECT_WEBHOOK_TARGET_BASE)),
+                (ENV_WEBHOOK_PATHS, Some("gh/github:POST")),
+                (ENV_WEBHOOK_ROUTE_PATH, Some("gh/.*")),
+                (ENV_WEBHOOK_ROUTE_METHODS, Some("POST")),
+                ("WEBHOOK.ROUTES.GITHUB.PRIORITY", Some("10")),
+            ],
+            Config::get_configuration,
+        )?;
+
+        let allowed_paths = config.webhook().allowed_paths()?;
+        let route = allowed_paths
+            .find("gh/github", &actix_web::http::Method::POST)
+            .unwrap();
+        assert_eq!(route.pattern(), "^gh/.*$");
+        assert_eq!(route.priority(), &10);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_get_configurations_targets() -> Result<(), Box<dyn std::error::Error>> {
+        let config = temp_env::with_vars(
+            vec![
+                (ENV_CLOUDFLARE_CLIENT_ID, Some(CORRECT_CLOUDFLARE_CLIENT_ID)),
+                (
+                    ENV_CLOUDFLARE_CLIENT_SECRET,
+                    Some(CORRECT_CLOUDFLARE_CLIENT_SECRET),
+                ),
+                (ENV_WEBHOOK_TARGET_BASE, Some(CORRECT_WEBHOOK_TARGET_BASE)),
+                (ENV_WEBHOOK_TLS_CA_PATHS, Some("/certs/a.pem, /certs/b.pem")),
+                (
+                    ENV_WEBHOOK_TARGET_AUDIT_BASE,
+                    Some("https://audit.example.com/"),
+                ),
+                (ENV_WEBHOOK_TARGET_AUDIT_MIN_VERSION, Some("TLSv1.3")),
+                ("WEBHOOK.TARGETS.AUDIT.TIMEOUT.REQUEST", Some("5")),
+                (ENV_WEBHOOK_ROUTE_PATH, Some("gh/.*")),
+                (ENV_WEBHOOK_ROUTE_METHODS, Some("POST")),
+                (ENV_WEBHOOK_ROUTE_TARGET, Some("audit")),
+            ],
+            Config::get_configuration,
+        )?;
+
+        let tls = config.webhook().tls().as_ref().unwrap();
+        assert_eq!(
+            tls.ca_paths(),
+            &vec![
+                std::path::PathBuf::from("/certs/a.pem"),
+                std::path::PathBuf::from("/certs/b.pem")
+            ]
+        );
+        assert_eq!(tls.min_version(), &TlsVersion::Tls12);
+        assert_eq!(config.webhook().timeout().connect(), &10);
+        assert_eq!(config.webhook().timeout().request(), &60);
+
+        let target = config.webhook().targets().get("audit").unwrap();
+        assert_eq!(target.base().as_str(), "https://audit.example.com/");
+        assert_eq!(
+            target.tls().as_ref().unwrap().min_version(),
+            &TlsVersion::Tls13
+        );
+        let timeout = target.timeout().as_ref().unwrap();
+        assert_eq!(timeout.connect(), &10);
+        assert_eq!(timeout.request(), &5);
+
+        let allowed_paths = config.webhook().allowed_paths()?;
+        let route = allowed_paths
+            .find("gh/repo", &actix_web::http::Method::POST)
+            .unwrap();
+        assert_eq!(route.options().target().as_deref(), Some("audit"));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_get_configurations_rewrite() -> Result<(), Box<dyn std::error::Error>> {
+        let config = temp_env::with_vars(
+            vec![
+                (ENV_CLOUDFLARE_CLIENT_ID, Some(CORRECT_CLOUDFLARE_CLIENT_ID)),
+                (
+                    ENV_CLOUDFLARE_CLIENT_SECRET,
+                    Some(CORRECT_CLOUDFLARE_CLIENT_SECRET),
+                ),
+                (ENV_WEBHOOK_TARGET_BASE, Some(CORRECT_WEBHOOK_TARGET_BASE)),
+                (ENV_WEBHOOK_ROUTE_PATH, Some("gh/(?P<repo>[^/]+)")),
+                (ENV_WEBHOOK_ROUTE_METHODS, Some("POST")),
+                (ENV_WEBHOOK_ROUTE_REWRITE, Some("hooks/github?repo=${repo}")),
+            ],
+            Config::get_configuration,
+        )?;
+
+        let allowed_paths = config.webhook().allowed_paths()?;
+        let route = allowed_paths
+            .find("gh/api", &actix_web::http::Method::POST)
+            .unwrap();
+        assert_eq!(
+            route.target_path("gh/api").unwrap(),
+            "hooks/github?repo=api"
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_get_configurations_unknown_target() -> Result<(), Box<dyn std::error::Error>> {
+        let config = temp_env::with_vars(
+            vec![
+                (ENV_CLOUDFLARE_CLIENT_ID, Some(CORRECT_CLOUDFLARE_CLIENT_ID)),
+                (
+                    ENV_CLOUDFLARE_CLIENT_SECRET,
+                    Some(CORRECT_CLOUDFLARE_CLIENT_SECRET),
+                ),
+                (ENV_WEBHOOK_TARGET_BASE, Some(CORRECT_WEBHOOK_TARGET_BASE)),
+                (ENV_WEBHOOK_ROUTE_PATH, Some("gh/.*")),
+                (ENV_WEBHOOK_ROUTE_METHODS, Some("POST")),
+                (ENV_WEBHOOK_ROUTE_TARGET, Some("audit")),
+            ],
+            Config::get_configuration,
+        )?;
+
+        assert!(config.webhook().allowed_paths().is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_get_configurations_default_route_target() -> Result<(), Box<dyn std::error::Error>> {
+        let config = temp_env::with_vars(
+            vec![
+                (ENV_CLOUDFLARE_CLIENT_ID, Some(CORRECT_CLOUDFLARE_CLIENT_ID)),
+                (
+                    ENV_CLOUDFLARE_CLIENT_SECRET,
+                    Some(CORRECT_CLOUDFLARE_CLIENT_SECRET),
+                ),
+                (ENV_WEBHOOK_TARGET_BASE, Some(CORRECT_WEBHOOK_TARGET_BASE)),
+                (ENV_WEBHOOK_ROUTE_PATH, Some("gh/.*")),
+                (ENV_WEBHOOK_ROUTE_METHODS, Some("POST")),
+                (ENV_WEBHOOK_ROUTE_TARGET, Some(DEFAULT_TARGET_NAME)),
+                (
+                    ENV_WEBHOOK_ROUTE_PROXY_URL,
+                    Some("socks5h://socks.example.com:1080"),
+                ),
+            ],
+            Config::get_configuration,
+        )?;
+
+        // The target base can be named like the other targets
+        config.webhook().allowed_paths()?;
+        let web_hook_data = config.build_web_hook_data()?;
+        let route_target = web_hook_data.find_target("route.github")?;
+        assert_eq!(route_target.base().as_str(), CORRECT_WEBHOOK_TARGET_BASE);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_get_configurations_proxy() -> Result<(), Box<dyn std::error::Error>> {
+        let config = temp_env::with_vars(
+            vec![
+                (ENV_CLOUDFLARE_CLIENT_ID, Some(CORRECT_CLOUDFLARE_CLIENT_ID)),
+                (
+                    ENV_CLOUDFLARE_CLIENT_SECRET,
+                    Some(CORRECT_CLOUDFLARE_CLIENT_SECRET),
+                ),
+                (ENV_WEBHOOK_TARGET_BASE, Some(CORRECT_WEBHOOK_TARGET_BASE)),
+                (ENV_WEBHOOK_PROXY_URL, Some("http://proxy.example.com:3128")),
+                (ENV_WEBHOOK_PROXY_NO_PROXY, Some("localhost,10.0.0.0/8")),
+                (ENV_WEBHOOK_PROXY_USERNAME, Some("proxy")),
+                // Parsed as a number from the environment
+                (ENV_WEBHOOK_PROXY_PASSWORD, Some("12345")),
+                (ENV_WEBHOOK_ROUTE_PATH, Some("gh/.*")),
+                (ENV_WEBHOOK_ROUTE_METHODS, Some("POST")),
+                (
+                    ENV_WEBHOOK_ROUTE_PROXY_URL,
+                    Some("socks5h://socks.example.com:1080"),
+                ),
+                (ENV_WEBHOOK_CONCURRENCY_MAX_IN_FLIGHT, Some("10")),
+            ],
+            Config::get_configuration,
+        )?;
+
+        let proxy = config.webhook().proxy().as_ref().unwrap();
+        assert_eq!(proxy.url(), "http://proxy.example.com:3128");
+        assert_eq!(proxy.no_proxy().as_deref(), Some("localhost,10.0.0.0/8"));
+        assert_eq!(proxy.password().as_ref().unwrap().expose_secret(), "12345");
+        assert!(!config.webhook().proxy_from_env());
+
+        // The route forwards to a copy of the target base with its own proxy
+        let allowed_paths = config.webhook().allowed_paths()?;
+        let route = allowed_paths
+            .find("gh/repo", &actix_web::http::Method::POST)
+            .unwrap();
+        assert_eq!(route.options().target().as_deref(), Some("route.github"));
+
+        // The copy shares the in flight limit of the target base
+        let web_hook_data = config.build_web_hook_data()?;
+        let route_target = web_hook_data.find_target("route.github")?;
+        assert_eq!(route_target.base().as_str(), CORRECT_WEBHOOK_TARGET_BASE);
+        assert_eq!(route_target.name(), DEFAULT_TARGET_NAME);
+        assert!(std::sync::Arc::ptr_eq(
+            route_target.concurrency_limiter().as_ref().unwrap(),
+            web_hook_data
+                .default_target()
+                .concurrency_limiter()
+                .as_ref()
+                .unwrap(),
+        ));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_get_configurations_reserved_target_name() -> Result<(), Box<dyn std::error::Error>> {
+        let dir = tempfile::tempdir()?;
+        let path = dir.path().join("config.yaml");
+        std::fs::write(
+            &path,
+            r#"
+cloudflare:
+  client_id: client_id
+  client_secret: client_secret
+webhook:
+  target_base: https://example.com/
+  targets:
+    route.github:
+      base: https://other.example.com/
+  routes:
+    github:
+      path: gh/.*
+      methods: [POST]
+      proxy:
+        url: socks5h://socks.example.com:1080
+"#,
+        )?;
+
+        let config = temp_env::with_var(
+            ENV_CONFIG_FILE,
+            Some(path.to_str().unwrap()),
+            Config::get_configuration,
+        )?;
+
+        assert!(config.webhook().targets().contains_key("route.github"));
+        assert!(config.build_web_hook_data().is_err());
+
+        // The target base is reserved as well
+        std::fs::write(
+            &path,
+            r#"
+cloudflare:
+  client_id: client_id
+  client_secret: client_secret
+webhook:
+  target_base: https://example.com/
+  targets:
+    default:
+      base: https://other.example.com/
+  routes:
+    github:
+      path: gh/.*
+      methods: [POST]
+"#,
+        )?;
+
+        let config = temp_env::with_var(
+            ENV_CONFIG_FILE,
+            Some(path.to_str().unwrap()),
+            Config::get_configuration,
+        )?;
+
+        assert!(config.build_web_hook_data().is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_get_configurations_rate_limit() -> Result<(), Box<dyn std::error::Error>> {
+        let config = temp_env::with_vars(
+            vec![
+                (ENV_CLOUDFLARE_CLIENT_ID, Some(CORRECT_CLOUDFLARE_CLIENT_ID)),
+                (
+                    ENV_CLOUDFLARE_CLIENT_SECRET,
+                    Some(CORRECT_CLOUDFLARE_CLIENT_SECRET),
+                ),
+                (ENV_WEBHOOK_TARGET_BASE, Some(CORRECT_WEBHOOK_TARGET_BASE)),
+                (ENV_WEBHOOK_RATE_LIMIT_REQUESTS, Some("100")),
+                (ENV_WEBHOOK_RATE_LIMIT_PERIOD, Some("60")),
+                (ENV_WEBHOOK_ROUTE_PATH, Some("gh/.*")),
+                (ENV_WEBHOOK_ROUTE_METHODS, Some("POST")),
+                (ENV_WEBHOOK_ROUTE_RATE_LIMIT_REQUESTS, Some("5")),
+                (ENV_WEBHOOK_ROUTE_RATE_LIMIT_KEY, Some("header")),
+                (ENV_WEBHOOK_ROUTE_RATE_LIMIT_HEADER, Some("X-Hub-Signature")),
+            ],
+            Config::get_configuration,
+        )?;
+
+        let rate_limit = config.webhook().rate_limit().as_ref().unwrap();
+        assert_eq!(*rate_limit.requests(), 100);
+        assert_eq!(*rate_limit.period(), 60);
+        assert_eq!(*rate_limit.key(), RateLimitKeyKind::ClientIp);
+
+        let rate_limiter = config.webhook().rate_limiter()?.unwrap();
+        assert_eq!(*rate_limiter.burst(), 100);
+
+        let allowed_paths = config.webhook().allowed_paths()?;
+        let route = allowed_paths
+            .find("gh/repo", &actix_web::http::Method::POST)
+            .unwrap();
+        let rate_limiter = route.options().rate_limiter().as_ref().unwrap();
+        assert_eq!(
+            *rate_limiter.key(),
+            RateLimitKey::Header(actix_web::http::header::HeaderName::from_static(
+                "x-hub-signature"
+            ))
+        );
+        assert_eq!(*rate_limiter.burst(), 5);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_get_configurations_rate_limit_missing_header() -> Result<(), Box<dyn std::error::Error>>
+    {
+        let config = temp_env::with_vars(
+            vec![
+                (ENV_CLOUDFLARE_CLIENT_ID, Some(CORRECT_CLOUDFLARE_CLIENT_ID)),
+                (
+                    ENV_CLOUDFLARE_CLIENT_SECRET,
+                    Some(CORRECT_CLOUDFLARE_CLIENT_SECRET),
+                ),
+                (ENV_WEBHOOK_TARGET_BASE, Some(CORRECT_WEBHOOK_TARGET_BASE)),
+                (ENV_WEBHOOK_ROUTE_PATH, Some("gh/.*")),
+                (ENV_WEBHOOK_ROUTE_METHODS, Some("POST")),
+                (ENV_WEBHOOK_ROUTE_RATE_LIMIT_REQUESTS, Some("5")),
+                (ENV_WEBHOOK_ROUTE_RATE_LIMIT_KEY, Some("header")),
+            ],
+            Config::get_configuration,
+        )?;
+
+        assert!(config.webhook().allowed_paths().is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_get_configurations_concurrency() -> Result<(), Box<dyn std::error::Error>> {
+        let config = temp_env::with_vars(
+            vec![
+                (ENV_CLOUDFLARE_CLIENT_ID, Some(CORRECT_CLOUDFLARE_CLIENT_ID)),
+                (
+                    ENV_CLOUDFLARE_CLIENT_SECRET,
+                    Some(CORRECT_CLOUDFLARE_CLIENT_SECRET),
+                ),
+                (ENV_WEBHOOK_TARGET_BASE, Some(CORRECT_WEBHOOK_TARGET_BASE)),
+                (ENV_WEBHOOK_PATHS, Some(CORRECT_WEBHOOK_PATHS)),
+                (ENV_WEBHOOK_CONCURRENCY_MAX_IN_FLIGHT, Some("10")),
+                (
+                    ENV_WEBHOOK_TARGET_AUDIT_BASE,
+                    Some("https://audit.example.com/"),
+                ),
+                (ENV_WEBHOOK_TARGET_AUDIT_MAX_IN_FLIGHT, Some("2")),
+                (ENV_WEBHOOK_TARGET_AUDIT_QUEUE_SIZE, Some("5")),
+            ],
+            Config::get_configuration,
+        )?;
+
+        let default_target = config.webhook().build_default_target()?;
+        let limiter = default_target.concurrency_limiter().as_ref().unwrap();
+        assert_eq!(*limiter.max_in_flight(), 10);
+        assert_eq!(*limiter.queue_size(), 0);
+        assert_eq!(*limiter.queue_timeout(), std::time::Duration::from_secs(5));
+
+        let targets = config
+            .webhook()
+            .build_targets(&config.webhook().build_default_target()?)?;
+        let limiter = targets["audit"].concurrency_limiter().as_ref().unwrap();
+        assert_eq!(*limiter.max_in_flight(), 2);
+        assert_eq!(*limiter.queue_size(), 5);
+
+        Ok(())
+    }
+
+    fn circuit_breaker_configuration(error_rate: &str) -> crate::Result<Config> {
+        temp_env::with_vars(
+            vec![
+                (ENV_CLOUDFLARE_CLIENT_ID, Some(CORRECT_CLOUDFLARE_CLIENT_ID)),
+                (
+                    ENV_CLOUDFLARE_CLIENT_SECRET,
+                    Some(CORRECT_CLOUDFLARE_CLIENT_SECRET),
+                ),
+                (ENV_WEBHOOK_TARGET_BASE, Some(CORRECT_WEBHOOK_TARGET_BASE)),
+                (ENV_WEBHOOK_PATHS, Some(CORRECT_WEBHOOK_PATHS)),
+                (ENV_WEBHOOK_CIRCUIT_BREAKER_ERROR_RATE, Some(error_rate)),
+                (
+                    ENV_WEBHOOK_TARGET_AUDIT_BASE,
+                    Some("https://audit.example.com/"),
+                ),
+                (ENV_WEBHOOK_TARGET_AUDIT_OPEN_DURATION, Some("120")),
+            ],
+            Config::get_configuration,
+        )
+    }
+
+    #[test]
+    fn test_get_configurations_circuit_breaker() -> Result<(), Box<dyn std::error::Error>> {
+        let config = circuit_breaker_configuration("0.5")?;
+
+        let default_target = config.webhook().build_default_target()?;
+        let circuit_breaker = default_target.circuit_breaker().as_ref().unwrap();
+        assert_eq!(circuit_breaker.target(), "default");
+        assert_eq!(*circuit_breaker.failure_threshold(), 5);
+        assert_eq!(*circuit_breaker.error_rate(), Some(0.5));
+        assert_eq!(*circuit_breaker.window(), 20);
+
+        let targets = config
+            .webhook()
+            .build_targets(&config.webhook().build_default_target()?)?;
+        let circuit_breaker = targets["audit"].circuit_breaker().as_ref().unwrap();
+        assert_eq!(circuit_breaker.target(), "audit");
+        assert_eq!(*circuit_breaker.error_rate(), None);
+        assert_eq!(
+            *circuit_breaker.open_duration(),
+            std::time::Duration::from_secs(120)
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_get_configurations_circuit_breaker_invalid_error_rate()
+    -> Result<(), Box<dyn std::error::Error>> {
+        let config = circuit_breaker_configuration("1.5")?;
+        assert!(config.webhook().build_default_target().is_err());
+
+        Ok(())
+    }
+
+    fn filter_configuration(filters: &str) -> crate::Result<Config> {
+        let dir = tempfile::tempdir()?;
+        let path = dir.path().join("config.yaml");
+        std::fs::write(
+            &path,
+            format!(
+                r#"
+cloudflare:
+  client_id: client_id
+  client_secret: client_secret
+webhook:
+  target_base: https://example.com/
+  routes:
+    github:
+      path: gh/.*
+      methods: [POST]
+      filtered_status: 200
+      filters:
+{filters}
+"#
+            ),
+        )?;
+
+        temp_env::with_var(
+            ENV_CONFIG_FILE,
+            Some(path.to_str().unwrap()),
+            Config::get_configuration,
+        )
+    }
+
+    #[test]
+    fn test_get_configurations_filters() -> Result<(), Box<dyn std::error::Error>> {
+        let config = filter_configuration(
+            r#"
+        - header: X-GitHub-Event
+          in: [push, workflow_run]
+        - json: $.ref
+          regex: ^refs/heads/main$
+"#,
+        )?;
+
+        let allowed_paths = config.webhook().allowed_paths()?;
+        let route = allowed_paths
+            .find("gh/repo", &actix_web::http::Method::POST)
+            .unwrap();
+        assert_eq!(route.options().filters().len(), 2);
+        assert!(route.options().requires_structured_body());
+        assert_eq!(
+            route.options().filtered_status(),
+            actix_web::http::StatusCode::OK
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_get_configurations_filters_invalid() -> Result<(), Box<dyn std::error::Error>> {
+        let config = filter_configuration(
+            r#"
+        - header: X-GitHub-Event
+          equals: push
+          regex: ^push$
+"#,
+        )?;
+        assert!(config.webhook().allowed_paths().is_err());
+
+        let config = filter_configuration(
+            r#"
+        - json: ref
+          equals: refs/heads/main
+"#,
+        )?;
+        assert!(config.webhook().allowed_paths().is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_get_configurations_transform() -> Result<(), Box<dyn std::error::Error>> {
+        let dir = tempfile::tempdir()?;
+        let template_path = dir.path().join("alert.j2");
+        std::fs::write(&template_path, "{{ body.status }}")?;
+
+        let config = temp_env::with_vars(
+            vec![
+                (ENV_CLOUDFLARE_CLIENT_ID, Some(CORRECT_CLOUDFLARE_CLIENT_ID)),
+                (
+                    ENV_CLOUDFLARE_CLIENT_SECRET,
+                    Some(CORRECT_CLOUDFLARE_CLIENT_SECRET),
+                ),
+                (ENV_WEBHOOK_TARGET_BASE, Some(CORRECT_WEBHOOK_TARGET_BASE)),
+                (ENV_WEBHOOK_ROUTE_PATH, Some("alerts")),
+                (ENV_WEBHOOK_ROUTE_METHODS, Some("POST")),
+                (
+                    ENV_WEBHOOK_ROUTE_TRANSFORM_TEMPLATE_PATH,
+                    Some(template_path.to_str().unwrap()),
+                ),
+                (ENV_WEBHOOK_ROUTE_TRANSFORM_CONTENT_TYPE, Some("text/plain")),
+            ],
+            Config::get_configuration,
+        )?;
+
+        let allowed_paths = config.webhook().allowed_paths()?;
+        let route = allowed_paths
+            .find("alerts", &actix_web::http::Method::POST)
+            .unwrap();
+        let transform = route.options().transform().as_ref().unwrap();
+        assert_eq!(transform.content_type(), "text/plain");
+
+        let body = serde_json::json!({ "status": "firing" });
+        let rendered = transform.render(&crate::data::TransformRequest::new(
+            "POST",
+            "alerts",
+            Default::default(),
+            &HashMap::new(),
+            &body,
+        ))?;
+        assert_eq!(rendered, "firing");
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_get_configurations_api_key() -> Result<(), Box<dyn std::error::Error>> {
+        let dir = tempfile::tempdir()?;
+        let keys_file = dir.path().join("api_keys");
+        std::fs::write(&keys_file, "file-key\n\nnext-key\n")?;
+
+        let config = temp_env::with_vars(
+            vec![
+                (ENV_CLOUDFLARE_CLIENT_ID, Some(CORRECT_CLOUDFLARE_CLIENT_ID)),
+                (
+                    ENV_CLOUDFLARE_CLIENT_SECRET,
+                    Some(CORRECT_CLOUDFLARE_CLIENT_SECRET),
+                ),
+                (ENV_WEBHOOK_TARGET_BASE, Some(CORRECT_WEBHOOK_TARGET_BASE)),
+                (ENV_WEBHOOK_ROUTE_PATH, Some("deploy")),
+                (ENV_WEBHOOK_ROUTE_METHODS, Some("POST")),
+                (ENV_WEBHOOK_ROUTE_API_KEY_SOURCE, Some("header")),
+                (ENV_WEBHOOK_ROUTE_API_KEY_NAME, Some("X-Api-Key")),
+                (ENV_WEBHOOK_ROUTE_API_KEY_KEYS, Some("env-key")),
+                (
+                    ENV_WEBHOOK_ROUTE_API_KEY_KEYS_FILE,
+                    Some(keys_file.to_str().unwrap()),
+                ),
+            ],
+            Config::get_configuration,
+        )?;
+
+        assert_eq!(config.secret_files(), vec![keys_file]);
+
+        let allowed_paths = config.webhook().allowed_paths()?;
+        let route = allowed_paths
+            .find("deploy", &actix_web::http::Method::POST)
+            .unwrap();
+        let api_key = route.options().api_key().as_ref().unwrap();
+        assert_eq!(
+            api_key.source(),
+            &ApiKeySource::Header(actix_web::http::header::HeaderName::from_static(
+                "x-api-key"
+            ))
+        );
+
+        let params = HashMap::new();
+        for key in ["env-key", "file-key", "next-key"] {
+            let mut headers = actix_web::http::header::HeaderMap::new();
+            headers.insert(
+                actix_web::http::header::HeaderName::from_static("x-api-key"),
+                actix_web::http::header::HeaderValue::from_static(key),
+            );
+            assert!(api_key.authenticate(&headers, &params).is_ok());
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_get_configurations_api_key_without_name() -> Result<(), Box<dyn std::error::Error>> {
+        let config = temp_env::with_vars(
+            vec![
+                (ENV_CLOUDFLARE_CLIENT_ID, Some(CORRECT_CLOUDFLARE_CLIENT_ID)),
+                (
+                    ENV_CLOUDFLARE_CLIENT_SECRET,
+                    Some(CORRECT_CLOUDFLARE_CLIENT_SECRET),
+                ),
+                (ENV_WEBHOOK_TARGET_BASE, Some(CORRECT_WEBHOOK_TARGET_BASE)),
+                (ENV_WEBHOOK_ROUTE_PATH, Some("deploy")),
+                (ENV_WEBHOOK_ROUTE_METHODS, Some("POST")),
+                (ENV_WEBHOOK_ROUTE_API_KEY_SOURCE, Some("query")),
+                (ENV_WEBHOOK_ROUTE_API_KEY_KEYS, Some("env-key")),
+            ],
+            Config::get_configuration,
+        )?;
+
+        assert!(config.webhook().allowed_paths().is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_get_configurations_replay() -> Result<(), Box<dyn std::error::Error>> {
+        let dir = tempfile::tempdir()?;
+        let config = temp_env::with_vars(
+            vec![
+                (ENV_CLOUDFLARE_CLIENT_ID, Some(CORRECT_CLOUDFLARE_CLIENT_ID)),
+                (
+                    ENV_CLOUDFLARE_CLIENT_SECRET,
+                    Some(CORRECT_CLOUDFLARE_CLIENT_SECRET),
+                ),
+                (ENV_WEBHOOK_TARGET_BASE, Some(CORRECT_WEBHOOK_TARGET_BASE)),
+                (ENV_WEBHOOK_ROUTE_PATH, Some("deploy")),
+                (ENV_WEBHOOK_ROUTE_METHODS, Some("POST")),
+                (
+                    ENV_WEBHOOK_ROUTE_REPLAY_TIMESTAMP_HEADER,
+                    Some("Stripe-Signature"),
+                ),
+                (ENV_WEBHOOK_ROUTE_REPLAY_TIMESTAMP_PATTERN, Some(r"t=(\d+)")),
+                (ENV_WEBHOOK_ROUTE_REPLAY_NONCE_JSON, Some("$.id")),
+                (ENV_WEBHOOK_ROUTE_REPLAY_WINDOW, Some("60")),
+                (
+                    ENV_WEBHOOK_ROUTE_REPLAY_STORE_PATH,
+                    Some(dir.path().join("nonces").to_str().unwrap()),
+                ),
+            ],
+            Config::get_configuration,
+        )?;
+
+        let allowed_paths = config.webhook().allowed_paths()?;
+        let route = allowed_paths
+            .find("deploy", &actix_web::http::Method::POST)
+            .unwrap();
+        let options = route.options();
+        let replay_guard = options.replay_guard().as_ref().unwrap();
+        assert_eq!(replay_guard.window(), &Duration::from_secs(60));
+        assert!(replay_guard.requires_json());
+        assert!(options.requires_structured_body());
+        assert!(matches!(
+            replay_guard.nonce(),
+            Some(FilterSource::Json(pointer)) if pointer == "/id"
+        ));
+        let timestamp = replay_guard.timestamp().as_ref().unwrap();
+        assert!(matches!(
+            timestamp.source(),
+            FilterSource::Header(name) if name == "stripe-signature"
+        ));
+        assert_eq!(timestamp.pattern().as_ref().unwrap().as_str(), r"t=(\d+)");
+        assert!(dir.path().join("nonces").is_dir());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_get_configurations_replay_without_source() -> Result<(), Box<dyn std::error::Error>> {
+        let config = temp_env::with_vars(
+            vec![
+                (ENV_CLOUDFLARE_CLIENT_ID, Some(CORRECT_CLOUDFLARE_CLIENT_ID)),
+                (
+                    ENV_CLOUDFLARE_CLIENT_SECRET,
+                    Some(CORRECT_CLOUDFLARE_CLIENT_SECRET),
+                ),
+                (ENV_WEBHOOK_TARGET_BASE, Some(CORRECT_WEBHOOK_TARGET_BASE)),
+                (ENV_WEBHOOK_ROUTE_PATH, Some("deploy")),
+                (ENV_WEBHOOK_ROUTE_METHODS, Some("POST")),
+                (ENV_WEBHOOK_ROUTE_REPLAY_WINDOW, Some("60")),
+            ],
+            Config::get_configuration,
+        )?;
+
+        assert!(config.webhook().allowed_paths().is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_get_configurations_access_jwt() -> Result<(), Box<dyn std::error::Error>> {
+        let config = temp_env::with_vars(
+            vec![
+                (ENV_CLOUDFLARE_CLIENT_ID, Some(CORRECT_CLOUDFLARE_CLIENT_ID)),
+                (
+                    ENV_CLOUDFLARE_CLIENT_SECRET,
+                    Some(CORRECT_CLOUDFLARE_CLIENT_SECRET),
+                ),
+                (ENV_WEBHOOK_TARGET_BASE, Some(CORRECT_WEBHOOK_TARGET_BASE)),
+                (ENV_WEBHOOK_ROUTE_PATH, Some("deploy")),
+                (ENV_WEBHOOK_ROUTE_METHODS, Some("POST")),
+                (
+                    ENV_WEBHOOK_ROUTE_ACCESS_JWT_TEAM_DOMAIN,
+                    Some("team.cloudflareaccess.com"),
+                ),
+                (ENV_WEBHOOK_ROUTE_ACCESS_JWT_AUDIENCES, Some("aud1, aud2")),
+                (ENV_WEBHOOK_ROUTE_ACCESS_JWT_EMAILS, Some("ci@example.com")),
+            ],
+            Config::get_configuration,
+        )?;
+
+        let allowed_paths = config.webhook().allowed_paths()?;
+        let route = allowed_paths
+            .find("deploy", &actix_web::http::Method::POST)
+            .unwrap();
+        let access_jwt = route.options().access_jwt().as_ref().unwrap();
+        assert_eq!(access_jwt.emails(), &vec!["ci@example.com".to_string()]);
+        assert!(access_jwt.groups().is_empty());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_get_configurations_access_jwt_without_keys() -> Result<(), Box<dyn std::error::Error>> {
+        let config = temp_env::with_vars(
+            vec![
+                (ENV_CLOUDFLARE_CLIENT_ID, Some(CORRECT_CLOUDFLARE_CLIENT_ID)),
+                (
+                    ENV_CLOUDFLARE_CLIENT_SECRET,
+                    Some(CORRECT_CLOUDFLARE_CLIENT_SECRET),
+                ),
+                (ENV_WEBHOOK_TARGET_BASE, Some(CORRECT_WEBHOOK_TARGET_BASE)),
+                (ENV_WEBHOOK_ROUTE_PATH, Some("deploy")),
+                (ENV_WEBHOOK_ROUTE_METHODS, Some("POST")),
+                (ENV_WEBHOOK_ROUTE_ACCESS_JWT_AUDIENCES, Some("aud1")),
+            ],
+            Config::get_configuration,
+        )?;
+
+        assert!(config.webhook().allowed_paths().is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_get_configurations_upstream_auth() -> Result<(), Box<dyn std::error::Error>> {
+        let dir = tempfile::tempdir()?;
+        let secret_file = dir.path().join("client_secret");
+        std::fs::write(&secret_file, "oauth_secret\n")?;
+        let path = dir.path().join("config.yaml");
+        std::fs::write(
+            &path,
+            format!(
+                r#"
+webhook:
+  target_base: https://example.com/
+  auth:
+    type: oauth2
+    token_url: https://idp.example.com/oauth/token
+    client_id: proxy
+    client_secret_file: {}
+    scopes: read, write
+  targets:
+    legacy:
+      base: https://legacy.example.com/
+      auth:
+        type: basic
+        username: proxy
+        password: password
+    grafana:
+      base: https://grafana.example.com/
+      auth:
+        type: headers
+        headers:
+          X-Api-Key: key
+          X-Org-Id: 42
+    access:
+      base: https://access.example.com/
+      auth:
+        type: cloudflare_access
+        client_id: access_id
+        client_secret: access_secret
+  paths: "test:POST"
+"#,
+                secret_file.display()
+            ),
+        )?;
+
+        let config = temp_env::with_var(
+            ENV_CONFIG_FILE,
+            Some(path.to_str().unwrap()),
+            Config::get_configuration,
+        )?;
+        assert_eq!(config.secret_files(), vec![secret_file]);
+
+        let web_hook_data = config.build_web_hook_data()?;
+        let UpstreamAuth::OAuth2(credentials) = web_hook_data.auth().as_ref() else {
+            panic!("Expected OAuth2 authentication");
+        };
+        assert_eq!(credentials.client_id(), "proxy");
+        assert_eq!(
+            credentials.scopes(),
+            &vec!["read".to_string(), "write".to_string()]
+        );
+
+        let targets = web_hook_data.targets();
+        assert!(matches!(
+            targets["legacy"].auth().as_deref(),
+            Some(UpstreamAuth::Basic(_))
+        ));
+        let Some(UpstreamAuth::Headers(headers)) = targets["grafana"].auth().as_deref() else {
+            panic!("Expected static headers");
+        };
+        assert_eq!(headers["x-api-key"], "key");
+        assert_eq!(headers["x-org-id"], "42");
+        let service_token = targets["access"].auth().as_ref().unwrap().service_token();
+        assert_eq!(service_token.unwrap().client_id(), "access_id");
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_get_configurations_upstream_auth_invalid() -> Result<(), Box<dyn std::error::Error>> {
+        // Without WEBHOOK.AUTH the Cloudflare service token is required
+        let config = temp_env::with_vars(
+            vec![
+                (ENV_WEBHOOK_TARGET_BASE, Some(CORRECT_WEBHOOK_TARGET_BASE)),
+                (ENV_WEBHOOK_PATHS, Some(CORRECT_WEBHOOK_PATHS)),
+            ],
+            Config::get_configuration,
+        )?;
+        assert!(config.build_web_hook_data().is_err());
+
+        let dir = tempfile::tempdir()?;
+        let path = dir.path().join("config.yaml");
+        std::fs::write(
+            &path,
+            r#"
+webhook:
+  target_base: https://example.com/
+  targets:
+    audit:
+      base: https://audit.example.com/
+      cloudflare:
+        client_id: audit_id
+        client_secret: audit_secret
+      auth:
+        type: basic
+        username: audit
+        password: password
+  paths: "test:POST"
+"#,
+        )?;
+        let config = temp_env::with_var(
+            ENV_CONFIG_FILE,
+            Some(path.to_str().unwrap()),
+            Config::get_configuration,
+        )?;
+        assert!(
+            config
+                .webhook()
+                .build_targets(&config.webhook().build_default_target()?)
+                .is_err()
+        );
+
+        Ok(())
+    }
+
+    fn fan_out_configuration(route: &str) -> crate::Result<Config> {
+        let dir = tempfile::tempdir()?;
+        let path = dir.path().join("config.yaml");
+        std::fs::write(
+            &path,
+            format!(
+                r#"
+cloudflare:
+  client_id: client_id
+  client_secret: client_secret
+webhook:
+  target_base: https://example.com/
+  targets:
+    audit:
+      base: https://audit.example.com/
+      cloudflare:
+        client_id: audit_id
+        client_secret: audit_secret
+  routes:
+    github:
+      path: gh/.*
+      methods: [POST]
+{route}
+"#
+            ),
+        )?;
+
+        temp_env::with_var(
+            ENV_CONFIG_FILE,
+            Some(path.to_str().unwrap()),
+            Config::get_configuration,
+        )
+    }
+
+    #[test]
+    fn test_get_configurations_fan_out() -> Result<(), Box<dyn std::error::Error>> {
+        let config = fan_out_configuration(
+            r#"
+      targets: [default, audit]
+      fan_out:
+        policy: all
+        retries: 5
+"#,
+        )?;
+
+        let allowed_paths = config.webhook().allowed_paths()?;
+        let route = allowed_paths
+            .find("gh/repo", &actix_web::http::Method::POST)
+            .unwrap();
+        assert_eq!(
+            route.options().target().as_deref(),
+            Some(DEFAULT_TARGET_NAME)
+        );
+        let fan_out = route.options().fan_out().as_ref().unwrap();
+        assert_eq!(fan_out.targets(), &vec!["audit".to_string()]);
+        assert_eq!(fan_out.policy(), &FanOutPolicy::All);
+        assert_eq!(*fan_out.retries(), 5);
+        assert_eq!(*fan_out.retry_delay(), Duration::from_secs(1));
+
+        let targets = config
+            .webhook()
+            .build_targets(&config.webhook().build_default_target()?)?;
+        let auth = targets["audit"].auth().as_ref().unwrap();
+        assert_eq!(auth.service_token().unwrap().client_id(), "audit_id");
+        assert!(config.webhook().build_default_target()?.auth().is_none());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_get_configurations_fan_out_invalid() -> Result<(), Box<dyn std::error::Error>> {
+        let config = fan_out_configuration("      targets: [default, unknown]")?;
+        assert!(config.webhook().allowed_paths().is_err());
+
+        let config = fan_out_configuration(
+            r#"
+      target: audit
+      targets: [default, audit]
+"#,
+        )?;
+        assert!(config.webhook().allowed_paths().is_err());
+
+        let config = fan_out_configuration(
+            r#"
+      targets: [audit]
+      fan_out:
+        policy: all
+"#,
+        )?;
+        assert!(config.webhook().allowed_paths().is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_get_configurations_mirror() -> Result<(), Box<dyn std::error::Error>> {
+        let config = fan_out_configuration(
+            r#"
+      mirror:
+        target: audit
+        percent: 10
+"#,
+        )?;
+
+        let allowed_paths = config.webhook().allowed_paths()?;
+        let route = allowed_paths
+            .find("gh/repo", &actix_web::http::Method::POST)
+            .unwrap();
+        let mirror = route.options().mirror().as_ref().unwrap();
+        assert_eq!(mirror.target(), "audit");
+        assert_eq!(*mirror.percent(), 10.0);
+
+        let config = fan_out_configuration("      mirror: { target: unknown }")?;
+        assert!(config.webhook().allowed_paths().is_err());
+
+        let config = fan_out_configuration("      mirror: { target: audit, percent: 120 }")?;
+        assert!(config.webhook().allowed_paths().is_err());
+
+        Ok(())
+    }
 }
@@ -11,17 +11,23 @@ impl ActixToReqwestConverter {
         !matches!(name, "host")
     }
 
+    /// Read the whole body, failing as soon as it grows beyond the max size.
     pub async fn convert_body(
         payload: &mut actix_web::web::Payload,
-    ) -> ConverterResult<reqwest::Body> {
+        max_size: Option<usize>,
+    ) -> ConverterResult<actix_web::web::Bytes> {
         let mut bytes = actix_web::web::BytesMut::new();
         while let Some(item) = payload.next().await {
             let item = item?;
+            if let Some(max_size) = max_size
+                && bytes.len() + item.len() > max_size
+            {
+                return Err(ConverterError::PayloadTooLarge(max_size));
+            }
             bytes.extend_from_slice(&item);
         }
 
-        let body = reqwest::Body::from(bytes.freeze());
-        Ok(body)
+        Ok(bytes.freeze())
     }
 
     pub fn convert_headers(
@@ -60,10 +66,21 @@ impl ReqwestToActixConverter {
         response: reqwest::Response,
     ) -> ConverterResult<actix_web::HttpResponse> {
         let status_code = ReqwestToActixConverter::convert_status_code(response.status())?;
+        // The body is passed through as is, so the caller needs to know how it is encoded
+        let content_encoding = response
+            .headers()
+            .get(reqwest::header::CONTENT_ENCODING)
+            .map(|value| value.as_bytes().to_vec());
         let body = response.bytes().await?;
 
-        let response = actix_web::HttpResponse::build(status_code).body(body);
-        Ok(response)
+        let mut builder = actix_web::HttpResponse::build(status_code);
+        if let Some(content_encoding) = content_encoding
+            && let Ok(value) = actix_web::http::header::HeaderValue::from_bytes(&content_encoding)
+        {
+            builder.insert_header((actix_web::http::header::CONTENT_ENCODING, value));
+        }
+
+        Ok(builder.body(body))
     }
 }
 
@@ -75,6 +92,8 @@ pub enum ConverterError {
     InvalidStatusCode(String),
     #[error("Reqwest Error")]
     ReqwestError(#[from] reqwest::Error),
+    #[error("Payload larger than {0} bytes")]
+    PayloadTooLarge(usize),
 }
 
 impl ConverterError {
@@ -85,7 +104,10 @@ impl ConverterError {
 
 impl From<ConverterError> for actix_web::Error {
     fn from(e: ConverterError) -> Self {
-        actix_web::error::ErrorBadRequest(e)
+        match e {
+            ConverterError::PayloadTooLarge(_) => actix_web::error::ErrorPayloadTooLarge(e),
+            _ => actix_web::error::ErrorBadRequest(e),
+        }
     }
 }
 
@@ -171,4 +193,28 @@ mod tests_reqwest_to_actix_converter {
 
         // TODO: VERIFY BODY
     }
+
+    #[tokio::test]
+    async fn test_convert_response_content_encoding() {
+        let url = Url::parse("https://example.com").unwrap();
+        let response = Builder::new()
+            .status(200)
+            .url(url.clone())
+            .header("Content-Encoding", "gzip")
+            .body("foo")
+            .unwrap();
+
+        let response = Response::from(response);
+        let actix_response = super::ReqwestToActixConverter::convert_response(response)
+            .await
+            .unwrap();
+
+        assert_eq!(
+            actix_response
+                .headers()
+                .get(actix_web::http::header::CONTENT_ENCODING)
+                .unwrap(),
+            "gzip"
+        );
+    }
 }
@@ -0,0 +1,194 @@
+use base64::Engine;
+use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+use reqwest::header::{HeaderMap, HeaderValue, SET_COOKIE};
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+const ACCESS_TOKEN_COOKIE: &str = "CF_Authorization";
+
+/// Cloudflare Access tokens issued after a service token login, per Access application origin.
+#[derive(Debug)]
+pub struct AccessTokenCache {
+    // Tokens are refreshed this long before they expire
+    refresh_margin: Duration,
+    tokens: Mutex<HashMap<String, CachedToken>>,
+}
+
+#[derive(Debug)]
+struct CachedToken {
+    value: HeaderValue,
+    expires_at: SystemTime,
+}
+
+impl AccessTokenCache {
+    pub fn new(refresh_margin: Duration) -> Self {
+        Self {
+            refresh_margin,
+            tokens: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// The cached token of the origin, unless it expires within the refresh margin.
+    pub fn get(&self, origin: &str) -> Option<HeaderValue> {
+        let mut tokens = self.tokens.lock().expect("Access token lock poisoned");
+        let token = tokens.get(origin)?;
+        if SystemTime::now() + self.refresh_margin < token.expires_at {
+            return Some(token.value.clone());
+        }
+
+        tokens.remove(origin);
+        None
+    }
+
+    /// Cache the token Cloudflare Access set as cookie in the response. Returns if a token was found.
+    pub fn store(&self, origin: &str, headers: &HeaderMap) -> bool {
+        let Some((mut value, expires_at)) = headers
+            .get_all(SET_COOKIE)
+            .iter()
+            .filter_map(|cookie| cookie.to_str().ok())
+            .find_map(access_token_cookie)
+            .and_then(|token| Some((HeaderValue::from_str(token).ok()?, token_expiry(token)?)))
+        else {
+            return false;
+        };
+
+        value.set_sensitive(true);
+        self.tokens
+            .lock()
+            .expect("Access token lock poisoned")
+            .insert(origin.to_string(), CachedToken { value, expires_at });
+
+        true
+    }
+
+    /// Forget the token of the origin, e.g. after Cloudflare Access rejected it.
+    pub fn invalidate(&self, origin: &str) {
+        self.tokens
+            .lock()
+            .expect("Access token lock poisoned")
+            .remove(origin);
+    }
+}
+
+/// Value of the `CF_Authorization` cookie of a `Set-Cookie` header.
+fn access_token_cookie(cookie: &str) -> Option<&str> {
+    let (name, value) = cookie.split(';').next()?.split_once('=')?;
+    (name.trim() == ACCESS_TOKEN_COOKIE && !value.trim().is_empty()).then(|| value.trim())
+}
+
+/// Expiry from the `exp` claim of the JWT. The signature is checked by Cloudflare Access, not by us.
+fn token_expiry(token: &str) -> Option<SystemTime> {
+    #[derive(serde::Deserialize)]
+    struct Claims {
+        exp: u64,
+    }
+
+    let payload = URL_SAFE_NO_PAD.decode(token.split('.').nth(1)?).ok()?;
+    let claims: Claims = serde_json::from_slice(&payload).ok()?;
+
+    UNIX_EPOCH.checked_add(Duration::from_secs(claims.exp))
+}
+
+#[cfg(test)]
+pub(crate) mod test_utils {
+    use base64::Engine;
+    use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+    use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+    /// Unsigned JWT expiring after the duration.
+    pub fn jwt(expires_in: Duration) -> String {
+        let exp = (SystemTime::now() + expires_in)
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_secs();
+        let claims = serde_json::json!({ "aud": ["audience"], "exp": exp });
+
+        format!(
+            "{}.{}.signature",
+            URL_SAFE_NO_PAD.encode(r#"{"alg":"RS256","typ":"JWT"}"#),
+            URL_SAFE_NO_PAD.encode(claims.to_string())
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::data::AccessTokenCache;
+    use crate::data::access_token::test_utils::jwt;
+    use crate::data::access_token::{access_token_cookie, token_expiry};
+    use base64::Engine;
+    use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+    use reqwest::header::{HeaderMap, HeaderValue, SET_COOKIE};
+    use std::time::{Duration, SystemTime};
+
+    const ORIGIN: &str = "https://example.com";
+
+    fn headers(cookies: &[String]) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        for cookie in cookies {
+            headers.append(SET_COOKIE, HeaderValue::from_str(cookie).unwrap());
+        }
+
+        headers
+    }
+
+    #[test]
+    fn test_access_token_cookie() {
+        assert_eq!(
+            access_token_cookie("CF_Authorization=abc.def.ghi; Path=/; HttpOnly; Secure"),
+            Some("abc.def.ghi")
+        );
+        assert_eq!(access_token_cookie("CF_Authorization=; Path=/"), None);
+        assert_eq!(access_token_cookie("CF_AppSession=abc; Path=/"), None);
+        assert_eq!(access_token_cookie("invalid"), None);
+    }
+
+    #[test]
+    fn test_token_expiry() {
+        let expiry = token_expiry(&jwt(Duration::from_secs(3600))).unwrap();
+        let remaining = expiry.duration_since(SystemTime::now()).unwrap();
+        assert!(remaining > Duration::from_secs(3590));
+
+        assert!(token_expiry("not-a-jwt").is_none());
+        assert!(token_expiry("a.bm90LWpzb24.c").is_none());
+
+        // Expiries beyond what the system time can hold are not cached instead of panicking
+        let claims = URL_SAFE_NO_PAD.encode(format!(r#"{{"exp":{}}}"#, u64::MAX));
+        assert!(token_expiry(&format!("a.{claims}.c")).is_none());
+    }
+
+    #[test]
+    fn test_store_and_get() {
+        let cache = AccessTokenCache::new(Duration::from_secs(60));
+        assert!(cache.get(ORIGIN).is_none());
+
+        let token = jwt(Duration::from_secs(3600));
+        assert!(!cache.store(ORIGIN, &headers(&["CF_AppSession=abc".to_string()])));
+        assert!(cache.store(
+            ORIGIN,
+            &headers(&[
+                "CF_AppSession=abc".to_string(),
+                format!("CF_Authorization={token}; Path=/; HttpOnly"),
+            ])
+        ));
+
+        let cached = cache.get(ORIGIN).unwrap();
+        assert_eq!(cached, token.as_str());
+        assert!(cached.is_sensitive());
+        assert!(cache.get("https://other.example.com").is_none());
+
+        cache.invalidate(ORIGIN);
+        assert!(cache.get(ORIGIN).is_none());
+    }
+
+    #[test]
+    fn test_get_refresh_margin() {
+        let cache = AccessTokenCache::new(Duration::from_secs(60));
+        let token = jwt(Duration::from_secs(30));
+        assert!(cache.store(ORIGIN, &headers(&[format!("CF_Authorization={token}")])));
+
+        // Expires within the refresh margin
+        assert!(cache.get(ORIGIN).is_none());
+    }
+}
@@ -0,0 +1,252 @@
+use actix_web::http::header::{AUTHORIZATION, HeaderMap, HeaderName};
+use base64::Engine;
+use base64::engine::general_purpose::STANDARD;
+use ring::digest::{SHA256, digest};
+use secrecy::{ExposeSecret, SecretString};
+use std::collections::HashMap;
+use subtle::ConstantTimeEq;
+
+/// Where callers present their key.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub enum ApiKeySource {
+    // Authorization: Bearer <key>
+    Bearer,
+    // Custom header carrying the key
+    Header(HeaderName),
+    // Query parameter, for senders that can only configure a URL
+    Query(String),
+    // Authorization: Basic with user:password as key
+    Basic,
+}
+
+impl ApiKeySource {
+    /// Header carrying the credential, which is not forwarded.
+    pub fn header(&self) -> Option<&HeaderName> {
+        match self {
+            ApiKeySource::Bearer | ApiKeySource::Basic => Some(&AUTHORIZATION),
+            ApiKeySource::Header(name) => Some(name),
+            ApiKeySource::Query(_) => None,
+        }
+    }
+
+    /// `WWW-Authenticate` challenge of rejected requests.
+    pub fn challenge(&self) -> Option<&'static str> {
+        match self {
+            ApiKeySource::Bearer => Some("Bearer"),
+            ApiKeySource::Basic => Some("Basic realm=\"webhook\""),
+            ApiKeySource::Header(_) | ApiKeySource::Query(_) => None,
+        }
+    }
+
+    /// Query parameter carrying the credential, which is not forwarded.
+    pub fn query(&self) -> Option<&str> {
+        match self {
+            ApiKeySource::Query(name) => Some(name),
+            _ => None,
+        }
+    }
+}
+
+/// Restricts a route to callers presenting one of the accepted keys.
+#[derive(Getters, Debug)]
+#[getset(get = "pub")]
+pub struct ApiKeyAuth {
+    source: ApiKeySource,
+    // SHA-256 of the accepted keys, so all comparisons take the same time
+    #[getset(skip)]
+    keys: Vec<[u8; 32]>,
+}
+
+impl ApiKeyAuth {
+    /// Accept any of the keys, several keys allow rotating them without downtime.
+    pub fn new(source: ApiKeySource, keys: &[SecretString]) -> Self {
+        Self {
+            source,
+            keys: keys
+                .iter()
+                .map(|key| hash(key.expose_secret().as_bytes()))
+                .collect(),
+        }
+    }
+
+    /// Check the credential of the request against all accepted keys.
+    pub fn authenticate(
+        &self,
+        headers: &HeaderMap,
+        params: &HashMap<String, String>,
+    ) -> Result<(), ApiKeyError> {
+        let credential = self
+            .credential(headers, params)
+            .ok_or(ApiKeyError::Missing)?;
+        let credential = hash(&credential);
+        let accepted = self
+            .keys
+            .iter()
+            .fold(subtle::Choice::from(0), |accepted, key| {
+                accepted | key.ct_eq(&credential)
+            });
+
+        if bool::from(accepted) {
+            Ok(())
+        } else {
+            Err(ApiKeyError::Invalid)
+        }
+    }
+
+    fn credential(&self, headers: &HeaderMap, params: &HashMap<String, String>) -> Option<Vec<u8>> {
+        match &self.source {
+            ApiKeySource::Bearer => authorization(headers, "Bearer").map(|key| key.to_vec()),
+            ApiKeySource::Header(name) => headers.get(name).map(|value| value.as_bytes().to_vec()),
+            ApiKeySource::Query(name) => params.get(name).map(|key| key.as_bytes().to_vec()),
+            ApiKeySource::Basic => STANDARD.decode(authorization(headers, "Basic")?).ok(),
+        }
+    }
+}
+
+/// Credentials of the `Authorization` header with the scheme, which is case-insensitive.
+fn authorization<'a>(headers: &'a HeaderMap, scheme: &str) -> Option<&'a [u8]> {
+    let value = headers.get(AUTHORIZATION)?.as_bytes();
+    let (prefix, credentials) = value.split_at_checked(scheme.len() + 1)?;
+    (prefix[..scheme.len()].eq_ignore_ascii_case(scheme.as_bytes()) && prefix[scheme.len()] == b' ')
+        .then(|| credentials.trim_ascii())
+}
+
+fn hash(value: &[u8]) -> [u8; 32] {
+    digest(&SHA256, value)
+        .as_ref()
+        .try_into()
+        .expect("SHA-256 digest is 32 bytes")
+}
+
+/// Why the key of a caller was not accepted.
+#[derive(Debug, thiserror::Error)]
+pub enum ApiKeyError {
+    #[error("No API key")]
+    Missing,
+    #[error("Invalid API key")]
+    Invalid,
+}
+
+impl ApiKeyError {
+    /// Label of the unauthorized metric.
+    pub fn reason(&self) -> &'static str {
+        match self {
+            ApiKeyError::Missing => "missing",
+            ApiKeyError::Invalid => "invalid",
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::data::{ApiKeyAuth, ApiKeyError, ApiKeySource};
+    use actix_web::http::header::{AUTHORIZATION, HeaderMap, HeaderName, HeaderValue};
+    use secrecy::SecretString;
+    use std::collections::HashMap;
+
+    fn auth(source: ApiKeySource) -> ApiKeyAuth {
+        ApiKeyAuth::new(
+            source,
+            &[SecretString::from("old-key"), SecretString::from("new-key")],
+        )
+    }
+
+    fn headers(name: HeaderName, value: &str) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        headers.insert(name, HeaderValue::from_str(value).unwrap());
+        headers
+    }
+
+    #[test]
+    fn test_authenticate_bearer() {
+        let auth = auth(ApiKeySource::Bearer);
+        let params = HashMap::new();
+
+        assert!(
+            auth.authenticate(&headers(AUTHORIZATION, "Bearer old-key"), &params)
+                .is_ok()
+        );
+        assert!(
+            auth.authenticate(&headers(AUTHORIZATION, "bearer new-key"), &params)
+                .is_ok()
+        );
+        assert!(matches!(
+            auth.authenticate(&headers(AUTHORIZATION, "Bearer other-key"), &params),
+            Err(ApiKeyError::Invalid)
+        ));
+        assert!(matches!(
+            auth.authenticate(&headers(AUTHORIZATION, "Basic old-key"), &params),
+            Err(ApiKeyError::Missing)
+        ));
+        assert!(matches!(
+            auth.authenticate(&HeaderMap::new(), &params),
+            Err(ApiKeyError::Missing)
+        ));
+    }
+
+    #[test]
+    fn test_authenticate_header() {
+        let name = HeaderName::from_static("x-api-key");
+        let auth = auth(ApiKeySource::Header(name.clone()));
+        let params = HashMap::new();
+
+        assert!(
+            auth.authenticate(&headers(name.clone(), "new-key"), &params)
+                .is_ok()
+        );
+        assert!(matches!(
+            auth.authenticate(&headers(name, "new-key2"), &params),
+            Err(ApiKeyError::Invalid)
+        ));
+        assert!(matches!(
+            auth.authenticate(&headers(AUTHORIZATION, "Bearer new-key"), &params),
+            Err(ApiKeyError::Missing)
+        ));
+    }
+
+    #[test]
+    fn test_authenticate_query() {
+        let auth = auth(ApiKeySource::Query("token".to_string()));
+
+        let params = HashMap::from([("token".to_string(), "old-key".to_string())]);
+        assert!(auth.authenticate(&HeaderMap::new(), &params).is_ok());
+
+        let params = HashMap::from([("key".to_string(), "old-key".to_string())]);
+        assert!(matches!(
+            auth.authenticate(&HeaderMap::new(), &params),
+            Err(ApiKeyError::Missing)
+        ));
+    }
+
+    #[test]
+    fn test_authenticate_basic() {
+        let auth = ApiKeyAuth::new(ApiKeySource::Basic, &[SecretString::from("ci:password")]);
+        let params = HashMap::new();
+
+        // ci:password
+        assert!(
+            auth.authenticate(&headers(AUTHORIZATION, "Basic Y2k6cGFzc3dvcmQ="), &params)
+                .is_ok()
+        );
+        // ci:other
+        assert!(matches!(
+            auth.authenticate(&headers(AUTHORIZATION, "Basic Y2k6b3RoZXI="), &params),
+            Err(ApiKeyError::Invalid)
+        ));
+        assert!(matches!(
+            auth.authenticate(&headers(AUTHORIZATION, "Basic not base64"), &params),
+            Err(ApiKeyError::Missing)
+        ));
+    }
+
+    #[test]
+    fn test_source_credential_location() {
+        assert_eq!(ApiKeySource::Bearer.header(), Some(&AUTHORIZATION));
+        assert_eq!(ApiKeySource::Basic.query(), None);
+        assert_eq!(ApiKeySource::Query("token".to_string()).header(), None);
+        assert_eq!(
+            ApiKeySource::Query("token".to_string()).query(),
+            Some("token")
+        );
+    }
+}
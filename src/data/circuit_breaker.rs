@@ -0,0 +1,328 @@
+use crate::metrics::METRICS;
+use std::collections::VecDeque;
+use std::sync::Mutex;
+use std::time::Duration;
+use tokio::time::Instant;
+
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum CircuitState {
+    Closed,
+    Open,
+    HalfOpen,
+}
+
+impl CircuitState {
+    pub fn name(&self) -> &'static str {
+        match self {
+            CircuitState::Closed => "closed",
+            CircuitState::Open => "open",
+            CircuitState::HalfOpen => "half_open",
+        }
+    }
+
+    /// Value of the circuit state gauge.
+    fn value(&self) -> i64 {
+        match self {
+            CircuitState::Closed => 0,
+            CircuitState::HalfOpen => 1,
+            CircuitState::Open => 2,
+        }
+    }
+}
+
+#[derive(Debug)]
+struct Circuit {
+    state: CircuitState,
+    consecutive_failures: u32,
+    // Outcomes of the latest requests, true for failures
+    outcomes: VecDeque<bool>,
+    opened_at: Instant,
+    probes_in_flight: u32,
+    probe_successes: u32,
+}
+
+/// Stops forwarding to a failing target and probes it again after a while.
+#[derive(Getters, Debug)]
+#[getset(get = "pub")]
+pub struct CircuitBreaker {
+    target: String,
+    // Consecutive failures that open the circuit
+    failure_threshold: u32,
+    // Failure rate within the window that opens the circuit
+    error_rate: Option<f64>,
+    window: usize,
+    open_duration: Duration,
+    // Successful probes required to close the circuit again
+    half_open_probes: u32,
+    #[getset(skip)]
+    circuit: Mutex<Circuit>,
+}
+
+impl CircuitBreaker {
+    pub fn new(
+        target: String,
+        failure_threshold: u32,
+        error_rate: Option<f64>,
+        window: usize,
+        open_duration: Duration,
+        half_open_probes: u32,
+    ) -> Self {
+        METRICS
+            .circuit_state()
+            .with_label_values(&[target.as_str()])
+            .set(CircuitState::Closed.value());
+
+        Self {
+            target,
+            failure_threshold,
+            error_rate,
+            window,
+            open_duration,
+            half_open_probes,
+            circuit: Mutex::new(Circuit {
+                state: CircuitState::Closed,
+                consecutive_failures: 0,
+                outcomes: VecDeque::with_capacity(window),
+                opened_at: Instant::now(),
+                probes_in_flight: 0,
+                probe_successes: 0,
+            }),
+        }
+    }
+
+    pub fn state(&self) -> CircuitState {
+        self.lock().state
+    }
+
+    /// Whether the other breaker was configured the same way.
+    pub fn same_settings(&self, other: &Self) -> bool {
+        self.target == other.target
+            && self.failure_threshold == other.failure_threshold
+            && self.error_rate == other.error_rate
+            && self.window == other.window
+            && self.open_duration == other.open_duration
+            && self.half_open_probes == other.half_open_probes
+    }
+
+    /// Set the state gauge again, after a new breaker of the target reset it.
+    pub fn report_state(&self) {
+        METRICS
+            .circuit_state()
+            .with_label_values(&[self.target.as_str()])
+            .set(self.state().value());
+    }
+
+    /// Allow a request through the circuit, or return how long until the target is probed again.
+    pub fn acquire(&self) -> Result<CircuitPermit<'_>, Duration> {
+        let mut circuit = self.lock();
+
+        if circuit.state == CircuitState::Open {
+            let elapsed = circuit.opened_at.elapsed();
+            if elapsed < self.open_duration {
+                return Err(self.open_duration - elapsed);
+            }
+
+            self.transition(&mut circuit, CircuitState::HalfOpen);
+        }
+
+        let probe = circuit.state == CircuitState::HalfOpen;
+        if probe {
+            if circuit.probes_in_flight >= self.half_open_probes {
+                return Err(Duration::ZERO);
+            }
+
+            circuit.probes_in_flight += 1;
+        }
+
+        Ok(CircuitPermit {
+            breaker: self,
+            probe,
+            recorded: false,
+        })
+    }
+
+    fn record(&self, probe: bool, failure: bool) {
+        let mut circuit = self.lock();
+        if probe {
+            circuit.probes_in_flight -= 1;
+        }
+
+        match circuit.state {
+            CircuitState::Closed => {
+                if failure {
+                    circuit.consecutive_failures += 1;
+                } else {
+                    circuit.consecutive_failures = 0;
+                }
+                if circuit.outcomes.len() >= self.window {
+                    circuit.outcomes.pop_front();
+                }
+                circuit.outcomes.push_back(failure);
+
+                if circuit.consecutive_failures >= self.failure_threshold
+                    || self.error_rate_exceeded(&circuit)
+                {
+                    self.transition(&mut circuit, CircuitState::Open);
+                }
+            }
+            CircuitState::HalfOpen if probe => {
+                if failure {
+                    self.transition(&mut circuit, CircuitState::Open);
+                } else {
+                    circuit.probe_successes += 1;
+                    if circuit.probe_successes >= self.half_open_probes {
+                        self.transition(&mut circuit, CircuitState::Closed);
+                    }
+                }
+            }
+            // Requests let through before the circuit opened
+            _ => {}
+        }
+    }
+
+    /// Only judged once the window is full, so a single early failure does not open the circuit.
+    fn error_rate_exceeded(&self, circuit: &Circuit) -> bool {
+        let Some(error_rate) = self.error_rate else {
+            return false;
+        };
+        if circuit.outcomes.len() < self.window {
+            return false;
+        }
+
+        let failures = circuit.outcomes.iter().filter(|failure| **failure).count();
+        failures as f64 / circuit.outcomes.len() as f64 >= error_rate
+    }
+
+    fn transition(&self, circuit: &mut Circuit, state: CircuitState) {
+        match state {
+            CircuitState::Open => warn!("Opening circuit of target {}", self.target),
+            _ => info!("Circuit of target {} is now {}", self.target, state.name()),
+        }
+
+        circuit.state = state;
+        circuit.consecutive_failures = 0;
+        circuit.outcomes.clear();
+        circuit.probe_successes = 0;
+        if state == CircuitState::Open {
+            circuit.opened_at = Instant::now();
+        }
+
+        METRICS
+            .circuit_state()
+            .with_label_values(&[self.target.as_str()])
+            .set(state.value());
+        METRICS
+            .circuit_transitions()
+            .with_label_values(&[self.target.as_str(), state.name()])
+            .inc();
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, Circuit> {
+        self.circuit.lock().expect("Circuit breaker poisoned")
+    }
+}
+
+/// Request let through the circuit. Dropping it without a result frees the probe slot.
+pub struct CircuitPermit<'a> {
+    breaker: &'a CircuitBreaker,
+    probe: bool,
+    recorded: bool,
+}
+
+impl CircuitPermit<'_> {
+    pub fn success(mut self) {
+        self.recorded = true;
+        self.breaker.record(self.probe, false);
+    }
+
+    pub fn failure(mut self) {
+        self.recorded = true;
+        self.breaker.record(self.probe, true);
+    }
+}
+
+impl Drop for CircuitPermit<'_> {
+    fn drop(&mut self) {
+        if !self.recorded && self.probe {
+            self.breaker.lock().probes_in_flight -= 1;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::data::{CircuitBreaker, CircuitState};
+    use std::time::Duration;
+
+    fn breaker(failure_threshold: u32, error_rate: Option<f64>) -> CircuitBreaker {
+        CircuitBreaker::new(
+            "test".to_string(),
+            failure_threshold,
+            error_rate,
+            4,
+            Duration::from_secs(30),
+            1,
+        )
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_consecutive_failures() {
+        let breaker = breaker(2, None);
+
+        breaker.acquire().unwrap().failure();
+        breaker.acquire().unwrap().success();
+        breaker.acquire().unwrap().failure();
+        assert_eq!(breaker.state(), CircuitState::Closed);
+
+        breaker.acquire().unwrap().failure();
+        assert_eq!(breaker.state(), CircuitState::Open);
+        assert_eq!(breaker.acquire().err(), Some(Duration::from_secs(30)));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_error_rate() {
+        let breaker = breaker(u32::MAX, Some(0.5));
+
+        breaker.acquire().unwrap().failure();
+        breaker.acquire().unwrap().success();
+        breaker.acquire().unwrap().failure();
+        assert_eq!(breaker.state(), CircuitState::Closed);
+
+        breaker.acquire().unwrap().success();
+        assert_eq!(breaker.state(), CircuitState::Open);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_half_open_probe() {
+        let breaker = breaker(1, None);
+        breaker.acquire().unwrap().failure();
+
+        tokio::time::advance(Duration::from_secs(10)).await;
+        assert_eq!(breaker.acquire().err(), Some(Duration::from_secs(20)));
+
+        // A failed probe opens the circuit again
+        tokio::time::advance(Duration::from_secs(20)).await;
+        let probe = breaker.acquire().unwrap();
+        assert_eq!(breaker.state(), CircuitState::HalfOpen);
+        assert_eq!(breaker.acquire().err(), Some(Duration::ZERO));
+        probe.failure();
+        assert_eq!(breaker.state(), CircuitState::Open);
+
+        // A successful probe closes it
+        tokio::time::advance(Duration::from_secs(30)).await;
+        breaker.acquire().unwrap().success();
+        assert_eq!(breaker.state(), CircuitState::Closed);
+        assert!(breaker.acquire().is_ok());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_cancelled_probe() {
+        let breaker = breaker(1, None);
+        breaker.acquire().unwrap().failure();
+        tokio::time::advance(Duration::from_secs(30)).await;
+
+        drop(breaker.acquire().unwrap());
+        assert_eq!(breaker.state(), CircuitState::HalfOpen);
+        assert!(breaker.acquire().is_ok());
+    }
+}
@@ -0,0 +1,151 @@
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::Duration;
+use tokio::sync::{Semaphore, SemaphorePermit};
+
+/// Why a request was shed instead of forwarded.
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum LoadShedReason {
+    QueueFull,
+    Timeout,
+}
+
+impl LoadShedReason {
+    pub fn name(&self) -> &'static str {
+        match self {
+            LoadShedReason::QueueFull => "queue_full",
+            LoadShedReason::Timeout => "timeout",
+        }
+    }
+}
+
+/// Caps the in flight requests towards a target, with a bounded queue of waiting requests.
+#[derive(Getters, Debug)]
+#[getset(get = "pub")]
+pub struct ConcurrencyLimiter {
+    max_in_flight: usize,
+    queue_size: usize,
+    queue_timeout: Duration,
+    #[getset(skip)]
+    semaphore: Semaphore,
+    #[getset(skip)]
+    queued: AtomicUsize,
+}
+
+impl ConcurrencyLimiter {
+    pub fn new(max_in_flight: usize, queue_size: usize, queue_timeout: Duration) -> Self {
+        Self {
+            max_in_flight,
+            queue_size,
+            queue_timeout,
+            semaphore: Semaphore::new(max_in_flight),
+            queued: AtomicUsize::new(0),
+        }
+    }
+
+    /// Wait for a free slot. The request is in flight until the permit is dropped.
+    pub async fn acquire(&self) -> Result<SemaphorePermit<'_>, LoadShedReason> {
+        if let Ok(permit) = self.semaphore.try_acquire() {
+            return Ok(permit);
+        }
+
+        let _slot =
+            QueueSlot::reserve(&self.queued, self.queue_size).ok_or(LoadShedReason::QueueFull)?;
+        match tokio::time::timeout(self.queue_timeout, self.semaphore.acquire()).await {
+            Ok(Ok(permit)) => Ok(permit),
+            _ => Err(LoadShedReason::Timeout),
+        }
+    }
+
+    pub fn in_flight(&self) -> usize {
+        self.max_in_flight - self.semaphore.available_permits()
+    }
+
+    pub fn queued(&self) -> usize {
+        self.queued.load(Ordering::SeqCst)
+    }
+
+    /// Whether the other limiter was configured the same way.
+    pub fn same_settings(&self, other: &Self) -> bool {
+        self.max_in_flight == other.max_in_flight
+            && self.queue_size == other.queue_size
+            && self.queue_timeout == other.queue_timeout
+    }
+}
+
+/// Frees the queue slot again, even if the waiting request is cancelled.
+struct QueueSlot<'a>(&'a AtomicUsize);
+
+impl<'a> QueueSlot<'a> {
+    fn reserve(queued: &'a AtomicUsize, queue_size: usize) -> Option<Self> {
+        queued
+            .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |current| {
+                (current < queue_size).then_some(current + 1)
+            })
+            .ok()
+            .map(|_| QueueSlot(queued))
+    }
+}
+
+impl Drop for QueueSlot<'_> {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::data::{ConcurrencyLimiter, LoadShedReason};
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    #[tokio::test(start_paused = true)]
+    async fn test_acquire_without_queue() {
+        let limiter = ConcurrencyLimiter::new(1, 0, Duration::from_secs(5));
+
+        let permit = limiter.acquire().await.unwrap();
+        assert_eq!(limiter.in_flight(), 1);
+        assert_eq!(
+            limiter.acquire().await.unwrap_err(),
+            LoadShedReason::QueueFull
+        );
+
+        drop(permit);
+        assert!(limiter.acquire().await.is_ok());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_acquire_queue_timeout() {
+        let limiter = ConcurrencyLimiter::new(1, 1, Duration::from_secs(5));
+
+        let _permit = limiter.acquire().await.unwrap();
+        assert_eq!(
+            limiter.acquire().await.unwrap_err(),
+            LoadShedReason::Timeout
+        );
+        assert_eq!(limiter.queued(), 0);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_acquire_queued() {
+        let limiter = Arc::new(ConcurrencyLimiter::new(1, 1, Duration::from_secs(5)));
+        let permit = limiter.acquire().await.unwrap();
+
+        let waiting = tokio::spawn({
+            let limiter = limiter.clone();
+            async move { limiter.acquire().await.map(|_| ()) }
+        });
+        tokio::task::yield_now().await;
+        assert_eq!(limiter.queued(), 1);
+
+        // The queue is full
+        assert_eq!(
+            limiter.acquire().await.unwrap_err(),
+            LoadShedReason::QueueFull
+        );
+
+        tokio::time::sleep(Duration::from_secs(1)).await;
+        drop(permit);
+        assert!(waiting.await.unwrap().is_ok());
+        assert_eq!(limiter.queued(), 0);
+    }
+}
@@ -0,0 +1,94 @@
+use derive_new::new;
+use std::time::Duration;
+
+/// Which response of a fanned out request is returned to the caller.
+#[derive(Debug, Clone, Copy, Default, Eq, PartialEq, serde::Deserialize, serde::Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum FanOutPolicy {
+    // Response of the first target, the others are delivered in the background
+    #[default]
+    First,
+    // Response of the first target if all targets succeeded, otherwise the first failure
+    All,
+    // First successful response, otherwise the response of the first target
+    Any,
+}
+
+impl FanOutPolicy {
+    /// Pick the response from the results of all targets, in target order.
+    pub fn select<T>(&self, results: Vec<(bool, T)>) -> Option<T> {
+        let index = match self {
+            FanOutPolicy::First => 0,
+            FanOutPolicy::All => results
+                .iter()
+                .position(|(success, _)| !success)
+                .unwrap_or(0),
+            FanOutPolicy::Any => results
+                .iter()
+                .position(|(success, _)| *success)
+                .unwrap_or(0),
+        };
+
+        results.into_iter().nth(index).map(|(_, result)| result)
+    }
+}
+
+/// Delivery of a route to additional targets.
+#[derive(new, Getters, Clone, Debug)]
+#[getset(get = "pub")]
+pub struct FanOut {
+    // Names of the targets after the first one
+    targets: Vec<String>,
+    policy: FanOutPolicy,
+    // Retries of background deliveries
+    retries: u32,
+    // Delay before the first retry, doubled for each further one
+    retry_delay: Duration,
+}
+
+impl FanOut {
+    /// Delay before the given retry, starting with 1.
+    pub fn delay_before(&self, retry: u32) -> Duration {
+        self.retry_delay
+            .saturating_mul(2u32.saturating_pow(retry.saturating_sub(1)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::data::{FanOut, FanOutPolicy};
+    use std::time::Duration;
+
+    #[test]
+    fn test_select() {
+        let results = || vec![(false, "ci"), (true, "audit"), (false, "chat")];
+
+        assert_eq!(FanOutPolicy::First.select(results()), Some("ci"));
+        assert_eq!(FanOutPolicy::All.select(results()), Some("ci"));
+        assert_eq!(FanOutPolicy::Any.select(results()), Some("audit"));
+
+        let results = vec![(true, "ci"), (true, "audit"), (false, "chat")];
+        assert_eq!(FanOutPolicy::All.select(results), Some("chat"));
+
+        let results = vec![(true, "ci"), (true, "audit")];
+        assert_eq!(FanOutPolicy::All.select(results), Some("ci"));
+
+        let results = vec![(false, "ci"), (false, "audit")];
+        assert_eq!(FanOutPolicy::Any.select(results), Some("ci"));
+        assert_eq!(FanOutPolicy::Any.select(Vec::<(bool, ())>::new()), None);
+    }
+
+    #[test]
+    fn test_delay_before() {
+        let fan_out = FanOut::new(
+            vec!["audit".to_string()],
+            FanOutPolicy::First,
+            3,
+            Duration::from_secs(1),
+        );
+
+        assert_eq!(fan_out.delay_before(1), Duration::from_secs(1));
+        assert_eq!(fan_out.delay_before(2), Duration::from_secs(2));
+        assert_eq!(fan_out.delay_before(3), Duration::from_secs(4));
+    }
+}
@@ -0,0 +1,233 @@
+use crate::Result;
+use crate::error::Error;
+use actix_web::http::header::{HeaderMap, HeaderName};
+use derive_new::new;
+use regex::Regex;
+
+/// Where a filter reads the value from.
+#[derive(Debug)]
+pub enum FilterSource {
+    Header(HeaderName),
+    // JSON pointer into the request body
+    Json(String),
+}
+
+impl FilterSource {
+    /// The first value of the header or the value at the pointer, missing for bodies that are not JSON.
+    pub fn value(&self, headers: &HeaderMap, body: Option<&serde_json::Value>) -> Option<String> {
+        self.values(headers, body).into_iter().next()
+    }
+
+    /// Every value of the header or the value at the pointer, empty for bodies that are not JSON.
+    pub fn values(&self, headers: &HeaderMap, body: Option<&serde_json::Value>) -> Vec<String> {
+        match self {
+            FilterSource::Header(name) => headers
+                .get_all(name)
+                .filter_map(|value| value.to_str().ok())
+                .map(ToString::to_string)
+                .collect(),
+            FilterSource::Json(pointer) => body
+                .and_then(|body| body.pointer(pointer))
+                .and_then(json_value_to_string)
+                .into_iter()
+                .collect(),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum FilterOperator {
+    Equals(String),
+    Regex(Regex),
+    In(Vec<String>),
+}
+
+impl FilterOperator {
+    fn matches(&self, value: &str) -> bool {
+        match self {
+            FilterOperator::Equals(expected) => value == expected,
+            FilterOperator::Regex(regex) => regex.is_match(value),
+            FilterOperator::In(values) => values.iter().any(|expected| value == expected),
+        }
+    }
+}
+
+/// Rule an event has to match to be forwarded.
+#[derive(new, Getters, Debug)]
+#[getset(get = "pub")]
+pub struct EventFilter {
+    source: FilterSource,
+    operator: FilterOperator,
+}
+
+impl EventFilter {
+    /// Missing values and bodies that are not JSON never match.
+    pub fn matches(&self, headers: &HeaderMap, body: Option<&serde_json::Value>) -> bool {
+        self.source
+            .values(headers, body)
+            .iter()
+            .any(|value| self.operator.matches(value))
+    }
+
+    pub fn requires_json(&self) -> bool {
+        matches!(self.source, FilterSource::Json(_))
+    }
+}
+
+/// Scalars are compared by their text, objects and arrays by their JSON.
+fn json_value_to_string(value: &serde_json::Value) -> Option<String> {
+    match value {
+        serde_json::Value::Null => None,
+        serde_json::Value::String(value) => Some(value.clone()),
+        value => Some(value.to_string()),
+    }
+}
+
+/// Convert a JSON pointer or a simple JSONPath like `$.commits[0]['author.name']` into a JSON pointer.
+pub fn json_pointer(path: &str) -> Result<String> {
+    if path.is_empty() || path.starts_with('/') {
+        return Ok(path.to_string());
+    }
+
+    let invalid = || Error::custom(format!("Invalid JSON path: {path}"));
+    let mut rest = path.strip_prefix('$').ok_or_else(invalid)?;
+    let mut pointer = String::new();
+    while !rest.is_empty() {
+        let segment;
+        if let Some(after_dot) = rest.strip_prefix('.') {
+            let end = after_dot.find(['.', '[']).unwrap_or(after_dot.len());
+            segment = &after_dot[..end];
+            rest = &after_dot[end..];
+        } else if let Some(after_bracket) = rest.strip_prefix("['") {
+            let end = after_bracket.find("']").ok_or_else(invalid)?;
+            segment = &after_bracket[..end];
+            rest = &after_bracket[end + 2..];
+        } else if let Some(after_bracket) = rest.strip_prefix('[') {
+            let end = after_bracket.find(']').ok_or_else(invalid)?;
+            segment = &after_bracket[..end];
+            if segment.parse::<usize>().is_err() {
+                return Err(invalid());
+            }
+            rest = &after_bracket[end + 1..];
+        } else {
+            return Err(invalid());
+        }
+
+        if segment.is_empty() {
+            return Err(invalid());
+        }
+        pointer.push('/');
+        pointer.push_str(&segment.replace('~', "~0").replace('/', "~1"));
+    }
+
+    Ok(pointer)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::data::{EventFilter, FilterOperator, FilterSource, json_pointer};
+    use actix_web::http::header::{HeaderMap, HeaderName, HeaderValue};
+    use regex::Regex;
+
+    fn event() -> serde_json::Value {
+        serde_json::json!({
+            "ref": "refs/heads/main",
+            "forced": false,
+            "commits": [{ "id": "abc", "author.name": "ci" }],
+        })
+    }
+
+    fn header_filter(operator: FilterOperator) -> EventFilter {
+        EventFilter::new(
+            FilterSource::Header(HeaderName::from_static("x-github-event")),
+            operator,
+        )
+    }
+
+    fn json_filter(path: &str, operator: FilterOperator) -> EventFilter {
+        EventFilter::new(FilterSource::Json(json_pointer(path).unwrap()), operator)
+    }
+
+    #[test]
+    fn test_json_pointer() {
+        assert_eq!(json_pointer("/ref").unwrap(), "/ref");
+        assert_eq!(json_pointer("$").unwrap(), "");
+        assert_eq!(json_pointer("$.ref").unwrap(), "/ref");
+        assert_eq!(
+            json_pointer("$.commits[0]['author.name']").unwrap(),
+            "/commits/0/author.name"
+        );
+        assert_eq!(json_pointer("$['a/b']").unwrap(), "/a~1b");
+        assert!(json_pointer("ref").is_err());
+        assert!(json_pointer("$.commits[first]").is_err());
+        assert!(json_pointer("$.commits[0").is_err());
+        assert!(json_pointer("$..ref").is_err());
+    }
+
+    #[test]
+    fn test_matches_header() {
+        let mut headers = HeaderMap::new();
+        headers.insert(
+            HeaderName::from_static("x-github-event"),
+            HeaderValue::from_static("push"),
+        );
+
+        let filter = header_filter(FilterOperator::In(vec![
+            "push".to_string(),
+            "workflow_run".to_string(),
+        ]));
+        assert!(filter.matches(&headers, None));
+        assert!(!filter.matches(&HeaderMap::new(), None));
+
+        let filter = header_filter(FilterOperator::Equals("issues".to_string()));
+        assert!(!filter.matches(&headers, None));
+    }
+
+    #[test]
+    fn test_source_values() {
+        let mut headers = HeaderMap::new();
+        let name = HeaderName::from_static("x-github-event");
+        headers.append(name.clone(), HeaderValue::from_static("push"));
+        headers.append(name.clone(), HeaderValue::from_static("workflow_run"));
+
+        // Filters match any value, replay protection reads the first one
+        let source = FilterSource::Header(name);
+        assert_eq!(source.value(&headers, None).as_deref(), Some("push"));
+        assert!(
+            header_filter(FilterOperator::Equals("workflow_run".to_string()))
+                .matches(&headers, None)
+        );
+
+        let source = FilterSource::Json(json_pointer("$.ref").unwrap());
+        assert_eq!(
+            source.values(&headers, Some(&event())),
+            vec!["refs/heads/main".to_string()]
+        );
+        assert!(source.values(&headers, None).is_empty());
+    }
+
+    #[test]
+    fn test_matches_json() {
+        let event = event();
+        let headers = HeaderMap::new();
+
+        let filter = json_filter(
+            "$.ref",
+            FilterOperator::Regex(Regex::new("^refs/heads/(main|release/.+)$").unwrap()),
+        );
+        assert!(filter.matches(&headers, Some(&event)));
+        assert!(!filter.matches(&headers, None));
+
+        let filter = json_filter("$.forced", FilterOperator::Equals("false".to_string()));
+        assert!(filter.matches(&headers, Some(&event)));
+
+        let filter = json_filter(
+            "/commits/0/author.name",
+            FilterOperator::Equals("ci".to_string()),
+        );
+        assert!(filter.matches(&headers, Some(&event)));
+
+        let filter = json_filter("$.missing", FilterOperator::Regex(Regex::new("").unwrap()));
+        assert!(!filter.matches(&headers, Some(&event)));
+    }
+}
@@ -0,0 +1,60 @@
+use std::sync::atomic::{AtomicU64, Ordering};
+
+/// Copies a share of the requests of a route to a shadow target, ignoring its responses.
+#[derive(Getters, Debug)]
+#[getset(get = "pub")]
+pub struct Mirror {
+    target: String,
+    // Share of the requests mirrored, from 0 to 100
+    percent: f64,
+    #[getset(skip)]
+    requests: AtomicU64,
+}
+
+impl Mirror {
+    pub fn new(target: String, percent: f64) -> Self {
+        Self {
+            target,
+            percent,
+            requests: AtomicU64::new(0),
+        }
+    }
+
+    /// Whether to mirror the next request. Mirrored requests are spread evenly instead of randomly.
+    pub fn sample(&self) -> bool {
+        let request = self.requests.fetch_add(1, Ordering::Relaxed) as f64;
+        let share = self.percent / 100.0;
+        ((request + 1.0) * share).floor() > (request * share).floor()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::data::Mirror;
+
+    fn sampled(percent: f64, requests: usize) -> Vec<bool> {
+        let mirror = Mirror::new("shadow".to_string(), percent);
+        (0..requests).map(|_| mirror.sample()).collect()
+    }
+
+    #[test]
+    fn test_sample() {
+        assert!(sampled(100.0, 10).into_iter().all(|sampled| sampled));
+        assert!(sampled(0.0, 10).into_iter().all(|sampled| !sampled));
+
+        let sampled = sampled(25.0, 8);
+        assert_eq!(
+            sampled,
+            vec![false, false, false, true, false, false, false, true]
+        );
+    }
+
+    #[test]
+    fn test_sample_share() {
+        let count = sampled(12.5, 1000)
+            .into_iter()
+            .filter(|sampled| *sampled)
+            .count();
+        assert_eq!(count, 125);
+    }
+}
@@ -1,5 +1,61 @@
+mod access_jwt;
+mod access_token;
+mod api_key;
+mod circuit_breaker;
+mod concurrency;
+mod fan_out;
+mod filter;
+mod mirror;
+mod oauth2;
+mod rate_limit;
+mod replay;
+mod route;
+mod target;
+mod transform;
+mod upstream_auth;
 mod webhook;
 
+pub use access_jwt::AccessClaims;
+pub use access_jwt::AccessJwtError;
+pub use access_jwt::AccessJwtValidator;
+pub use access_jwt::AccessKeysSource;
+#[cfg(test)]
+pub(crate) use access_jwt::test_utils as access_jwt_utils;
+pub use access_token::AccessTokenCache;
+#[cfg(test)]
+pub(crate) use access_token::test_utils::jwt;
+pub use api_key::ApiKeyAuth;
+pub use api_key::ApiKeyError;
+pub use api_key::ApiKeySource;
+pub use circuit_breaker::CircuitBreaker;
+pub use circuit_breaker::CircuitPermit;
+pub use circuit_breaker::CircuitState;
+pub use concurrency::ConcurrencyLimiter;
+pub use concurrency::LoadShedReason;
+pub use fan_out::FanOut;
+pub use fan_out::FanOutPolicy;
+pub use filter::EventFilter;
+pub use filter::FilterOperator;
+pub use filter::FilterSource;
+pub use filter::json_pointer;
+pub use mirror::Mirror;
+pub use oauth2::OAuth2ClientCredentials;
+pub use oauth2::OAuth2Error;
+pub use rate_limit::RateLimitKey;
+pub use rate_limit::RateLimiter;
+pub use replay::NonceStore;
+pub use replay::ReplayError;
+pub use replay::ReplayGuard;
+pub use replay::ReplayTimestamp;
+pub use route::ClientIdentityRequirement;
+pub use route::RouteOptions;
+pub use target::DEFAULT_TARGET_NAME;
+pub use target::ServiceToken;
+pub use target::Target;
+pub use transform::PayloadTransform;
+pub use transform::TransformRequest;
+pub use upstream_auth::UpstreamAuth;
+pub use upstream_auth::UpstreamAuthAttempt;
 pub use webhook::AllowedPath;
 pub use webhook::AllowedPaths;
 pub use webhook::WebHookData;
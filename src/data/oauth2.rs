@@ -0,0 +1,308 @@
+use arc_swap::ArcSwapOption;
+use reqwest::Url;
+use reqwest::header::HeaderValue;
+use reqwest_middleware::ClientWithMiddleware;
+use secrecy::{ExposeSecret, SecretString};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::Mutex;
+use tokio::time::Instant;
+
+const TOKEN_REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// OAuth2 client credentials grant, caching the access token until shortly before it expires.
+#[derive(Getters, Debug)]
+#[getset(get = "pub")]
+pub struct OAuth2ClientCredentials {
+    token_url: Url,
+    client_id: String,
+    #[getset(skip)]
+    client_secret: SecretString,
+    scopes: Vec<String>,
+    // Requested audience, for identity providers like Auth0 that require one
+    audience: Option<String>,
+    // Tokens are refreshed this long before they expire, at most half of their lifetime
+    refresh_margin: Duration,
+    #[getset(skip)]
+    client: ClientWithMiddleware,
+    #[getset(skip)]
+    token: ArcSwapOption<CachedToken>,
+    // Held while requesting a token, so concurrent requests share one token request
+    #[getset(skip)]
+    refreshing: Mutex<()>,
+}
+
+#[derive(Debug)]
+struct CachedToken {
+    // Authorization header value with the bearer token
+    value: HeaderValue,
+    // Tokens without expires_in are kept until the target rejects them
+    refresh_at: Option<Instant>,
+}
+
+#[derive(serde::Deserialize)]
+struct TokenResponse {
+    access_token: String,
+    expires_in: Option<u64>,
+}
+
+impl OAuth2ClientCredentials {
+    pub fn new(
+        client: ClientWithMiddleware,
+        token_url: Url,
+        client_id: String,
+        client_secret: SecretString,
+        scopes: Vec<String>,
+        audience: Option<String>,
+        refresh_margin: Duration,
+    ) -> Self {
+        Self {
+            token_url,
+            client_id,
+            client_secret,
+            scopes,
+            audience,
+            refresh_margin,
+            client,
+            token: ArcSwapOption::empty(),
+            refreshing: Mutex::new(()),
+        }
+    }
+
+    /// Whether the other grant requests tokens the same way, so its cached token still applies.
+    pub fn same_settings(&self, other: &Self) -> bool {
+        self.token_url == other.token_url
+            && self.client_id == other.client_id
+            && self.client_secret.expose_secret() == other.client_secret.expose_secret()
+            && self.scopes == other.scopes
+            && self.audience == other.audience
+            && self.refresh_margin == other.refresh_margin
+    }
+
+    /// `Authorization` header value with the cached token, requesting a new one when needed.
+    pub async fn authorization(&self) -> Result<HeaderValue, OAuth2Error> {
+        if let Some(value) = self.cached() {
+            return Ok(value);
+        }
+
+        // Concurrent requests wait for the same token request
+        let _refreshing = self.refreshing.lock().await;
+        if let Some(value) = self.cached() {
+            return Ok(value);
+        }
+
+        let cached = self.request_token().await?;
+        let value = cached.value.clone();
+        self.token.store(Some(Arc::new(cached)));
+
+        Ok(value)
+    }
+
+    /// Forget the token the target rejected, unless another request already replaced it.
+    pub fn invalidate(&self, rejected: &HeaderValue) {
+        self.token
+            .rcu(|token| token.clone().filter(|cached| cached.value != rejected));
+    }
+
+    /// The cached token, unless it expires within the refresh margin.
+    fn cached(&self) -> Option<HeaderValue> {
+        self.token
+            .load()
+            .as_ref()
+            .filter(|cached| {
+                cached
+                    .refresh_at
+                    .is_none_or(|refresh_at| Instant::now() < refresh_at)
+            })
+            .map(|cached| cached.value.clone())
+    }
+
+    async fn request_token(&self) -> Result<CachedToken, OAuth2Error> {
+        let scope = self.scopes.join(" ");
+        let mut form = vec![
+            ("grant_type", "client_credentials"),
+            ("client_id", self.client_id.as_str()),
+            ("client_secret", self.client_secret.expose_secret()),
+        ];
+        if !scope.is_empty() {
+            form.push(("scope", scope.as_str()));
+        }
+        if let Some(audience) = &self.audience {
+            form.push(("audience", audience.as_str()));
+        }
+
+        let requested_at = Instant::now();
+        let response = self
+            .client
+            .post(self.token_url.clone())
+            .form(&form)
+            .timeout(TOKEN_REQUEST_TIMEOUT)
+            .send()
+            .await
+            .map_err(|e| OAuth2Error::Request(e.to_string()))?;
+        if !response.status().is_success() {
+            return Err(OAuth2Error::Rejected(response.status().as_u16()));
+        }
+
+        let token: TokenResponse = response
+            .json()
+            .await
+            .map_err(|e| OAuth2Error::Request(e.to_string()))?;
+        let mut value = HeaderValue::from_str(&format!("Bearer {}", token.access_token))
+            .map_err(|_| OAuth2Error::Request("Invalid access token".to_string()))?;
+        value.set_sensitive(true);
+
+        debug!("Requested OAuth2 access token from {}", self.token_url);
+        Ok(CachedToken {
+            value,
+            // Lifetimes beyond what the clock can hold are kept like tokens without expires_in
+            refresh_at: token.expires_in.and_then(|expires_in| {
+                let expires_in = Duration::from_secs(expires_in);
+                // Short lived tokens would otherwise be requested again for every request
+                requested_at.checked_add(expires_in - self.refresh_margin.min(expires_in / 2))
+            }),
+        })
+    }
+}
+
+/// Why no access token could be requested.
+#[derive(Debug, thiserror::Error)]
+pub enum OAuth2Error {
+    #[error("Failed to request OAuth2 access token: {0}")]
+    Request(String),
+    #[error("OAuth2 token endpoint answered with status {0}")]
+    Rejected(u16),
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::client;
+    use crate::data::{OAuth2ClientCredentials, OAuth2Error};
+    use reqwest::Url;
+    use secrecy::SecretString;
+    use std::time::Duration;
+    use wiremock::matchers::{body_string_contains, method, path};
+    use wiremock::{Mock, MockServer, ResponseTemplate};
+
+    fn credentials(mock_server: &MockServer, refresh_margin: Duration) -> OAuth2ClientCredentials {
+        OAuth2ClientCredentials::new(
+            client::build_client(None, None, false).unwrap(),
+            Url::parse(&format!("{}/oauth/token", mock_server.uri())).unwrap(),
+            "client".to_string(),
+            SecretString::from("secret"),
+            vec!["read".to_string(), "write".to_string()],
+            Some("https://api.example.com".to_string()),
+            refresh_margin,
+        )
+    }
+
+    #[tokio::test]
+    async fn test_authorization() {
+        let mock_server = MockServer::start().await;
+        Mock::given(method("POST"))
+            .and(path("/oauth/token"))
+            .and(body_string_contains("grant_type=client_credentials"))
+            .and(body_string_contains("client_id=client"))
+            .and(body_string_contains("client_secret=secret"))
+            .and(body_string_contains("scope=read+write"))
+            .and(body_string_contains(
+                "audience=https%3A%2F%2Fapi.example.com",
+            ))
+            .respond_with(ResponseTemplate::new(200).set_body_json(serde_json::json!({
+                "access_token": "token",
+                "token_type": "Bearer",
+                "expires_in": 3600,
+            })))
+            .expect(2)
+            .mount(&mock_server)
+            .await;
+
+        let credentials = credentials(&mock_server, Duration::from_secs(60));
+        let authorization = credentials.authorization().await.unwrap();
+        assert_eq!(authorization, "Bearer token");
+        assert!(authorization.is_sensitive());
+
+        // Cached
+        credentials.authorization().await.unwrap();
+
+        credentials.invalidate(&authorization);
+        credentials.authorization().await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_authorization_concurrent() {
+        let mock_server = MockServer::start().await;
+        Mock::given(path("/oauth/token"))
+            .respond_with(
+                ResponseTemplate::new(200)
+                    .set_body_json(serde_json::json!({
+                        "access_token": "token",
+                        "expires_in": 3600,
+                    }))
+                    .set_delay(Duration::from_millis(100)),
+            )
+            .expect(1)
+            .mount(&mock_server)
+            .await;
+
+        // Requests waiting for the token share the first token request
+        let credentials = credentials(&mock_server, Duration::from_secs(60));
+        let authorizations =
+            futures_util::future::join_all((0..10).map(|_| credentials.authorization())).await;
+        assert!(
+            authorizations
+                .into_iter()
+                .all(|authorization| authorization.unwrap() == "Bearer token")
+        );
+    }
+
+    #[tokio::test]
+    async fn test_authorization_refresh_margin() {
+        let mock_server = MockServer::start().await;
+        Mock::given(path("/oauth/token"))
+            .respond_with(ResponseTemplate::new(200).set_body_json(serde_json::json!({
+                "access_token": "token",
+                "expires_in": 30,
+            })))
+            .expect(1)
+            .mount(&mock_server)
+            .await;
+
+        // Lives shorter than the refresh margin, still reused for half of its lifetime
+        let credentials = credentials(&mock_server, Duration::from_secs(60));
+        credentials.authorization().await.unwrap();
+        credentials.authorization().await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_authorization_long_lived() {
+        let mock_server = MockServer::start().await;
+        Mock::given(path("/oauth/token"))
+            .respond_with(ResponseTemplate::new(200).set_body_json(serde_json::json!({
+                "access_token": "token",
+                "expires_in": u64::MAX,
+            })))
+            .expect(1)
+            .mount(&mock_server)
+            .await;
+
+        let credentials = credentials(&mock_server, Duration::from_secs(60));
+        credentials.authorization().await.unwrap();
+        credentials.authorization().await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_authorization_rejected() {
+        let mock_server = MockServer::start().await;
+        Mock::given(path("/oauth/token"))
+            .respond_with(ResponseTemplate::new(401))
+            .mount(&mock_server)
+            .await;
+
+        let credentials = credentials(&mock_server, Duration::from_secs(60));
+        assert!(matches!(
+            credentials.authorization().await,
+            Err(OAuth2Error::Rejected(401))
+        ));
+    }
+}
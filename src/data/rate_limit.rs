@@ -0,0 +1,290 @@
+use actix_web::HttpRequest;
+use actix_web::http::header::HeaderName;
+use std::collections::{BTreeMap, HashMap};
+use std::sync::Mutex;
+use std::time::Duration;
+use tokio::time::Instant;
+
+// Callers tracked at once, new callers wait for the least recently used bucket to refill
+const MAX_TRACKED_BUCKETS: usize = 10_000;
+// Bucket shared by requests without the key header, header values can not contain line breaks
+const MISSING_HEADER_KEY: &str = "\n";
+
+/// What requests share a token bucket.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub enum RateLimitKey {
+    ClientIp,
+    Route,
+    Header(HeaderName),
+}
+
+impl RateLimitKey {
+    /// The bucket the request is counted against.
+    pub fn resolve(&self, request: &HttpRequest, route_pattern: &str) -> String {
+        match self {
+            RateLimitKey::ClientIp => request
+                .peer_addr()
+                .map(|address| address.ip().to_string())
+                .unwrap_or_default(),
+            RateLimitKey::Route => route_pattern.to_string(),
+            RateLimitKey::Header(name) => request.headers().get(name).map_or_else(
+                || MISSING_HEADER_KEY.to_string(),
+                |value| String::from_utf8_lossy(value.as_bytes()).into_owned(),
+            ),
+        }
+    }
+}
+
+#[derive(Debug)]
+struct Bucket {
+    tokens: f64,
+    updated: Instant,
+    // Position in the least recently used order
+    used: u64,
+}
+
+#[derive(Debug, Default)]
+struct Buckets {
+    entries: HashMap<String, Bucket>,
+    // Keys by the last time they were used, oldest first
+    recent: BTreeMap<u64, String>,
+    sequence: u64,
+}
+
+/// Token bucket rate limiter with one bucket per key.
+#[derive(Getters, Debug)]
+#[getset(get = "pub")]
+pub struct RateLimiter {
+    key: RateLimitKey,
+    burst: u32,
+    #[getset(skip)]
+    tokens_per_second: f64,
+    #[getset(skip)]
+    buckets: Mutex<Buckets>,
+}
+
+impl RateLimiter {
+    /// Allow `requests` per `period` with up to `burst` requests at once.
+    pub fn new(key: RateLimitKey, requests: u32, period: Duration, burst: u32) -> Self {
+        Self {
+            key,
+            burst,
+            tokens_per_second: f64::from(requests) / period.as_secs_f64(),
+            buckets: Mutex::new(Buckets::default()),
+        }
+    }
+
+    /// Take a token for the key, or return how long to wait for the next one.
+    pub fn check(&self, key: &str) -> Result<(), Duration> {
+        Self::check_all(&[(self, key)]).map_err(|(_, retry_after)| retry_after)
+    }
+
+    /// Take a token from every limiter for its key, or from none of them when one has to wait.
+    ///
+    /// Returns the position of the limiter that rejected the request and how long to wait.
+    pub fn check_all(checks: &[(&RateLimiter, &str)]) -> Result<(), (usize, Duration)> {
+        let now = Instant::now();
+        // Always locked in the given order, callers pass the global limiter first
+        let mut guards: Vec<_> = checks
+            .iter()
+            .map(|(limiter, _)| limiter.buckets.lock().expect("Rate limit buckets poisoned"))
+            .collect();
+
+        for (position, ((limiter, key), buckets)) in checks.iter().zip(&mut guards).enumerate() {
+            let tokens = limiter
+                .refill(buckets, key, now)
+                .map_err(|retry_after| (position, retry_after))?;
+            if tokens < 1.0 {
+                return Err((
+                    position,
+                    Duration::from_secs_f64((1.0 - tokens) / limiter.tokens_per_second),
+                ));
+            }
+        }
+
+        for ((_, key), buckets) in checks.iter().zip(&mut guards) {
+            if let Some(bucket) = buckets.entries.get_mut(*key) {
+                bucket.tokens -= 1.0;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Refill the bucket of the key and mark it as used, returning its tokens.
+    ///
+    /// A new key takes over the least recently used bucket once the limit of tracked buckets is
+    /// reached, but only after that bucket refilled. Until then the new key has to wait. Header
+    /// keys are chosen by the caller, so they take over the bucket right away instead of letting
+    /// a caller sending many values lock out everyone else.
+    fn refill(&self, buckets: &mut Buckets, key: &str, now: Instant) -> Result<f64, Duration> {
+        let capacity = f64::from(self.burst);
+
+        if !buckets.entries.contains_key(key)
+            && buckets.entries.len() >= MAX_TRACKED_BUCKETS
+            && let Some(oldest) = buckets.recent.first_entry()
+        {
+            let bucket = &buckets.entries[oldest.get()];
+            let missing = capacity - (bucket.tokens + self.elapsed(bucket, now));
+            if missing > 0.0 && !matches!(self.key, RateLimitKey::Header(_)) {
+                return Err(Duration::from_secs_f64(missing / self.tokens_per_second));
+            }
+            buckets.entries.remove(&oldest.remove());
+        }
+
+        buckets.sequence += 1;
+        let used = buckets.sequence;
+        let bucket = buckets.entries.entry(key.to_string()).or_insert(Bucket {
+            tokens: capacity,
+            updated: now,
+            used,
+        });
+        buckets.recent.remove(&bucket.used);
+        buckets.recent.insert(used, key.to_string());
+
+        bucket.tokens = (bucket.tokens + self.elapsed(bucket, now)).min(capacity);
+        bucket.updated = now;
+        bucket.used = used;
+
+        Ok(bucket.tokens)
+    }
+
+    /// Whether the other limiter was configured the same way.
+    pub fn same_settings(&self, other: &Self) -> bool {
+        self.key == other.key
+            && self.burst == other.burst
+            && self.tokens_per_second == other.tokens_per_second
+    }
+
+    fn elapsed(&self, bucket: &Bucket, now: Instant) -> f64 {
+        (now - bucket.updated).as_secs_f64() * self.tokens_per_second
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::data::rate_limit::{MAX_TRACKED_BUCKETS, MISSING_HEADER_KEY};
+    use crate::data::{RateLimitKey, RateLimiter};
+    use actix_web::http::header::HeaderName;
+    use actix_web::test::TestRequest;
+    use std::time::Duration;
+
+    #[tokio::test(start_paused = true)]
+    async fn test_check_burst() {
+        let limiter = RateLimiter::new(RateLimitKey::ClientIp, 1, Duration::from_secs(10), 3);
+
+        for _ in 0..3 {
+            assert!(limiter.check("10.0.0.1").is_ok());
+        }
+        assert_eq!(limiter.check("10.0.0.1"), Err(Duration::from_secs(10)));
+
+        // Other keys have their own bucket
+        assert!(limiter.check("10.0.0.2").is_ok());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_check_refill() {
+        let limiter = RateLimiter::new(RateLimitKey::ClientIp, 2, Duration::from_secs(1), 2);
+
+        assert!(limiter.check("key").is_ok());
+        assert!(limiter.check("key").is_ok());
+        assert_eq!(limiter.check("key"), Err(Duration::from_millis(500)));
+
+        tokio::time::advance(Duration::from_millis(500)).await;
+        assert!(limiter.check("key").is_ok());
+        assert!(limiter.check("key").is_err());
+
+        // Never refills above the burst
+        tokio::time::advance(Duration::from_secs(60)).await;
+        assert!(limiter.check("key").is_ok());
+        assert!(limiter.check("key").is_ok());
+        assert!(limiter.check("key").is_err());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_check_tracked_buckets() {
+        let limiter = RateLimiter::new(RateLimitKey::ClientIp, 1, Duration::from_secs(10), 1);
+
+        for key in 0..MAX_TRACKED_BUCKETS {
+            assert!(limiter.check(&key.to_string()).is_ok());
+        }
+
+        // New callers wait until the least recently used bucket refilled
+        assert_eq!(limiter.check("new"), Err(Duration::from_secs(10)));
+
+        tokio::time::advance(Duration::from_secs(10)).await;
+        assert!(limiter.check("new").is_ok());
+        assert_eq!(
+            limiter.buckets.lock().unwrap().entries.len(),
+            MAX_TRACKED_BUCKETS
+        );
+        assert!(!limiter.buckets.lock().unwrap().entries.contains_key("0"));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_check_tracked_buckets_header() {
+        let limiter = RateLimiter::new(
+            RateLimitKey::Header(HeaderName::from_static("x-api-key")),
+            1,
+            Duration::from_secs(10),
+            1,
+        );
+
+        for key in 0..MAX_TRACKED_BUCKETS {
+            assert!(limiter.check(&key.to_string()).is_ok());
+        }
+
+        // Callers choose header values, so new values never wait for old ones
+        assert!(limiter.check("new").is_ok());
+        assert_eq!(
+            limiter.buckets.lock().unwrap().entries.len(),
+            MAX_TRACKED_BUCKETS
+        );
+        assert!(!limiter.buckets.lock().unwrap().entries.contains_key("0"));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_check_all() {
+        let global = RateLimiter::new(RateLimitKey::ClientIp, 1, Duration::from_secs(10), 2);
+        let route = RateLimiter::new(RateLimitKey::ClientIp, 1, Duration::from_secs(10), 1);
+
+        assert!(RateLimiter::check_all(&[(&global, "key"), (&route, "key")]).is_ok());
+        assert_eq!(
+            RateLimiter::check_all(&[(&global, "key"), (&route, "key")]),
+            Err((1, Duration::from_secs(10)))
+        );
+
+        // The global token was not taken by the rejected request
+        assert!(global.check("key").is_ok());
+        assert!(global.check("key").is_err());
+    }
+
+    #[test]
+    fn test_resolve_key() {
+        let request = TestRequest::default()
+            .peer_addr("10.0.0.1:1234".parse().unwrap())
+            .insert_header(("X-Api-Key", "team-a"))
+            .insert_header(("X-Empty", ""))
+            .to_http_request();
+
+        assert_eq!(
+            RateLimitKey::ClientIp.resolve(&request, "^test$"),
+            "10.0.0.1"
+        );
+        assert_eq!(RateLimitKey::Route.resolve(&request, "^test$"), "^test$");
+        assert_eq!(
+            RateLimitKey::Header(HeaderName::from_static("x-api-key")).resolve(&request, "^test$"),
+            "team-a"
+        );
+        assert_eq!(
+            RateLimitKey::Header(HeaderName::from_static("x-empty")).resolve(&request, "^test$"),
+            ""
+        );
+
+        // Requests without the header share a bucket apart from every header value
+        assert_eq!(
+            RateLimitKey::Header(HeaderName::from_static("x-other")).resolve(&request, "^test$"),
+            MISSING_HEADER_KEY
+        );
+    }
+}
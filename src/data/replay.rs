@@ -0,0 +1,630 @@
+use crate::data::FilterSource;
+use actix_web::http::header::HeaderMap;
+use derive_new::new;
+use regex::Regex;
+use ring::digest::{SHA256, digest};
+use ring::rand::{SecureRandom, SystemRandom};
+use std::collections::{BTreeSet, HashMap};
+use std::fs::OpenOptions;
+use std::io::{ErrorKind, Write};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+// Timestamps above this are taken as milliseconds
+const MILLISECOND_TIMESTAMPS: u64 = 100_000_000_000;
+// Expired nonce files are deleted in the background after this many new nonces
+const DIRECTORY_PURGE_INTERVAL: usize = 1000;
+// Nonce files still without expiry after this time were left behind by a failed write
+const DIRECTORY_WRITE_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// Where seen nonces are kept until they leave the replay window.
+#[derive(Debug)]
+pub enum NonceStore {
+    // Nonces of this instance, bounded to the max entries
+    Memory {
+        nonces: Mutex<MemoryNonces>,
+        max_entries: usize,
+    },
+    // One file per nonce, shared by instances mounting the same directory
+    Directory {
+        path: PathBuf,
+        inserts: AtomicUsize,
+    },
+}
+
+impl NonceStore {
+    pub fn memory(max_entries: usize) -> Self {
+        NonceStore::Memory {
+            nonces: Mutex::new(MemoryNonces::default()),
+            max_entries,
+        }
+    }
+
+    pub fn directory(path: PathBuf) -> crate::Result<Self> {
+        std::fs::create_dir_all(&path)?;
+        Ok(NonceStore::Directory {
+            path,
+            inserts: AtomicUsize::new(0),
+        })
+    }
+
+    /// Whether the other store keeps its nonces in the same place and with the same limit.
+    pub fn same_settings(&self, other: &Self) -> bool {
+        match (self, other) {
+            (
+                NonceStore::Memory { max_entries, .. },
+                NonceStore::Memory {
+                    max_entries: other, ..
+                },
+            ) => max_entries == other,
+            (NonceStore::Directory { path, .. }, NonceStore::Directory { path: other, .. }) => {
+                path == other
+            }
+            _ => false,
+        }
+    }
+
+    /// Remember the nonce until it expires. Returns false if it was already seen.
+    async fn insert(&self, key: &str, expires_at: SystemTime) -> Result<bool, ReplayError> {
+        match self {
+            NonceStore::Memory {
+                nonces,
+                max_entries,
+            } => nonces.lock().expect("Nonce store poisoned").insert(
+                key,
+                expires_at,
+                *max_entries,
+                SystemTime::now(),
+            ),
+            NonceStore::Directory { path, inserts } => {
+                let insert = inserts.fetch_add(1, Ordering::Relaxed);
+                if insert % DIRECTORY_PURGE_INTERVAL == DIRECTORY_PURGE_INTERVAL - 1 {
+                    let path = path.clone();
+                    tokio::task::spawn_blocking(move || purge_directory(&path));
+                }
+
+                let (path, key) = (path.clone(), key.to_string());
+                tokio::task::spawn_blocking(move || insert_file(&path, &key, expires_at))
+                    .await
+                    .map_err(|e| ReplayError::Store(e.to_string()))?
+                    .map_err(|e| ReplayError::Store(e.to_string()))
+            }
+        }
+    }
+
+    /// Forget the nonce, so the sender can retry a request that was not delivered.
+    async fn remove(&self, key: &str) {
+        match self {
+            NonceStore::Memory { nonces, .. } => {
+                nonces.lock().expect("Nonce store poisoned").remove(key);
+            }
+            NonceStore::Directory { path, .. } => {
+                let file = path.join(file_name(key));
+                let _ = tokio::task::spawn_blocking(move || std::fs::remove_file(file)).await;
+            }
+        }
+    }
+
+    /// Drop expired nonces. Returns the number of dropped nonces.
+    pub fn purge(&self) -> usize {
+        match self {
+            NonceStore::Memory { nonces, .. } => nonces
+                .lock()
+                .expect("Nonce store poisoned")
+                .drop_expired(SystemTime::now()),
+            NonceStore::Directory { path, .. } => purge_directory(path),
+        }
+    }
+}
+
+/// Nonces of one instance with their expiry, ordered by expiry to drop the expired ones first.
+#[derive(Debug, Default)]
+pub struct MemoryNonces {
+    expiries: HashMap<String, SystemTime>,
+    by_expiry: BTreeSet<(SystemTime, String)>,
+}
+
+impl MemoryNonces {
+    /// Unexpired nonces are never evicted, new ones are rejected while the store is full.
+    fn insert(
+        &mut self,
+        key: &str,
+        expires_at: SystemTime,
+        max_entries: usize,
+        now: SystemTime,
+    ) -> Result<bool, ReplayError> {
+        self.drop_expired(now);
+        if self.expiries.contains_key(key) {
+            return Ok(false);
+        }
+        if self.expiries.len() >= max_entries {
+            return Err(ReplayError::Full);
+        }
+
+        self.expiries.insert(key.to_string(), expires_at);
+        self.by_expiry.insert((expires_at, key.to_string()));
+        Ok(true)
+    }
+
+    fn remove(&mut self, key: &str) {
+        if let Some(expires_at) = self.expiries.remove(key) {
+            self.by_expiry.remove(&(expires_at, key.to_string()));
+        }
+    }
+
+    fn drop_expired(&mut self, now: SystemTime) -> usize {
+        let mut dropped = 0;
+        while self
+            .by_expiry
+            .first()
+            .is_some_and(|(expires_at, _)| *expires_at <= now)
+            && let Some((_, key)) = self.by_expiry.pop_first()
+        {
+            self.expiries.remove(&key);
+            dropped += 1;
+        }
+
+        dropped
+    }
+}
+
+/// Create the nonce file, unless an unexpired one exists. Blocks on the file system.
+fn insert_file(path: &Path, key: &str, expires_at: SystemTime) -> std::io::Result<bool> {
+    let file = path.join(file_name(key));
+    if create_file(&file, expires_at)? {
+        return Ok(true);
+    }
+
+    // Expired nonces are replaced, only one instance creates the new file
+    if !remove_expired(&file, SystemTime::now())? {
+        return Ok(false);
+    }
+    create_file(&file, expires_at)
+}
+
+/// Create the file with the expiry. Returns false if it already exists.
+fn create_file(file: &Path, expires_at: SystemTime) -> std::io::Result<bool> {
+    let mut created = match OpenOptions::new().write(true).create_new(true).open(file) {
+        Ok(created) => created,
+        Err(e) if e.kind() == ErrorKind::AlreadyExists => return Ok(false),
+        Err(e) => return Err(e),
+    };
+
+    let expires_at = expires_at.duration_since(UNIX_EPOCH).unwrap_or_default();
+    if let Err(e) = writeln!(created, "{}", expires_at.as_secs()) {
+        let _ = std::fs::remove_file(file);
+        return Err(e);
+    }
+
+    Ok(true)
+}
+
+/// Expiry in the nonce file. Files another instance is still writing have none yet.
+fn read_expiry(file: &Path) -> std::io::Result<Option<SystemTime>> {
+    let content = std::fs::read_to_string(file)?;
+    match content.trim().parse::<u64>() {
+        Ok(expiry) => Ok(Some(UNIX_EPOCH + Duration::from_secs(expiry))),
+        Err(_)
+            if file
+                .metadata()?
+                .modified()?
+                .elapsed()
+                .is_ok_and(|age| age > DIRECTORY_WRITE_TIMEOUT) =>
+        {
+            Ok(Some(UNIX_EPOCH))
+        }
+        Err(_) => Ok(None),
+    }
+}
+
+/// Remove the nonce file if it expired. The file is moved aside and read again before it is
+/// deleted, so a nonce another instance stored in the meantime is put back instead. Returns
+/// whether no unexpired nonce is left.
+fn remove_expired(file: &Path, now: SystemTime) -> std::io::Result<bool> {
+    match read_expiry(file) {
+        Ok(Some(expires_at)) if expires_at <= now => {}
+        Ok(_) => return Ok(false),
+        Err(e) if e.kind() == ErrorKind::NotFound => return Ok(true),
+        Err(e) => return Err(e),
+    }
+
+    let mut suffix = [0; 8];
+    SystemRandom::new()
+        .fill(&mut suffix)
+        .map_err(|_| std::io::Error::other("Failed to generate random file name"))?;
+    let moved = file.with_file_name(format!(
+        ".{}-{}",
+        file.file_name().unwrap_or_default().to_string_lossy(),
+        hex(&suffix)
+    ));
+    match std::fs::rename(file, &moved) {
+        Ok(()) => {}
+        Err(e) if e.kind() == ErrorKind::NotFound => return Ok(true),
+        Err(e) => return Err(e),
+    }
+
+    let expired = read_expiry(&moved)?.is_some_and(|expires_at| expires_at <= now);
+    if !expired {
+        match std::fs::hard_link(&moved, file) {
+            Ok(()) => {}
+            Err(e) if e.kind() == ErrorKind::AlreadyExists => {}
+            Err(e) => return Err(e),
+        }
+    }
+    std::fs::remove_file(&moved)?;
+
+    Ok(expired)
+}
+
+/// Delete the expired nonce files. Returns the number of deleted files.
+fn purge_directory(path: &Path) -> usize {
+    let Ok(entries) = std::fs::read_dir(path) else {
+        return 0;
+    };
+
+    let now = SystemTime::now();
+    entries
+        .filter_map(|entry| entry.ok())
+        // Nonces being replaced start with a dot
+        .filter(|entry| !entry.file_name().to_string_lossy().starts_with('.'))
+        .filter(|entry| remove_expired(&entry.path(), now).unwrap_or(false))
+        .count()
+}
+
+/// Hex SHA-256 of the nonce, which can contain any characters.
+fn file_name(key: &str) -> String {
+    hex(digest(&SHA256, key.as_bytes()).as_ref())
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{byte:02x}")).collect()
+}
+
+/// Timestamp of the request, optionally extracted with the first capture group of the pattern.
+#[derive(new, Getters, Debug)]
+#[getset(get = "pub")]
+pub struct ReplayTimestamp {
+    source: FilterSource,
+    pattern: Option<Regex>,
+}
+
+impl ReplayTimestamp {
+    /// Unix timestamp in seconds or milliseconds.
+    fn read(&self, headers: &HeaderMap, body: Option<&serde_json::Value>) -> Option<SystemTime> {
+        let value = self.source.value(headers, body)?;
+        let value = match &self.pattern {
+            Some(pattern) => pattern.captures(&value)?.get(1)?.as_str().to_string(),
+            None => value,
+        };
+        let timestamp = value.trim().parse::<u64>().ok()?;
+
+        Some(if timestamp >= MILLISECOND_TIMESTAMPS {
+            UNIX_EPOCH + Duration::from_millis(timestamp)
+        } else {
+            UNIX_EPOCH + Duration::from_secs(timestamp)
+        })
+    }
+}
+
+/// Rejects requests outside the time window and nonces already seen within it.
+#[derive(new, Getters, Debug)]
+#[getset(get = "pub")]
+pub struct ReplayGuard {
+    timestamp: Option<ReplayTimestamp>,
+    // Unique ID of the delivery, e.g. X-GitHub-Delivery
+    nonce: Option<FilterSource>,
+    window: Duration,
+    // Shared with the guards of later reloads using the same store
+    #[new(into)]
+    #[getset(skip)]
+    store: Arc<NonceStore>,
+}
+
+impl ReplayGuard {
+    pub fn requires_json(&self) -> bool {
+        matches!(self.nonce, Some(FilterSource::Json(_)))
+            || self
+                .timestamp
+                .as_ref()
+                .is_some_and(|timestamp| matches!(timestamp.source, FilterSource::Json(_)))
+    }
+
+    /// Check the request and remember its nonce. Returns the key to release the nonce with.
+    pub async fn check(
+        &self,
+        route: &str,
+        headers: &HeaderMap,
+        body: Option<&serde_json::Value>,
+    ) -> Result<Option<String>, ReplayError> {
+        let now = SystemTime::now();
+        let mut expires_at = now + self.window;
+        if let Some(timestamp) = &self.timestamp {
+            let sent_at = timestamp
+                .read(headers, body)
+                .ok_or(ReplayError::MissingTimestamp)?;
+            let age = now.duration_since(sent_at).unwrap_or_else(|e| e.duration());
+            if age > self.window {
+                return Err(ReplayError::Stale);
+            }
+
+            // Requests are only accepted within the window around their timestamp
+            expires_at = sent_at + self.window;
+        }
+
+        let Some(nonce) = &self.nonce else {
+            return Ok(None);
+        };
+        let nonce = nonce
+            .value(headers, body)
+            .filter(|nonce| !nonce.is_empty())
+            .ok_or(ReplayError::MissingNonce)?;
+        let key = format!("{route}\n{nonce}");
+        match self.store.insert(&key, expires_at).await? {
+            true => Ok(Some(key)),
+            false => Err(ReplayError::Duplicate),
+        }
+    }
+
+    /// Forget the nonce of a request that was not delivered.
+    pub async fn release(&self, key: &str) {
+        self.store.remove(key).await;
+    }
+
+    /// Drop expired nonces.
+    pub fn purge(&self) -> usize {
+        self.store.purge()
+    }
+
+    /// Keep the nonces of the previous guard if it used the same store.
+    pub fn with_store_of(mut self, previous: &ReplayGuard) -> Self {
+        if self.store.same_settings(&previous.store) {
+            self.store = previous.store.clone();
+        }
+        self
+    }
+}
+
+/// Why a request was taken as replay.
+#[derive(Debug, thiserror::Error)]
+pub enum ReplayError {
+    #[error("No request timestamp")]
+    MissingTimestamp,
+    #[error("Request timestamp outside of the replay window")]
+    Stale,
+    #[error("No request nonce")]
+    MissingNonce,
+    #[error("Request nonce was already seen")]
+    Duplicate,
+    #[error("Failed to store request nonce: {0}")]
+    Store(String),
+    #[error("Nonce store is full")]
+    Full,
+}
+
+impl ReplayError {
+    /// Label of the replay metric.
+    pub fn reason(&self) -> &'static str {
+        match self {
+            ReplayError::MissingTimestamp => "missing_timestamp",
+            ReplayError::Stale => "stale",
+            ReplayError::MissingNonce => "missing_nonce",
+            ReplayError::Duplicate => "duplicate",
+            ReplayError::Store(_) => "store_failed",
+            ReplayError::Full => "store_full",
+        }
+    }
+}
+
+impl From<ReplayError> for actix_web::Error {
+    fn from(e: ReplayError) -> Self {
+        match e {
+            ReplayError::Duplicate => actix_web::error::ErrorConflict(e),
+            ReplayError::Store(_) | ReplayError::Full => {
+                actix_web::error::ErrorServiceUnavailable(e)
+            }
+            _ => actix_web::error::ErrorUnauthorized(e),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::data::{FilterSource, NonceStore, ReplayError, ReplayGuard, ReplayTimestamp};
+    use actix_web::http::header::{HeaderMap, HeaderName, HeaderValue};
+    use regex::Regex;
+    use std::sync::Arc;
+    use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+    const ROUTE: &str = "^hooks$";
+
+    fn now() -> u64 {
+        SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_secs()
+    }
+
+    fn headers(values: &[(&'static str, String)]) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        for (name, value) in values {
+            headers.insert(
+                HeaderName::from_static(name),
+                HeaderValue::from_str(value).unwrap(),
+            );
+        }
+
+        headers
+    }
+
+    fn guard(store: NonceStore) -> ReplayGuard {
+        ReplayGuard::new(
+            Some(ReplayTimestamp::new(
+                FilterSource::Header(HeaderName::from_static("x-timestamp")),
+                None,
+            )),
+            Some(FilterSource::Header(HeaderName::from_static("x-delivery"))),
+            Duration::from_secs(300),
+            store,
+        )
+    }
+
+    #[tokio::test]
+    async fn test_check_nonce() {
+        let guard = guard(NonceStore::memory(100));
+        let request = headers(&[
+            ("x-timestamp", now().to_string()),
+            ("x-delivery", "1".into()),
+        ]);
+
+        let key = guard.check(ROUTE, &request, None).await.unwrap().unwrap();
+        assert!(matches!(
+            guard.check(ROUTE, &request, None).await,
+            Err(ReplayError::Duplicate)
+        ));
+        // Nonces are tracked per route
+        assert!(guard.check("^other$", &request, None).await.is_ok());
+
+        guard.release(&key).await;
+        assert!(guard.check(ROUTE, &request, None).await.is_ok());
+
+        let request = headers(&[("x-timestamp", now().to_string())]);
+        assert!(matches!(
+            guard.check(ROUTE, &request, None).await,
+            Err(ReplayError::MissingNonce)
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_check_timestamp() {
+        let guard = guard(NonceStore::memory(100));
+
+        let stale = headers(&[
+            ("x-timestamp", (now() - 600).to_string()),
+            ("x-delivery", "1".into()),
+        ]);
+        assert!(matches!(
+            guard.check(ROUTE, &stale, None).await,
+            Err(ReplayError::Stale)
+        ));
+        let future = headers(&[
+            ("x-timestamp", (now() + 600).to_string()),
+            ("x-delivery", "2".into()),
+        ]);
+        assert!(matches!(
+            guard.check(ROUTE, &future, None).await,
+            Err(ReplayError::Stale)
+        ));
+        let milliseconds = headers(&[
+            ("x-timestamp", (now() * 1000).to_string()),
+            ("x-delivery", "3".into()),
+        ]);
+        assert!(guard.check(ROUTE, &milliseconds, None).await.is_ok());
+        assert!(matches!(
+            guard
+                .check(ROUTE, &headers(&[("x-delivery", "4".into())]), None)
+                .await,
+            Err(ReplayError::MissingTimestamp)
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_check_timestamp_pattern() {
+        let guard = ReplayGuard::new(
+            Some(ReplayTimestamp::new(
+                FilterSource::Header(HeaderName::from_static("stripe-signature")),
+                Some(Regex::new(r"t=(\d+)").unwrap()),
+            )),
+            Some(FilterSource::Json("/id".to_string())),
+            Duration::from_secs(300),
+            NonceStore::memory(100),
+        );
+        assert!(guard.requires_json());
+
+        let request = headers(&[("stripe-signature", format!("t={},v1=abc", now()))]);
+        let body = serde_json::json!({ "id": "evt_1" });
+        assert!(guard.check(ROUTE, &request, Some(&body)).await.is_ok());
+        assert!(matches!(
+            guard.check(ROUTE, &request, Some(&body)).await,
+            Err(ReplayError::Duplicate)
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_memory_store_bounded() {
+        let store = NonceStore::memory(2);
+        let expires_at = SystemTime::now() + Duration::from_secs(60);
+        assert!(store.insert("1", expires_at).await.unwrap());
+        assert!(store.insert("2", expires_at).await.unwrap());
+
+        // Unexpired nonces are kept while the store is full
+        assert!(matches!(
+            store.insert("3", expires_at).await,
+            Err(ReplayError::Full)
+        ));
+        assert!(!store.insert("1", expires_at).await.unwrap());
+        assert!(!store.insert("2", expires_at).await.unwrap());
+
+        let store = NonceStore::memory(1);
+        assert!(store.insert("expired", SystemTime::now()).await.unwrap());
+        assert!(store.insert("expired", SystemTime::now()).await.unwrap());
+        assert!(store.insert("1", expires_at).await.unwrap());
+    }
+
+    #[tokio::test]
+    async fn test_directory_store() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("nonces");
+        let store = NonceStore::directory(path.clone()).unwrap();
+        // Second instance sharing the directory
+        let shared = NonceStore::directory(path.clone()).unwrap();
+
+        let expires_at = SystemTime::now() + Duration::from_secs(60);
+        assert!(store.insert("route\n1", expires_at).await.unwrap());
+        assert!(!shared.insert("route\n1", expires_at).await.unwrap());
+
+        store.remove("route\n1").await;
+        assert!(shared.insert("route\n1", expires_at).await.unwrap());
+
+        assert!(store.insert("route\n2", SystemTime::now()).await.unwrap());
+        assert_eq!(store.purge(), 1);
+        assert_eq!(std::fs::read_dir(&path).unwrap().count(), 1);
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    async fn test_directory_store_race() {
+        let dir = tempfile::tempdir().unwrap();
+        let stores = [
+            Arc::new(NonceStore::directory(dir.path().to_path_buf()).unwrap()),
+            Arc::new(NonceStore::directory(dir.path().to_path_buf()).unwrap()),
+        ];
+
+        let expires_at = SystemTime::now() + Duration::from_secs(60);
+        for nonce in 0..20 {
+            let key = format!("route\n{nonce}");
+            assert!(
+                stores[0]
+                    .insert(&key, SystemTime::now() - Duration::from_secs(1))
+                    .await
+                    .unwrap()
+            );
+
+            // Both instances replace the expired nonce at the same time, only one of them wins
+            let inserts = (0..8).map(|insert| {
+                let (store, key) = (stores[insert % 2].clone(), key.clone());
+                tokio::spawn(async move { store.insert(&key, expires_at).await.unwrap() })
+            });
+            let inserted = futures_util::future::join_all(inserts)
+                .await
+                .into_iter()
+                .filter(|inserted| *inserted.as_ref().unwrap())
+                .count();
+            assert_eq!(inserted, 1);
+
+            // The nonce of the winner is kept
+            assert!(!stores[1].insert(&key, expires_at).await.unwrap());
+        }
+        assert_eq!(std::fs::read_dir(dir.path()).unwrap().count(), 20);
+    }
+}
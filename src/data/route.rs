@@ -0,0 +1,227 @@
+use crate::data::webhook::keep_previous;
+use crate::data::{
+    AccessJwtValidator, ApiKeyAuth, EventFilter, FanOut, Mirror, PayloadTransform, RateLimiter,
+    ReplayGuard,
+};
+use crate::tls::ClientIdentity;
+use actix_web::http::StatusCode;
+use actix_web::http::header::HeaderMap;
+use derive_new::new;
+use regex::Regex;
+use std::sync::Arc;
+
+/// Route specific behaviour, configured with `WEBHOOK.ROUTES`.
+#[derive(Getters, Default, Debug)]
+#[getset(get = "pub")]
+pub struct RouteOptions {
+    client_identity: Option<ClientIdentityRequirement>,
+    // Cloudflare Access token callers behind Access have to present
+    access_jwt: Option<AccessJwtValidator>,
+    // Static keys callers have to present
+    api_key: Option<ApiKeyAuth>,
+    target: Option<String>,
+    // Additional targets the route is delivered to
+    fan_out: Option<FanOut>,
+    // Shadow target receiving a copy of the requests
+    mirror: Option<Mirror>,
+    // Template for the forwarded path, using the capture groups of the route path
+    rewrite: Option<String>,
+    rate_limiter: Option<Arc<RateLimiter>>,
+    // Rejects requests outside the time window and nonces already seen
+    replay_guard: Option<ReplayGuard>,
+    max_body_size: Option<usize>,
+    // Forward the decoded body instead of the compressed one
+    decompress: bool,
+    // Events are only forwarded when they match all filters
+    filters: Vec<EventFilter>,
+    #[getset(skip)]
+    filtered_status: Option<StatusCode>,
+    // Renders the forwarded body from the parsed body
+    transform: Option<PayloadTransform>,
+}
+
+impl RouteOptions {
+    pub fn with_target(mut self, target: String) -> Self {
+        self.target = Some(target);
+        self
+    }
+
+    pub fn with_fan_out(mut self, fan_out: FanOut) -> Self {
+        self.fan_out = Some(fan_out);
+        self
+    }
+
+    pub fn with_mirror(mut self, mirror: Mirror) -> Self {
+        self.mirror = Some(mirror);
+        self
+    }
+
+    pub fn with_rewrite(mut self, rewrite: String) -> Self {
+        self.rewrite = Some(rewrite);
+        self
+    }
+
+    pub fn with_client_identity(mut self, client_identity: ClientIdentityRequirement) -> Self {
+        self.client_identity = Some(client_identity);
+        self
+    }
+
+    pub fn with_access_jwt(mut self, access_jwt: AccessJwtValidator) -> Self {
+        self.access_jwt = Some(access_jwt);
+        self
+    }
+
+    pub fn with_api_key(mut self, api_key: ApiKeyAuth) -> Self {
+        self.api_key = Some(api_key);
+        self
+    }
+
+    pub fn with_rate_limiter(mut self, rate_limiter: RateLimiter) -> Self {
+        self.rate_limiter = Some(Arc::new(rate_limiter));
+        self
+    }
+
+    pub fn with_replay_guard(mut self, replay_guard: ReplayGuard) -> Self {
+        self.replay_guard = Some(replay_guard);
+        self
+    }
+
+    pub fn with_max_body_size(mut self, max_body_size: usize) -> Self {
+        self.max_body_size = Some(max_body_size);
+        self
+    }
+
+    pub fn with_decompress(mut self, decompress: bool) -> Self {
+        self.decompress = decompress;
+        self
+    }
+
+    pub fn with_filters(mut self, filters: Vec<EventFilter>, filtered_status: StatusCode) -> Self {
+        self.filters = filters;
+        self.filtered_status = Some(filtered_status);
+        self
+    }
+
+    pub fn with_transform(mut self, transform: PayloadTransform) -> Self {
+        self.transform = Some(transform);
+        self
+    }
+
+    /// Keep the rate limit buckets, Access keys and nonces of the same route before a reload.
+    pub fn with_state_of(mut self, previous: &RouteOptions) -> Self {
+        keep_previous(
+            &mut self.rate_limiter,
+            &previous.rate_limiter,
+            RateLimiter::same_settings,
+        );
+        if let Some(previous) = &previous.access_jwt {
+            self.access_jwt = self
+                .access_jwt
+                .map(|access_jwt| access_jwt.with_keys_of(previous));
+        }
+        if let Some(previous) = &previous.replay_guard {
+            self.replay_guard = self
+                .replay_guard
+                .map(|replay_guard| replay_guard.with_store_of(previous));
+        }
+        self
+    }
+
+    /// Status acknowledging filtered out events.
+    pub fn filtered_status(&self) -> StatusCode {
+        self.filtered_status.unwrap_or(StatusCode::ACCEPTED)
+    }
+
+    /// Filters on body fields, transforms and replay guards reading the body need the parsed body.
+    pub fn requires_structured_body(&self) -> bool {
+        self.transform.is_some()
+            || self.filters.iter().any(EventFilter::requires_json)
+            || self
+                .replay_guard
+                .as_ref()
+                .is_some_and(ReplayGuard::requires_json)
+    }
+
+    /// Check the event against all filters of the route.
+    pub fn accepts(&self, headers: &HeaderMap, body: Option<&serde_json::Value>) -> bool {
+        self.filters
+            .iter()
+            .all(|filter| filter.matches(headers, body))
+    }
+}
+
+/// Restricts a route to callers presenting a client certificate with a matching identity.
+#[derive(new, Getters, Debug)]
+#[getset(get = "pub")]
+pub struct ClientIdentityRequirement {
+    subject: Option<Regex>,
+    san: Option<Regex>,
+}
+
+impl ClientIdentityRequirement {
+    pub fn is_satisfied(&self, identity: Option<&ClientIdentity>) -> bool {
+        let Some(identity) = identity else {
+            return false;
+        };
+
+        let subject_matches = self
+            .subject
+            .as_ref()
+            .is_none_or(|subject| subject.is_match(identity.subject()));
+        let san_matches = self
+            .san
+            .as_ref()
+            .is_none_or(|san| identity.sans().iter().any(|value| san.is_match(value)));
+
+        subject_matches && san_matches
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::data::ClientIdentityRequirement;
+    use crate::tls::ClientIdentity;
+    use regex::Regex;
+
+    fn identity() -> ClientIdentity {
+        ClientIdentity::new(
+            "CN=ci, O=Example".to_string(),
+            vec!["ci.example.com".to_string(), "10.0.0.1".to_string()],
+        )
+    }
+
+    #[test]
+    fn test_is_satisfied_missing_identity() {
+        let requirement = ClientIdentityRequirement::new(None, None);
+        assert!(!requirement.is_satisfied(None));
+    }
+
+    #[test]
+    fn test_is_satisfied_any_identity() {
+        let requirement = ClientIdentityRequirement::new(None, None);
+        assert!(requirement.is_satisfied(Some(&identity())));
+    }
+
+    #[test]
+    fn test_is_satisfied_subject() {
+        let requirement = ClientIdentityRequirement::new(Some(Regex::new("CN=ci,").unwrap()), None);
+        assert!(requirement.is_satisfied(Some(&identity())));
+
+        let requirement =
+            ClientIdentityRequirement::new(Some(Regex::new("CN=audit,").unwrap()), None);
+        assert!(!requirement.is_satisfied(Some(&identity())));
+    }
+
+    #[test]
+    fn test_is_satisfied_san() {
+        let requirement =
+            ClientIdentityRequirement::new(None, Some(Regex::new(r"^ci\.example\.com$").unwrap()));
+        assert!(requirement.is_satisfied(Some(&identity())));
+
+        let requirement = ClientIdentityRequirement::new(
+            Some(Regex::new("CN=ci,").unwrap()),
+            Some(Regex::new(r"^audit\.example\.com$").unwrap()),
+        );
+        assert!(!requirement.is_satisfied(Some(&identity())));
+    }
+}
@@ -0,0 +1,188 @@
+use crate::Result;
+use crate::data::webhook::keep_previous;
+use crate::data::{AccessTokenCache, CircuitBreaker, ConcurrencyLimiter, UpstreamAuth};
+use crate::error::Error;
+use derive_new::new;
+use reqwest::Url;
+use reqwest::header::HeaderValue;
+use reqwest_middleware::ClientWithMiddleware;
+use secrecy::{ExposeSecret, SecretString};
+use std::collections::HashSet;
+use std::sync::{Arc, RwLock};
+
+/// Name of the target configured with `WEBHOOK.TARGET_BASE`.
+pub const DEFAULT_TARGET_NAME: &str = "default";
+
+/// Protected service requests are forwarded to.
+#[derive(new, Getters, Debug)]
+#[getset(get = "pub")]
+pub struct Target {
+    name: String,
+    base: Url,
+    client: ClientWithMiddleware,
+    // Shared with the copies of the target sending through another client
+    #[new(default)]
+    concurrency_limiter: Option<Arc<ConcurrencyLimiter>>,
+    #[new(default)]
+    circuit_breaker: Option<Arc<CircuitBreaker>>,
+    // Authentication used instead of the global one
+    #[new(default)]
+    auth: Option<Arc<UpstreamAuth>>,
+}
+
+impl Target {
+    pub fn with_concurrency_limiter(
+        mut self,
+        concurrency_limiter: Option<ConcurrencyLimiter>,
+    ) -> Self {
+        self.concurrency_limiter = concurrency_limiter.map(Arc::new);
+        self
+    }
+
+    pub fn with_circuit_breaker(mut self, circuit_breaker: Option<CircuitBreaker>) -> Self {
+        self.circuit_breaker = circuit_breaker.map(Arc::new);
+        self
+    }
+
+    pub fn with_auth(mut self, auth: Option<UpstreamAuth>) -> Self {
+        self.auth = auth.map(Arc::new);
+        self
+    }
+
+    /// Copy of the target sending through another client, sharing its limits and credentials.
+    pub fn with_client(&self, client: ClientWithMiddleware) -> Self {
+        Self {
+            name: self.name.clone(),
+            base: self.base.clone(),
+            client,
+            concurrency_limiter: self.concurrency_limiter.clone(),
+            circuit_breaker: self.circuit_breaker.clone(),
+            auth: self.auth.clone(),
+        }
+    }
+
+    /// Keep the limits, circuit and cached tokens of the target before a reload if configured the same way.
+    pub fn with_state_of(mut self, previous: &Target) -> Self {
+        keep_previous(
+            &mut self.concurrency_limiter,
+            &previous.concurrency_limiter,
+            ConcurrencyLimiter::same_settings,
+        );
+        if keep_previous(
+            &mut self.circuit_breaker,
+            &previous.circuit_breaker,
+            CircuitBreaker::same_settings,
+        ) && let Some(circuit_breaker) = &self.circuit_breaker
+        {
+            circuit_breaker.report_state();
+        }
+        keep_previous(&mut self.auth, &previous.auth, UpstreamAuth::same_settings);
+        self
+    }
+
+    pub fn url(&self, path: &str) -> Result<Url> {
+        self.base
+            .join(path)
+            .map_err(|e| Error::custom(format!("Failed to join URL: {}", e)))
+    }
+}
+
+/// Cloudflare Access service token sent with every forwarded request.
+#[derive(Getters, Debug)]
+#[getset(get = "pub")]
+pub struct ServiceToken {
+    client_id: HeaderValue,
+    client_secret: HeaderValue,
+    // Send the issued CF_Authorization token instead of the credentials while it is valid
+    token_cache: Option<AccessTokenCache>,
+    // Tried when Access rejects these credentials, e.g. while rotating the service token
+    secondary: Option<Box<ServiceToken>>,
+    // Targets that last accepted the secondary token, which is then tried first
+    #[getset(skip)]
+    secondary_targets: RwLock<HashSet<String>>,
+}
+
+impl ServiceToken {
+    pub fn new(client_id: &SecretString, client_secret: &SecretString) -> Result<Self> {
+        let client_id = HeaderValue::from_str(client_id.expose_secret())
+            .map_err(|_| Error::custom("Failed to map access id to header value"))?;
+        let mut client_secret = HeaderValue::from_str(client_secret.expose_secret())
+            .map_err(|_| Error::custom("Failed to map access secret to header value"))?;
+        client_secret.set_sensitive(true);
+
+        Ok(Self {
+            client_id,
+            client_secret,
+            token_cache: None,
+            secondary: None,
+            secondary_targets: RwLock::new(HashSet::new()),
+        })
+    }
+
+    pub fn with_secondary(mut self, secondary: Option<ServiceToken>) -> Self {
+        self.secondary = secondary.map(Box::new);
+        self
+    }
+
+    pub fn with_token_cache(mut self, token_cache: Option<AccessTokenCache>) -> Self {
+        self.token_cache = token_cache;
+        self
+    }
+
+    /// The token the target accepted last, this one until the secondary replaced it.
+    pub fn preferred(&self, target_name: &str) -> &ServiceToken {
+        match self.secondary.as_deref() {
+            Some(secondary) if self.read_secondary_targets().contains(target_name) => secondary,
+            _ => self,
+        }
+    }
+
+    /// The other token to try after Access rejected one of them.
+    pub fn other(&self, token: &ServiceToken) -> Option<&ServiceToken> {
+        if std::ptr::eq(token, self) {
+            self.secondary.as_deref()
+        } else {
+            Some(self)
+        }
+    }
+
+    /// Remember the token Access accepted for the target, so later requests start with it.
+    pub fn accepted(&self, target_name: &str, token: &ServiceToken) {
+        if self.secondary.is_none() {
+            return;
+        }
+
+        let secondary = !std::ptr::eq(token, self);
+        if self.read_secondary_targets().contains(target_name) == secondary {
+            return;
+        }
+
+        let mut secondary_targets = self
+            .secondary_targets
+            .write()
+            .expect("Service token lock poisoned");
+        if secondary {
+            secondary_targets.insert(target_name.to_string());
+        } else {
+            secondary_targets.remove(target_name);
+        }
+    }
+
+    fn read_secondary_targets(&self) -> std::sync::RwLockReadGuard<'_, HashSet<String>> {
+        self.secondary_targets
+            .read()
+            .expect("Service token lock poisoned")
+    }
+
+    /// Whether the other token has the same credentials, secondary and caching.
+    pub fn same_settings(&self, other: &Self) -> bool {
+        self.client_id == other.client_id
+            && self.client_secret == other.client_secret
+            && self.token_cache.is_some() == other.token_cache.is_some()
+            && match (&self.secondary, &other.secondary) {
+                (Some(secondary), Some(other)) => secondary.same_settings(other),
+                (None, None) => true,
+                _ => false,
+            }
+    }
+}
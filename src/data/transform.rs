@@ -0,0 +1,151 @@
+use crate::Result;
+use crate::error::Error;
+use derive_new::new;
+use minijinja::{AutoEscape, Environment};
+use reqwest::header::HeaderValue;
+use std::collections::{BTreeMap, HashMap};
+
+const TEMPLATE_NAME: &str = "transform";
+
+/// Renders a new request body from the parsed body and the request metadata.
+#[derive(Getters, Debug)]
+#[getset(get = "pub")]
+pub struct PayloadTransform {
+    content_type: HeaderValue,
+    #[getset(skip)]
+    json: bool,
+    #[getset(skip)]
+    environment: Environment<'static>,
+}
+
+impl PayloadTransform {
+    pub fn new(template: String, content_type: &str) -> Result<Self> {
+        let content_type = HeaderValue::from_str(content_type)
+            .map_err(|e| Error::custom(format!("Invalid transform content type: {e}")))?;
+        let json = content_type
+            .to_str()
+            .is_ok_and(|value| value.to_ascii_lowercase().contains("json"));
+
+        // JSON output is escaped, so values can be placed into the template as they are
+        let mut environment = Environment::new();
+        environment.set_auto_escape_callback(move |_| {
+            if json {
+                AutoEscape::Json
+            } else {
+                AutoEscape::None
+            }
+        });
+        environment.add_template_owned(TEMPLATE_NAME, template)?;
+
+        Ok(Self {
+            content_type,
+            json,
+            environment,
+        })
+    }
+
+    pub fn render(&self, request: &TransformRequest) -> Result<String> {
+        let rendered = self
+            .environment
+            .get_template(TEMPLATE_NAME)?
+            .render(request)?;
+
+        // Catch templates producing broken JSON before the target does
+        if self.json {
+            serde_json::from_str::<serde::de::IgnoredAny>(&rendered)?;
+        }
+
+        Ok(rendered)
+    }
+}
+
+/// Values available to transform templates.
+#[derive(new, serde::Serialize, Debug)]
+pub struct TransformRequest<'a> {
+    method: &'a str,
+    path: &'a str,
+    // Lowercase header names, repeated headers are joined with a comma
+    headers: BTreeMap<String, String>,
+    query: &'a HashMap<String, String>,
+    body: &'a serde_json::Value,
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::data::{PayloadTransform, TransformRequest};
+    use std::collections::{BTreeMap, HashMap};
+
+    fn alert() -> serde_json::Value {
+        serde_json::json!({
+            "status": "firing",
+            "alerts": [
+                { "labels": { "alertname": "HighLoad" }, "annotations": { "summary": "Load \"high\"" } },
+                { "labels": { "alertname": "DiskFull" }, "annotations": { "summary": "Disk full" } },
+            ],
+        })
+    }
+
+    fn render(transform: &PayloadTransform, body: &serde_json::Value) -> crate::Result<String> {
+        let mut headers = BTreeMap::new();
+        headers.insert("x-source".to_string(), "alertmanager".to_string());
+        let mut query = HashMap::new();
+        query.insert("channel".to_string(), "ops".to_string());
+
+        transform.render(&TransformRequest::new(
+            "POST", "alerts", headers, &query, body,
+        ))
+    }
+
+    #[test]
+    fn test_render_json() {
+        let transform = PayloadTransform::new(
+            r#"{"channel": {{ query.channel }}, "source": {{ headers["x-source"] }}, "text": {{ body.alerts | map(attribute="annotations.summary") | join("\n") }}, "count": {{ body.alerts | length }}}"#
+                .to_string(),
+            "application/json",
+        )
+        .unwrap();
+
+        let rendered: serde_json::Value =
+            serde_json::from_str(&render(&transform, &alert()).unwrap()).unwrap();
+        assert_eq!(
+            rendered,
+            serde_json::json!({
+                "channel": "ops",
+                "source": "alertmanager",
+                "text": "Load \"high\"\nDisk full",
+                "count": 2,
+            })
+        );
+        assert_eq!(transform.content_type(), "application/json");
+    }
+
+    #[test]
+    fn test_render_text() {
+        let transform = PayloadTransform::new(
+            "{{ method }} {{ path }}: {% for alert in body.alerts %}{{ alert.labels.alertname }} {% endfor %}".to_string(),
+            "text/plain",
+        )
+        .unwrap();
+
+        assert_eq!(
+            render(&transform, &alert()).unwrap(),
+            "POST alerts: HighLoad DiskFull "
+        );
+    }
+
+    #[test]
+    fn test_render_invalid_json() {
+        let transform = PayloadTransform::new(
+            r#"{"status": {{ body.status }}"#.to_string(),
+            "application/json",
+        )
+        .unwrap();
+        assert!(render(&transform, &alert()).is_err());
+    }
+
+    #[test]
+    fn test_new_invalid_template() {
+        assert!(PayloadTransform::new("{{ body.status".to_string(), "application/json").is_err());
+        assert!(PayloadTransform::new("{}".to_string(), "application/json\n").is_err());
+    }
+}
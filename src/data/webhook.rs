@@ -1,101 +1,264 @@
 use crate::Result;
+use crate::data::{DEFAULT_TARGET_NAME, RateLimiter, RouteOptions, Target, UpstreamAuth};
 use crate::error::Error;
 use derive_new::new;
-use regex::RegexSet;
+use percent_encoding::{AsciiSet, NON_ALPHANUMERIC, utf8_percent_encode};
+use regex::{Regex, RegexSet};
 use reqwest::Url;
-use reqwest::header::HeaderValue;
-use reqwest_middleware::ClientWithMiddleware;
-use secrecy::{ExposeSecret, SecretString};
+use reqwest::header::HeaderName;
+use std::borrow::Cow;
 use std::collections::{HashMap, HashSet};
+use std::sync::Arc;
 
 #[derive(Getters, Debug)]
 #[getset(get = "pub")]
 pub struct WebHookData {
-    client: ClientWithMiddleware,
-    #[getset(skip)]
-    target_host: Url,
+    default_target: Target,
+    targets: HashMap<String, Target>,
     allowed_paths: AllowedPaths,
-    access_id: HeaderValue,
-    access_secret: HeaderValue,
+    // Authentication towards targets without their own
+    auth: Arc<UpstreamAuth>,
+    client_identity_header: Option<HeaderName>,
+    rate_limiter: Option<Arc<RateLimiter>>,
+    max_body_size: Option<usize>,
 }
 
 impl WebHookData {
-    pub fn new(
-        client: ClientWithMiddleware,
-        target_host: Url,
-        allowed_paths: AllowedPaths,
-        access_id: SecretString,
-        access_secret: SecretString,
-    ) -> Result<Self> {
-        let access_id = HeaderValue::from_str(access_id.expose_secret())
-            .map_err(|_| Error::custom("Failed to map access id to header value"))?;
-        let access_secret = HeaderValue::from_str(access_secret.expose_secret())
-            .map_err(|_| Error::custom("Failed to map access secret to header value"))?;
-        Ok(Self {
-            client,
-            target_host,
+    pub fn new(default_target: Target, allowed_paths: AllowedPaths, auth: UpstreamAuth) -> Self {
+        Self {
+            default_target,
+            targets: HashMap::new(),
             allowed_paths,
-            access_id,
-            access_secret,
-        })
+            auth: Arc::new(auth),
+            client_identity_header: None,
+            rate_limiter: None,
+            max_body_size: None,
+        }
+    }
+
+    /// Forward the verified client certificate identity to the target with this header.
+    pub fn with_client_identity_header(mut self, header: Option<HeaderName>) -> Self {
+        self.client_identity_header = header;
+        self
+    }
+
+    /// Replace the target routes forward to by default.
+    pub fn with_default_target(mut self, default_target: Target) -> Self {
+        self.default_target = default_target;
+        self
+    }
+
+    /// Replace the authentication used for targets without their own.
+    pub fn with_auth(mut self, auth: UpstreamAuth) -> Self {
+        self.auth = Arc::new(auth);
+        self
+    }
+
+    /// Additional targets routes can forward to by name.
+    pub fn with_targets(mut self, targets: HashMap<String, Target>) -> Self {
+        self.targets = targets;
+        self
+    }
+
+    /// Rate limit applied to every route in addition to the route specific ones.
+    pub fn with_rate_limiter(mut self, rate_limiter: Option<RateLimiter>) -> Self {
+        self.rate_limiter = rate_limiter.map(Arc::new);
+        self
+    }
+
+    /// Keep the state of targets and routes configured the same way before a reload, like open
+    /// circuits, requests in flight, rate limit buckets, cached tokens and seen nonces.
+    pub fn with_state_of(mut self, previous: &WebHookData) -> Self {
+        if self.auth.same_settings(&previous.auth) {
+            self.auth = previous.auth.clone();
+        }
+        keep_previous(
+            &mut self.rate_limiter,
+            &previous.rate_limiter,
+            RateLimiter::same_settings,
+        );
+
+        let with_state_of = |target: Target| match previous.find_target(target.name()) {
+            Ok(previous) => target.with_state_of(previous),
+            Err(_) => target,
+        };
+        self.default_target = with_state_of(self.default_target);
+        // Route proxies find the target they are a copy of by its name
+        self.targets = self
+            .targets
+            .into_iter()
+            .map(|(name, target)| (name, with_state_of(target)))
+            .collect();
+        self.allowed_paths = self.allowed_paths.with_state_of(&previous.allowed_paths);
+
+        self
+    }
+
+    /// Body size limit in bytes for routes without their own limit.
+    pub fn with_max_body_size(mut self, max_body_size: Option<usize>) -> Self {
+        self.max_body_size = max_body_size;
+        self
+    }
+
+    /// Body size limit in bytes of the route.
+    pub fn max_body_size_of(&self, route: &AllowedPath) -> Option<usize> {
+        route.options().max_body_size().or(self.max_body_size)
     }
 
     pub fn get_target_url(&self, path: &str) -> Result<Url> {
-        self.target_host
-            .join(path)
-            .map_err(|e| Error::custom(format!("Failed to join URL: {}", e)))
+        self.default_target.url(path)
+    }
+
+    /// The target of the route, falling back to the target base.
+    pub fn target(&self, route: &AllowedPath) -> Result<&Target> {
+        match route.options().target() {
+            Some(name) => self.find_target(name),
+            None => Ok(&self.default_target),
+        }
+    }
+
+    /// The target with the name, `default` being the target base.
+    pub fn find_target(&self, name: &str) -> Result<&Target> {
+        if name == DEFAULT_TARGET_NAME {
+            return Ok(&self.default_target);
+        }
+
+        self.targets
+            .get(name)
+            .ok_or_else(|| Error::custom(format!("Unknown target: {name}")))
+    }
+
+    /// The authentication towards the target, falling back to the global one.
+    pub fn auth_of<'a>(&'a self, target: &'a Target) -> &'a UpstreamAuth {
+        target.auth().as_deref().unwrap_or(&self.auth)
     }
 
     pub fn is_allowed_path(&self, path: &str, method: &actix_web::http::Method) -> bool {
         self.allowed_paths.is_allowed(path, method)
     }
+
+    pub fn find_route(&self, path: &str, method: &actix_web::http::Method) -> Option<&AllowedPath> {
+        self.allowed_paths.find(path, method)
+    }
 }
 
 #[derive(Getters, Debug)]
 #[getset(get = "pub")]
 pub struct AllowedPaths {
     allowed_paths: RegexSet,
-    allowed_methods: HashMap<String, AllowedPath>,
+    // Routes in the order they are matched, the index being the one of their regex in allowed_paths
+    routes: Vec<AllowedPath>,
 }
 
 impl AllowedPaths {
-    /// Escape regex keys with ^ and $. This is required or otherwise our input /test/ will also match /d/test/d.
+    /// Escape the regex with ^ and $. This is required or otherwise our input /test/ will also match /d/test/d.
+    pub fn escape_regex(path: &str) -> String {
+        let mut path = path.to_string();
+        // Escape start of the regex
+        if !path.starts_with('^') {
+            path = format!("^{}", path);
+        }
+
+        // Escape end of the regex
+        if !path.ends_with('$') {
+            path = format!("{}$", path);
+        }
+
+        path
+    }
+
+    /// Escape regex keys with ^ and $, see [AllowedPaths::escape_regex].
     fn escape_regexes(paths: HashMap<String, AllowedPath>) -> HashMap<String, AllowedPath> {
         paths
             .into_iter()
-            .map(|(mut k, v)| {
-                // Escape start of the regex
-                if !k.starts_with('^') {
-                    k = format!("^{}", k);
-                }
-
-                // Escape end of the regex
-                if !k.ends_with('$') {
-                    k = format!("{}$", k);
-                }
-
-                (k, v)
-            })
+            .map(|(k, v)| (AllowedPaths::escape_regex(&k), v))
             .collect()
     }
 
     pub fn new(allowed_methods: HashMap<String, AllowedPath>) -> Result<Self> {
-        let allowed_methods = AllowedPaths::escape_regexes(allowed_methods);
-        let allowed_paths = RegexSet::new(allowed_methods.keys())?;
+        // test and ^test$ would otherwise silently replace each other
+        let mut patterns = HashSet::with_capacity(allowed_methods.len());
+        for path in allowed_methods.keys() {
+            let pattern = AllowedPaths::escape_regex(path);
+            if !patterns.insert(pattern) {
+                return Err(Error::custom(format!(
+                    "Path {path} is configured more than once"
+                )));
+            }
+        }
+
+        let mut routes = Vec::with_capacity(allowed_methods.len());
+        for (pattern, mut allowed_path) in AllowedPaths::escape_regexes(allowed_methods) {
+            // Rewrites need the capture groups of their own route
+            if let Some(rewrite) = allowed_path.options.rewrite() {
+                let regex = Regex::new(&pattern)?;
+                Self::validate_rewrite(&regex, rewrite)?;
+                allowed_path.regex = Some(regex);
+            }
+            allowed_path.pattern = pattern;
+            routes.push(allowed_path);
+        }
+
+        // Paths matching the same request are tried by priority, then the longer and more specific pattern
+        routes.sort_unstable_by(|a, b| {
+            b.priority
+                .cmp(&a.priority)
+                .then_with(|| b.pattern.len().cmp(&a.pattern.len()))
+                .then_with(|| a.pattern.cmp(&b.pattern))
+        });
+        let allowed_paths = RegexSet::new(routes.iter().map(|route| &route.pattern))?;
 
         Ok(Self {
             allowed_paths,
-            allowed_methods,
+            routes,
+        })
+    }
+
+    /// Unknown groups would silently expand to nothing, e.g. `$1_hook` instead of `${1}_hook`.
+    fn validate_rewrite(regex: &Regex, rewrite: &str) -> Result<()> {
+        expand_rewrite(rewrite, |group| {
+            let known = match group.parse::<usize>() {
+                Ok(index) => index < regex.captures_len(),
+                Err(_) => regex.capture_names().flatten().any(|name| name == group),
+            };
+            if !known {
+                return Err(Error::custom(format!(
+                    "Unknown group {group} in rewrite {rewrite} of path {}",
+                    regex.as_str()
+                )));
+            }
+            Ok(String::new())
         })
+        .map(|_| ())
     }
 
     pub fn is_allowed(&self, path: &str, method: &actix_web::http::Method) -> bool {
-        let matches = self.allowed_paths.matches(path);
-        matches
+        self.find(path, method).is_some()
+    }
+
+    /// Keep the state of routes with the same path before a reload.
+    pub fn with_state_of(mut self, previous: &AllowedPaths) -> Self {
+        for allowed_path in self.routes.iter_mut() {
+            if let Some(previous) = previous.route(&allowed_path.pattern) {
+                allowed_path.options =
+                    std::mem::take(&mut allowed_path.options).with_state_of(&previous.options);
+            }
+        }
+        self
+    }
+
+    /// The route with the escaped pattern.
+    pub fn route(&self, pattern: &str) -> Option<&AllowedPath> {
+        self.routes.iter().find(|route| route.pattern == pattern)
+    }
+
+    /// Find the first route in match order matching the path that allows the method.
+    pub fn find(&self, path: &str, method: &actix_web::http::Method) -> Option<&AllowedPath> {
+        self.allowed_paths
+            .matches(path)
             .into_iter()
-            .map(|i| self.allowed_paths.patterns().get(i).unwrap())
-            .filter_map(|p| self.allowed_methods.get(p))
-            .any(|p| p.is_allowed(method))
+            .map(|i| &self.routes[i])
+            .find(|p| p.is_allowed(method))
     }
 }
 
@@ -104,18 +267,130 @@ impl AllowedPaths {
 pub struct AllowedPath {
     all: bool,
     methods: HashSet<actix_web::http::Method>,
+    #[new(default)]
+    options: RouteOptions,
+    // Routes with a higher priority are matched first when several paths match a request
+    #[new(default)]
+    priority: i32,
+    // Escaped regex of the route, set by AllowedPaths
+    #[new(default)]
+    pattern: String,
+    #[new(default)]
+    #[getset(skip)]
+    regex: Option<Regex>,
 }
 
 impl AllowedPath {
+    pub fn with_options(mut self, options: RouteOptions) -> Self {
+        self.options = options;
+        self
+    }
+
+    pub fn with_priority(mut self, priority: i32) -> Self {
+        self.priority = priority;
+        self
+    }
+
     pub fn is_allowed(&self, method: &actix_web::http::Method) -> bool {
         self.all || self.methods.contains(method)
     }
+
+    /// Path forwarded to the target, the rewrite template expanded with the captures of the route.
+    ///
+    /// Captures are percent-encoded so they can't add path segments or query parameters. `None`
+    /// when a capture is `.` or `..`, which would still move up the path of the target.
+    pub fn target_path<'a>(&self, path: &'a str) -> Option<Cow<'a, str>> {
+        let (Some(template), Some(regex)) = (self.options.rewrite(), &self.regex) else {
+            return Some(Cow::Borrowed(path));
+        };
+        let Some(captures) = regex.captures(path) else {
+            return Some(Cow::Borrowed(path));
+        };
+
+        let target_path = expand_rewrite(template, |group| {
+            let capture = match group.parse::<usize>() {
+                Ok(index) => captures.get(index),
+                Err(_) => captures.name(group),
+            }
+            .map_or("", |capture| capture.as_str());
+            if capture == "." || capture == ".." {
+                return Err(Error::custom(format!(
+                    "Capture {group} of path {path} is a relative path"
+                )));
+            }
+            Ok(utf8_percent_encode(capture, CAPTURE_ENCODE_SET).to_string())
+        })
+        .inspect_err(|e| debug!("Failed to rewrite path: {}", e))
+        .ok()?;
+        // Keep the rewritten path relative to the target base
+        Some(Cow::Owned(target_path.trim_start_matches('/').to_string()))
+    }
+}
+
+// Everything but the unreserved characters of RFC 3986
+const CAPTURE_ENCODE_SET: &AsciiSet = &NON_ALPHANUMERIC
+    .remove(b'-')
+    .remove(b'.')
+    .remove(b'_')
+    .remove(b'~');
+
+/// Replace the component with the previous one if both are configured the same way, keeping its state.
+pub(super) fn keep_previous<T>(
+    current: &mut Option<Arc<T>>,
+    previous: &Option<Arc<T>>,
+    same_settings: impl Fn(&T, &T) -> bool,
+) -> bool {
+    match (current.as_deref(), previous) {
+        (Some(component), Some(previous)) if same_settings(component, previous) => {
+            *current = Some(previous.clone());
+            true
+        }
+        _ => false,
+    }
+}
+
+/// Expand `$1`, `${1}`, `$name`, `${name}` and `$$` in a rewrite template with the value of each group.
+fn expand_rewrite(
+    rewrite: &str,
+    mut expand_group: impl FnMut(&str) -> Result<String>,
+) -> Result<String> {
+    let mut expanded = String::new();
+    let mut rest = rewrite;
+    while let Some(start) = rest.find('$') {
+        expanded.push_str(&rest[..start]);
+        rest = &rest[start + 1..];
+        if let Some(after_escape) = rest.strip_prefix('$') {
+            expanded.push('$');
+            rest = after_escape;
+            continue;
+        }
+
+        let group;
+        if let Some(after_brace) = rest.strip_prefix('{') {
+            let end = after_brace
+                .find('}')
+                .ok_or_else(|| Error::custom(format!("Unclosed group in rewrite: {rewrite}")))?;
+            group = &after_brace[..end];
+            rest = &after_brace[end + 1..];
+        } else {
+            let end = rest
+                .find(|c: char| !c.is_ascii_alphanumeric() && c != '_')
+                .unwrap_or(rest.len());
+            group = &rest[..end];
+            rest = &rest[end..];
+        }
+
+        expanded.push_str(&expand_group(group)?);
+    }
+    expanded.push_str(rest);
+
+    Ok(expanded)
 }
 
 #[cfg(test)]
 mod tests_webhook_data {
     use crate::config::AllowedMethod;
-    use crate::data::WebHookData;
+    use crate::data::{DEFAULT_TARGET_NAME, ServiceToken, Target, UpstreamAuth, WebHookData};
     use lazy_static::lazy_static;
     use reqwest::Url;
     use reqwest_middleware::{ClientBuilder, ClientWithMiddleware};
@@ -166,13 +441,20 @@ mod tests_webhook_data {
     impl From<TestWebHookData> for WebHookData {
         fn from(test_web_hook_data: TestWebHookData) -> Self {
             WebHookData::new(
-                test_web_hook_data.client,
-                test_web_hook_data.target_host,
+                Target::new(
+                    DEFAULT_TARGET_NAME.to_string(),
+                    test_web_hook_data.target_host,
+                    test_web_hook_data.client,
+                ),
                 test_web_hook_data.allowed_paths.clone().try_into().unwrap(),
-                test_web_hook_data.access_id,
-                test_web_hook_data.access_secret,
+                UpstreamAuth::CloudflareAccess(
+                    ServiceToken::new(
+                        &test_web_hook_data.access_id,
+                        &test_web_hook_data.access_secret,
+                    )
+                    .unwrap(),
+                ),
             )
-            .unwrap()
         }
     }
 
@@ -289,7 +571,7 @@ mod tests_webhook_data {
 
         // Check /test/ path
         ALL_HTTP_METHODS.iter().for_each(|method| {
-            if method == &actix_web::http::Method::GET || method == &actix_web::http::Method::POST {
+            if *method == actix_web::http::Method::GET || *method == actix_web::http::Method::POST {
                 assert!(web_hook_data.is_allowed_path("/test/", method));
             } else {
                 assert!(!web_hook_data.is_allowed_path("/test/", method));
@@ -324,7 +606,7 @@ mod tests_webhook_data {
 
 #[cfg(test)]
 mod tests_allowed_paths {
-    use crate::data::{AllowedPath, AllowedPaths};
+    use crate::data::{AllowedPath, AllowedPaths, RouteOptions};
     use std::collections::HashMap;
 
     fn create_map(paths: Vec<&str>) -> HashMap<String, AllowedPath> {
@@ -379,4 +661,134 @@ mod tests_allowed_paths {
     fn test_escape_regexes_escaped() {
         verify_paths(vec![r"^/test/$", "^$", r"^/data/\d*/private$"]);
     }
+
+    #[test]
+    fn test_find_overlapping_paths() {
+        let route = |target: &str, priority: i32| {
+            AllowedPath::new(true, Default::default())
+                .with_options(RouteOptions::default().with_target(target.to_string()))
+                .with_priority(priority)
+        };
+
+        // The more specific path wins regardless of the order of the map
+        let paths = AllowedPaths::new(HashMap::from([
+            ("hooks/.*".to_string(), route("catch_all", 0)),
+            ("hooks/github".to_string(), route("github", 0)),
+        ]))
+        .unwrap();
+        let find_target = |paths: &AllowedPaths, path: &str| {
+            paths
+                .find(path, &actix_web::http::Method::POST)
+                .and_then(|route| route.options().target().clone())
+        };
+        assert_eq!(find_target(&paths, "hooks/github").unwrap(), "github");
+        assert_eq!(find_target(&paths, "hooks/gitlab").unwrap(), "catch_all");
+
+        let paths = AllowedPaths::new(HashMap::from([
+            ("hooks/.*".to_string(), route("catch_all", 1)),
+            ("hooks/github".to_string(), route("github", 0)),
+        ]))
+        .unwrap();
+        assert_eq!(find_target(&paths, "hooks/github").unwrap(), "catch_all");
+    }
+
+    #[test]
+    fn test_duplicate_escaped_paths() {
+        let paths = AllowedPaths::new(HashMap::from([
+            (
+                "test".to_string(),
+                AllowedPath::new(true, Default::default()),
+            ),
+            (
+                "^test$".to_string(),
+                AllowedPath::new(true, Default::default()),
+            ),
+        ]));
+        assert!(paths.is_err());
+    }
+
+    fn rewrite_paths(routes: Vec<(&str, Option<&str>)>) -> AllowedPaths {
+        let mut map = HashMap::new();
+        for (path, rewrite) in routes {
+            let mut options = RouteOptions::default();
+            if let Some(rewrite) = rewrite {
+                options = options.with_rewrite(rewrite.to_string());
+            }
+            map.insert(
+                path.to_string(),
+                AllowedPath::new(true, Default::default()).with_options(options),
+            );
+        }
+
+        AllowedPaths::new(map).unwrap()
+    }
+
+    fn target_path(paths: &AllowedPaths, path: &str) -> String {
+        paths
+            .find(path, &actix_web::http::Method::POST)
+            .unwrap()
+            .target_path(path)
+            .unwrap()
+            .into_owned()
+    }
+
+    #[test]
+    fn test_target_path_rewrite() {
+        let paths = rewrite_paths(vec![
+            (r"gh/(?P<repo>[^/]+)", Some("/hooks/github?repo=${repo}")),
+            (r"gl/([^/]+)/(\d+)", Some("gitlab/$2/${1}_hook")),
+            (r"plain/.*", None),
+        ]);
+
+        assert_eq!(target_path(&paths, "gh/api"), "hooks/github?repo=api");
+        assert_eq!(target_path(&paths, "gl/web/42"), "gitlab/42/web_hook");
+        assert_eq!(target_path(&paths, "plain/a/b"), "plain/a/b");
+        assert!(
+            paths
+                .find("gh/api/extra", &actix_web::http::Method::POST)
+                .is_none()
+        );
+    }
+
+    #[test]
+    fn test_target_path_encoded_captures() {
+        let paths = rewrite_paths(vec![
+            (r"gh/(?P<repo>[^/]+)", Some("hooks/github?repo=${repo}")),
+            (r"files/(.+)", Some("storage/$1/content")),
+        ]);
+
+        assert_eq!(
+            target_path(&paths, "gh/api&admin=true#x"),
+            "hooks/github?repo=api%26admin%3Dtrue%23x"
+        );
+        assert_eq!(target_path(&paths, "gh/a%26b"), "hooks/github?repo=a%2526b");
+        assert_eq!(
+            target_path(&paths, "files/../admin?x"),
+            "storage/..%2Fadmin%3Fx/content"
+        );
+    }
+
+    #[test]
+    fn test_target_path_relative_capture() {
+        let paths = rewrite_paths(vec![(r"files/([^/]+)", Some("storage/$1/content"))]);
+
+        for path in ["files/..", "files/."] {
+            let route = paths.find(path, &actix_web::http::Method::POST).unwrap();
+            assert!(route.target_path(path).is_none(), "{path}");
+        }
+        assert_eq!(target_path(&paths, "files/..."), "storage/.../content");
+    }
+
+    #[test]
+    fn test_target_path_unknown_group() {
+        for rewrite in ["hooks/${name}", "hooks/$2", "hooks/$1_hook", "hooks/${repo"] {
+            let mut map = HashMap::new();
+            map.insert(
+                r"gh/(?P<repo>[^/]+)".to_string(),
+                AllowedPath::new(true, Default::default())
+                    .with_options(RouteOptions::default().with_rewrite(rewrite.to_string())),
+            );
+            assert!(AllowedPaths::new(map).is_err(), "{rewrite}");
+        }
+    }
 }
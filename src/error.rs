@@ -13,8 +13,14 @@ pub enum Error {
     Regex(#[from] regex::Error),
     #[error("Invalid route")]
     InvalidRoute(String),
+    #[error("Reqwest error")]
+    Reqwest(#[from] reqwest::Error),
+    #[error("TLS error")]
+    Tls(#[from] rustls::Error),
     #[error("Config error")]
     Config(#[from] config::ConfigError),
+    #[error("Template error")]
+    Template(#[from] minijinja::Error),
     #[error("{0}")]
     Custom(String),
 }
@@ -5,11 +5,18 @@ extern crate tracing;
 
 use crate::error::Error;
 
+pub mod access;
+pub mod body;
+pub mod client;
 pub mod config;
 pub mod converter;
 pub mod data;
 pub mod error;
+pub mod metrics;
+pub mod reload;
 mod routes;
 pub mod server;
+pub mod shutdown;
+pub mod tls;
 
 pub type Result<T> = anyhow::Result<T, Error>;
@@ -1,8 +1,9 @@
 use std::env;
 use std::str::FromStr;
+use std::sync::Arc;
+use std::time::Duration;
 
-use reqwest_middleware::ClientBuilder;
-use reqwest_tracing::{SpanBackendWithUrl, TracingMiddleware};
+use arc_swap::ArcSwap;
 use sentry::ClientInitGuard;
 use tracing_subscriber::layer::SubscriberExt;
 use tracing_subscriber::util::SubscriberInitExt;
@@ -10,7 +11,7 @@ use tracing_subscriber::{Layer, filter};
 
 use cloudflare_access_webhook_redirect::Result;
 use cloudflare_access_webhook_redirect::config::Config;
-use cloudflare_access_webhook_redirect::data::WebHookData;
+use cloudflare_access_webhook_redirect::reload::ConfigReloader;
 use cloudflare_access_webhook_redirect::server::Server;
 
 #[macro_use]
@@ -20,34 +21,47 @@ const ENV_SENTRY_DSN: &str = "SENTRY_DSN";
 const ENV_LOG_LEVEL: &str = "LOG_LEVEL";
 
 const DEFAULT_LOG_LEVEL: &str = "info";
+const SENTRY_FLUSH_TIMEOUT: Duration = Duration::from_secs(5);
 
 #[tokio::main]
 async fn main() -> Result<()> {
     setup_tracing()?;
 
     // Prevents the process from exiting until all events are sent
-    let _sentry = setup_sentry();
+    let sentry = setup_sentry();
 
     let server;
     let web_hook_data;
+    let shared_config;
+    let config_reloader;
+    let config_reload_interval;
     {
         let config = Config::get_configuration()?;
 
-        server = Server::new(config.server().host().to_string(), *config.server().port());
-        let client = ClientBuilder::new(reqwest::Client::new())
-            .with(TracingMiddleware::<SpanBackendWithUrl>::new())
-            .build();
-
-        web_hook_data = WebHookData::new(
-            client,
-            config.webhook().target_base().clone(),
-            config.webhook().paths().clone().try_into()?,
-            config.cloudflare().client_id().clone(),
-            config.cloudflare().client_secret().clone(),
-        )?;
+        server = Server::from(config.server());
+        web_hook_data = Arc::new(ArcSwap::from_pointee(config.build_web_hook_data()?));
+        config_reload_interval = match *config.server().config_reload_interval() {
+            0 => None,
+            seconds => Some(Duration::from_secs(seconds)),
+        };
+        shared_config = Arc::new(ArcSwap::from_pointee(config));
+        config_reloader = Arc::new(ConfigReloader::new(
+            web_hook_data.clone(),
+            shared_config.clone(),
+        ));
     }
 
-    server.run_until_stopped(web_hook_data).await?;
+    config_reloader.spawn_reload(config_reload_interval)?;
+    server
+        .run_until_stopped(web_hook_data, shared_config)
+        .await?;
+
+    // Send the events of the shutdown before exiting
+    if let Some(sentry) = sentry
+        && !sentry.flush(Some(SENTRY_FLUSH_TIMEOUT))
+    {
+        warn!("Failed to send all Sentry events before exiting");
+    }
 
     Ok(())
 }
@@ -0,0 +1,191 @@
+use prometheus::{Encoder, IntCounter, IntCounterVec, IntGaugeVec, Opts, Registry, TextEncoder};
+use std::sync::LazyLock;
+
+pub static METRICS: LazyLock<Metrics> = LazyLock::new(Metrics::new);
+
+#[derive(Getters, Debug)]
+#[getset(get = "pub")]
+pub struct Metrics {
+    #[getset(skip)]
+    registry: Registry,
+    access_denied: IntCounter,
+    // Labeled with the scope of the limit, global or route
+    rate_limited: IntCounterVec,
+    // Labeled with the target and why the request was shed
+    load_shed: IntCounterVec,
+    // Labeled with the target, 0 closed, 1 half open and 2 open
+    circuit_state: IntGaugeVec,
+    // Labeled with the target and the new state
+    circuit_transitions: IntCounterVec,
+    // Labeled with the route pattern
+    filtered: IntCounterVec,
+    // Labeled with the target and the result, success or failure
+    deliveries: IntCounterVec,
+    // Labeled with the shadow target and how its response compared to the primary one
+    mirrored: IntCounterVec,
+    // Labeled with the route pattern and why the caller was rejected
+    unauthorized: IntCounterVec,
+    // Labeled with the route pattern and why the request was taken as replay
+    replays: IntCounterVec,
+}
+
+impl Metrics {
+    fn new() -> Self {
+        let registry = Registry::new_custom(Some("webhook_redirect".to_string()), None)
+            .expect("Failed to create metrics registry");
+
+        let access_denied = IntCounter::new(
+            "access_denied_total",
+            "Requests rejected by Cloudflare Access",
+        )
+        .expect("Failed to create access_denied_total metric");
+        registry
+            .register(Box::new(access_denied.clone()))
+            .expect("Failed to register access_denied_total metric");
+
+        let rate_limited = IntCounterVec::new(
+            Opts::new("rate_limited_total", "Requests rejected by a rate limit"),
+            &["scope"],
+        )
+        .expect("Failed to create rate_limited_total metric");
+        registry
+            .register(Box::new(rate_limited.clone()))
+            .expect("Failed to register rate_limited_total metric");
+
+        let load_shed = IntCounterVec::new(
+            Opts::new(
+                "load_shed_total",
+                "Requests rejected by the concurrency limit of a target",
+            ),
+            &["target", "reason"],
+        )
+        .expect("Failed to create load_shed_total metric");
+        registry
+            .register(Box::new(load_shed.clone()))
+            .expect("Failed to register load_shed_total metric");
+
+        let circuit_state = IntGaugeVec::new(
+            Opts::new(
+                "circuit_state",
+                "Circuit breaker state per target, 0 closed, 1 half open and 2 open",
+            ),
+            &["target"],
+        )
+        .expect("Failed to create circuit_state metric");
+        registry
+            .register(Box::new(circuit_state.clone()))
+            .expect("Failed to register circuit_state metric");
+
+        let circuit_transitions = IntCounterVec::new(
+            Opts::new(
+                "circuit_transitions_total",
+                "Circuit breaker state changes per target",
+            ),
+            &["target", "state"],
+        )
+        .expect("Failed to create circuit_transitions_total metric");
+        registry
+            .register(Box::new(circuit_transitions.clone()))
+            .expect("Failed to register circuit_transitions_total metric");
+
+        let filtered = IntCounterVec::new(
+            Opts::new(
+                "filtered_total",
+                "Events acknowledged without forwarding them",
+            ),
+            &["route"],
+        )
+        .expect("Failed to create filtered_total metric");
+        registry
+            .register(Box::new(filtered.clone()))
+            .expect("Failed to register filtered_total metric");
+
+        let deliveries = IntCounterVec::new(
+            Opts::new("deliveries_total", "Requests delivered to a target"),
+            &["target", "result"],
+        )
+        .expect("Failed to create deliveries_total metric");
+        registry
+            .register(Box::new(deliveries.clone()))
+            .expect("Failed to register deliveries_total metric");
+
+        let mirrored = IntCounterVec::new(
+            Opts::new("mirrored_total", "Requests mirrored to a shadow target"),
+            &["target", "result"],
+        )
+        .expect("Failed to create mirrored_total metric");
+        registry
+            .register(Box::new(mirrored.clone()))
+            .expect("Failed to register mirrored_total metric");
+
+        let unauthorized = IntCounterVec::new(
+            Opts::new(
+                "unauthorized_total",
+                "Requests rejected by the inbound authentication of a route",
+            ),
+            &["route", "reason"],
+        )
+        .expect("Failed to create unauthorized_total metric");
+        registry
+            .register(Box::new(unauthorized.clone()))
+            .expect("Failed to register unauthorized_total metric");
+
+        let replays = IntCounterVec::new(
+            Opts::new(
+                "replays_total",
+                "Requests rejected by the replay guard of a route",
+            ),
+            &["route", "reason"],
+        )
+        .expect("Failed to create replays_total metric");
+        registry
+            .register(Box::new(replays.clone()))
+            .expect("Failed to register replays_total metric");
+
+        Self {
+            registry,
+            access_denied,
+            rate_limited,
+            load_shed,
+            circuit_state,
+            circuit_transitions,
+            filtered,
+            deliveries,
+            mirrored,
+            unauthorized,
+            replays,
+        }
+    }
+
+    /// Encode all registered metrics in the Prometheus text format.
+    pub fn encode(&self) -> crate::Result<String> {
+        let mut buffer = Vec::new();
+        TextEncoder::new()
+            .encode(&self.registry.gather(), &mut buffer)
+            .map_err(|e| crate::Error::custom(format!("Failed to encode metrics: {e}")))?;
+
+        String::from_utf8(buffer)
+            .map_err(|e| crate::Error::custom(format!("Failed to encode metrics: {e}")))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::metrics::METRICS;
+
+    #[test]
+    fn test_encode_contains_access_denied() {
+        METRICS.access_denied().inc();
+
+        let encoded = METRICS.encode().unwrap();
+        assert!(encoded.contains("webhook_redirect_access_denied_total"));
+    }
+
+    #[test]
+    fn test_encode_contains_rate_limited() {
+        METRICS.rate_limited().with_label_values(&["route"]).inc();
+
+        let encoded = METRICS.encode().unwrap();
+        assert!(encoded.contains(r#"webhook_redirect_rate_limited_total{scope="route"}"#));
+    }
+}
@@ -0,0 +1,449 @@
+use crate::Result;
+use crate::config::{Config, ENV_CONFIG_FILE};
+use crate::data::WebHookData;
+use arc_swap::ArcSwap;
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime};
+
+/// Web hook data shared by all workers, replaced as a whole on configuration reloads.
+pub type SharedWebHookData = ArcSwap<WebHookData>;
+
+/// Configuration the current web hook data was built from.
+pub type SharedConfig = ArcSwap<Config>;
+
+/// Rebuilds the web hook data from the configuration on SIGHUP or when the config file or a secret
+/// file changes. Requests in flight keep the web hook data they started with.
+#[derive(Debug)]
+pub struct ConfigReloader {
+    web_hook_data: Arc<SharedWebHookData>,
+    config: Arc<SharedConfig>,
+    // Config file and secret files with their modification time at the last check
+    watched_files: Mutex<HashMap<PathBuf, Option<SystemTime>>>,
+}
+
+impl ConfigReloader {
+    pub fn new(web_hook_data: Arc<SharedWebHookData>, config: Arc<SharedConfig>) -> Self {
+        let reloader = Self {
+            web_hook_data,
+            config,
+            watched_files: Mutex::new(HashMap::new()),
+        };
+        reloader.watch(&reloader.config.load());
+
+        reloader
+    }
+
+    fn modified(path: &Path) -> Option<SystemTime> {
+        std::fs::metadata(path).and_then(|m| m.modified()).ok()
+    }
+
+    /// Watch the files of the configuration, keeping the last check of files already watched.
+    fn watch(&self, config: &Config) {
+        let config_file = std::env::var_os(ENV_CONFIG_FILE).map(PathBuf::from);
+        let mut watched_files = self
+            .watched_files
+            .lock()
+            .expect("Config reload lock poisoned");
+        *watched_files = config_file
+            .into_iter()
+            .chain(config.secret_files())
+            .map(|path| {
+                let modified = watched_files
+                    .get(&path)
+                    .copied()
+                    .unwrap_or_else(|| ConfigReloader::modified(&path));
+                (path, modified)
+            })
+            .collect();
+    }
+
+    /// Load and validate the configuration, the current web hook data is kept if it is invalid.
+    /// Targets and routes configured the same way keep their limits, circuits and caches.
+    pub fn reload(&self) -> Result<()> {
+        let config = Config::get_configuration()?;
+        let web_hook_data = config
+            .build_web_hook_data()?
+            .with_state_of(&self.web_hook_data.load());
+
+        // The stored configuration shows what the process actually runs with
+        let running = self.config.load();
+        if !config.server().same_settings(running.server()) {
+            warn!("Changed SERVER settings are only applied after a restart");
+        }
+        let config = config.with_server_of(&running);
+
+        self.web_hook_data.store(Arc::new(web_hook_data));
+        self.watch(&config);
+        self.config.store(Arc::new(config));
+
+        Ok(())
+    }
+
+    /// Reload if a watched file changed since the last check.
+    pub fn reload_if_changed(&self) -> Result<bool> {
+        let mut changed = false;
+        for (path, last_modified) in self
+            .watched_files
+            .lock()
+            .expect("Config reload lock poisoned")
+            .iter_mut()
+        {
+            let modified = ConfigReloader::modified(path);
+            // A broken file is only reported once, until it changes again
+            if modified != *last_modified {
+                *last_modified = modified;
+                changed = true;
+            }
+        }
+
+        if changed {
+            self.reload()?;
+        }
+
+        Ok(changed)
+    }
+
+    fn log_reload(&self, trigger: &str, result: Result<bool>) {
+        match result {
+            Ok(true) => info!(
+                "Reloaded configuration after {} with {} routes",
+                trigger,
+                self.web_hook_data
+                    .load()
+                    .allowed_paths()
+                    .allowed_paths()
+                    .len()
+            ),
+            Ok(false) => {}
+            Err(e) => error!(
+                "Rejected configuration after {}, keeping the current one: {:?}",
+                trigger, e
+            ),
+        }
+    }
+
+    /// Watch the config file with the interval and reload on SIGHUP.
+    pub fn spawn_reload(self: Arc<Self>, interval: Option<Duration>) -> Result<()> {
+        #[cfg(unix)]
+        {
+            let mut hangup =
+                tokio::signal::unix::signal(tokio::signal::unix::SignalKind::hangup())?;
+            let reloader = self.clone();
+            tokio::spawn(async move {
+                while hangup.recv().await.is_some() {
+                    reloader.log_reload("SIGHUP", reloader.reload().map(|_| true));
+                }
+            });
+        }
+
+        if let Some(interval) = interval {
+            info!(
+                "Watching {:?} for configuration changes",
+                self.watched_files
+                    .lock()
+                    .expect("Config reload lock poisoned")
+                    .keys()
+                    .collect::<Vec<_>>()
+            );
+            tokio::spawn(async move {
+                let mut interval = tokio::time::interval(interval);
+                loop {
+                    interval.tick().await;
+                    self.log_reload("file change", self.reload_if_changed());
+                }
+            });
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::config::{Config, ENV_CONFIG_FILE};
+    use crate::data::WebHookData;
+    use crate::reload::ConfigReloader;
+    use actix_web::http::header::{HeaderMap, HeaderName, HeaderValue};
+    use arc_swap::ArcSwap;
+    use futures_util::FutureExt;
+    use std::path::Path;
+    use std::sync::Arc;
+    use std::time::{Duration, SystemTime};
+
+    fn write_config(path: &Path, paths: &str) {
+        std::fs::write(
+            path,
+            format!(
+                r#"
+cloudflare:
+  client_id: client_id
+  client_secret: client_secret
+webhook:
+  target_base: https://example.com/
+  paths: "{paths}"
+"#
+            ),
+        )
+        .unwrap();
+    }
+
+    fn touch(path: &Path, seconds: u64) {
+        std::fs::File::options()
+            .write(true)
+            .open(path)
+            .unwrap()
+            .set_modified(SystemTime::now() + Duration::from_secs(seconds))
+            .unwrap();
+    }
+
+    fn is_allowed(web_hook_data: &ArcSwap<WebHookData>, path: &str) -> bool {
+        web_hook_data
+            .load()
+            .is_allowed_path(path, &actix_web::http::Method::POST)
+    }
+
+    #[test]
+    fn test_reload_if_changed() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("config.yaml");
+        write_config(&path, "old:POST");
+
+        temp_env::with_var(ENV_CONFIG_FILE, Some(path.to_str().unwrap()), || {
+            let config = Arc::new(ArcSwap::from_pointee(Config::get_configuration().unwrap()));
+            let web_hook_data = Arc::new(ArcSwap::from_pointee(
+                config.load().build_web_hook_data().unwrap(),
+            ));
+            let reloader = ConfigReloader::new(web_hook_data.clone(), config.clone());
+            assert!(!reloader.reload_if_changed().unwrap());
+
+            write_config(&path, "new:POST");
+            touch(&path, 60);
+            assert!(reloader.reload_if_changed().unwrap());
+            assert!(is_allowed(&web_hook_data, "new"));
+            assert!(!is_allowed(&web_hook_data, "old"));
+            assert!(config.load().webhook().paths().contains_key("new"));
+
+            // Server settings are kept until a restart
+            std::fs::write(
+                &path,
+                std::fs::read_to_string(&path).unwrap() + "server:\n  port: 9090\n",
+            )
+            .unwrap();
+            touch(&path, 120);
+            assert!(reloader.reload_if_changed().unwrap());
+            assert_eq!(config.load().server().port(), &8080);
+        });
+    }
+
+    #[test]
+    fn test_reload_if_secret_file_changed() {
+        let dir = tempfile::tempdir().unwrap();
+        let secret_file = dir.path().join("client_secret");
+        std::fs::write(&secret_file, "old_secret").unwrap();
+
+        temp_env::with_vars(
+            [
+                ("CLOUDFLARE.CLIENT_ID", Some("client_id")),
+                (
+                    "CLOUDFLARE.CLIENT_SECRET_FILE",
+                    Some(secret_file.to_str().unwrap()),
+                ),
+                ("WEBHOOK.TARGET_BASE", Some("https://example.com/")),
+                ("WEBHOOK.PATHS", Some("test:POST")),
+            ],
+            || {
+                let config = Arc::new(ArcSwap::from_pointee(Config::get_configuration().unwrap()));
+                let web_hook_data = Arc::new(ArcSwap::from_pointee(
+                    config.load().build_web_hook_data().unwrap(),
+                ));
+                let reloader = ConfigReloader::new(web_hook_data.clone(), config.clone());
+                assert!(!reloader.reload_if_changed().unwrap());
+
+                // Rotate the secret
+                std::fs::write(&secret_file, "new_secret\n").unwrap();
+                touch(&secret_file, 60);
+                assert!(reloader.reload_if_changed().unwrap());
+                assert_eq!(
+                    web_hook_data
+                        .load()
+                        .auth()
+                        .service_token()
+                        .unwrap()
+                        .client_secret(),
+                    "new_secret"
+                );
+            },
+        );
+    }
+
+    fn write_stateful_config(path: &Path, slack_max_in_flight: usize) {
+        std::fs::write(
+            path,
+            format!(
+                r#"
+cloudflare:
+  client_id: client_id
+  client_secret: client_secret
+webhook:
+  target_base: https://example.com/
+  concurrency:
+    max_in_flight: 10
+  circuit_breaker:
+    failure_threshold: 5
+  targets:
+    slack:
+      base: https://slack.example.com/
+      concurrency:
+        max_in_flight: {slack_max_in_flight}
+  routes:
+    github:
+      path: gh/.*
+      methods: [ POST ]
+      rate_limit:
+        requests: 10
+      replay:
+        nonce_header: X-GitHub-Delivery
+"#
+            ),
+        )
+        .unwrap();
+    }
+
+    #[test]
+    fn test_reload_keeps_state() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("config.yaml");
+        write_stateful_config(&path, 5);
+
+        temp_env::with_var(ENV_CONFIG_FILE, Some(path.to_str().unwrap()), || {
+            let config = Arc::new(ArcSwap::from_pointee(Config::get_configuration().unwrap()));
+            let web_hook_data = Arc::new(ArcSwap::from_pointee(
+                config.load().build_web_hook_data().unwrap(),
+            ));
+            let reloader = ConfigReloader::new(web_hook_data.clone(), config.clone());
+
+            let previous = web_hook_data.load_full();
+            let route = |data: &WebHookData| {
+                data.allowed_paths()
+                    .find("gh/hook", &actix_web::http::Method::POST)
+                    .unwrap()
+                    .options()
+                    .rate_limiter()
+                    .clone()
+                    .unwrap()
+            };
+            let mut headers = HeaderMap::new();
+            headers.insert(
+                HeaderName::from_static("x-github-delivery"),
+                HeaderValue::from_static("delivery-1"),
+            );
+            let replay_guard = previous
+                .allowed_paths()
+                .find("gh/hook", &actix_web::http::Method::POST)
+                .unwrap()
+                .options()
+                .replay_guard()
+                .as_ref()
+                .unwrap();
+            // The memory store completes without a runtime
+            assert!(
+                replay_guard
+                    .check("gh/.*", &headers, None)
+                    .now_or_never()
+                    .unwrap()
+                    .is_ok()
+            );
+
+            write_stateful_config(&path, 20);
+            touch(&path, 60);
+            assert!(reloader.reload_if_changed().unwrap());
+            let current = web_hook_data.load_full();
+            assert!(!Arc::ptr_eq(&previous, &current));
+
+            let (previous_target, current_target) =
+                (previous.default_target(), current.default_target());
+            assert!(Arc::ptr_eq(
+                previous_target.concurrency_limiter().as_ref().unwrap(),
+                current_target.concurrency_limiter().as_ref().unwrap()
+            ));
+            assert!(Arc::ptr_eq(
+                previous_target.circuit_breaker().as_ref().unwrap(),
+                current_target.circuit_breaker().as_ref().unwrap()
+            ));
+            assert!(Arc::ptr_eq(previous.auth(), current.auth()));
+
+            // Changed settings start over
+            assert!(!Arc::ptr_eq(
+                previous
+                    .find_target("slack")
+                    .unwrap()
+                    .concurrency_limiter()
+                    .as_ref()
+                    .unwrap(),
+                current
+                    .find_target("slack")
+                    .unwrap()
+                    .concurrency_limiter()
+                    .as_ref()
+                    .unwrap()
+            ));
+            assert_eq!(
+                *current
+                    .find_target("slack")
+                    .unwrap()
+                    .concurrency_limiter()
+                    .as_ref()
+                    .unwrap()
+                    .max_in_flight(),
+                20
+            );
+
+            let (previous_route, current_route) = (route(&previous), route(&current));
+            assert!(Arc::ptr_eq(&previous_route, &current_route));
+
+            // Nonces seen before the reload are still rejected
+            let replay_guard = current
+                .allowed_paths()
+                .find("gh/hook", &actix_web::http::Method::POST)
+                .unwrap()
+                .options()
+                .replay_guard()
+                .as_ref()
+                .unwrap();
+            assert!(
+                replay_guard
+                    .check("gh/.*", &headers, None)
+                    .now_or_never()
+                    .unwrap()
+                    .is_err()
+            );
+        });
+    }
+
+    #[test]
+    fn test_reload_keeps_data_on_error() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("config.yaml");
+        write_config(&path, "old:POST");
+
+        temp_env::with_var(ENV_CONFIG_FILE, Some(path.to_str().unwrap()), || {
+            let config = Arc::new(ArcSwap::from_pointee(Config::get_configuration().unwrap()));
+            let web_hook_data = Arc::new(ArcSwap::from_pointee(
+                config.load().build_web_hook_data().unwrap(),
+            ));
+            let reloader = ConfigReloader::new(web_hook_data.clone(), config.clone());
+
+            // Invalid regex
+            write_config(&path, "(old:POST");
+            touch(&path, 60);
+            assert!(reloader.reload_if_changed().is_err());
+            assert!(is_allowed(&web_hook_data, "old"));
+            assert!(config.load().webhook().paths().contains_key("old"));
+
+            // Not reported again until the file changes
+            assert!(!reloader.reload_if_changed().unwrap());
+        });
+    }
+}
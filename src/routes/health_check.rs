@@ -1,13 +1,30 @@
+use crate::shutdown::Shutdown;
 use actix_web::{HttpResponse, web};
 
 pub fn get_config(cfg: &mut web::ServiceConfig) {
     cfg.service(web::resource("/health").route(web::get().to(HttpResponse::Ok)));
 }
 
+/// Only registered when enabled, it would otherwise shadow a webhook route with the same path.
+pub fn get_ready_config(cfg: &mut web::ServiceConfig) {
+    cfg.service(web::resource("/ready").route(web::get().to(ready)));
+}
+
+/// Fails once the shutdown started, while requests in flight are still served.
+async fn ready(shutdown: web::Data<Shutdown>) -> HttpResponse {
+    if shutdown.is_ready() {
+        HttpResponse::Ok().finish()
+    } else {
+        HttpResponse::ServiceUnavailable().finish()
+    }
+}
+
 #[cfg(test)]
 mod tests {
-    use crate::routes::health_check::get_config;
-    use actix_web::{App, test};
+    use crate::routes::health_check::{get_config, get_ready_config};
+    use crate::shutdown::Shutdown;
+    use actix_web::http::StatusCode;
+    use actix_web::{App, test, web};
 
     #[actix_web::test]
     async fn test_handle_web_hook() {
@@ -18,4 +35,30 @@ mod tests {
         let resp = test::call_service(&app, req).await;
         assert!(resp.status().is_success());
     }
+
+    #[actix_web::test]
+    async fn test_ready() {
+        let shutdown = web::Data::new(Shutdown::default());
+        let app = test::init_service(
+            App::new()
+                .app_data(shutdown.clone())
+                .configure(get_config)
+                .configure(get_ready_config),
+        )
+        .await;
+
+        let req = test::TestRequest::get().uri("/ready").to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+
+        shutdown.begin();
+        let req = test::TestRequest::get().uri("/ready").to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::SERVICE_UNAVAILABLE);
+
+        // Liveness is not affected by the shutdown
+        let req = test::TestRequest::get().uri("/health").to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+    }
 }
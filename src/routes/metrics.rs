@@ -0,0 +1,33 @@
+use crate::metrics::METRICS;
+use actix_web::{HttpResponse, web};
+
+pub fn get_config(cfg: &mut web::ServiceConfig) {
+    cfg.service(web::resource("/metrics").route(web::get().to(metrics)));
+}
+
+async fn metrics() -> core::result::Result<HttpResponse, actix_web::Error> {
+    let encoded = METRICS.encode().map_err(|e| {
+        error!("Failed to encode metrics: {}", e);
+        actix_web::error::ErrorInternalServerError(e)
+    })?;
+
+    Ok(HttpResponse::Ok()
+        .content_type(prometheus::TEXT_FORMAT)
+        .body(encoded))
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::routes::metrics::get_config;
+    use actix_web::{App, test};
+
+    #[actix_web::test]
+    async fn test_metrics() {
+        let app = test::init_service(App::new().configure(get_config)).await;
+
+        let req = test::TestRequest::get().uri("/metrics").to_request();
+
+        let resp = test::call_service(&app, req).await;
+        assert!(resp.status().is_success());
+    }
+}
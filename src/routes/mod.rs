@@ -1,2 +1,4 @@
+pub mod admin;
 pub mod health_check;
+pub mod metrics;
 pub mod redirect;
@@ -1,11 +1,25 @@
+use crate::access;
+use crate::body::RequestBody;
 use crate::converter::{ActixToReqwestConverter, ReqwestToActixConverter};
-use crate::data::WebHookData;
-use actix_web::http::Method;
-use actix_web::web::Query;
+use crate::data::{
+    AllowedPath, FanOut, FanOutPolicy, RateLimitKey, RateLimiter, Target, TransformRequest,
+    WebHookData,
+};
+use crate::metrics::METRICS;
+use crate::reload::SharedWebHookData;
+use crate::shutdown::Shutdown;
+use crate::tls::ClientIdentity;
+use actix_web::body::{BoxBody, MessageBody};
+use actix_web::http::header::{CONTENT_LENGTH, HeaderName, RETRY_AFTER, WWW_AUTHENTICATE};
+use actix_web::http::{Method, StatusCode};
+use actix_web::web::{Bytes, Query};
 use actix_web::{HttpRequest, HttpResponse, web};
 use reqwest::{Body, Url};
 use reqwest_middleware::{ClientWithMiddleware, RequestBuilder};
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::oneshot;
 
 pub fn get_config(cfg: &mut web::ServiceConfig) {
     cfg.service(
@@ -22,59 +36,597 @@ async fn redirect(
     mut payload: web::Payload,
     request: HttpRequest,
     path: web::Path<String>,
-    web_hook_data: web::Data<WebHookData>,
+    web_hook_data: web::Data<SharedWebHookData>,
+    shutdown: web::Data<Shutdown>,
 ) -> core::result::Result<HttpResponse, actix_web::Error> {
+    // Keep the web hook data the request started with, even if the configuration is reloaded
+    let web_hook_data = web_hook_data.load_full();
+    // Delay the shutdown until the request is answered
+    let _in_flight = shutdown.token();
+    let client_identity = request.conn_data::<ClientIdentity>();
+
     // Only allow specific paths
-    info!("Received {} request for path: {}", request.method(), path);
-    if !web_hook_data.is_allowed_path(&path, request.method()) {
+    info!(
+        client_identity = client_identity.map(|identity| identity.subject().as_str()),
+        "Received {} request for path: {}",
+        request.method(),
+        path
+    );
+    let Some(route) = web_hook_data.find_route(&path, request.method()) else {
         debug!("Path not allowed: {}", path);
         return Ok(HttpResponse::NotFound().finish());
+    };
+
+    // Limit requests per caller, globally and per route
+    if let Some(response) = rate_limit(&request, route, &web_hook_data) {
+        return Ok(response);
     }
 
-    // Craft target url
-    let target_url = web_hook_data.get_target_url(path.as_str()).map_err(|e| {
-        error!("Failed to join URL: {}", e);
-        actix_web::error::ErrorBadRequest(e)
-    })?;
+    // Only allow callers with a matching client certificate
+    if let Some(requirement) = route.options().client_identity()
+        && !requirement.is_satisfied(client_identity)
+    {
+        warn!(
+            "Client identity {:?} is not allowed for path: {}",
+            client_identity, path
+        );
+        return Ok(HttpResponse::Forbidden().finish());
+    }
+
+    // Only allow callers presenting a valid Cloudflare Access token of their own
+    if let Some(validator) = route.options().access_jwt() {
+        match validator.validate(request.headers()).await {
+            Ok(claims) => debug!(
+                "Accepted Cloudflare Access token of {} for path: {}",
+                claims.subject(),
+                path
+            ),
+            Err(e) => {
+                warn!("Rejected Cloudflare Access token for path {}: {}", path, e);
+                METRICS
+                    .unauthorized()
+                    .with_label_values(&[route.pattern().as_str(), e.reason()])
+                    .inc();
+                return Err(e.into());
+            }
+        }
+    }
+
+    // Query params
+    let mut params =
+        Query::<HashMap<String, String>>::from_query(request.query_string())?.into_inner();
+
+    // Only allow callers presenting one of the keys of the route, which is not forwarded
+    let api_key = route.options().api_key().as_ref();
+    if let Some(api_key) = api_key {
+        if let Err(e) = api_key.authenticate(request.headers(), &params) {
+            warn!("Rejected API key for path {}: {}", path, e);
+            METRICS
+                .unauthorized()
+                .with_label_values(&[route.pattern().as_str(), e.reason()])
+                .inc();
+            let mut response = HttpResponse::Unauthorized();
+            if let Some(challenge) = api_key.source().challenge() {
+                response.insert_header((WWW_AUTHENTICATE, challenge));
+            }
+            return Ok(response.finish());
+        }
+
+        if let Some(name) = api_key.source().query() {
+            params.remove(name);
+        }
+    }
+    let credential_header = api_key.and_then(|api_key| api_key.source().header());
+
+    // Craft the target urls, the first target answers the caller
+    let fan_out = route.options().fan_out().as_ref();
+    let target_names = fan_out.map_or(&[][..], |fan_out| fan_out.targets().as_slice());
+    let mut targets = vec![web_hook_data.target(route)];
+    targets.extend(
+        target_names
+            .iter()
+            .map(|name| web_hook_data.find_target(name)),
+    );
+    let targets = targets
+        .into_iter()
+        .collect::<crate::Result<Vec<_>>>()
+        .map_err(|e| {
+            error!("Failed to find target: {}", e);
+            actix_web::error::ErrorInternalServerError(e)
+        })?;
+    let Some(target_path) = route.target_path(&path) else {
+        warn!("Rejected path {} with a relative path capture", path);
+        return Ok(HttpResponse::BadRequest().finish());
+    };
+    let target_urls = targets
+        .iter()
+        .map(|target| target.url(&target_path))
+        .collect::<crate::Result<Vec<_>>>()
+        .map_err(|e| {
+            error!("Failed to join URL: {}", e);
+            actix_web::error::ErrorBadRequest(e)
+        })?;
+
+    // Reject oversized bodies before reading them, the size is checked again while reading
+    let max_body_size = web_hook_data.max_body_size_of(route);
+    if let Some(max_body_size) = max_body_size
+        && content_length(&request).is_some_and(|length| length > max_body_size)
+    {
+        debug!("Body of request for path {} is too large", path);
+        return Ok(HttpResponse::PayloadTooLarge().finish());
+    }
 
     // Convert body
-    let body = ActixToReqwestConverter::convert_body(&mut payload).await?;
+    let body = RequestBody::new(
+        ActixToReqwestConverter::convert_body(&mut payload, max_body_size).await?,
+        request.headers(),
+        max_body_size,
+    );
+
+    // Parse JSON, form and multipart bodies once for filters and transforms
+    let options = route.options();
+    let structured = if options.requires_structured_body() {
+        body.structured().await?
+    } else {
+        None
+    };
+
+    // Acknowledge events the target is not interested in without forwarding them
+    if !options.accepts(request.headers(), structured.as_ref()) {
+        debug!("Filtered out event for path: {}", path);
+        METRICS
+            .filtered()
+            .with_label_values(&[route.pattern().as_str()])
+            .inc();
+        return Ok(HttpResponse::build(options.filtered_status()).finish());
+    }
 
     // Convert headers
     let mut target_headers: reqwest::header::HeaderMap =
         ActixToReqwestConverter::convert_headers(request.headers(), 2);
 
-    // Add Cloudflare Access headers
-    target_headers.append("CF-Access-Client-Id", web_hook_data.access_id().clone());
-    target_headers.append(
-        "CF-Access-Client-Secret",
-        web_hook_data.access_secret().clone(),
-    );
+    // Never forward the API key of the caller
+    if let Some(header) = credential_header {
+        target_headers.remove(header.as_str());
+    }
 
-    // Query params
-    let params = Query::<HashMap<String, String>>::from_query(request.query_string())?;
+    // Forward the decoded body, the raw body stays untouched for signature checks
+    let target_body = if *options.decompress() && body.is_encoded() {
+        target_headers.remove(reqwest::header::CONTENT_ENCODING);
+        target_headers.remove(reqwest::header::CONTENT_LENGTH);
+        body.decoded()?
+    } else {
+        body.raw().clone()
+    };
+
+    // Render the body the target expects from the parsed body of the caller
+    let target_body = match options.transform() {
+        Some(transform) => {
+            let Some(structured) = structured.as_ref() else {
+                debug!("Body of request for path {} can not be parsed", path);
+                return Ok(HttpResponse::BadRequest().finish());
+            };
+            let rendered = transform
+                .render(&TransformRequest::new(
+                    request.method().as_str(),
+                    path.as_str(),
+                    transform_headers(
+                        &request,
+                        credential_header,
+                        web_hook_data.client_identity_header().as_ref(),
+                        client_identity,
+                    ),
+                    &params,
+                    structured,
+                ))
+                .map_err(|e| {
+                    error!("Failed to transform body for path {}: {:?}", path, e);
+                    actix_web::error::ErrorInternalServerError(e)
+                })?;
+
+            target_headers.remove(reqwest::header::CONTENT_ENCODING);
+            target_headers.remove(reqwest::header::CONTENT_LENGTH);
+            target_headers.insert(
+                reqwest::header::CONTENT_TYPE,
+                transform.content_type().clone(),
+            );
+            actix_web::web::Bytes::from(rendered)
+        }
+        None => target_body,
+    };
+
+    // Forward the verified client identity, never the one sent by the caller
+    if let Some(header) = web_hook_data.client_identity_header() {
+        target_headers.remove(header);
+        if let Some(value) = client_identity
+            .and_then(|identity| reqwest::header::HeaderValue::from_str(identity.subject()).ok())
+        {
+            target_headers.insert(header.clone(), value);
+        }
+    }
+
+    // Reject deliveries seen before or sent outside the time window of the route
+    let replay_key = match options.replay_guard() {
+        Some(guard) => guard
+            .check(
+                route.pattern().as_str(),
+                request.headers(),
+                structured.as_ref(),
+            )
+            .await
+            .map_err(|e| {
+                warn!("Rejected replayed request for path {}: {}", path, e);
+                METRICS
+                    .replays()
+                    .with_label_values(&[route.pattern().as_str(), e.reason()])
+                    .inc();
+                e
+            })?,
+        None => None,
+    };
+
+    let forward_request = ForwardRequest {
+        method: request.method().clone(),
+        path: path.to_string(),
+        headers: target_headers,
+        body: target_body,
+        params,
+    };
+
+    // Copy a share of the requests to the shadow target of the route
+    let mirror = route
+        .options()
+        .mirror()
+        .as_ref()
+        .filter(|mirror| mirror.sample())
+        .and_then(|mirror| {
+            let shadow = web_hook_data
+                .find_target(mirror.target())
+                .and_then(|shadow| Ok((shadow, shadow.url(&target_path)?)));
+            match shadow {
+                Ok((shadow, shadow_url)) => {
+                    let (sender, receiver) = oneshot::channel();
+                    actix_web::rt::spawn(shutdown.track(mirror_in_background(
+                        web_hook_data.clone(),
+                        shadow.name().clone(),
+                        shadow_url,
+                        forward_request.clone(),
+                        receiver,
+                    )));
+                    Some(sender)
+                }
+                Err(e) => {
+                    error!(
+                        "Failed to mirror request for path {}: {}",
+                        forward_request.path, e
+                    );
+                    None
+                }
+            }
+        });
+
+    let response = match fan_out {
+        None => {
+            forward(
+                &web_hook_data,
+                targets[0],
+                target_urls[0].clone(),
+                &forward_request,
+            )
+            .await
+        }
+        Some(fan_out) => {
+            fan_out_deliveries(
+                &web_hook_data,
+                &shutdown,
+                fan_out,
+                targets.into_iter().zip(target_urls),
+                &forward_request,
+            )
+            .await
+        }
+    };
+
+    // Let the sender retry deliveries the target did not accept
+    if let (Some(guard), Some(key)) = (options.replay_guard(), replay_key)
+        && !response.status().is_success()
+    {
+        guard.release(&key).await;
+    }
+
+    Ok(match mirror {
+        Some(sender) => share_with_mirror(response, sender),
+        None => response,
+    })
+}
+
+async fn fan_out_deliveries<'a>(
+    web_hook_data: &Arc<WebHookData>,
+    shutdown: &Shutdown,
+    fan_out: &FanOut,
+    mut deliveries: impl Iterator<Item = (&'a Target, Url)>,
+    forward_request: &ForwardRequest,
+) -> HttpResponse {
+    match fan_out.policy() {
+        FanOutPolicy::First => {
+            let (target, target_url) = deliveries.next().expect("Route without target");
+            for (secondary, secondary_url) in deliveries {
+                actix_web::rt::spawn(shutdown.track(deliver_in_background(
+                    web_hook_data.clone(),
+                    secondary.name().clone(),
+                    secondary_url,
+                    forward_request.clone(),
+                    fan_out.clone(),
+                )));
+            }
+
+            forward(web_hook_data, target, target_url, forward_request).await
+        }
+        policy => {
+            let responses =
+                futures_util::future::join_all(deliveries.map(|(target, target_url)| {
+                    forward(web_hook_data, target, target_url, forward_request)
+                }))
+                .await;
+            let results = responses
+                .into_iter()
+                .map(|response| (response.status().is_success(), response))
+                .collect();
+
+            policy.select(results).expect("Route without target")
+        }
+    }
+}
+
+/// Hand the status and body of the primary response to the mirror for comparison.
+fn share_with_mirror(
+    response: HttpResponse,
+    sender: oneshot::Sender<(StatusCode, Option<Bytes>)>,
+) -> HttpResponse {
+    let (response, body) = response.into_parts();
+    match body.try_into_bytes() {
+        Ok(bytes) => {
+            let _ = sender.send((response.status(), Some(bytes.clone())));
+            response.set_body(BoxBody::new(bytes))
+        }
+        Err(body) => {
+            let _ = sender.send((response.status(), None));
+            response.set_body(body)
+        }
+    }
+}
+
+/// Send the request to the shadow target and compare its response with the primary one.
+async fn mirror_in_background(
+    web_hook_data: Arc<WebHookData>,
+    shadow_name: String,
+    shadow_url: Url,
+    forward_request: ForwardRequest,
+    primary: oneshot::Receiver<(StatusCode, Option<Bytes>)>,
+) {
+    let Ok(shadow) = web_hook_data.find_target(&shadow_name) else {
+        return;
+    };
+
+    let response = send(&web_hook_data, shadow, shadow_url, &forward_request)
+        .await
+        .unwrap_or_else(|e| e.error_response());
+    let (response, body) = response.into_parts();
+    let body = body.try_into_bytes().ok();
+
+    // The primary request was cancelled
+    let Ok((primary_status, primary_body)) = primary.await else {
+        return;
+    };
+
+    let result = if response.status() != primary_status {
+        "status_mismatch"
+    } else if primary_body.is_some() && body != primary_body {
+        "body_mismatch"
+    } else {
+        "match"
+    };
+
+    if result == "match" {
+        debug!(
+            "Shadow target {} matched the response for path {}",
+            shadow_name, forward_request.path
+        );
+    } else {
+        info!(
+            shadow_status = response.status().as_u16(),
+            primary_status = primary_status.as_u16(),
+            shadow_body_size = body.as_ref().map(Bytes::len),
+            primary_body_size = primary_body.as_ref().map(Bytes::len),
+            "Shadow target {} differs from the primary response for path {}: {}",
+            shadow_name,
+            forward_request.path,
+            result
+        );
+    }
+    METRICS
+        .mirrored()
+        .with_label_values(&[shadow_name.as_str(), result])
+        .inc();
+}
+
+/// Request as forwarded to each target of the route.
+#[derive(Clone)]
+struct ForwardRequest {
+    method: Method,
+    // Incoming path, for logs
+    path: String,
+    headers: reqwest::header::HeaderMap,
+    body: actix_web::web::Bytes,
+    params: HashMap<String, String>,
+}
+
+/// Deliver the request to one target. Failures are answered with the response for the caller.
+async fn forward(
+    web_hook_data: &WebHookData,
+    target: &Target,
+    target_url: Url,
+    forward_request: &ForwardRequest,
+) -> HttpResponse {
+    let response = send(web_hook_data, target, target_url, forward_request)
+        .await
+        .unwrap_or_else(|e| e.error_response());
+    let success = response.status().is_success();
+    if success {
+        debug!(
+            "Delivered request for path {} to target {} with status {}",
+            forward_request.path,
+            target.name(),
+            response.status()
+        );
+    } else {
+        warn!(
+            "Failed to deliver request for path {} to target {} with status {}",
+            forward_request.path,
+            target.name(),
+            response.status()
+        );
+    }
+    METRICS
+        .deliveries()
+        .with_label_values(&[
+            target.name().as_str(),
+            if success { "success" } else { "failure" },
+        ])
+        .inc();
+
+    response
+}
+
+async fn send(
+    web_hook_data: &WebHookData,
+    target: &Target,
+    target_url: Url,
+    forward_request: &ForwardRequest,
+) -> core::result::Result<HttpResponse, actix_web::Error> {
+    let path = &forward_request.path;
+
+    // Fail fast while the target is failing
+    let circuit_permit = match target.circuit_breaker() {
+        Some(circuit_breaker) => match circuit_breaker.acquire() {
+            Ok(permit) => Some(permit),
+            Err(retry_after) => {
+                debug!("Circuit of target {} is open", target.name());
+                return Ok(HttpResponse::ServiceUnavailable()
+                    .insert_header((RETRY_AFTER, retry_after_seconds(retry_after)))
+                    .json(serde_json::json!({
+                        "reason": "circuit_open",
+                        "message": "The target is failing, requests are paused",
+                    })));
+            }
+        },
+        None => None,
+    };
+
+    // Shed load when the target already has too many requests in flight
+    let _permit = match target.concurrency_limiter() {
+        Some(limiter) => match limiter.acquire().await {
+            Ok(permit) => Some(permit),
+            Err(reason) => {
+                warn!(
+                    "Target {} is overloaded with {} requests in flight: {}",
+                    target.name(),
+                    limiter.in_flight(),
+                    reason.name()
+                );
+                METRICS
+                    .load_shed()
+                    .with_label_values(&[target.name().as_str(), reason.name()])
+                    .inc();
+
+                return Ok(HttpResponse::ServiceUnavailable().json(serde_json::json!({
+                    "reason": "overloaded",
+                    "message": "Too many requests in flight for the target",
+                })));
+            }
+        },
+        None => None,
+    };
+
+    // Credentials of the target, which are replaced when the target rejects them
+    let auth = web_hook_data.auth_of(target);
+    let mut credentials = auth.begin(target.name(), target_url.origin().ascii_serialization());
 
     // Redirect request
-    let response = ReqwestBuilder::new(
-        web_hook_data.client(),
-        target_url,
-        body,
-        target_headers,
-        params.0,
-        request.method(),
-    )
-    .build()
-    .map_err(|e| {
-        error!("Failed to build request: {}", e);
-        actix_web::error::ErrorBadRequest(e)
-    })?
-    .send()
-    .await
-    .map_err(|e| {
+    let response = loop {
+        let headers = match credentials.headers(&forward_request.headers).await {
+            Ok(headers) => headers,
+            Err(e) => {
+                error!(
+                    "Failed to authenticate towards target {}: {}",
+                    target.name(),
+                    e
+                );
+                return Ok(HttpResponse::BadGateway().json(serde_json::json!({
+                    "reason": "upstream_auth_failed",
+                    "message": "Failed to get credentials for the target",
+                })));
+            }
+        };
+        let response = ReqwestBuilder::new(
+            target.client(),
+            target_url.clone(),
+            Body::from(forward_request.body.clone()),
+            headers,
+            forward_request.params.clone(),
+            &forward_request.method,
+        )
+        .build()
+        .map_err(|e| {
+            error!("Failed to build request: {}", e);
+            actix_web::error::ErrorBadRequest(e)
+        })?
+        .send()
+        .await;
+
+        if let Ok(response) = &response
+            && credentials
+                .retry(response.status(), response.headers())
+                .await
+        {
+            continue;
+        }
+
+        break response;
+    };
+
+    // Server errors and failed connections count against the target
+    if let Some(permit) = circuit_permit {
+        match &response {
+            Ok(response) if !response.status().is_server_error() => permit.success(),
+            _ => permit.failure(),
+        }
+    }
+
+    let response = response.map_err(|e| {
         error!("Failed to send request: {}", e);
         actix_web::error::ErrorBadRequest(e)
     })?;
 
+    // Cloudflare Access rejected our service token
+    if access::is_access_rejection(response.status(), response.headers()) {
+        warn!(
+            "Cloudflare Access rejected request for path {} with status {}",
+            path,
+            response.status()
+        );
+        METRICS.access_denied().inc();
+        sentry::capture_message(
+            &format!("Cloudflare Access rejected request for path {path}"),
+            sentry::Level::Warning,
+        );
+
+        return Ok(HttpResponse::BadGateway().json(serde_json::json!({
+            "reason": "access_denied",
+            "message": "Cloudflare Access rejected the service token",
+        })));
+    }
+
     // Parse reqwest response
     let converted_response = ReqwestToActixConverter::convert_response(response).await?;
 
@@ -82,6 +634,131 @@ async fn redirect(
     Ok(converted_response)
 }
 
+/// Deliver the request to a secondary target, retrying failures with an exponential backoff.
+async fn deliver_in_background(
+    web_hook_data: Arc<WebHookData>,
+    target_name: String,
+    target_url: Url,
+    forward_request: ForwardRequest,
+    fan_out: FanOut,
+) {
+    let Ok(target) = web_hook_data.find_target(&target_name) else {
+        return;
+    };
+
+    for retry in 0..=*fan_out.retries() {
+        if retry > 0 {
+            tokio::time::sleep(fan_out.delay_before(retry)).await;
+        }
+
+        let response = forward(&web_hook_data, target, target_url.clone(), &forward_request).await;
+        if response.status().is_success() {
+            return;
+        }
+    }
+
+    error!(
+        "Giving up delivering request for path {} to target {} after {} retries",
+        forward_request.path,
+        target_name,
+        fan_out.retries()
+    );
+}
+
+fn rate_limit(
+    request: &HttpRequest,
+    route: &AllowedPath,
+    web_hook_data: &WebHookData,
+) -> Option<HttpResponse> {
+    let limiters: Vec<_> = [
+        ("global", web_hook_data.rate_limiter().as_deref()),
+        ("route", route.options().rate_limiter().as_deref()),
+    ]
+    .into_iter()
+    .filter_map(|(scope, limiter)| {
+        limiter.map(|limiter| {
+            (
+                scope,
+                limiter,
+                limiter.key().resolve(request, route.pattern()),
+            )
+        })
+    })
+    .collect();
+    let checks: Vec<_> = limiters
+        .iter()
+        .map(|(_, limiter, key)| (*limiter, key.as_str()))
+        .collect();
+
+    let (position, retry_after) = RateLimiter::check_all(&checks).err()?;
+    let (scope, limiter, key) = &limiters[position];
+    // Header values can be credentials, so they are never logged
+    let key = match limiter.key() {
+        RateLimitKey::Header(_) => "<redacted>",
+        _ => key.as_str(),
+    };
+    warn!(
+        "Rate limited {} request for route {} with key {:?}",
+        scope,
+        route.pattern(),
+        key
+    );
+    METRICS.rate_limited().with_label_values(&[scope]).inc();
+
+    Some(
+        HttpResponse::TooManyRequests()
+            .insert_header((RETRY_AFTER, retry_after_seconds(retry_after)))
+            .finish(),
+    )
+}
+
+/// Headers of the request for transform templates, repeated headers joined with a comma.
+fn transform_headers(
+    request: &HttpRequest,
+    credential_header: Option<&HeaderName>,
+    client_identity_header: Option<&reqwest::header::HeaderName>,
+    client_identity: Option<&ClientIdentity>,
+) -> BTreeMap<String, String> {
+    let mut headers = BTreeMap::<String, String>::new();
+    for (name, value) in request.headers() {
+        // Templates see the verified client identity, never the one sent by the caller
+        if Some(name) == credential_header
+            || client_identity_header.is_some_and(|header| header.as_str() == name.as_str())
+        {
+            continue;
+        }
+        let Ok(value) = value.to_str() else {
+            continue;
+        };
+        headers
+            .entry(name.as_str().to_string())
+            .and_modify(|joined| {
+                joined.push_str(", ");
+                joined.push_str(value);
+            })
+            .or_insert_with(|| value.to_string());
+    }
+
+    if let (Some(header), Some(identity)) = (client_identity_header, client_identity) {
+        headers.insert(header.as_str().to_string(), identity.subject().clone());
+    }
+
+    headers
+}
+
+fn content_length(request: &HttpRequest) -> Option<usize> {
+    request
+        .headers()
+        .get(CONTENT_LENGTH)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.parse().ok())
+}
+
+/// Retry-After only supports whole seconds.
+fn retry_after_seconds(retry_after: Duration) -> String {
+    (retry_after.as_secs_f64().ceil().max(1.0) as u64).to_string()
+}
+
 struct ReqwestBuilder<'a> {
     client: &'a ClientWithMiddleware,
     url: Url,
@@ -169,9 +846,19 @@ impl<'a> ReqwestBuilder<'a> {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::body::test_utils::gzip;
+    use crate::client;
     use crate::config::AllowedMethod;
+    use crate::data::{
+        AccessJwtValidator, AccessKeysSource, AccessTokenCache, AllowedPaths, ApiKeyAuth,
+        ApiKeySource, CircuitBreaker, ClientIdentityRequirement, ConcurrencyLimiter,
+        DEFAULT_TARGET_NAME, EventFilter, FanOut, FanOutPolicy, FilterOperator, FilterSource,
+        Mirror, NonceStore, OAuth2ClientCredentials, PayloadTransform, RateLimitKey, RateLimiter,
+        ReplayGuard, ReplayTimestamp, RouteOptions, ServiceToken, Target, UpstreamAuth,
+        access_jwt_utils, jwt,
+    };
     use actix_web::{App, test};
-    use reqwest_middleware::ClientBuilder;
+    use arc_swap::ArcSwap;
     use secrecy::SecretString;
     use std::collections::HashSet;
     use wiremock::{Mock, ResponseTemplate};
@@ -181,8 +868,9 @@ mod tests {
     #[derive(Getters)]
     #[getset(get = "pub")]
     pub struct TestApp {
-        _mock_server: wiremock::MockServer,
-        web_hook_data: web::Data<WebHookData>,
+        mock_server: wiremock::MockServer,
+        web_hook_data: web::Data<SharedWebHookData>,
+        shutdown: web::Data<Shutdown>,
     }
 
     impl TestApp {
@@ -192,20 +880,25 @@ mod tests {
             allowed_method: &str,
             allowed_path: &str,
         ) -> Self {
-            let mock_server = wiremock::MockServer::start().await;
-            Mock::given(wiremock::matchers::method(mock_method))
-                .and(wiremock::matchers::path(mock_path))
-                .respond_with(
-                    ResponseTemplate::new(200)
-                        .set_body_string(RETURN_STRING)
-                        .insert_header("Test", "123"),
-                )
-                .expect(1)
-                .mount(&mock_server)
-                .await;
-
-            let target = Url::parse(mock_server.uri().as_str()).unwrap();
+            TestApp::with_response(
+                mock_method,
+                mock_path,
+                allowed_method,
+                allowed_path,
+                ResponseTemplate::new(200)
+                    .set_body_string(RETURN_STRING)
+                    .insert_header("Test", "123"),
+            )
+            .await
+        }
 
+        pub async fn with_response(
+            mock_method: &str,
+            mock_path: &str,
+            allowed_method: &str,
+            allowed_path: &str,
+            response: ResponseTemplate,
+        ) -> Self {
             let mut paths = HashMap::new();
 
             let mut methods: HashSet<AllowedMethod> = HashSet::new();
@@ -214,33 +907,93 @@ mod tests {
 
             let allowed_paths = paths.try_into().unwrap();
 
-            let client = ClientBuilder::new(reqwest::Client::new()).build();
+            TestApp::with_allowed_paths(
+                TestApp::mock(mock_method, mock_path, response, 1).await,
+                allowed_paths,
+                |web_hook_data| web_hook_data,
+            )
+        }
+
+        pub async fn mock(
+            mock_method: &str,
+            mock_path: &str,
+            response: ResponseTemplate,
+            expected: u64,
+        ) -> wiremock::MockServer {
+            let mock_server = wiremock::MockServer::start().await;
+            Mock::given(wiremock::matchers::method(mock_method))
+                .and(wiremock::matchers::path(mock_path))
+                .respond_with(response)
+                .expect(expected)
+                .mount(&mock_server)
+                .await;
+
+            mock_server
+        }
+
+        pub fn with_allowed_paths(
+            mock_server: wiremock::MockServer,
+            allowed_paths: AllowedPaths,
+            configure: impl FnOnce(WebHookData) -> WebHookData,
+        ) -> Self {
+            let target = Target::new(
+                DEFAULT_TARGET_NAME.to_string(),
+                Url::parse(mock_server.uri().as_str()).unwrap(),
+                client::build_client(None, None, false).unwrap(),
+            );
+
             let web_hook_data = WebHookData::new(
-                client,
                 target,
                 allowed_paths,
-                SecretString::new(Box::from("access-id")),
-                SecretString::new(Box::from("access-secret")),
-            )
-            .unwrap();
+                UpstreamAuth::CloudflareAccess(
+                    ServiceToken::new(
+                        &SecretString::from("access-id"),
+                        &SecretString::from("access-secret"),
+                    )
+                    .unwrap(),
+                ),
+            );
 
-            let web_hook_data = web::Data::new(web_hook_data);
+            let web_hook_data = web::Data::new(ArcSwap::from_pointee(configure(web_hook_data)));
             Self {
-                _mock_server: mock_server,
+                mock_server,
                 web_hook_data,
+                shutdown: web::Data::new(Shutdown::default()),
             }
         }
+
+        /// The redirect service with the web hook data and shutdown of the test app.
+        pub async fn init_service(
+            &self,
+        ) -> impl actix_web::dev::Service<
+            actix_http::Request,
+            Response = actix_web::dev::ServiceResponse,
+            Error = actix_web::Error,
+        > {
+            test::init_service(
+                App::new()
+                    .app_data(self.web_hook_data.clone())
+                    .app_data(self.shutdown.clone())
+                    .configure(get_config),
+            )
+            .await
+        }
+
+        pub fn allowed_path(path: &str, allowed_path: AllowedPath) -> AllowedPaths {
+            let mut paths = HashMap::new();
+            paths.insert(path.to_string(), allowed_path);
+            AllowedPaths::new(paths).unwrap()
+        }
+
+        pub fn route(methods: Vec<actix_web::http::Method>, options: RouteOptions) -> AllowedPath {
+            AllowedPath::new(false, methods.into_iter().collect()).with_options(options)
+        }
     }
 
     #[actix_web::test]
     async fn test_redirect_get() {
         let test_app = TestApp::new("GET", "test", "GET", "test").await;
-        let app = test::init_service(
-            App::new()
-                .app_data(test_app.web_hook_data().clone())
-                .configure(get_config),
-        )
-        .await;
+        let app = test_app.init_service().await;
 
         // Valid request
         let req = test::TestRequest::get().uri("/test").to_request();
@@ -262,12 +1015,7 @@ mod tests {
     #[actix_web::test]
     async fn test_redirect_all() {
         let test_app = TestApp::new("PUT", "test", "ALL", "test").await;
-        let app = test::init_service(
-            App::new()
-                .app_data(test_app.web_hook_data().clone())
-                .configure(get_config),
-        )
-        .await;
+        let app = test_app.init_service().await;
 
         // Valid request
         let req = test::TestRequest::put().uri("/test").to_request();
@@ -289,12 +1037,7 @@ mod tests {
     #[actix_web::test]
     async fn test_redirect_regex() {
         let test_app = TestApp::new("PUT", "test/10090", "ALL", r"test/\d*").await;
-        let app = test::init_service(
-            App::new()
-                .app_data(test_app.web_hook_data().clone())
-                .configure(get_config),
-        )
-        .await;
+        let app = test_app.init_service().await;
 
         // Valid request
         let req = test::TestRequest::put().uri("/test/10090").to_request();
@@ -321,4 +1064,1176 @@ mod tests {
         let resp = test::call_service(&app, req).await;
         assert_eq!(resp.status(), 404);
     }
+
+    #[actix_web::test]
+    async fn test_redirect_access_login_redirect() {
+        let test_app = TestApp::with_response(
+            "POST",
+            "test",
+            "POST",
+            "test",
+            ResponseTemplate::new(302).insert_header(
+                "Location",
+                "https://team.cloudflareaccess.com/cdn-cgi/access/login/example.com",
+            ),
+        )
+        .await;
+        let app = test_app.init_service().await;
+
+        let denied_before = METRICS.access_denied().get();
+
+        let req = test::TestRequest::post().uri("/test").to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), 502);
+
+        let body: serde_json::Value = test::read_body_json(resp).await;
+        assert_eq!(body["reason"], "access_denied");
+        assert!(METRICS.access_denied().get() > denied_before);
+    }
+
+    #[actix_web::test]
+    async fn test_redirect_access_forbidden() {
+        let test_app = TestApp::with_response(
+            "POST",
+            "test",
+            "POST",
+            "test",
+            ResponseTemplate::new(403)
+                .insert_header("CF-Access-Domain", "example.com")
+                .set_body_string("<html>Forbidden</html>"),
+        )
+        .await;
+        let app = test_app.init_service().await;
+
+        let req = test::TestRequest::post().uri("/test").to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), 502);
+
+        let body: serde_json::Value = test::read_body_json(resp).await;
+        assert_eq!(body["reason"], "access_denied");
+    }
+
+    #[actix_web::test]
+    async fn test_redirect_secondary_service_token() {
+        let mock_server = wiremock::MockServer::start().await;
+        Mock::given(wiremock::matchers::header(
+            "CF-Access-Client-Id",
+            "access-id",
+        ))
+        .respond_with(ResponseTemplate::new(403).insert_header("CF-Access-Domain", "example.com"))
+        .expect(1)
+        .mount(&mock_server)
+        .await;
+        Mock::given(wiremock::matchers::header(
+            "CF-Access-Client-Id",
+            "secondary-id",
+        ))
+        .and(wiremock::matchers::header(
+            "CF-Access-Client-Secret",
+            "secondary-secret",
+        ))
+        .respond_with(ResponseTemplate::new(200))
+        .expect(2)
+        .mount(&mock_server)
+        .await;
+
+        let test_app = TestApp::with_allowed_paths(
+            mock_server,
+            TestApp::allowed_path(
+                "test",
+                TestApp::route(vec![Method::POST], RouteOptions::default()),
+            ),
+            |web_hook_data| {
+                web_hook_data.with_auth(UpstreamAuth::CloudflareAccess(
+                    ServiceToken::new(
+                        &SecretString::from("access-id"),
+                        &SecretString::from("access-secret"),
+                    )
+                    .unwrap()
+                    .with_secondary(Some(
+                        ServiceToken::new(
+                            &SecretString::from("secondary-id"),
+                            &SecretString::from("secondary-secret"),
+                        )
+                        .unwrap(),
+                    )),
+                ))
+            },
+        );
+        let app = test_app.init_service().await;
+
+        // The revoked primary is only sent until the secondary was accepted
+        for _ in 0..2 {
+            let req = test::TestRequest::post().uri("/test").to_request();
+            let resp = test::call_service(&app, req).await;
+            assert_eq!(resp.status(), 200);
+        }
+    }
+
+    #[actix_web::test]
+    async fn test_redirect_access_token_cache() {
+        let token = jwt(Duration::from_secs(3600));
+        let mock_server = wiremock::MockServer::start().await;
+        Mock::given(wiremock::matchers::header(
+            "cf-access-token",
+            token.as_str(),
+        ))
+        .respond_with(ResponseTemplate::new(200))
+        .up_to_n_times(1)
+        .expect(1)
+        .mount(&mock_server)
+        .await;
+        // The token is revoked after the first use
+        Mock::given(wiremock::matchers::header(
+            "cf-access-token",
+            token.as_str(),
+        ))
+        .respond_with(ResponseTemplate::new(403).insert_header("CF-Access-Domain", "example.com"))
+        .expect(1)
+        .mount(&mock_server)
+        .await;
+        Mock::given(wiremock::matchers::header(
+            "CF-Access-Client-Id",
+            "access-id",
+        ))
+        .respond_with(ResponseTemplate::new(200).insert_header(
+            "Set-Cookie",
+            format!("CF_Authorization={token}; Path=/").as_str(),
+        ))
+        .expect(2)
+        .mount(&mock_server)
+        .await;
+
+        let test_app = TestApp::with_allowed_paths(
+            mock_server,
+            TestApp::allowed_path(
+                "test",
+                TestApp::route(vec![Method::POST], RouteOptions::default()),
+            ),
+            |web_hook_data| {
+                web_hook_data.with_auth(UpstreamAuth::CloudflareAccess(
+                    ServiceToken::new(
+                        &SecretString::from("access-id"),
+                        &SecretString::from("access-secret"),
+                    )
+                    .unwrap()
+                    .with_token_cache(Some(AccessTokenCache::new(Duration::from_secs(60)))),
+                ))
+            },
+        );
+        let app = test_app.init_service().await;
+
+        // Log in with the service token, then use the token until Access rejects it
+        for _ in 0..3 {
+            let req = test::TestRequest::post().uri("/test").to_request();
+            let resp = test::call_service(&app, req).await;
+            assert_eq!(resp.status(), 200);
+        }
+    }
+
+    #[actix_web::test]
+    async fn test_redirect_oauth2() {
+        let mock_server = wiremock::MockServer::start().await;
+        Mock::given(wiremock::matchers::path("/oauth/token"))
+            .respond_with(ResponseTemplate::new(200).set_body_json(serde_json::json!({
+                "access_token": "revoked-token",
+                "expires_in": 3600,
+            })))
+            .up_to_n_times(1)
+            .expect(1)
+            .mount(&mock_server)
+            .await;
+        Mock::given(wiremock::matchers::path("/oauth/token"))
+            .respond_with(ResponseTemplate::new(200).set_body_json(serde_json::json!({
+                "access_token": "token",
+                "expires_in": 3600,
+            })))
+            .expect(1)
+            .mount(&mock_server)
+            .await;
+        Mock::given(wiremock::matchers::path("/test"))
+            .and(wiremock::matchers::header("Authorization", "Bearer token"))
+            .respond_with(ResponseTemplate::new(200))
+            .expect(2)
+            .mount(&mock_server)
+            .await;
+        Mock::given(wiremock::matchers::path("/test"))
+            .and(wiremock::matchers::header(
+                "Authorization",
+                "Bearer revoked-token",
+            ))
+            .respond_with(ResponseTemplate::new(401))
+            .expect(1)
+            .mount(&mock_server)
+            .await;
+
+        let token_url = Url::parse(&format!("{}/oauth/token", mock_server.uri())).unwrap();
+        let test_app = TestApp::with_allowed_paths(
+            mock_server,
+            TestApp::allowed_path(
+                "test",
+                TestApp::route(vec![Method::POST], RouteOptions::default()),
+            ),
+            |web_hook_data| {
+                web_hook_data.with_auth(UpstreamAuth::OAuth2(OAuth2ClientCredentials::new(
+                    client::build_client(None, None, false).unwrap(),
+                    token_url,
+                    "client".to_string(),
+                    SecretString::from("secret"),
+                    vec![],
+                    None,
+                    Duration::from_secs(60),
+                )))
+            },
+        );
+        let app = test_app.init_service().await;
+
+        // The first token is rejected and replaced, the second request reuses the new one
+        for _ in 0..2 {
+            let req = test::TestRequest::post().uri("/test").to_request();
+            let resp = test::call_service(&app, req).await;
+            assert_eq!(resp.status(), 200);
+        }
+
+        let requests = test_app.mock_server().received_requests().await.unwrap();
+        assert!(
+            requests
+                .iter()
+                .filter(|request| request.url.path() == "/test")
+                .all(|request| !request.headers.contains_key("cf-access-client-id"))
+        );
+    }
+
+    #[actix_web::test]
+    async fn test_redirect_oauth2_token_endpoint_failed() {
+        let mock_server = wiremock::MockServer::start().await;
+        Mock::given(wiremock::matchers::path("/oauth/token"))
+            .respond_with(ResponseTemplate::new(500))
+            .mount(&mock_server)
+            .await;
+        Mock::given(wiremock::matchers::path("/test"))
+            .respond_with(ResponseTemplate::new(200))
+            .expect(0)
+            .mount(&mock_server)
+            .await;
+
+        let token_url = Url::parse(&format!("{}/oauth/token", mock_server.uri())).unwrap();
+        let test_app = TestApp::with_allowed_paths(
+            mock_server,
+            TestApp::allowed_path(
+                "test",
+                TestApp::route(vec![Method::POST], RouteOptions::default()),
+            ),
+            |web_hook_data| {
+                web_hook_data.with_auth(UpstreamAuth::OAuth2(OAuth2ClientCredentials::new(
+                    client::build_client(None, None, false).unwrap(),
+                    token_url,
+                    "client".to_string(),
+                    SecretString::from("secret"),
+                    vec![],
+                    None,
+                    Duration::from_secs(60),
+                )))
+            },
+        );
+        let app = test_app.init_service().await;
+
+        let req = test::TestRequest::post().uri("/test").to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), 502);
+    }
+
+    #[actix_web::test]
+    async fn test_redirect_access_jwt() {
+        let mock_server = TestApp::mock("POST", "test", ResponseTemplate::new(200), 1).await;
+        Mock::given(wiremock::matchers::path("/cdn-cgi/access/certs"))
+            .respond_with(ResponseTemplate::new(200).set_body_json(access_jwt_utils::jwks()))
+            .mount(&mock_server)
+            .await;
+        let certs_url = Url::parse(&format!("{}/cdn-cgi/access/certs", mock_server.uri())).unwrap();
+        let test_app = TestApp::with_allowed_paths(
+            mock_server,
+            TestApp::allowed_path(
+                "test",
+                TestApp::route(
+                    vec![Method::POST],
+                    RouteOptions::default().with_access_jwt(AccessJwtValidator::new(
+                        AccessKeysSource::Url(certs_url),
+                        client::build_client(None, None, false).unwrap(),
+                        Some(access_jwt_utils::ISSUER.to_string()),
+                        vec![access_jwt_utils::AUDIENCE.to_string()],
+                        vec!["ci@example.com".to_string()],
+                        vec![],
+                        Duration::from_secs(3600),
+                    )),
+                ),
+            ),
+            |web_hook_data| web_hook_data,
+        );
+        let app = test_app.init_service().await;
+
+        let req = test::TestRequest::post().uri("/test").to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), 401);
+
+        let req = test::TestRequest::post()
+            .uri("/test")
+            .insert_header((
+                "cf-access-jwt-assertion",
+                access_jwt_utils::access_jwt(serde_json::json!({ "email": "other@example.com" })),
+            ))
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), 403);
+
+        let req = test::TestRequest::post()
+            .uri("/test")
+            .insert_header((
+                "cf-access-jwt-assertion",
+                access_jwt_utils::access_jwt(serde_json::json!({})),
+            ))
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), 200);
+    }
+
+    #[actix_web::test]
+    async fn test_redirect_client_identity_required() {
+        let test_app = TestApp::with_allowed_paths(
+            TestApp::mock("POST", "test", ResponseTemplate::new(200), 0).await,
+            TestApp::allowed_path(
+                "test",
+                TestApp::route(
+                    vec![Method::POST],
+                    RouteOptions::default()
+                        .with_client_identity(ClientIdentityRequirement::new(None, None)),
+                ),
+            ),
+            |web_hook_data| web_hook_data,
+        );
+        let app = test_app.init_service().await;
+
+        // Plain connection without client certificate
+        let req = test::TestRequest::post().uri("/test").to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), 403);
+    }
+
+    #[actix_web::test]
+    async fn test_redirect_client_identity_header_stripped() {
+        let test_app = TestApp::with_allowed_paths(
+            TestApp::mock("POST", "test", ResponseTemplate::new(200), 1).await,
+            TestApp::allowed_path(
+                "test",
+                TestApp::route(vec![Method::POST], RouteOptions::default()),
+            ),
+            |web_hook_data| {
+                web_hook_data.with_client_identity_header(Some(
+                    reqwest::header::HeaderName::from_static("x-client-identity"),
+                ))
+            },
+        );
+        let app = test_app.init_service().await;
+
+        let req = test::TestRequest::post()
+            .uri("/test")
+            .insert_header(("X-Client-Identity", "CN=spoofed"))
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert!(resp.status().is_success());
+
+        let requests = test_app.mock_server().received_requests().await.unwrap();
+        assert_eq!(requests.len(), 1);
+        assert!(!requests[0].headers.contains_key("x-client-identity"));
+    }
+
+    #[actix_web::test]
+    async fn test_redirect_api_key_bearer() {
+        let test_app = TestApp::with_allowed_paths(
+            TestApp::mock("POST", "test", ResponseTemplate::new(200), 1).await,
+            TestApp::allowed_path(
+                "test",
+                TestApp::route(
+                    vec![Method::POST],
+                    RouteOptions::default().with_api_key(ApiKeyAuth::new(
+                        ApiKeySource::Bearer,
+                        &[SecretString::from("old-key"), SecretString::from("new-key")],
+                    )),
+                ),
+            ),
+            |web_hook_data| web_hook_data,
+        );
+        let app = test_app.init_service().await;
+
+        let req = test::TestRequest::post().uri("/test").to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), 401);
+        assert_eq!(resp.headers().get(WWW_AUTHENTICATE).unwrap(), "Bearer");
+
+        let req = test::TestRequest::post()
+            .uri("/test")
+            .insert_header(("Authorization", "Bearer other-key"))
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), 401);
+
+        let req = test::TestRequest::post()
+            .uri("/test")
+            .insert_header(("Authorization", "Bearer new-key"))
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), 200);
+
+        let requests = test_app.mock_server().received_requests().await.unwrap();
+        assert_eq!(requests.len(), 1);
+        assert!(!requests[0].headers.contains_key("authorization"));
+    }
+
+    #[actix_web::test]
+    async fn test_redirect_api_key_query() {
+        let test_app = TestApp::with_allowed_paths(
+            TestApp::mock("GET", "test", ResponseTemplate::new(200), 1).await,
+            TestApp::allowed_path(
+                "test",
+                TestApp::route(
+                    vec![Method::GET],
+                    RouteOptions::default().with_api_key(ApiKeyAuth::new(
+                        ApiKeySource::Query("token".to_string()),
+                        &[SecretString::from("key")],
+                    )),
+                ),
+            ),
+            |web_hook_data| web_hook_data,
+        );
+        let app = test_app.init_service().await;
+
+        let req = test::TestRequest::get()
+            .uri("/test?token=other&repo=app")
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), 401);
+        assert!(resp.headers().get(WWW_AUTHENTICATE).is_none());
+
+        let req = test::TestRequest::get()
+            .uri("/test?token=key&repo=app")
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), 200);
+
+        let requests = test_app.mock_server().received_requests().await.unwrap();
+        assert_eq!(requests.len(), 1);
+        assert_eq!(requests[0].url.query(), Some("repo=app"));
+    }
+
+    #[actix_web::test]
+    async fn test_redirect_route_target() {
+        let audit_server = TestApp::mock("POST", "test", ResponseTemplate::new(200), 1).await;
+        let audit_target = Target::new(
+            "audit".to_string(),
+            Url::parse(&audit_server.uri()).unwrap(),
+            client::build_client(None, None, false).unwrap(),
+        );
+
+        let test_app = TestApp::with_allowed_paths(
+            TestApp::mock("POST", "test", ResponseTemplate::new(200), 0).await,
+            TestApp::allowed_path(
+                "test",
+                TestApp::route(
+                    vec![Method::POST],
+                    RouteOptions::default().with_target("audit".to_string()),
+                ),
+            ),
+            |web_hook_data| {
+                web_hook_data.with_targets(HashMap::from([("audit".to_string(), audit_target)]))
+            },
+        );
+        let app = test_app.init_service().await;
+
+        let req = test::TestRequest::post().uri("/test").to_request();
+        let resp = test::call_service(&app, req).await;
+        assert!(resp.status().is_success());
+    }
+
+    #[actix_web::test]
+    async fn test_redirect_rate_limited() {
+        let test_app = TestApp::with_allowed_paths(
+            TestApp::mock("POST", "test", ResponseTemplate::new(200), 2).await,
+            TestApp::allowed_path(
+                "test",
+                TestApp::route(
+                    vec![Method::POST],
+                    RouteOptions::default().with_rate_limiter(RateLimiter::new(
+                        RateLimitKey::Header(actix_web::http::header::HeaderName::from_static(
+                            "x-api-key",
+                        )),
+                        1,
+                        Duration::from_secs(60),
+                        1,
+                    )),
+                ),
+            ),
+            |web_hook_data| web_hook_data,
+        );
+        let app = test_app.init_service().await;
+
+        let request = |key: &'static str| {
+            test::TestRequest::post()
+                .uri("/test")
+                .insert_header(("X-Api-Key", key))
+                .to_request()
+        };
+
+        let resp = test::call_service(&app, request("team-a")).await;
+        assert!(resp.status().is_success());
+
+        let resp = test::call_service(&app, request("team-a")).await;
+        assert_eq!(resp.status(), 429);
+        assert_eq!(resp.headers().get(RETRY_AFTER).unwrap(), "60");
+
+        // Other callers are not affected
+        let resp = test::call_service(&app, request("team-b")).await;
+        assert!(resp.status().is_success());
+    }
+
+    #[actix_web::test]
+    async fn test_redirect_target_overloaded() {
+        let response = ResponseTemplate::new(200).set_delay(Duration::from_millis(500));
+        let test_app = TestApp::with_allowed_paths(
+            TestApp::mock("POST", "test", response, 1).await,
+            TestApp::allowed_path(
+                "test",
+                TestApp::route(vec![Method::POST], RouteOptions::default()),
+            ),
+            |web_hook_data| {
+                let target = Target::new(
+                    DEFAULT_TARGET_NAME.to_string(),
+                    web_hook_data.default_target().base().clone(),
+                    web_hook_data.default_target().client().clone(),
+                )
+                .with_concurrency_limiter(Some(ConcurrencyLimiter::new(
+                    1,
+                    0,
+                    Duration::from_secs(5),
+                )));
+                web_hook_data.with_default_target(target)
+            },
+        );
+        let app = test_app.init_service().await;
+
+        let (first, second) = tokio::join!(
+            test::call_service(&app, test::TestRequest::post().uri("/test").to_request()),
+            async {
+                tokio::time::sleep(Duration::from_millis(100)).await;
+                test::call_service(&app, test::TestRequest::post().uri("/test").to_request()).await
+            }
+        );
+        assert!(first.status().is_success());
+        assert_eq!(second.status(), 503);
+
+        let body: serde_json::Value = test::read_body_json(second).await;
+        assert_eq!(body["reason"], "overloaded");
+    }
+
+    #[actix_web::test]
+    async fn test_redirect_circuit_open() {
+        let test_app = TestApp::with_allowed_paths(
+            TestApp::mock("POST", "test", ResponseTemplate::new(500), 1).await,
+            TestApp::allowed_path(
+                "test",
+                TestApp::route(vec![Method::POST], RouteOptions::default()),
+            ),
+            |web_hook_data| {
+                let target = Target::new(
+                    DEFAULT_TARGET_NAME.to_string(),
+                    web_hook_data.default_target().base().clone(),
+                    web_hook_data.default_target().client().clone(),
+                )
+                .with_circuit_breaker(Some(CircuitBreaker::new(
+                    DEFAULT_TARGET_NAME.to_string(),
+                    1,
+                    None,
+                    10,
+                    Duration::from_secs(30),
+                    1,
+                )));
+                web_hook_data.with_default_target(target)
+            },
+        );
+        let app = test_app.init_service().await;
+
+        let req = test::TestRequest::post().uri("/test").to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), 500);
+
+        // The target is not called while the circuit is open
+        let req = test::TestRequest::post().uri("/test").to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), 503);
+        assert_eq!(resp.headers().get(RETRY_AFTER).unwrap(), "30");
+
+        let body: serde_json::Value = test::read_body_json(resp).await;
+        assert_eq!(body["reason"], "circuit_open");
+    }
+
+    #[actix_web::test]
+    async fn test_redirect_payload_too_large() {
+        let test_app = TestApp::with_allowed_paths(
+            TestApp::mock("POST", "test", ResponseTemplate::new(200), 1).await,
+            TestApp::allowed_path(
+                "test",
+                TestApp::route(
+                    vec![Method::POST],
+                    RouteOptions::default().with_max_body_size(8),
+                ),
+            ),
+            |web_hook_data| web_hook_data.with_max_body_size(Some(1024)),
+        );
+        let app = test_app.init_service().await;
+
+        let req = test::TestRequest::post()
+            .uri("/test")
+            .set_payload("12345678")
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert!(resp.status().is_success());
+
+        // Rejected from the Content-Length header
+        let req = test::TestRequest::post()
+            .uri("/test")
+            .set_payload("123456789")
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), 413);
+
+        // Rejected while reading the body
+        let req = test::TestRequest::post()
+            .uri("/test")
+            .set_payload("123456789")
+            .insert_header((CONTENT_LENGTH, "1"))
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), 413);
+    }
+
+    #[actix_web::test]
+    async fn test_redirect_decompress() {
+        let data = br#"{"action":"opened"}"#;
+        let test_app = TestApp::with_allowed_paths(
+            TestApp::mock("POST", "test", ResponseTemplate::new(200), 1).await,
+            TestApp::allowed_path(
+                "test",
+                TestApp::route(
+                    vec![Method::POST],
+                    RouteOptions::default().with_decompress(true),
+                ),
+            ),
+            |web_hook_data| web_hook_data,
+        );
+        let app = test_app.init_service().await;
+
+        let req = test::TestRequest::post()
+            .uri("/test")
+            .insert_header(("Content-Encoding", "gzip"))
+            .set_payload(gzip(data))
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert!(resp.status().is_success());
+
+        let requests = test_app.mock_server().received_requests().await.unwrap();
+        assert_eq!(requests[0].body, data);
+        assert!(!requests[0].headers.contains_key("content-encoding"));
+    }
+
+    #[actix_web::test]
+    async fn test_redirect_filtered() {
+        let test_app = TestApp::with_allowed_paths(
+            TestApp::mock("POST", "test", ResponseTemplate::new(200), 1).await,
+            TestApp::allowed_path(
+                "test",
+                TestApp::route(
+                    vec![Method::POST],
+                    RouteOptions::default().with_filters(
+                        vec![
+                            EventFilter::new(
+                                FilterSource::Header(
+                                    actix_web::http::header::HeaderName::from_static(
+                                        "x-github-event",
+                                    ),
+                                ),
+                                FilterOperator::In(vec!["push".to_string()]),
+                            ),
+                            EventFilter::new(
+                                FilterSource::Json("/ref".to_string()),
+                                FilterOperator::Equals("refs/heads/main".to_string()),
+                            ),
+                        ],
+                        actix_web::http::StatusCode::NO_CONTENT,
+                    ),
+                ),
+            ),
+            |web_hook_data| web_hook_data,
+        );
+        let app = test_app.init_service().await;
+
+        let request = |event: &'static str, branch: &'static str| {
+            test::TestRequest::post()
+                .uri("/test")
+                .insert_header(("X-GitHub-Event", event))
+                .set_json(serde_json::json!({ "ref": branch }))
+                .to_request()
+        };
+
+        let resp = test::call_service(&app, request("push", "refs/heads/main")).await;
+        assert_eq!(resp.status(), 200);
+
+        let resp = test::call_service(&app, request("issues", "refs/heads/main")).await;
+        assert_eq!(resp.status(), 204);
+
+        let resp = test::call_service(&app, request("push", "refs/heads/feature")).await;
+        assert_eq!(resp.status(), 204);
+    }
+
+    #[actix_web::test]
+    async fn test_redirect_replay() {
+        let mock_server = wiremock::MockServer::start().await;
+        Mock::given(wiremock::matchers::header("x-delivery", "failed"))
+            .respond_with(ResponseTemplate::new(503))
+            .expect(2)
+            .mount(&mock_server)
+            .await;
+        Mock::given(wiremock::matchers::path("/test"))
+            .respond_with(ResponseTemplate::new(200))
+            .expect(1)
+            .mount(&mock_server)
+            .await;
+        let test_app = TestApp::with_allowed_paths(
+            mock_server,
+            TestApp::allowed_path(
+                "test",
+                TestApp::route(
+                    vec![Method::POST],
+                    RouteOptions::default().with_replay_guard(ReplayGuard::new(
+                        Some(ReplayTimestamp::new(
+                            FilterSource::Json("/timestamp".to_string()),
+                            None,
+                        )),
+                        Some(FilterSource::Header(
+                            actix_web::http::header::HeaderName::from_static("x-delivery"),
+                        )),
+                        Duration::from_secs(300),
+                        NonceStore::memory(100),
+                    )),
+                ),
+            ),
+            |web_hook_data| web_hook_data,
+        );
+        let app = test_app.init_service().await;
+
+        let now = std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .unwrap()
+            .as_secs();
+        let request = |delivery: &'static str, timestamp: u64| {
+            test::TestRequest::post()
+                .uri("/test")
+                .insert_header(("X-Delivery", delivery))
+                .set_json(serde_json::json!({ "timestamp": timestamp }))
+                .to_request()
+        };
+        let duplicates_before = METRICS
+            .replays()
+            .with_label_values(&["^test$", "duplicate"])
+            .get();
+
+        let resp = test::call_service(&app, request("1", now)).await;
+        assert_eq!(resp.status(), 200);
+
+        let resp = test::call_service(&app, request("1", now)).await;
+        assert_eq!(resp.status(), 409);
+        assert_eq!(
+            METRICS
+                .replays()
+                .with_label_values(&["^test$", "duplicate"])
+                .get(),
+            duplicates_before + 1
+        );
+
+        let resp = test::call_service(&app, request("2", now - 600)).await;
+        assert_eq!(resp.status(), 401);
+
+        // Failed deliveries can be retried
+        let resp = test::call_service(&app, request("failed", now)).await;
+        assert_eq!(resp.status(), 503);
+        let resp = test::call_service(&app, request("failed", now)).await;
+        assert_eq!(resp.status(), 503);
+    }
+
+    #[actix_web::test]
+    async fn test_redirect_rewrite() {
+        let test_app = TestApp::with_allowed_paths(
+            TestApp::mock("POST", "hooks/github", ResponseTemplate::new(200), 2).await,
+            TestApp::allowed_path(
+                r"gh/(?P<repo>[^/]+)",
+                TestApp::route(
+                    vec![Method::POST],
+                    RouteOptions::default().with_rewrite("/hooks/github?repo=${repo}".to_string()),
+                ),
+            ),
+            |web_hook_data| web_hook_data,
+        );
+        let app = test_app.init_service().await;
+
+        let req = test::TestRequest::post()
+            .uri("/gh/api?delivery=1")
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert!(resp.status().is_success());
+
+        let requests = test_app.mock_server().received_requests().await.unwrap();
+        let query: HashMap<_, _> = requests[0].url.query_pairs().into_owned().collect();
+        assert_eq!(query.get("repo").map(String::as_str), Some("api"));
+        assert_eq!(query.get("delivery").map(String::as_str), Some("1"));
+
+        // Captures can't add query parameters or leave the path of the target
+        let req = test::TestRequest::post()
+            .uri("/gh/api%26admin=true")
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert!(resp.status().is_success());
+
+        let requests = test_app.mock_server().received_requests().await.unwrap();
+        let query: HashMap<_, _> = requests[1].url.query_pairs().into_owned().collect();
+        assert_eq!(
+            query.get("repo").map(String::as_str),
+            Some("api&admin=true")
+        );
+        assert!(!query.contains_key("admin"));
+
+        for uri in ["/gh/..", "/gh/%2E%2E"] {
+            let req = test::TestRequest::post().uri(uri).to_request();
+            let resp = test::call_service(&app, req).await;
+            assert_eq!(resp.status(), 400, "{uri}");
+        }
+    }
+
+    #[actix_web::test]
+    async fn test_redirect_transform() {
+        let test_app = TestApp::with_allowed_paths(
+            TestApp::mock("POST", "alerts", ResponseTemplate::new(200), 1).await,
+            TestApp::allowed_path(
+                "alerts",
+                TestApp::route(
+                    vec![Method::POST],
+                    RouteOptions::default().with_transform(
+                        PayloadTransform::new(
+                            r#"{"text": {{ body.alerts | map(attribute="labels.alertname") | join(", ") }}, "via": {{ headers["user-agent"] }}}"#
+                                .to_string(),
+                            "application/json",
+                        )
+                        .unwrap(),
+                    ),
+                ),
+            ),
+            |web_hook_data| web_hook_data,
+        );
+        let app = test_app.init_service().await;
+
+        let alerts = br#"{"alerts":[{"labels":{"alertname":"HighLoad"}},{"labels":{"alertname":"DiskFull"}}]}"#;
+        let req = test::TestRequest::post()
+            .uri("/alerts")
+            .insert_header(("User-Agent", "Alertmanager"))
+            .insert_header(("Content-Type", "text/plain"))
+            .insert_header(("Content-Encoding", "gzip"))
+            .set_payload(gzip(alerts))
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert!(resp.status().is_success());
+
+        let requests = test_app.mock_server().received_requests().await.unwrap();
+        assert_eq!(
+            serde_json::from_slice::<serde_json::Value>(&requests[0].body).unwrap(),
+            serde_json::json!({ "text": "HighLoad, DiskFull", "via": "Alertmanager" })
+        );
+        assert_eq!(requests[0].headers["content-type"], "application/json");
+        assert!(!requests[0].headers.contains_key("content-encoding"));
+
+        // Bodies that are not JSON can not be transformed
+        let req = test::TestRequest::post()
+            .uri("/alerts")
+            .set_payload("alerts")
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), actix_web::http::StatusCode::BAD_REQUEST);
+    }
+
+    #[actix_web::test]
+    async fn test_redirect_transform_client_identity_spoofed() {
+        let test_app = TestApp::with_allowed_paths(
+            TestApp::mock("POST", "alerts", ResponseTemplate::new(200), 1).await,
+            TestApp::allowed_path(
+                "alerts",
+                TestApp::route(
+                    vec![Method::POST],
+                    RouteOptions::default().with_transform(
+                        PayloadTransform::new(
+                            r#"{"identity": {{ headers["x-client-identity"] | default("none") }}}"#
+                                .to_string(),
+                            "application/json",
+                        )
+                        .unwrap(),
+                    ),
+                ),
+            ),
+            |web_hook_data| {
+                web_hook_data.with_client_identity_header(Some(
+                    reqwest::header::HeaderName::from_static("x-client-identity"),
+                ))
+            },
+        );
+        let app = test_app.init_service().await;
+
+        let req = test::TestRequest::post()
+            .uri("/alerts")
+            .insert_header(("X-Client-Identity", "CN=spoofed"))
+            .set_payload("{}")
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert!(resp.status().is_success());
+
+        let requests = test_app.mock_server().received_requests().await.unwrap();
+        assert_eq!(
+            serde_json::from_slice::<serde_json::Value>(&requests[0].body).unwrap(),
+            serde_json::json!({ "identity": "none" })
+        );
+    }
+
+    #[actix_web::test]
+    async fn test_redirect_form_filtered() {
+        let test_app = TestApp::with_allowed_paths(
+            TestApp::mock("POST", "slack", ResponseTemplate::new(200), 1).await,
+            TestApp::allowed_path(
+                "slack",
+                TestApp::route(
+                    vec![Method::POST],
+                    RouteOptions::default().with_filters(
+                        vec![EventFilter::new(
+                            FilterSource::Json("/command".to_string()),
+                            FilterOperator::Equals("/deploy".to_string()),
+                        )],
+                        actix_web::http::StatusCode::OK,
+                    ),
+                ),
+            ),
+            |web_hook_data| web_hook_data,
+        );
+        let app = test_app.init_service().await;
+
+        let request = |body: &'static str| {
+            test::TestRequest::post()
+                .uri("/slack")
+                .insert_header(("Content-Type", "application/x-www-form-urlencoded"))
+                .set_payload(body)
+                .to_request()
+        };
+
+        let resp = test::call_service(&app, request("command=%2Fstatus&text=api")).await;
+        assert_eq!(resp.status(), actix_web::http::StatusCode::OK);
+
+        // Forwarded unchanged
+        let body = "command=%2Fdeploy&text=api+prod";
+        let resp = test::call_service(&app, request(body)).await;
+        assert!(resp.status().is_success());
+
+        let requests = test_app.mock_server().received_requests().await.unwrap();
+        assert_eq!(requests.len(), 1);
+        assert_eq!(requests[0].body, body.as_bytes());
+    }
+
+    async fn fan_out_app(
+        policy: FanOutPolicy,
+        status: u16,
+        audit_server: &wiremock::MockServer,
+    ) -> TestApp {
+        let audit_target = Target::new(
+            "audit".to_string(),
+            Url::parse(&audit_server.uri()).unwrap(),
+            client::build_client(None, None, false).unwrap(),
+        )
+        .with_auth(Some(UpstreamAuth::CloudflareAccess(
+            ServiceToken::new(
+                &SecretString::from("audit_id"),
+                &SecretString::from("audit_secret"),
+            )
+            .unwrap(),
+        )));
+
+        TestApp::with_allowed_paths(
+            TestApp::mock("POST", "test", ResponseTemplate::new(status), 1).await,
+            TestApp::allowed_path(
+                "test",
+                TestApp::route(
+                    vec![Method::POST],
+                    RouteOptions::default().with_fan_out(FanOut::new(
+                        vec!["audit".to_string()],
+                        policy,
+                        2,
+                        Duration::ZERO,
+                    )),
+                ),
+            ),
+            |web_hook_data| {
+                web_hook_data.with_targets(HashMap::from([("audit".to_string(), audit_target)]))
+            },
+        )
+    }
+
+    #[actix_web::test]
+    async fn test_redirect_fan_out_first() {
+        let audit_server = wiremock::MockServer::start().await;
+        Mock::given(wiremock::matchers::path("/test"))
+            .and(wiremock::matchers::header(
+                "CF-Access-Client-Id",
+                "audit_id",
+            ))
+            .respond_with(ResponseTemplate::new(503))
+            .up_to_n_times(2)
+            .mount(&audit_server)
+            .await;
+        Mock::given(wiremock::matchers::path("/test"))
+            .respond_with(ResponseTemplate::new(200))
+            .mount(&audit_server)
+            .await;
+
+        let test_app = fan_out_app(FanOutPolicy::First, 201, &audit_server).await;
+        let app = test_app.init_service().await;
+
+        let req = test::TestRequest::post().uri("/test").to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), actix_web::http::StatusCode::CREATED);
+
+        // The audit target is retried in the background until it succeeds, the shutdown waits for it
+        assert_eq!(test_app.shutdown().drain(Duration::from_secs(5)).await, 0);
+        assert_eq!(audit_server.received_requests().await.unwrap().len(), 3);
+    }
+
+    #[actix_web::test]
+    async fn test_redirect_fan_out_all() {
+        let audit_server = TestApp::mock("POST", "test", ResponseTemplate::new(500), 1).await;
+        let test_app = fan_out_app(FanOutPolicy::All, 200, &audit_server).await;
+        let app = test_app.init_service().await;
+
+        let req = test::TestRequest::post().uri("/test").to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(
+            resp.status(),
+            actix_web::http::StatusCode::INTERNAL_SERVER_ERROR
+        );
+    }
+
+    #[actix_web::test]
+    async fn test_redirect_fan_out_any() {
+        let audit_server = TestApp::mock("POST", "test", ResponseTemplate::new(202), 1).await;
+        let test_app = fan_out_app(FanOutPolicy::Any, 500, &audit_server).await;
+        let app = test_app.init_service().await;
+
+        let req = test::TestRequest::post().uri("/test").to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), actix_web::http::StatusCode::ACCEPTED);
+    }
+
+    #[actix_web::test]
+    async fn test_redirect_mirror() {
+        let shadow_server = TestApp::mock(
+            "POST",
+            "test",
+            ResponseTemplate::new(200).set_body_string("shadow"),
+            2,
+        )
+        .await;
+        let shadow_target = Target::new(
+            "mirror_shadow".to_string(),
+            Url::parse(&shadow_server.uri()).unwrap(),
+            client::build_client(None, None, false).unwrap(),
+        );
+
+        let test_app = TestApp::with_allowed_paths(
+            TestApp::mock(
+                "POST",
+                "test",
+                ResponseTemplate::new(200).set_body_string("primary"),
+                4,
+            )
+            .await,
+            TestApp::allowed_path(
+                "test",
+                TestApp::route(
+                    vec![Method::POST],
+                    RouteOptions::default()
+                        .with_mirror(Mirror::new("mirror_shadow".to_string(), 50.0)),
+                ),
+            ),
+            |web_hook_data| {
+                web_hook_data.with_targets(HashMap::from([(
+                    "mirror_shadow".to_string(),
+                    shadow_target,
+                )]))
+            },
+        );
+        let app = test_app.init_service().await;
+
+        // The caller always gets the primary response
+        for _ in 0..4 {
+            let req = test::TestRequest::post().uri("/test").to_request();
+            let resp = test::call_service(&app, req).await;
+            assert!(resp.status().is_success());
+            assert_eq!(test::read_body(resp).await, "primary");
+        }
+
+        let mismatches = METRICS
+            .mirrored()
+            .with_label_values(&["mirror_shadow", "body_mismatch"]);
+        for _ in 0..100 {
+            if mismatches.get() >= 2 {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        assert_eq!(mismatches.get(), 2);
+    }
+
+    #[actix_web::test]
+    async fn test_redirect_reloaded_web_hook_data() {
+        let test_app = TestApp::new("POST", "new", "POST", "old").await;
+        let app = test_app.init_service().await;
+
+        let req = test::TestRequest::post().uri("/new").to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), actix_web::http::StatusCode::NOT_FOUND);
+
+        // Swap the web hook data of the running app
+        let target = Target::new(
+            DEFAULT_TARGET_NAME.to_string(),
+            Url::parse(&test_app.mock_server().uri()).unwrap(),
+            client::build_client(None, None, false).unwrap(),
+        );
+        let web_hook_data = WebHookData::new(
+            target,
+            TestApp::allowed_path(
+                "new",
+                TestApp::route(vec![Method::POST], Default::default()),
+            ),
+            UpstreamAuth::CloudflareAccess(
+                ServiceToken::new(
+                    &SecretString::from("access-id"),
+                    &SecretString::from("access-secret"),
+                )
+                .unwrap(),
+            ),
+        );
+        test_app.web_hook_data().store(Arc::new(web_hook_data));
+
+        let req = test::TestRequest::post().uri("/new").to_request();
+        let resp = test::call_service(&app, req).await;
+        assert!(resp.status().is_success());
+    }
 }
@@ -1,37 +1,234 @@
 use crate::Result;
-use crate::data::WebHookData;
-use crate::routes::{health_check, redirect};
+use crate::config::{AdminConfig, ServerConfig, TlsConfig};
+use crate::reload::{SharedConfig, SharedWebHookData};
+use crate::routes::admin::AdminState;
+use crate::routes::{admin, health_check, metrics, redirect};
+use crate::shutdown::Shutdown;
+use crate::tls;
+use crate::tls::CertificateResolver;
+use actix_web::dev::ServerHandle;
+use actix_web::middleware::{Compress, Condition};
 use actix_web::{App, HttpServer, web};
-use derive_new::new;
+use std::sync::Arc;
+use std::time::Duration;
 use tracing_actix_web::TracingLogger;
 
-#[derive(new)]
 pub struct Server {
     host: String,
     port: u16,
+    tls: Option<TlsConfig>,
+    compress_responses: bool,
+    metrics_enabled: bool,
+    ready_enabled: bool,
+    // Time between failing readiness and no longer accepting connections
+    shutdown_delay: Duration,
+    // Time requests in flight and background deliveries get to finish
+    shutdown_timeout: Duration,
+    admin: Option<AdminConfig>,
+}
+
+impl From<&ServerConfig> for Server {
+    fn from(config: &ServerConfig) -> Self {
+        Self {
+            host: config.host().clone(),
+            port: *config.port(),
+            tls: config.tls().clone(),
+            compress_responses: *config.compress_responses(),
+            metrics_enabled: *config.metrics_enabled(),
+            ready_enabled: *config.ready_enabled(),
+            shutdown_delay: Duration::from_secs(*config.shutdown_delay()),
+            shutdown_timeout: Duration::from_secs(*config.shutdown_timeout()),
+            admin: config.admin().clone(),
+        }
+    }
 }
 
 impl Server {
-    pub async fn run_until_stopped(&self, web_hook_data: WebHookData) -> Result<()> {
+    /// Serve until SIGTERM or SIGINT and drain the requests in flight before returning.
+    pub async fn run_until_stopped(
+        &self,
+        web_hook_data: Arc<SharedWebHookData>,
+        config: Arc<SharedConfig>,
+    ) -> Result<()> {
         info!(
-            "Starting server on {}:{} with allowed paths {:#?}",
+            "Starting server on {}:{} with {} routes",
             self.host,
             self.port,
-            web_hook_data.allowed_paths().allowed_paths()
+            web_hook_data.load().allowed_paths().allowed_paths().len()
         );
 
-        let web_hook_data = web::Data::new(web_hook_data);
-        let server = HttpServer::new(move || {
+        let admin_server = match &self.admin {
+            Some(admin_config) => {
+                Some(self.start_admin(admin_config, web_hook_data.clone(), config)?)
+            }
+            None => None,
+        };
+
+        let web_hook_data = web::Data::from(web_hook_data);
+        let shutdown = web::Data::new(Shutdown::default());
+        let app_shutdown = shutdown.clone();
+        let compress_responses = self.compress_responses;
+        let metrics_enabled = self.metrics_enabled;
+        let ready_enabled = self.ready_enabled;
+        let mut server = HttpServer::new(move || {
             App::new()
+                .wrap(Condition::new(compress_responses, Compress::default()))
                 .wrap(TracingLogger::default())
                 .app_data(web_hook_data.clone())
+                .app_data(app_shutdown.clone())
                 .configure(health_check::get_config)
+                .configure(|cfg| {
+                    if ready_enabled {
+                        health_check::get_ready_config(cfg);
+                    }
+                })
+                .configure(|cfg| {
+                    if metrics_enabled {
+                        metrics::get_config(cfg);
+                    }
+                })
                 .configure(redirect::get_config)
         })
-        .bind((self.host.clone(), self.port))?;
+        .on_connect(tls::on_connect)
+        // Signals are handled below to drain requests and background deliveries first
+        .disable_signals();
+
+        match &self.tls {
+            Some(tls_config) => {
+                let resolver = Arc::new(CertificateResolver::new(
+                    tls_config.cert_path().clone(),
+                    tls_config.key_path().clone(),
+                )?);
+                resolver
+                    .clone()
+                    .spawn_reload(Duration::from_secs(*tls_config.reload_interval()));
+
+                info!("Listening for HTTPS on {}:{}", self.host, tls_config.port());
+                let client_verifier = match tls_config.client_ca_path() {
+                    Some(ca_path) => {
+                        info!(
+                            "Verifying client certificates against {}",
+                            ca_path.display()
+                        );
+                        Some(tls::client_verifier(
+                            ca_path,
+                            *tls_config.client_auth_required(),
+                        )?)
+                    }
+                    None => None,
+                };
+
+                server = server.bind_rustls_0_23(
+                    (self.host.clone(), *tls_config.port()),
+                    tls::server_config(resolver, client_verifier)?,
+                )?;
 
-        server.run().await?;
+                if *tls_config.http_enabled() {
+                    info!("Listening for HTTP on {}:{}", self.host, self.port);
+                    server = server.bind((self.host.clone(), self.port))?;
+                }
+            }
+            None => {
+                server = server.bind((self.host.clone(), self.port))?;
+            }
+        }
+
+        let server = server.run();
+        tokio::spawn(stop_on_signal(
+            server.handle(),
+            shutdown.into_inner(),
+            self.shutdown_delay,
+            self.shutdown_timeout,
+        ));
+        server.await?;
+        if let Some(admin_server) = admin_server {
+            admin_server.stop(true).await;
+        }
+        info!("Server stopped");
 
         Ok(())
     }
+
+    /// Serve the admin API on its own listener, stopped after the main server.
+    fn start_admin(
+        &self,
+        admin_config: &AdminConfig,
+        web_hook_data: Arc<SharedWebHookData>,
+        config: Arc<SharedConfig>,
+    ) -> Result<ServerHandle> {
+        let state = web::Data::new(AdminState::new(
+            config,
+            web_hook_data,
+            admin_config.api_key().clone().try_into()?,
+        ));
+
+        info!(
+            "Listening for admin requests on {}:{}",
+            admin_config.host(),
+            admin_config.port()
+        );
+        let server = HttpServer::new(move || {
+            App::new()
+                .wrap(TracingLogger::default())
+                .app_data(state.clone())
+                .configure(admin::get_config)
+        })
+        .workers(1)
+        .disable_signals()
+        .bind((admin_config.host().clone(), *admin_config.port()))?
+        .run();
+        let handle = server.handle();
+        tokio::spawn(server);
+
+        Ok(handle)
+    }
+}
+
+async fn stop_on_signal(
+    server: ServerHandle,
+    shutdown: Arc<Shutdown>,
+    delay: Duration,
+    timeout: Duration,
+) {
+    if let Err(e) = wait_for_signal().await {
+        error!("Failed to listen for shutdown signals: {:?}", e);
+        return;
+    }
+
+    info!(
+        "Shutting down, accepting connections for another {}s",
+        delay.as_secs()
+    );
+    shutdown.begin();
+    tokio::time::sleep(delay).await;
+
+    // Background deliveries run on the workers, so they are drained before the workers stop
+    server.pause().await;
+    info!("Draining requests for up to {}s", timeout.as_secs());
+    let remaining = shutdown.drain(timeout).await;
+    if remaining > 0 {
+        warn!(
+            "Drain timeout reached with {} requests or deliveries still running",
+            remaining
+        );
+    }
+
+    // Requests were drained above, so connections still open are closed right away
+    server.stop(false).await;
+}
+
+async fn wait_for_signal() -> Result<()> {
+    #[cfg(unix)]
+    {
+        let mut terminate =
+            tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate())?;
+        tokio::select! {
+            _ = terminate.recv() => {}
+            result = tokio::signal::ctrl_c() => result?,
+        }
+    }
+    #[cfg(not(unix))]
+    tokio::signal::ctrl_c().await?;
+
+    Ok(())
 }
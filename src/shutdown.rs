@@ -0,0 +1,72 @@
+use std::future::Future;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::Duration;
+use tokio_util::task::TaskTracker;
+use tokio_util::task::task_tracker::{TaskTrackerToken, TrackedFuture};
+
+/// Readiness of the server and the work that has to finish before it stops.
+#[derive(Debug, Default)]
+pub struct Shutdown {
+    draining: AtomicBool,
+    // Requests in flight and background deliveries
+    tasks: TaskTracker,
+}
+
+impl Shutdown {
+    /// Ready until the shutdown starts.
+    pub fn is_ready(&self) -> bool {
+        !self.draining.load(Ordering::Relaxed)
+    }
+
+    /// Fail readiness, so load balancers stop sending new requests.
+    pub fn begin(&self) {
+        self.draining.store(true, Ordering::Relaxed);
+    }
+
+    /// Keep the shutdown waiting while the token is alive.
+    pub fn token(&self) -> TaskTrackerToken {
+        self.tasks.token()
+    }
+
+    /// Keep the shutdown waiting until the future completed.
+    pub fn track<F: Future>(&self, future: F) -> TrackedFuture<F> {
+        self.tasks.track_future(future)
+    }
+
+    /// Wait for the tracked work up to the timeout. Returns the number of tasks still running.
+    pub async fn drain(&self, timeout: Duration) -> usize {
+        self.tasks.close();
+        let _ = tokio::time::timeout(timeout, self.tasks.wait()).await;
+
+        self.tasks.len()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::shutdown::Shutdown;
+    use std::time::Duration;
+
+    #[tokio::test]
+    async fn test_drain() {
+        let shutdown = Shutdown::default();
+        assert!(shutdown.is_ready());
+
+        let token = shutdown.token();
+        tokio::spawn(shutdown.track(tokio::time::sleep(Duration::from_millis(50))));
+        shutdown.begin();
+        assert!(!shutdown.is_ready());
+
+        drop(token);
+        assert_eq!(shutdown.drain(Duration::from_secs(5)).await, 0);
+    }
+
+    #[tokio::test]
+    async fn test_drain_timeout() {
+        let shutdown = Shutdown::default();
+        let _token = shutdown.token();
+        tokio::spawn(shutdown.track(tokio::time::sleep(Duration::from_secs(60))));
+
+        assert_eq!(shutdown.drain(Duration::from_millis(50)).await, 2);
+    }
+}
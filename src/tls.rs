@@ -0,0 +1,507 @@
+use crate::Result;
+use crate::error::Error;
+use actix_tls::accept::rustls_0_23::TlsStream;
+use actix_web::dev::Extensions;
+use actix_web::rt::net::TcpStream;
+use derive_new::new;
+use rustls::RootCertStore;
+use rustls::crypto::CryptoProvider;
+use rustls::pki_types::pem::PemObject;
+use rustls::pki_types::{CertificateDer, PrivateKeyDer};
+use rustls::server::danger::ClientCertVerifier;
+use rustls::server::{ClientHello, ResolvesServerCert, WebPkiClientVerifier};
+use rustls::sign::CertifiedKey;
+use std::any::Any;
+use std::fmt::{Display, Formatter};
+use std::net::IpAddr;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::{Duration, SystemTime};
+
+pub fn crypto_provider() -> Arc<CryptoProvider> {
+    Arc::new(rustls::crypto::ring::default_provider())
+}
+
+pub fn load_certificates(path: &Path) -> Result<Vec<CertificateDer<'static>>> {
+    let certificates = CertificateDer::pem_file_iter(path)
+        .and_then(|certificates| certificates.collect::<std::result::Result<Vec<_>, _>>())
+        .map_err(|e| {
+            Error::custom(format!(
+                "Failed to read certificates from {}: {e}",
+                path.display()
+            ))
+        })?;
+
+    if certificates.is_empty() {
+        return Err(Error::custom(format!(
+            "No certificates found in {}",
+            path.display()
+        )));
+    }
+
+    Ok(certificates)
+}
+
+pub fn load_private_key(path: &Path) -> Result<PrivateKeyDer<'static>> {
+    PrivateKeyDer::from_pem_file(path).map_err(|e| {
+        Error::custom(format!(
+            "Failed to read private key from {}: {e}",
+            path.display()
+        ))
+    })
+}
+
+/// Serves the certificate from the given PEM files and picks up renewed files without a restart.
+#[derive(Debug)]
+pub struct CertificateResolver {
+    cert_path: PathBuf,
+    key_path: PathBuf,
+    certified_key: RwLock<Arc<CertifiedKey>>,
+    modified: Mutex<Option<SystemTime>>,
+}
+
+impl CertificateResolver {
+    pub fn new(cert_path: PathBuf, key_path: PathBuf) -> Result<Self> {
+        let modified = CertificateResolver::modified(&cert_path, &key_path);
+        let certified_key = CertificateResolver::load(&cert_path, &key_path)?;
+
+        Ok(Self {
+            cert_path,
+            key_path,
+            certified_key: RwLock::new(Arc::new(certified_key)),
+            modified: Mutex::new(modified),
+        })
+    }
+
+    fn load(cert_path: &Path, key_path: &Path) -> Result<CertifiedKey> {
+        let certificates = load_certificates(cert_path)?;
+        let private_key = load_private_key(key_path)?;
+
+        CertifiedKey::from_der(certificates, private_key, &crypto_provider()).map_err(Error::from)
+    }
+
+    /// The latest modification time of the certificate and key files.
+    fn modified(cert_path: &Path, key_path: &Path) -> Option<SystemTime> {
+        [cert_path, key_path]
+            .iter()
+            .filter_map(|path| std::fs::metadata(path).and_then(|m| m.modified()).ok())
+            .max()
+    }
+
+    pub fn certified_key(&self) -> Arc<CertifiedKey> {
+        self.certified_key
+            .read()
+            .expect("Certificate lock poisoned")
+            .clone()
+    }
+
+    /// Reload the certificate if the files changed since the last load.
+    /// The current certificate is kept if the new files are invalid.
+    pub fn reload_if_changed(&self) -> Result<bool> {
+        let modified = CertificateResolver::modified(&self.cert_path, &self.key_path);
+        let mut last_modified = self.modified.lock().expect("Certificate lock poisoned");
+        if modified == *last_modified {
+            return Ok(false);
+        }
+
+        let certified_key = CertificateResolver::load(&self.cert_path, &self.key_path)?;
+        *self
+            .certified_key
+            .write()
+            .expect("Certificate lock poisoned") = Arc::new(certified_key);
+        *last_modified = modified;
+
+        Ok(true)
+    }
+
+    pub fn spawn_reload(self: Arc<Self>, interval: Duration) {
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(interval);
+            loop {
+                interval.tick().await;
+                match self.reload_if_changed() {
+                    Ok(true) => info!("Reloaded TLS certificate {}", self.cert_path.display()),
+                    Ok(false) => {}
+                    Err(e) => error!("Failed to reload TLS certificate: {}", e),
+                }
+            }
+        });
+    }
+}
+
+impl ResolvesServerCert for CertificateResolver {
+    fn resolve(&self, _client_hello: ClientHello<'_>) -> Option<Arc<CertifiedKey>> {
+        Some(self.certified_key())
+    }
+}
+
+/// Verifies client certificates against the CA bundle.
+/// Callers without a certificate are still accepted unless `required` is set.
+pub fn client_verifier(ca_path: &Path, required: bool) -> Result<Arc<dyn ClientCertVerifier>> {
+    let mut roots = RootCertStore::empty();
+    for certificate in load_certificates(ca_path)? {
+        roots.add(certificate)?;
+    }
+
+    let mut builder =
+        WebPkiClientVerifier::builder_with_provider(Arc::new(roots), crypto_provider());
+    if !required {
+        builder = builder.allow_unauthenticated();
+    }
+
+    builder
+        .build()
+        .map_err(|e| Error::custom(format!("Failed to build client verifier: {e}")))
+}
+
+pub fn server_config(
+    resolver: Arc<CertificateResolver>,
+    client_verifier: Option<Arc<dyn ClientCertVerifier>>,
+) -> Result<rustls::ServerConfig> {
+    let builder = rustls::ServerConfig::builder_with_provider(crypto_provider())
+        .with_safe_default_protocol_versions()?;
+
+    let config = match client_verifier {
+        Some(client_verifier) => builder.with_client_cert_verifier(client_verifier),
+        None => builder.with_no_client_auth(),
+    }
+    .with_cert_resolver(resolver);
+
+    Ok(config)
+}
+
+/// Identity of a caller that presented a verified client certificate.
+#[derive(new, Getters, Clone, Debug, PartialEq)]
+#[getset(get = "pub")]
+pub struct ClientIdentity {
+    subject: String,
+    sans: Vec<String>,
+}
+
+impl ClientIdentity {
+    pub fn from_der(certificate: &CertificateDer<'_>) -> Result<Self> {
+        let (_, certificate) = x509_parser::parse_x509_certificate(certificate.as_ref())
+            .map_err(|e| Error::custom(format!("Failed to parse client certificate: {e}")))?;
+
+        let sans = certificate
+            .subject_alternative_name()
+            .map_err(|e| Error::custom(format!("Failed to parse client certificate: {e}")))?
+            .map(|extension| {
+                extension
+                    .value
+                    .general_names
+                    .iter()
+                    .filter_map(ClientIdentity::general_name_to_string)
+                    .collect()
+            })
+            .unwrap_or_default();
+
+        Ok(Self {
+            subject: certificate.subject().to_string(),
+            sans,
+        })
+    }
+
+    fn general_name_to_string(name: &x509_parser::extensions::GeneralName) -> Option<String> {
+        use x509_parser::extensions::GeneralName;
+
+        match name {
+            GeneralName::DNSName(value) => Some(value.to_string()),
+            GeneralName::RFC822Name(value) => Some(value.to_string()),
+            GeneralName::URI(value) => Some(value.to_string()),
+            GeneralName::IPAddress(bytes) => match bytes.len() {
+                4 => <[u8; 4]>::try_from(*bytes).ok().map(IpAddr::from),
+                16 => <[u8; 16]>::try_from(*bytes).ok().map(IpAddr::from),
+                _ => None,
+            }
+            .map(|ip| ip.to_string()),
+            _ => None,
+        }
+    }
+}
+
+impl Display for ClientIdentity {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.subject)
+    }
+}
+
+/// Store the verified client certificate identity of TLS connections in the connection data.
+pub fn on_connect(connection: &dyn Any, data: &mut Extensions) {
+    let Some(stream) = connection.downcast_ref::<TlsStream<TcpStream>>() else {
+        return;
+    };
+
+    let (_, session) = stream.get_ref();
+    let Some(certificate) = session
+        .peer_certificates()
+        .and_then(|certificates| certificates.first())
+    else {
+        return;
+    };
+
+    match ClientIdentity::from_der(certificate) {
+        Ok(identity) => {
+            data.insert(identity);
+        }
+        Err(e) => warn!("Failed to read client certificate identity: {}", e),
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod test_utils {
+    use rcgen::{
+        BasicConstraints, CertificateParams, CertifiedIssuer, CertifiedKey, DnType, IsCa, KeyPair,
+        generate_simple_self_signed,
+    };
+    use std::path::{Path, PathBuf};
+    use std::sync::Arc;
+
+    /// HTTPS server answering with the subject of the client certificate.
+    pub struct TestTlsServer {
+        pub port: u16,
+        pub ca: CertifiedIssuer<'static, KeyPair>,
+        pub ca_path: PathBuf,
+        pub server_key: CertifiedKey<KeyPair>,
+        pub dir: tempfile::TempDir,
+        handle: actix_web::dev::ServerHandle,
+    }
+
+    impl TestTlsServer {
+        pub fn start(client_auth_required: bool) -> Self {
+            use actix_web::{App, HttpRequest, HttpServer, web};
+
+            let dir = tempfile::tempdir().unwrap();
+            let ca = certificate_authority();
+            let ca_path = dir.path().join("ca.crt");
+            std::fs::write(&ca_path, ca.pem()).unwrap();
+
+            let server_key = issue(&ca, "localhost", vec!["localhost".to_string()]);
+            let cert_path = dir.path().join("server.crt");
+            let key_path = dir.path().join("server.key");
+            std::fs::write(&cert_path, server_key.cert.pem()).unwrap();
+            std::fs::write(&key_path, server_key.signing_key.serialize_pem()).unwrap();
+
+            let resolver = Arc::new(super::CertificateResolver::new(cert_path, key_path).unwrap());
+            let client_verifier = super::client_verifier(&ca_path, client_auth_required).unwrap();
+            let config = super::server_config(resolver, Some(client_verifier)).unwrap();
+
+            let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+            let port = listener.local_addr().unwrap().port();
+            let server = HttpServer::new(|| {
+                App::new().default_service(web::to(|request: HttpRequest| async move {
+                    request
+                        .conn_data::<super::ClientIdentity>()
+                        .map(|identity| identity.subject().clone())
+                        .unwrap_or_else(|| "anonymous".to_string())
+                }))
+            })
+            .on_connect(super::on_connect)
+            .workers(1)
+            .listen_rustls_0_23(listener, config)
+            .unwrap()
+            .run();
+            let handle = server.handle();
+            actix_web::rt::spawn(server);
+
+            Self {
+                port,
+                ca,
+                ca_path,
+                server_key,
+                dir,
+                handle,
+            }
+        }
+
+        pub fn url(&self) -> String {
+            format!("https://localhost:{}/", self.port)
+        }
+
+        /// Write a client certificate issued by the server CA.
+        pub fn write_client_certificate(&self, common_name: &str) -> (PathBuf, PathBuf) {
+            let client_key = issue(&self.ca, common_name, vec![]);
+            let cert_path = self.dir.path().join(format!("{common_name}.crt"));
+            let key_path = self.dir.path().join(format!("{common_name}.key"));
+            std::fs::write(&cert_path, client_key.cert.pem()).unwrap();
+            std::fs::write(&key_path, client_key.signing_key.serialize_pem()).unwrap();
+
+            (cert_path, key_path)
+        }
+
+        pub async fn stop(self) {
+            self.handle.stop(true).await;
+        }
+    }
+
+    pub fn certificate_authority() -> CertifiedIssuer<'static, KeyPair> {
+        let mut params = CertificateParams::new(vec![]).unwrap();
+        params.is_ca = IsCa::Ca(BasicConstraints::Unconstrained);
+        params
+            .distinguished_name
+            .push(DnType::CommonName, "Test CA");
+
+        CertifiedIssuer::self_signed(params, KeyPair::generate().unwrap()).unwrap()
+    }
+
+    pub fn issue(
+        issuer: &CertifiedIssuer<'static, KeyPair>,
+        common_name: &str,
+        sans: Vec<String>,
+    ) -> CertifiedKey<KeyPair> {
+        let mut params = CertificateParams::new(sans).unwrap();
+        params
+            .distinguished_name
+            .push(DnType::CommonName, common_name);
+
+        let signing_key = KeyPair::generate().unwrap();
+        let cert = params.signed_by(&signing_key, issuer).unwrap();
+        CertifiedKey { cert, signing_key }
+    }
+
+    pub fn write_self_signed(
+        dir: &Path,
+        name: &str,
+    ) -> (PathBuf, PathBuf, CertifiedKey<rcgen::KeyPair>) {
+        let certified_key = generate_simple_self_signed(vec!["localhost".to_string()]).unwrap();
+
+        let cert_path = dir.join(format!("{name}.crt"));
+        let key_path = dir.join(format!("{name}.key"));
+        std::fs::write(&cert_path, certified_key.cert.pem()).unwrap();
+        std::fs::write(&key_path, certified_key.signing_key.serialize_pem()).unwrap();
+
+        (cert_path, key_path, certified_key)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::test_utils::write_self_signed;
+    use super::*;
+    use std::fs::File;
+
+    fn set_modified(path: &Path, time: SystemTime) {
+        File::options()
+            .write(true)
+            .open(path)
+            .unwrap()
+            .set_modified(time)
+            .unwrap();
+    }
+
+    #[test]
+    fn test_client_identity_from_der() {
+        let ca = test_utils::certificate_authority();
+        let client = test_utils::issue(
+            &ca,
+            "ci",
+            vec!["ci.example.com".to_string(), "10.0.0.1".to_string()],
+        );
+
+        let identity = ClientIdentity::from_der(client.cert.der()).unwrap();
+        assert_eq!(identity.subject(), "CN=ci");
+        assert_eq!(
+            identity.sans(),
+            &vec!["ci.example.com".to_string(), "10.0.0.1".to_string()]
+        );
+    }
+
+    #[test]
+    fn test_client_verifier() {
+        let dir = tempfile::tempdir().unwrap();
+        let ca_path = dir.path().join("ca.crt");
+        std::fs::write(&ca_path, test_utils::certificate_authority().pem()).unwrap();
+
+        let verifier = client_verifier(&ca_path, true).unwrap();
+        assert!(verifier.client_auth_mandatory());
+
+        let verifier = client_verifier(&ca_path, false).unwrap();
+        assert!(!verifier.client_auth_mandatory());
+    }
+
+    #[test]
+    fn test_resolver_loads_certificate() {
+        let dir = tempfile::tempdir().unwrap();
+        let (cert_path, key_path, certified_key) = write_self_signed(dir.path(), "server");
+
+        let resolver = CertificateResolver::new(cert_path, key_path).unwrap();
+        assert_eq!(
+            resolver.certified_key().cert[0].as_ref(),
+            certified_key.cert.der().as_ref()
+        );
+    }
+
+    #[test]
+    fn test_resolver_invalid_files() {
+        let dir = tempfile::tempdir().unwrap();
+        let cert_path = dir.path().join("server.crt");
+        let key_path = dir.path().join("server.key");
+        std::fs::write(&cert_path, "invalid").unwrap();
+        std::fs::write(&key_path, "invalid").unwrap();
+
+        assert!(CertificateResolver::new(cert_path, key_path).is_err());
+    }
+
+    #[test]
+    fn test_resolver_reload() {
+        let dir = tempfile::tempdir().unwrap();
+        let (cert_path, key_path, _) = write_self_signed(dir.path(), "server");
+        let resolver = CertificateResolver::new(cert_path.clone(), key_path.clone()).unwrap();
+
+        // Unchanged files
+        assert!(!resolver.reload_if_changed().unwrap());
+
+        // Renewed certificate
+        let (_, _, renewed) = write_self_signed(dir.path(), "server");
+        let modified = SystemTime::now() + Duration::from_secs(60);
+        set_modified(&cert_path, modified);
+        set_modified(&key_path, modified);
+
+        assert!(resolver.reload_if_changed().unwrap());
+        assert_eq!(
+            resolver.certified_key().cert[0].as_ref(),
+            renewed.cert.der().as_ref()
+        );
+    }
+
+    #[test]
+    fn test_resolver_reload_keeps_certificate_on_error() {
+        let dir = tempfile::tempdir().unwrap();
+        let (cert_path, key_path, certified_key) = write_self_signed(dir.path(), "server");
+        let resolver = CertificateResolver::new(cert_path.clone(), key_path).unwrap();
+
+        std::fs::write(&cert_path, "invalid").unwrap();
+        set_modified(&cert_path, SystemTime::now() + Duration::from_secs(60));
+
+        assert!(resolver.reload_if_changed().is_err());
+        assert_eq!(
+            resolver.certified_key().cert[0].as_ref(),
+            certified_key.cert.der().as_ref()
+        );
+    }
+
+    #[actix_web::test]
+    async fn test_on_connect_client_identity() {
+        let server = test_utils::TestTlsServer::start(false);
+
+        let client_key = test_utils::issue(&server.ca, "ci", vec!["ci.example.com".to_string()]);
+        let identity = format!(
+            "{}{}",
+            client_key.cert.pem(),
+            client_key.signing_key.serialize_pem()
+        );
+        let client = reqwest::Client::builder()
+            .use_rustls_tls()
+            .add_root_certificate(
+                reqwest::Certificate::from_pem(server.ca.pem().as_bytes()).unwrap(),
+            )
+            .identity(reqwest::Identity::from_pem(identity.as_bytes()).unwrap())
+            .build()
+            .unwrap();
+
+        let response = client.get(server.url()).send().await.unwrap();
+        assert_eq!(response.text().await.unwrap(), "CN=ci");
+
+        server.stop().await;
+    }
+}